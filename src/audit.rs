@@ -0,0 +1,437 @@
+//! A local audit log of the statements run through
+//! [`crate::backend::TiberiusBackend`] and [`crate::sql_client`]: who ran
+//! what, where, and how it went.
+//!
+//! Each entry is one JSON line holding the local user, the server login,
+//! the profile, server and database, the SQL text, a SHA-256 of the
+//! parameter values (never the values), the rows returned or affected and
+//! the error, if any. Passwords in the SQL and the error are hidden with
+//! [`crate::auth::redact`]. Entries are hash-chained: each holds the hash of the
+//! one before it and a hash of itself, so [`AuditLog::verify`] finds an
+//! entry that was edited, removed or reordered. Cutting entries off the end
+//! leaves a valid chain; keep the head hash that `verify` prints somewhere
+//! else to notice that.
+//!
+//! Logging is off in the library until [`enable`] or [`init_from_env`] is
+//! called, which applies to connections opened afterwards; the command
+//! line tools call the latter.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use futures_util::{AsyncRead, AsyncWrite};
+use serde_json::{json, Value as Json};
+use sha2::{Digest, Sha256};
+use tiberius::{Client, ToSql};
+
+use crate::profile;
+
+static LOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+
+/// The `prev` of the first entry.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Audit the connections opened from now on to the log at `path`.
+pub fn enable(path: impl Into<PathBuf>) {
+    *LOG.lock().unwrap() = Some(AuditLog::new(path));
+}
+
+pub fn disable() {
+    *LOG.lock().unwrap() = None;
+}
+
+pub fn enabled() -> bool {
+    LOG.lock().unwrap().is_some()
+}
+
+/// Log to `TIBERIUS_AUDIT_LOG` if set, or to [`default_path`]. Setting it
+/// to `off` turns logging off.
+pub fn init_from_env() {
+    match std::env::var("TIBERIUS_AUDIT_LOG") {
+        Ok(value) if value.eq_ignore_ascii_case("off") => disable(),
+        Ok(path) => enable(path),
+        Err(_) => enable(default_path()),
+    }
+}
+
+/// `audit.log` next to the profiles file.
+pub fn default_path() -> PathBuf {
+    profile::config_dir().join("audit.log")
+}
+
+/// The log new connections are audited to, if any.
+pub fn current() -> Option<AuditLog> {
+    LOG.lock().unwrap().clone()
+}
+
+/// The [`current`] log and the session of `client`, connected to `server`,
+/// for auditing statements run on a connection of its own. `None` when
+/// nothing is audited.
+pub async fn session<S>(client: &mut Client<S>, server: &str) -> anyhow::Result<Option<(AuditLog, Session)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let Some(log) = current() else {
+        return Ok(None);
+    };
+    let mut session = Session::new(server);
+    session.identify(client).await?;
+    Ok(Some((log, session)))
+}
+
+/// Who is connected to what.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+    /// The local account running the tool.
+    pub os_user: String,
+    /// The login as the server names it, e.g. `CORP\ana` or `ci_reader`.
+    pub login: Option<String>,
+    pub profile: Option<String>,
+    pub server: String,
+    pub database: Option<String>,
+}
+
+impl Session {
+    pub fn new(server: &str) -> Self {
+        Session {
+            os_user: std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .unwrap_or_default(),
+            server: server.to_string(),
+            ..Session::default()
+        }
+    }
+
+    /// Ask the server for the login and database of the connection.
+    pub async fn identify<S>(&mut self, client: &mut Client<S>) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let row = client.simple_query("SELECT SUSER_SNAME(), DB_NAME()").await?.into_row().await?;
+        if let Some(row) = row {
+            self.login = row.get::<&str, _>(0).map(str::to_string);
+            self.database = row.get::<&str, _>(1).map(str::to_string);
+        }
+        Ok(())
+    }
+}
+
+/// One statement in the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Counts from 1.
+    pub seq: u64,
+    pub time: DateTime<Utc>,
+    pub session: Session,
+    /// `query`, `execute` or `batch`.
+    pub operation: String,
+    pub sql: String,
+    /// Hex SHA-256 of the parameter values; `None` without parameters.
+    pub params_sha256: Option<String>,
+    pub rows: Option<u64>,
+    /// Why the statement failed; `None` when it succeeded.
+    pub error: Option<String>,
+    /// The hash of the entry before.
+    pub prev: String,
+    pub hash: String,
+}
+
+impl Entry {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+
+    fn body(&self) -> Json {
+        json!({
+            "seq": self.seq,
+            "time": self.time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            "os_user": self.session.os_user,
+            "login": self.session.login,
+            "profile": self.session.profile,
+            "server": self.session.server,
+            "database": self.session.database,
+            "operation": self.operation,
+            "sql": self.sql,
+            "params_sha256": self.params_sha256,
+            "rows": self.rows,
+            "error": self.error,
+            "prev": self.prev,
+        })
+    }
+
+    /// The hash of everything but `hash`.
+    fn digest(&self) -> String {
+        hex(&Sha256::digest(self.body().to_string()))
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = self.body();
+        json["hash"] = Json::String(self.hash.clone());
+        json.to_string()
+    }
+
+    pub fn from_json(line: &str) -> anyhow::Result<Self> {
+        let json: Json = serde_json::from_str(line)?;
+        let string = |field: &str| match &json[field] {
+            Json::String(s) => Ok(s.clone()),
+            _ => Err(anyhow::anyhow!("no {:?}", field)),
+        };
+        let optional = |field: &str| match &json[field] {
+            Json::String(s) => Ok(Some(s.clone())),
+            Json::Null => Ok(None),
+            _ => Err(anyhow::anyhow!("{:?} must be a string or null", field)),
+        };
+
+        Ok(Entry {
+            seq: json["seq"].as_u64().ok_or_else(|| anyhow::anyhow!("no \"seq\""))?,
+            time: DateTime::parse_from_rfc3339(&string("time")?)?.with_timezone(&Utc),
+            session: Session {
+                os_user: string("os_user")?,
+                login: optional("login")?,
+                profile: optional("profile")?,
+                server: string("server")?,
+                database: optional("database")?,
+            },
+            operation: string("operation")?,
+            sql: string("sql")?,
+            params_sha256: optional("params_sha256")?,
+            rows: match &json["rows"] {
+                Json::Null => None,
+                rows => Some(rows.as_u64().ok_or_else(|| anyhow::anyhow!("\"rows\" must be a count"))?),
+            },
+            error: optional("error")?,
+            prev: string("prev")?,
+            hash: string("hash")?,
+        })
+    }
+
+    fn matches(&self, filter: &Filter) -> bool {
+        let contains = |value: &str, wanted: &Option<String>| {
+            wanted
+                .as_ref()
+                .is_none_or(|w| value.to_lowercase().contains(&w.to_lowercase()))
+        };
+        let optional = |value: &Option<String>, wanted: &Option<String>| {
+            wanted.is_none() || value.as_deref().is_some_and(|v| contains(v, wanted))
+        };
+
+        contains(&self.sql, &filter.sql)
+            && contains(&self.session.os_user, &filter.os_user)
+            && optional(&self.session.login, &filter.login)
+            && optional(&self.session.profile, &filter.profile)
+            && contains(&self.session.server, &filter.server)
+            && optional(&self.session.database, &filter.database)
+            && filter.since.is_none_or(|since| self.time >= since)
+            && filter.until.is_none_or(|until| self.time < until)
+            && (!filter.failed || !self.succeeded())
+    }
+}
+
+/// What [`AuditLog::search`] looks for. Text fields match a
+/// case-insensitive substring; unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub sql: Option<String>,
+    pub os_user: Option<String>,
+    pub login: Option<String>,
+    pub profile: Option<String>,
+    pub server: Option<String>,
+    pub database: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only statements that failed.
+    pub failed: bool,
+}
+
+/// The result of a successful [`AuditLog::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verified {
+    pub entries: u64,
+    /// The hash of the last entry; the genesis hash for an empty log.
+    pub head: String,
+}
+
+/// An audit log file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        AuditLog { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an entry for a statement run in `session`: the rows it
+    /// returned or affected, or why it failed.
+    pub fn record(
+        &self,
+        session: &Session,
+        operation: &str,
+        sql: &str,
+        params: &[&dyn ToSql],
+        outcome: Result<Option<u64>, String>,
+    ) -> anyhow::Result<Entry> {
+        let (rows, error) = match outcome {
+            Ok(rows) => (rows, None),
+            Err(error) => (None, Some(crate::auth::redact(&error))),
+        };
+        let entry = Entry {
+            seq: 0,
+            // The precision the log keeps.
+            time: Utc::now().trunc_subsecs(6),
+            session: session.clone(),
+            operation: operation.to_string(),
+            sql: crate::auth::redact(sql),
+            params_sha256: params_hash(params),
+            rows,
+            error,
+            prev: String::new(),
+            hash: String::new(),
+        };
+        self.append(entry)
+            .map_err(|e| anyhow::anyhow!("Could not write the audit log {}: {}", self.path.display(), e))
+    }
+
+    /// [`record`](Self::record) the result of a statement. The statement
+    /// has run either way, so a log that cannot be written is reported as
+    /// a warning instead of replacing its result.
+    pub fn record_result<T, E: fmt::Display>(
+        &self,
+        session: &Session,
+        operation: &str,
+        sql: &str,
+        params: &[&dyn ToSql],
+        result: &Result<T, E>,
+        rows: impl FnOnce(&T) -> Option<u64>,
+    ) {
+        let outcome = result.as_ref().map(rows).map_err(|e| format!("{:#}", e));
+        if let Err(e) = self.record(session, operation, sql, params, outcome) {
+            tracing::warn!("{:#}", e);
+        }
+    }
+
+    /// Chain `entry` to the last entry and append it, filling in its
+    /// `seq`, `prev` and `hash`. The file is locked meanwhile, so several
+    /// connections and processes can share a log.
+    pub fn append(&self, mut entry: Entry) -> anyhow::Result<Entry> {
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&self.path)?;
+        file.lock()?;
+
+        (entry.seq, entry.prev) = match last_line(&mut file)? {
+            Some(line) => {
+                let last = Entry::from_json(&line)
+                    .map_err(|e| anyhow::anyhow!("its last entry is damaged ({}); run `cargo-audit verify`", e))?;
+                (last.seq + 1, last.hash)
+            }
+            None => (1, GENESIS.to_string()),
+        };
+        entry.hash = entry.digest();
+
+        let mut line = entry.to_json();
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(entry)
+    }
+
+    /// Every entry, oldest first. A missing log has none.
+    pub fn entries(&self) -> anyhow::Result<Vec<Entry>> {
+        self.lines()?
+            .into_iter()
+            .enumerate()
+            .map(|(i, line)| Entry::from_json(&line).map_err(|e| anyhow::anyhow!("line {}: {}", i + 1, e)))
+            .collect()
+    }
+
+    /// The entries matching `filter`, oldest first.
+    pub fn search(&self, filter: &Filter) -> anyhow::Result<Vec<Entry>> {
+        Ok(self.entries()?.into_iter().filter(|e| e.matches(filter)).collect())
+    }
+
+    /// Check every entry's hash and its link to the entry before, failing
+    /// at the first that does not hold.
+    pub fn verify(&self) -> anyhow::Result<Verified> {
+        let mut head = GENESIS.to_string();
+        let mut entries = 0;
+
+        for (i, line) in self.lines()?.iter().enumerate() {
+            let number = i + 1;
+            let entry = Entry::from_json(line).map_err(|e| anyhow::anyhow!("line {}: {}", number, e))?;
+            if entry.to_json() != *line {
+                anyhow::bail!("line {}: not written by this tool; it was edited", number);
+            }
+            if entry.hash != entry.digest() {
+                anyhow::bail!("line {}: the hash does not match the entry; it was edited", number);
+            }
+            if entry.prev != head || entry.seq != entries + 1 {
+                anyhow::bail!(
+                    "line {}: does not follow the entry before it; entries were removed, added or reordered",
+                    number
+                );
+            }
+            head = entry.hash;
+            entries += 1;
+        }
+
+        Ok(Verified { entries, head })
+    }
+
+    fn lines(&self) -> anyhow::Result<Vec<String>> {
+        match File::open(&self.path) {
+            Ok(file) => Ok(BufReader::new(file).lines().collect::<io::Result<_>>()?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The last line of `file`, read from the end so a long log is not read
+/// in full.
+fn last_line(file: &mut File) -> anyhow::Result<Option<String>> {
+    let len = file.metadata()?.len();
+    let mut window = 4096;
+    loop {
+        let start = len.saturating_sub(window);
+        file.seek(SeekFrom::Start(start))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+
+        let tail = tail.strip_suffix(b"\n").unwrap_or(&tail);
+        if let Some(newline) = tail.iter().rposition(|&b| b == b'\n') {
+            return Ok(Some(String::from_utf8(tail[newline + 1..].to_vec())?));
+        }
+        if start == 0 {
+            return Ok((!tail.is_empty()).then(|| String::from_utf8(tail.to_vec())).transpose()?);
+        }
+        window *= 4;
+    }
+}
+
+/// A hash of the parameter values, so runs with the same values can be
+/// matched without logging personal data.
+fn params_hash(params: &[&dyn ToSql]) -> Option<String> {
+    if params.is_empty() {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    for param in params {
+        hasher.update(format!("{:?}\n", param.to_sql()));
+    }
+    Some(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
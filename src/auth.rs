@@ -0,0 +1,296 @@
+//! SQL Server logins, for machines where Windows authentication is not
+//! available.
+//!
+//! A password is never written into a profile or a connection string kept
+//! on disk. A [`PasswordSource`] says where to fetch it when connecting:
+//!
+//! - `prompt`: ask on the terminal, without echo
+//! - `env:<VAR>`: an environment variable
+//! - `file:<path>`: the first line of a file only its owner can read
+//! - `keyring`: the OS keyring (Secret Service, Keychain or Credential
+//!   Manager), with `--features keyring`
+//!
+//! The password is held in a [`Secret`], whose `Debug` and `Display` print
+//! `***`, and [`redact`] hides passwords in text bound for logs or error
+//! messages.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use tiberius::AuthMethod;
+
+const REDACTED: &str = "***";
+
+/// A password. Printing it shows `***`; [`Secret::expose`] gives the value.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// Where a password comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordSource {
+    Prompt,
+    Env(String),
+    File(PathBuf),
+    Keyring,
+}
+
+impl FromStr for PasswordSource {
+    type Err = anyhow::Error;
+
+    /// Deliberately never echoes `s`, which may be a password typed in the
+    /// wrong place.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.eq_ignore_ascii_case("prompt") {
+            return Ok(PasswordSource::Prompt);
+        }
+        if s.eq_ignore_ascii_case("keyring") {
+            return Ok(PasswordSource::Keyring);
+        }
+        if let Some(var) = s.strip_prefix("env:").filter(|v| !v.is_empty()) {
+            return Ok(PasswordSource::Env(var.to_string()));
+        }
+        if let Some(path) = s.strip_prefix("file:").filter(|p| !p.is_empty()) {
+            return Ok(PasswordSource::File(PathBuf::from(path)));
+        }
+        anyhow::bail!(
+            "A password source is prompt, env:<VAR>, file:<path> or keyring; passwords themselves are not stored"
+        )
+    }
+}
+
+impl fmt::Display for PasswordSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordSource::Prompt => f.write_str("prompt"),
+            PasswordSource::Env(var) => write!(f, "env:{}", var),
+            PasswordSource::File(path) => write!(f, "file:{}", path.display()),
+            PasswordSource::Keyring => f.write_str("keyring"),
+        }
+    }
+}
+
+impl PasswordSource {
+    /// Fetch the password of `user` on `server`.
+    pub fn resolve(&self, user: &str, server: &str) -> anyhow::Result<Secret> {
+        match self {
+            PasswordSource::Prompt => {
+                let password = rpassword::prompt_password(format!("Password for {} on {}: ", user, server))?;
+                Ok(Secret(password))
+            }
+            PasswordSource::Env(var) => std::env::var(var)
+                .map(Secret)
+                .map_err(|_| anyhow::anyhow!("The password variable {} is not set", var)),
+            PasswordSource::File(path) => read_password_file(path),
+            PasswordSource::Keyring => keyring_get(user, server),
+        }
+    }
+}
+
+/// The first line of `path`. On Unix the file must not be readable by the
+/// group or others, as with ssh keys.
+fn read_password_file(path: &Path) -> anyhow::Result<Secret> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            anyhow::bail!(
+                "{} can be read by other users (mode {:o}); run `chmod 600 {}`",
+                path.display(),
+                mode & 0o777,
+                path.display()
+            );
+        }
+    }
+
+    let text = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let password = text.lines().next().unwrap_or_default();
+    if password.is_empty() {
+        anyhow::bail!("{} is empty", path.display());
+    }
+    Ok(Secret(password.to_string()))
+}
+
+/// The keyring entry of `user` on `server`.
+#[cfg(feature = "keyring")]
+fn keyring_entry(user: &str, server: &str) -> anyhow::Result<keyring::Entry> {
+    Ok(keyring::Entry::new(env!("CARGO_PKG_NAME"), &format!("{}@{}", user, server))?)
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_get(user: &str, server: &str) -> anyhow::Result<Secret> {
+    match keyring_entry(user, server)?.get_password() {
+        Ok(password) => Ok(Secret(password)),
+        Err(keyring::Error::NoEntry) => {
+            anyhow::bail!("The keyring has no password for {} on {}; store one with cargo-sqllogin", user, server)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_get(_user: &str, _server: &str) -> anyhow::Result<Secret> {
+    anyhow::bail!("Keyring support requires building with `--features keyring`")
+}
+
+/// Keep the password of `user` on `server` in the OS keyring.
+pub fn keyring_set(user: &str, server: &str, password: &Secret) -> anyhow::Result<()> {
+    #[cfg(feature = "keyring")]
+    return Ok(keyring_entry(user, server)?.set_password(password.expose())?);
+    #[cfg(not(feature = "keyring"))]
+    {
+        let _ = (user, server, password);
+        anyhow::bail!("Keyring support requires building with `--features keyring`")
+    }
+}
+
+/// Remove the password of `user` on `server` from the OS keyring.
+pub fn keyring_delete(user: &str, server: &str) -> anyhow::Result<()> {
+    #[cfg(feature = "keyring")]
+    return match keyring_entry(user, server)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.into()),
+    };
+    #[cfg(not(feature = "keyring"))]
+    {
+        let _ = (user, server);
+        anyhow::bail!("Keyring support requires building with `--features keyring`")
+    }
+}
+
+/// A SQL Server login and its password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqlLogin {
+    pub user: String,
+    pub password: Secret,
+}
+
+impl SqlLogin {
+    pub fn new(user: &str, password: Secret) -> Self {
+        SqlLogin {
+            user: user.to_string(),
+            password,
+        }
+    }
+
+    pub fn method(&self) -> AuthMethod {
+        AuthMethod::sql_server(&self.user, self.password.expose())
+    }
+
+    /// The login named by `TIBERIUS_USER`, if set. The password comes from
+    /// `TIBERIUS_PASSWORD_SOURCE`, else `TIBERIUS_PASSWORD`, else a prompt.
+    pub fn from_env(server: &str) -> anyhow::Result<Option<Self>> {
+        let Ok(user) = std::env::var("TIBERIUS_USER") else {
+            return Ok(None);
+        };
+
+        let source = match std::env::var("TIBERIUS_PASSWORD_SOURCE") {
+            Ok(source) => source.parse()?,
+            Err(_) if std::env::var_os("TIBERIUS_PASSWORD").is_some() => {
+                PasswordSource::Env("TIBERIUS_PASSWORD".to_string())
+            }
+            Err(_) => PasswordSource::Prompt,
+        };
+
+        let password = source.resolve(&user, server)?;
+        Ok(Some(SqlLogin::new(&user, password)))
+    }
+}
+
+/// The SQL login from the environment (see [`SqlLogin::from_env`]), or
+/// Windows authentication where that is available.
+pub fn default_method(server: &str) -> anyhow::Result<AuthMethod> {
+    if let Some(login) = SqlLogin::from_env(server)? {
+        return Ok(login.method());
+    }
+
+    #[cfg(windows)]
+    return Ok(AuthMethod::Integrated);
+    #[cfg(not(windows))]
+    anyhow::bail!("Windows authentication is only available on Windows; set TIBERIUS_USER to use a SQL login")
+}
+
+/// Replace the values of `password=` and `pwd=` in `text` with `***`, for
+/// connection strings that end up in logs or error messages. Quoted values
+/// (`"..."`, `'...'`, `{...}`) are hidden whole.
+pub fn redact(text: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let mut out = String::with_capacity(text.len());
+    let mut pos = 0;
+
+    while pos < text.len() {
+        let next = ["password", "pwd"]
+            .iter()
+            .filter_map(|key| {
+                lower[pos..]
+                    .match_indices(key)
+                    .map(|(i, _)| pos + i)
+                    .find(|&start| {
+                        let word_start = !lower[..start].ends_with(|c: char| c.is_alphanumeric() || c == '_');
+                        word_start && lower[start + key.len()..].trim_start_matches(' ').starts_with('=')
+                    })
+                    .map(|start| (start, key.len()))
+            })
+            .min();
+
+        let Some((start, key_len)) = next else {
+            break;
+        };
+
+        let eq = start + key_len + lower[start + key_len..].find('=').unwrap_or(0);
+        out.push_str(&text[pos..=eq]);
+        let value_start = eq + 1 + (text[eq + 1..].len() - text[eq + 1..].trim_start().len());
+        out.push_str(&text[eq + 1..value_start]);
+
+        let rest = &text[value_start..];
+        let value_len = match rest.chars().next() {
+            Some(open @ ('{' | '"' | '\'')) => {
+                let close = if open == '{' { '}' } else { open };
+                let mut end = rest.len();
+                let mut chars = rest.char_indices().skip(1).peekable();
+                while let Some((i, c)) = chars.next() {
+                    if c == close {
+                        if chars.peek().is_some_and(|(_, next)| *next == close) {
+                            chars.next();
+                        } else {
+                            end = i + c.len_utf8();
+                            break;
+                        }
+                    }
+                }
+                end
+            }
+            _ => rest.find([';', '\n']).unwrap_or(rest.len()),
+        };
+
+        out.push_str(REDACTED);
+        pos = value_start + value_len;
+    }
+
+    out.push_str(&text[pos..]);
+    out
+}
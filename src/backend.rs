@@ -0,0 +1,400 @@
+//! The database operations the CLI and tools need, behind one trait, so
+//! they can run against SQL Server or, for local development, a SQLite
+//! file.
+//!
+//! Statements use SQL Server's `@P1, @P2, ...` parameter names on every
+//! backend.
+
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tiberius::{Client, Config, ToSql};
+use tracing::Span;
+
+use crate::audit::{self, AuditLog};
+use crate::batch::{self, BatchItem, BatchResult, ResultSet};
+use crate::cancel::{self, CancelHandle, CancellableStream};
+use crate::guard;
+use crate::profile::Profile;
+use crate::schema::{self, TableColumn};
+use crate::sql_client;
+use crate::stats::Statistics;
+use crate::tls;
+use crate::telemetry::{self, Operation};
+use crate::value::{Record, Value};
+
+/// A connection to a database.
+pub trait Backend: Send {
+    /// A short name for messages, e.g. `sqlserver`.
+    fn name(&self) -> &'static str;
+
+    /// Run a statement and return the rows of its first result set.
+    fn query<'a>(
+        &'a mut self,
+        sql: &'a str,
+        params: &'a [Value],
+    ) -> BoxFuture<'a, anyhow::Result<Vec<Record>>>;
+
+    /// Run a statement and return the number of rows it affected.
+    fn execute<'a>(&'a mut self, sql: &'a str, params: &'a [Value]) -> BoxFuture<'a, anyhow::Result<u64>>;
+
+    /// Run several statements and return all their output. Backends that
+    /// cannot return a result set with its row counts and messages return
+    /// the first result set only.
+    fn batch<'a>(&'a mut self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<BatchResult>> {
+        async move {
+            let rows = self.query(sql, &[]).await?;
+            let columns = rows.first().map(|r| r.columns().to_vec()).unwrap_or_default();
+            Ok(BatchResult {
+                items: vec![BatchItem::ResultSet(ResultSet { columns, rows })],
+                complete: false,
+            })
+        }
+        .boxed()
+    }
+
+    /// The names of the user tables, schema-qualified where the backend
+    /// has schemas.
+    fn tables(&mut self) -> BoxFuture<'_, anyhow::Result<Vec<String>>>;
+
+    /// The columns of a table in their declared order.
+    fn columns<'a>(&'a mut self, table: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<TableColumn>>>;
+
+    /// The columns of a unique, non-nullable key to walk a table in order,
+    /// see [`KeysetReader`](crate::paging::KeysetReader). By default the
+    /// primary key.
+    fn keyset_columns<'a>(&'a mut self, table: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        async move {
+            let columns = self.columns(table).await?;
+            let key: Vec<String> = schema::primary_key(&columns).iter().map(|c| c.name.clone()).collect();
+            if key.is_empty() {
+                anyhow::bail!("Table {} has no primary key", table);
+            }
+            Ok(key)
+        }
+        .boxed()
+    }
+
+    /// Start a transaction.
+    fn begin(&mut self) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Roll back the open transaction, if there is one.
+    fn rollback(&mut self) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Cancel statements running longer than `timeout`; `None` lets them
+    /// run.
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> anyhow::Result<()> {
+        match timeout {
+            Some(_) => anyhow::bail!("Statements on {} cannot be timed out", self.name()),
+            None => Ok(()),
+        }
+    }
+
+    /// A handle to cancel the running statement from another task or a
+    /// signal handler, where the backend can.
+    fn cancel_handle(&self) -> Option<CancelHandle> {
+        None
+    }
+
+    /// Refuse statements that change anything, see
+    /// [`guard::check_read_only`].
+    fn set_read_only(&mut self, read_only: bool) -> anyhow::Result<()> {
+        if read_only {
+            anyhow::bail!("Read-only mode is not supported on {}", self.name());
+        }
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, anyhow::Result<()>>;
+}
+
+/// Open a backend from a target string: `sqlite:<path>` (or
+/// `sqlite::memory:`) for SQLite, anything else is read as an ADO.NET
+/// connection string for SQL Server, whose login `TIBERIUS_USER` replaces
+/// as [`sql_client::config_from_ado_string`] does.
+pub async fn open(target: &str) -> anyhow::Result<Box<dyn Backend>> {
+    match target.strip_prefix("sqlite:") {
+        #[cfg(feature = "sqlite")]
+        Some(path) => Ok(Box::new(crate::sqlite::SqliteBackend::open(path)?)),
+        #[cfg(not(feature = "sqlite"))]
+        Some(_) => anyhow::bail!("SQLite support requires building with `--features sqlite`"),
+        None => {
+            let config = sql_client::config_from_ado_string(target)?;
+            Ok(Box::new(TiberiusBackend::connect(config).await?))
+        }
+    }
+}
+
+/// SQL Server through tiberius. Statements can be cancelled and timed
+/// out, see [`cancel`], and are written to the audit log when one is on,
+/// see [`audit`].
+pub struct TiberiusBackend {
+    client: Client<CancellableStream>,
+    cancel: CancelHandle,
+    timeout: Option<Duration>,
+    read_only: bool,
+    audit: Option<AuditLog>,
+    session: audit::Session,
+    /// Known when tracing was on at connect time.
+    spid: Option<i16>,
+}
+
+impl TiberiusBackend {
+    pub async fn connect(config: Config) -> anyhow::Result<Self> {
+        let addr = config.get_addr();
+        let session = audit::Session::new(&addr);
+        let (mut client, cancel, spid) = cancel::connect_traced_at(addr, config, None).await?;
+        let audit = audit::current();
+        Ok(TiberiusBackend {
+            session: identify(&mut client, session, audit.is_some()).await?,
+            audit,
+            client,
+            cancel,
+            timeout: None,
+            read_only: false,
+            spid,
+        })
+    }
+
+    /// Connect with a profile: its TLS settings, its statement timeout and
+    /// whether it is read-only.
+    pub async fn connect_profile(profile: &Profile) -> anyhow::Result<Self> {
+        Self::connect_profile_with(profile, profile.config()?).await
+    }
+
+    /// [`connect_profile`](Self::connect_profile) with the profile's
+    /// configuration made already, so its password is fetched only once.
+    pub(crate) async fn connect_profile_with(profile: &Profile, config: Config) -> anyhow::Result<Self> {
+        let (addr, config) = tls::prepare(config, &profile.tls)?;
+        let (mut client, cancel, spid) =
+            cancel::connect_traced_at(addr, config, Some(&profile.tls)).await.map_err(tls::explain)?;
+        let session = audit::Session {
+            profile: Some(profile.name.clone()),
+            ..audit::Session::new(&profile.server)
+        };
+        let audit = audit::current();
+        Ok(TiberiusBackend {
+            session: identify(&mut client, session, audit.is_some()).await?,
+            audit,
+            client,
+            cancel,
+            timeout: profile.query_timeout,
+            read_only: profile.read_only,
+            spid,
+        })
+    }
+
+    /// The underlying client, for SQL Server-only features. Statements run
+    /// on it directly are not timed out; wrap them in [`cancel::run`].
+    pub fn client(&mut self) -> &mut Client<CancellableStream> {
+        &mut self.client
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Audit the statements of this connection to `log`, or stop with
+    /// `None`. By default connections use [`audit::current`].
+    pub async fn set_audit_log(&mut self, log: Option<AuditLog>) -> anyhow::Result<()> {
+        if log.is_some() && self.audit.is_none() {
+            self.session.identify(&mut self.client).await?;
+        }
+        self.audit = log;
+        Ok(())
+    }
+
+    /// Write the statement to the audit log, if there is one.
+    fn audit<T>(
+        &self,
+        operation: &str,
+        sql: &str,
+        params: &[&dyn ToSql],
+        result: &anyhow::Result<T>,
+        rows: impl FnOnce(&T) -> Option<u64>,
+    ) {
+        if let Some(log) = &self.audit {
+            log.record_result(&self.session, operation, sql, params, result, rows);
+        }
+    }
+
+    fn check(&self, sql: &str) -> anyhow::Result<()> {
+        if self.read_only {
+            guard::check_read_only(sql)?;
+        }
+        Ok(())
+    }
+
+    fn span(&self, operation: Operation, sql: &str, params: &[&dyn ToSql]) -> Span {
+        let span = telemetry::statement_span(operation, sql, params);
+        if let Some(spid) = self.spid {
+            span.record("spid", spid);
+        }
+        span
+    }
+}
+
+/// Ask the server who the connection logged in as when statements are
+/// audited; it costs a round trip otherwise not needed.
+async fn identify(
+    client: &mut Client<CancellableStream>,
+    mut session: audit::Session,
+    audited: bool,
+) -> anyhow::Result<audit::Session> {
+    if audited {
+        session.identify(client).await?;
+    }
+    Ok(session)
+}
+
+const TABLES_SQL: &str = "
+SELECT TABLE_SCHEMA + '.' + TABLE_NAME
+FROM INFORMATION_SCHEMA.TABLES
+WHERE TABLE_TYPE = 'BASE TABLE'
+ORDER BY TABLE_SCHEMA, TABLE_NAME";
+
+impl Backend for TiberiusBackend {
+    fn name(&self) -> &'static str {
+        "sqlserver"
+    }
+
+    fn query<'a>(
+        &'a mut self,
+        sql: &'a str,
+        params: &'a [Value],
+    ) -> BoxFuture<'a, anyhow::Result<Vec<Record>>> {
+        async move {
+            let params: Vec<&dyn ToSql> = params.iter().map(|v| v as &dyn ToSql).collect();
+            let result = async {
+                self.check(sql)?;
+                let span = self.span(Operation::Query, sql, &params);
+                let client = &mut self.client;
+                let work = async { Ok(client.query(sql, &params).await?.into_first_result().await?) };
+                let rows = telemetry::traced(span, cancel::run(&self.cancel, self.timeout, work), |rows| {
+                    Some(rows.len() as u64)
+                })
+                .await?;
+                Ok(rows.into_iter().map(Record::from_row).collect::<Vec<_>>())
+            }
+            .await;
+            self.audit("query", sql, &params, &result, |rows| Some(rows.len() as u64));
+            result
+        }
+        .boxed()
+    }
+
+    fn execute<'a>(&'a mut self, sql: &'a str, params: &'a [Value]) -> BoxFuture<'a, anyhow::Result<u64>> {
+        async move {
+            let params: Vec<&dyn ToSql> = params.iter().map(|v| v as &dyn ToSql).collect();
+            let result = async {
+                self.check(sql)?;
+                let span = self.span(Operation::Execute, sql, &params);
+                let client = &mut self.client;
+                let work = async { Ok(client.execute(sql, &params).await?) };
+                let result = telemetry::traced(span, cancel::run(&self.cancel, self.timeout, work), |r| {
+                    Some(r.rows_affected().iter().sum())
+                })
+                .await?;
+                Ok(result.total())
+            }
+            .await;
+            self.audit("execute", sql, &params, &result, |&rows| Some(rows));
+            result
+        }
+        .boxed()
+    }
+
+    fn batch<'a>(&'a mut self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<BatchResult>> {
+        async move {
+            let result = async {
+                self.check(sql)?;
+                let span = self.span(Operation::Query, sql, &[]);
+                let work = batch::run(&mut self.client, &self.cancel, sql);
+                let result = telemetry::traced(span.clone(), cancel::run(&self.cancel, self.timeout, work), |result| {
+                    Some(result.result_sets().map(|set| set.rows.len() as u64).sum())
+                })
+                .await?;
+                Statistics::from_messages(result.messages()).record(&span);
+                Ok(result)
+            }
+            .await;
+            // Rows returned and affected, as for single statements.
+            self.audit("batch", sql, &[], &result, |result| {
+                let returned: u64 = result.result_sets().map(|set| set.rows.len() as u64).sum();
+                Some(returned + result.rows_affected().iter().sum::<u64>())
+            });
+            result
+        }
+        .boxed()
+    }
+
+    fn tables(&mut self) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+        async move {
+            let span = self.span(Operation::Query, TABLES_SQL, &[]);
+            let client = &mut self.client;
+            let work = async { Ok(client.simple_query(TABLES_SQL).await?.into_first_result().await?) };
+            let rows = telemetry::traced(span, cancel::run(&self.cancel, self.timeout, work), |rows| {
+                Some(rows.len() as u64)
+            })
+            .await?;
+            Ok(rows
+                .iter()
+                .filter_map(|r| r.get::<&str, _>(0).map(str::to_string))
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn columns<'a>(&'a mut self, table: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<TableColumn>>> {
+        cancel::run(&self.cancel, self.timeout, schema::table_columns(&mut self.client, table)).boxed()
+    }
+
+    /// The primary key, else a unique clustered index, else any unique
+    /// index, see [`schema::keyset_columns`].
+    fn keyset_columns<'a>(&'a mut self, table: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        cancel::run(&self.cancel, self.timeout, schema::keyset_columns(&mut self.client, table)).boxed()
+    }
+
+    fn begin(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            self.batch("BEGIN TRAN").await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn rollback(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            self.batch("IF @@TRANCOUNT > 0 ROLLBACK").await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> anyhow::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_read_only(&mut self, read_only: bool) -> anyhow::Result<()> {
+        self.read_only = read_only;
+        Ok(())
+    }
+
+    fn cancel_handle(&self) -> Option<CancelHandle> {
+        Some(self.cancel.clone())
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, anyhow::Result<()>> {
+        async move {
+            self.client.close().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,438 @@
+//! Batches of several statements, with everything the server sent back in
+//! order: each result set with its columns and rows, the rows affected by
+//! each statement and the messages of `PRINT` and `RAISERROR` with a
+//! severity of 10 or less.
+//!
+//! tiberius returns the rows but drops row counts and messages, so those
+//! are read from a copy of the response kept by
+//! [`CancellableStream`](crate::cancel::CancellableStream). The copy can
+//! be read on every profile session, encrypted or not, as the crate does
+//! their TLS below the stream. When tiberius encrypts the session itself,
+//! as with a bare [`tiberius::Config`] asking for encryption, it cannot
+//! (see [`crate::cancel`]): [`BatchResult::complete`] is then false and
+//! only the result sets are returned.
+
+use std::fmt;
+
+use futures_util::TryStreamExt;
+use tiberius::{Client, QueryItem};
+
+use crate::cancel::{CancelHandle, CancellableStream};
+use crate::tds::{
+    Reader, DONE_COUNT, TOKEN_COLINFO, TOKEN_COLMETADATA, TOKEN_DONE, TOKEN_DONEINPROC, TOKEN_DONEPROC,
+    TOKEN_ENVCHANGE, TOKEN_ERROR, TOKEN_INFO, TOKEN_LOGINACK, TOKEN_NBCROW, TOKEN_ORDER, TOKEN_RETURNVALUE,
+    TOKEN_RETURN_STATUS, TOKEN_ROW, TOKEN_SESSIONSTATE, TOKEN_SSPI, TOKEN_TABNAME,
+};
+use crate::value::Record;
+
+/// The rows of one `SELECT`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResultSet {
+    /// Known even when there are no rows.
+    pub columns: Vec<String>,
+    pub rows: Vec<Record>,
+}
+
+/// A message from the server that did not fail the batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// 0 for `PRINT`, 50000 for `RAISERROR` with a text.
+    pub number: u32,
+    pub state: u8,
+    /// The severity: 0 for `PRINT`, 1 to 10 for warnings.
+    pub class: u8,
+    pub text: String,
+    /// Empty outside stored procedures.
+    pub procedure: String,
+    pub line: u32,
+}
+
+impl Message {
+    pub fn is_warning(&self) -> bool {
+        self.class > 0
+    }
+}
+
+impl fmt::Display for Message {
+    /// `PRINT` output as is, others as SQL Server Management Studio shows
+    /// them.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.is_warning() {
+            return f.write_str(&self.text);
+        }
+        write!(f, "Msg {}, Level {}, State {}, ", self.number, self.class, self.state)?;
+        if !self.procedure.is_empty() {
+            write!(f, "Procedure {}, ", self.procedure)?;
+        }
+        write!(f, "Line {}: {}", self.line, self.text)
+    }
+}
+
+/// One part of a batch's output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchItem {
+    ResultSet(ResultSet),
+    /// The rows an `INSERT`, `UPDATE`, `DELETE` or `MERGE` affected.
+    RowsAffected(u64),
+    Message(Message),
+}
+
+/// The output of a batch, in the order the server sent it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchResult {
+    pub items: Vec<BatchItem>,
+    /// Whether row counts and messages are included. `false` on a session
+    /// tiberius encrypts, where they cannot be read; see the module
+    /// documentation.
+    pub complete: bool,
+}
+
+impl BatchResult {
+    pub fn result_sets(&self) -> impl Iterator<Item = &ResultSet> {
+        self.items.iter().filter_map(|item| match item {
+            BatchItem::ResultSet(set) => Some(set),
+            _ => None,
+        })
+    }
+
+    /// The rows affected by each statement that changed rows. Always empty
+    /// when the result is not [`complete`](Self::complete).
+    pub fn rows_affected(&self) -> Vec<u64> {
+        self.items
+            .iter()
+            .filter_map(|item| match item {
+                BatchItem::RowsAffected(count) => Some(*count),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The `PRINT` output and warnings. Always empty when the result is not
+    /// [`complete`](Self::complete).
+    pub fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.items.iter().filter_map(|item| match item {
+            BatchItem::Message(message) => Some(message),
+            _ => None,
+        })
+    }
+}
+
+/// Run `sql` as a batch on the connection of `handle`. On a session
+/// tiberius encrypts only the result sets are returned, with
+/// [`BatchResult::complete`] false: check it before reading row counts or
+/// messages, whose absence then means nothing.
+pub async fn run(
+    client: &mut Client<CancellableStream>,
+    handle: &CancelHandle,
+    sql: &str,
+) -> anyhow::Result<BatchResult> {
+    let recording = handle.start_recording();
+    let sets = read_result_sets(client, sql).await;
+    let tokens = handle.stop_recording();
+    let sets = sets?;
+
+    if !recording {
+        return Ok(BatchResult {
+            items: sets.into_iter().map(BatchItem::ResultSet).collect(),
+            complete: false,
+        });
+    }
+
+    let mut items = Vec::new();
+    let mut sets = sets.into_iter();
+    // The DONE ending a result set counts its rows, which are not affected.
+    let mut in_result_set = false;
+    for event in scan(&tokens)? {
+        match event {
+            Event::ResultSet => {
+                let set = sets
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("The response has more result sets than tiberius returned"))?;
+                items.push(BatchItem::ResultSet(set));
+                in_result_set = true;
+            }
+            Event::Done(count) => {
+                match count {
+                    Some(count) if !in_result_set => items.push(BatchItem::RowsAffected(count)),
+                    _ => (),
+                }
+                in_result_set = false;
+            }
+            Event::Message(message) => items.push(BatchItem::Message(message)),
+        }
+    }
+
+    Ok(BatchResult { items, complete: true })
+}
+
+async fn read_result_sets(client: &mut Client<CancellableStream>, sql: &str) -> anyhow::Result<Vec<ResultSet>> {
+    let mut stream = client.simple_query(sql).await?;
+    let mut sets: Vec<ResultSet> = Vec::new();
+
+    while let Some(item) = stream.try_next().await? {
+        match item {
+            QueryItem::Metadata(meta) => sets.push(ResultSet {
+                columns: meta.columns().iter().map(|c| c.name().to_string()).collect(),
+                rows: Vec::new(),
+            }),
+            QueryItem::Row(row) => match sets.last_mut() {
+                Some(set) => set.rows.push(Record::from_row(row)),
+                None => anyhow::bail!("A row arrived before its columns"),
+            },
+        }
+    }
+
+    Ok(sets)
+}
+
+/// What the token stream says beyond the rows.
+#[derive(Debug)]
+enum Event {
+    ResultSet,
+    /// The end of a statement, with its row count if it has one.
+    Done(Option<u64>),
+    Message(Message),
+}
+
+/// How a column's values are laid out in a row.
+#[derive(Debug, Clone, Copy)]
+enum Layout {
+    Fixed(usize),
+    /// A 1-byte length, 0 for `NULL`.
+    ByteLen,
+    /// A 2-byte length, 0xFFFF for `NULL`.
+    UShortLen,
+    /// A 4-byte length.
+    LongLen,
+    /// Chunks of the `max` types, `xml` and CLR types.
+    Plp,
+    /// `text`, `ntext` and `image`: text pointer, timestamp and data.
+    Text,
+}
+
+/// Walk a response's tokens, skipping the rows tiberius has decoded.
+fn scan(tokens: &[u8]) -> anyhow::Result<Vec<Event>> {
+    let mut r = Reader::new(tokens);
+    let mut columns: Vec<Layout> = Vec::new();
+    let mut events = Vec::new();
+
+    while !r.rest().is_empty() {
+        match r.u8()? {
+            TOKEN_COLMETADATA => {
+                let count = r.u16()?;
+                // 0xFFFF: no metadata, the previous columns still apply.
+                if count != 0xFFFF {
+                    columns = (0..count).map(|_| column(&mut r)).collect::<anyhow::Result<_>>()?;
+                }
+                events.push(Event::ResultSet);
+            }
+            TOKEN_ROW => {
+                for &layout in &columns {
+                    skip_value(&mut r, layout)?;
+                }
+            }
+            TOKEN_NBCROW => {
+                let nulls = r.take(columns.len().div_ceil(8))?;
+                for (i, &layout) in columns.iter().enumerate() {
+                    if nulls[i / 8] & (1 << (i % 8)) == 0 {
+                        skip_value(&mut r, layout)?;
+                    }
+                }
+            }
+            TOKEN_DONE | TOKEN_DONEINPROC => {
+                let status = r.u16()?;
+                let _command = r.u16()?;
+                let count = r.u64()?;
+                events.push(Event::Done((status & DONE_COUNT != 0).then_some(count)));
+            }
+            // The end of a procedure, whose count repeats that of its last
+            // statement, already given by its DONEINPROC.
+            TOKEN_DONEPROC => {
+                r.take(12)?;
+            }
+            TOKEN_INFO => {
+                let len = r.u16()? as usize;
+                let mut info = Reader::new(r.take(len)?);
+                let number = info.u32()?;
+                let state = info.u8()?;
+                let class = info.u8()?;
+                let text = info.us_varchar()?;
+                let _server = info.b_varchar()?;
+                let procedure = info.b_varchar()?;
+                let line = info.u32()?;
+                events.push(Event::Message(Message {
+                    number,
+                    state,
+                    class,
+                    text,
+                    procedure,
+                    line,
+                }));
+            }
+            TOKEN_RETURN_STATUS => {
+                r.take(4)?;
+            }
+            TOKEN_RETURNVALUE => {
+                let _ordinal = r.u16()?;
+                let _name = r.b_varchar()?;
+                let _status = r.u8()?;
+                let _user_type = r.u32()?;
+                let _flags = r.u16()?;
+                let layout = type_info(&mut r)?;
+                skip_value(&mut r, layout)?;
+            }
+            TOKEN_ERROR | TOKEN_ENVCHANGE | TOKEN_ORDER | TOKEN_COLINFO | TOKEN_TABNAME | TOKEN_LOGINACK
+            | TOKEN_SSPI => {
+                let len = r.u16()? as usize;
+                r.take(len)?;
+            }
+            TOKEN_SESSIONSTATE => {
+                let len = r.u32()? as usize;
+                r.take(len)?;
+            }
+            other => anyhow::bail!("Unexpected token 0x{:02X} in the response", other),
+        }
+    }
+
+    Ok(events)
+}
+
+/// One column of a `COLMETADATA` token.
+fn column(r: &mut Reader) -> anyhow::Result<Layout> {
+    let _user_type = r.u32()?;
+    let _flags = r.u16()?;
+    let layout = type_info(r)?;
+    if let Layout::Text = layout {
+        for _ in 0..r.u8()? {
+            r.us_varchar()?;
+        }
+    }
+    let _name = r.b_varchar()?;
+    Ok(layout)
+}
+
+/// Read a `TYPE_INFO` and return how values of the type are laid out.
+fn type_info(r: &mut Reader) -> anyhow::Result<Layout> {
+    let layout = match r.u8()? {
+        // NULLTYPE
+        0x1F => Layout::Fixed(0),
+        // tinyint, bit
+        0x30 | 0x32 => Layout::Fixed(1),
+        // smallint
+        0x34 => Layout::Fixed(2),
+        // int, smalldatetime, real, smallmoney
+        0x38 | 0x3A | 0x3B | 0x7A => Layout::Fixed(4),
+        // money, datetime, float, bigint
+        0x3C | 0x3D | 0x3E | 0x7F => Layout::Fixed(8),
+        // Nullable guid, integer, bit, float, money and datetime: a length.
+        0x24 | 0x26 | 0x68 | 0x6D | 0x6E | 0x6F => {
+            r.u8()?;
+            Layout::ByteLen
+        }
+        // decimal, numeric: length, precision and scale.
+        0x37 | 0x3F | 0x6A | 0x6C => {
+            r.take(3)?;
+            Layout::ByteLen
+        }
+        // date
+        0x28 => Layout::ByteLen,
+        // time, datetime2, datetimeoffset: a scale.
+        0x29..=0x2B => {
+            r.u8()?;
+            Layout::ByteLen
+        }
+        // varbinary, binary
+        0xA5 | 0xAD => match r.u16()? {
+            0xFFFF => Layout::Plp,
+            _ => Layout::UShortLen,
+        },
+        // varchar, char, nvarchar, nchar: a length and a collation.
+        0xA7 | 0xAF | 0xE7 | 0xEF => {
+            let max_len = r.u16()?;
+            r.take(5)?;
+            match max_len {
+                0xFFFF => Layout::Plp,
+                _ => Layout::UShortLen,
+            }
+        }
+        // image
+        0x22 => {
+            r.u32()?;
+            Layout::Text
+        }
+        // text, ntext: a length and a collation.
+        0x23 | 0x63 => {
+            r.u32()?;
+            r.take(5)?;
+            Layout::Text
+        }
+        // xml, with an optional schema collection.
+        0xF1 => {
+            if r.u8()? != 0 {
+                r.b_varchar()?;
+                r.b_varchar()?;
+                r.us_varchar()?;
+            }
+            Layout::Plp
+        }
+        // CLR types: a length, the type's names and its assembly.
+        0xF0 => {
+            r.u16()?;
+            r.b_varchar()?;
+            r.b_varchar()?;
+            r.b_varchar()?;
+            r.us_varchar()?;
+            Layout::Plp
+        }
+        // sql_variant
+        0x62 => {
+            r.u32()?;
+            Layout::LongLen
+        }
+        other => anyhow::bail!("Unsupported column type 0x{:02X}", other),
+    };
+    Ok(layout)
+}
+
+fn skip_value(r: &mut Reader, layout: Layout) -> anyhow::Result<()> {
+    match layout {
+        Layout::Fixed(len) => {
+            r.take(len)?;
+        }
+        Layout::ByteLen => {
+            let len = r.u8()? as usize;
+            r.take(len)?;
+        }
+        Layout::UShortLen => match r.u16()? {
+            0xFFFF => (),
+            len => {
+                r.take(len as usize)?;
+            }
+        },
+        Layout::LongLen => {
+            let len = r.u32()? as usize;
+            r.take(len)?;
+        }
+        Layout::Plp => {
+            if r.u64()? != u64::MAX {
+                loop {
+                    match r.u32()? as usize {
+                        0 => break,
+                        len => {
+                            r.take(len)?;
+                        }
+                    }
+                }
+            }
+        }
+        Layout::Text => {
+            let pointer = r.u8()? as usize;
+            if pointer > 0 {
+                r.take(pointer + 8)?;
+                let len = r.u32()? as usize;
+                r.take(len)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,254 @@
+//! Benchmark a query or procedure: run it many times over a [`Pool`],
+//! as many at a time as the pool has connections, and report throughput,
+//! latency percentiles and errors.
+//!
+//! Parameters come from a CSV file, a row per run, taken in turn. For a
+//! query the columns are `@P1, @P2, ...` in order; for a procedure the
+//! header names its parameters. Empty cells are `NULL`. Values are sent
+//! as `nvarchar` and converted by the server.
+//!
+//! Latency is measured from sending the statement to reading its last
+//! row, on a connection already taken from the pool, so logins are not
+//! counted.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use serde_json::{json, Value as Json};
+
+use crate::ident::quote_table;
+use crate::pool::Pool;
+use crate::value::Value;
+
+/// The parameter sets of a benchmark.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParamSets {
+    /// The CSV header.
+    pub names: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl ParamSets {
+    pub fn from_csv(reader: impl Read) -> anyhow::Result<Self> {
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+        let names = reader.headers()?.iter().map(str::to_string).collect();
+        let rows = reader
+            .records()
+            .map(|r| {
+                Ok(r?
+                    .iter()
+                    .map(|cell| match cell {
+                        "" => Value::Null,
+                        cell => Value::String(cell.to_string()),
+                    })
+                    .collect())
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(ParamSets { names, rows })
+    }
+}
+
+/// `EXEC procedure @name = @P1, ...` for parameters named `names`, with
+/// or without their `@`.
+pub fn procedure_call(procedure: &str, names: &[String]) -> anyhow::Result<String> {
+    let mut sql = format!("EXEC {}", quote_table(procedure));
+    for (i, name) in names.iter().enumerate() {
+        let name = name.trim_start_matches('@');
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || "_#$@".contains(c)) {
+            anyhow::bail!("{:?} is not a parameter name", name);
+        }
+        write!(sql, "{} @{} = @P{}", if i == 0 { "" } else { "," }, name, i + 1).unwrap();
+    }
+    Ok(sql)
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Measured runs.
+    pub iterations: usize,
+    /// Runs before measuring, to fill caches and compile the plan. Their
+    /// errors are ignored.
+    pub warmup: usize,
+    /// Run `i` takes the parameters of row `i` modulo their number.
+    pub params: Vec<Vec<Value>>,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            iterations: 100,
+            warmup: 5,
+            params: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub sql: String,
+    pub started: DateTime<Utc>,
+    pub iterations: usize,
+    pub concurrency: usize,
+    pub warmup: usize,
+    /// Of the runs that succeeded, shortest first.
+    pub latencies: Vec<Duration>,
+    /// How often each error message came up.
+    pub errors: BTreeMap<String, usize>,
+    /// From the first measured run to the last.
+    pub elapsed: Duration,
+}
+
+/// Run `sql` as `options` say, on up to `pool.max_size()` connections at
+/// once. Statement errors are counted; failing to connect stops the
+/// benchmark.
+pub async fn run(pool: &Pool, sql: &str, options: &BenchOptions) -> anyhow::Result<BenchReport> {
+    runs(pool, sql, options, 0..options.warmup).await?;
+
+    let started = Utc::now();
+    let start = Instant::now();
+    let results = runs(pool, sql, options, options.warmup..options.warmup + options.iterations).await?;
+    let elapsed = start.elapsed();
+
+    let mut latencies = Vec::new();
+    let mut errors = BTreeMap::new();
+    for result in results {
+        match result {
+            Ok(latency) => latencies.push(latency),
+            Err(error) => *errors.entry(error).or_insert(0) += 1,
+        }
+    }
+    latencies.sort();
+
+    Ok(BenchReport {
+        sql: sql.to_string(),
+        started,
+        iterations: options.iterations,
+        concurrency: pool.max_size(),
+        warmup: options.warmup,
+        latencies,
+        errors,
+        elapsed,
+    })
+}
+
+/// The latency or error of each run in `range`.
+async fn runs(
+    pool: &Pool,
+    sql: &str,
+    options: &BenchOptions,
+    range: std::ops::Range<usize>,
+) -> anyhow::Result<Vec<Result<Duration, String>>> {
+    stream::iter(range)
+        .map(|i| async move {
+            let params: &[Value] = match options.params.len() {
+                0 => &[],
+                n => &options.params[i % n],
+            };
+            let mut db = pool.get().await?;
+            let start = Instant::now();
+            let result = db.query(sql, params).await;
+            let latency = start.elapsed();
+            match result {
+                Ok(_) => anyhow::Ok(Ok(latency)),
+                Err(e) => {
+                    // After a server error the connection is ready for the
+                    // next statement; any other may leave it mid-response.
+                    if !matches!(e.downcast_ref::<tiberius::error::Error>(), Some(tiberius::error::Error::Server(_))) {
+                        db.discard();
+                    }
+                    Ok(Err(e.to_string()))
+                }
+            }
+        })
+        .buffer_unordered(pool.max_size())
+        .try_collect()
+        .await
+}
+
+impl BenchReport {
+    pub fn error_count(&self) -> usize {
+        self.errors.values().sum()
+    }
+
+    /// Runs finished per second, failed ones included.
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => (self.latencies.len() + self.error_count()) as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// The latency `percent`% of the successful runs were at most, by the
+    /// nearest-rank method.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let rank = (percent / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies
+            .get(rank.clamp(1, self.latencies.len().max(1)) - 1)
+            .copied()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let total: Duration = self.latencies.iter().sum();
+        (!self.latencies.is_empty()).then(|| total / self.latencies.len() as u32)
+    }
+
+    /// Latencies in milliseconds, so runs can be compared with `jq` or a
+    /// spreadsheet.
+    pub fn to_json(&self) -> Json {
+        let ms = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+        json!({
+            "sql": self.sql,
+            "started": self.started.to_rfc3339(),
+            "iterations": self.iterations,
+            "concurrency": self.concurrency,
+            "warmup": self.warmup,
+            "elapsed_ms": self.elapsed.as_secs_f64() * 1000.0,
+            "throughput_per_sec": self.throughput(),
+            "latency_ms": {
+                "min": ms(self.latencies.first().copied()),
+                "mean": ms(self.mean()),
+                "p50": ms(self.percentile(50.0)),
+                "p95": ms(self.percentile(95.0)),
+                "p99": ms(self.percentile(99.0)),
+                "max": ms(self.latencies.last().copied()),
+            },
+            "errors": self.error_count(),
+            "error_messages": self.errors,
+        })
+    }
+
+    pub fn render(&self) -> String {
+        let ms = |d: Option<Duration>| match d {
+            Some(d) => format!("{:.1} ms", d.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        let mut out = format!(
+            "{} runs on {} connections in {:.2} s, after {} warmup runs: {:.1} runs/s\n",
+            self.iterations,
+            self.concurrency,
+            self.elapsed.as_secs_f64(),
+            self.warmup,
+            self.throughput()
+        );
+        writeln!(
+            out,
+            "Latency: min {}, mean {}, p50 {}, p95 {}, p99 {}, max {}",
+            ms(self.latencies.first().copied()),
+            ms(self.mean()),
+            ms(self.percentile(50.0)),
+            ms(self.percentile(95.0)),
+            ms(self.percentile(99.0)),
+            ms(self.latencies.last().copied())
+        )
+        .unwrap();
+        writeln!(out, "Errors: {}", self.error_count()).unwrap();
+        for (error, count) in &self.errors {
+            writeln!(out, "  {} x {}", count, error).unwrap();
+        }
+        out
+    }
+}
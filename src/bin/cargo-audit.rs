@@ -0,0 +1,141 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Arg, ArgMatches, Command};
+use colored::Colorize;
+use prettytable::{Cell, Row, Table};
+use tiberius_sqlserver::audit::{self, AuditLog, Filter};
+
+fn main() -> anyhow::Result<()> {
+    let log_arg = Arg::new("log")
+        .long("log")
+        .takes_value(true)
+        .help("The audit log; defaults to TIBERIUS_AUDIT_LOG or audit.log next to the profiles");
+    let matches = Command::new("cargo-audit")
+        .about("Check and search the audit log of executed statements")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("verify")
+                .about("Check that no entry was edited, removed or reordered")
+                .arg(log_arg.clone()),
+        )
+        .subcommand(
+            Command::new("search")
+                .about("List the entries matching all the given filters")
+                .arg(log_arg)
+                .arg(Arg::new("sql").long("sql").takes_value(true).help("Text in the statement"))
+                .arg(Arg::new("user").long("user").takes_value(true).help("Local user"))
+                .arg(Arg::new("login").long("login").takes_value(true).help("Server login"))
+                .arg(Arg::new("profile").long("profile").takes_value(true))
+                .arg(Arg::new("server").long("server").takes_value(true))
+                .arg(Arg::new("database").long("database").takes_value(true))
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .takes_value(true)
+                        .help("A date (YYYY-MM-DD, UTC) or RFC 3339 time"),
+                )
+                .arg(Arg::new("until").long("until").takes_value(true).help("Like --since, exclusive"))
+                .arg(Arg::new("failed").long("failed").help("Only statements that failed"))
+                .arg(Arg::new("json").long("json").help("Print the matching entries as JSON lines")),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        Some(("verify", args)) => verify(&log(args)),
+        Some(("search", args)) => search(&log(args), args),
+        _ => unreachable!("a subcommand is required"),
+    }
+}
+
+fn log(args: &ArgMatches) -> AuditLog {
+    match args.get_one::<String>("log") {
+        Some(path) => AuditLog::new(path),
+        None => match std::env::var("TIBERIUS_AUDIT_LOG") {
+            Ok(path) if !path.eq_ignore_ascii_case("off") => AuditLog::new(path),
+            _ => AuditLog::new(audit::default_path()),
+        },
+    }
+}
+
+fn verify(log: &AuditLog) -> anyhow::Result<()> {
+    match log.verify() {
+        Ok(verified) => {
+            println!("{} {} entries in {}", "OK".green().bold(), verified.entries, log.path().display());
+            println!("Head: {}", verified.head);
+            println!("Keep the head elsewhere to notice entries cut off the end later.");
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{} {}: {}", "TAMPERED".red().bold(), log.path().display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn search(log: &AuditLog, args: &ArgMatches) -> anyhow::Result<()> {
+    let text = |name: &str| args.get_one::<String>(name).cloned();
+    let filter = Filter {
+        sql: text("sql"),
+        os_user: text("user"),
+        login: text("login"),
+        profile: text("profile"),
+        server: text("server"),
+        database: text("database"),
+        since: text("since").map(|t| parse_time(&t)).transpose()?,
+        until: text("until").map(|t| parse_time(&t)).transpose()?,
+        failed: args.contains_id("failed"),
+    };
+    let entries = log.search(&filter)?;
+
+    if args.contains_id("json") {
+        for entry in &entries {
+            println!("{}", entry.to_json());
+        }
+        return Ok(());
+    }
+
+    let mut out = Table::new();
+    out.set_titles(Row::new(
+        ["#", "Time (UTC)", "User", "Login", "Server", "Database", "Statement", "Outcome"]
+            .iter()
+            .map(|t| Cell::new(t))
+            .collect(),
+    ));
+    for entry in &entries {
+        let outcome = match (&entry.error, entry.rows) {
+            (Some(error), _) => format!("failed: {}", error),
+            (None, Some(rows)) => format!("ok, {} rows", rows),
+            (None, None) => "ok".to_string(),
+        };
+        out.add_row(Row::new(vec![
+            Cell::new(&entry.seq.to_string()),
+            Cell::new(&entry.time.format("%Y-%m-%d %H:%M:%S").to_string()),
+            Cell::new(&entry.session.os_user),
+            Cell::new(entry.session.login.as_deref().unwrap_or_default()),
+            Cell::new(&entry.session.server),
+            Cell::new(entry.session.database.as_deref().unwrap_or_default()),
+            Cell::new(&shorten(&entry.sql)),
+            Cell::new(&outcome),
+        ]));
+    }
+    out.printstd();
+    println!("{} entries", entries.len());
+    Ok(())
+}
+
+fn parse_time(text: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(date) = text.parse::<NaiveDate>() {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+    Ok(DateTime::parse_from_rfc3339(text)
+        .map_err(|_| anyhow::anyhow!("Expected YYYY-MM-DD or an RFC 3339 time, got {:?}", text))?
+        .with_timezone(&Utc))
+}
+
+/// The statement on one line, cut short for the table.
+fn shorten(sql: &str) -> String {
+    let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+    match sql.char_indices().nth(60) {
+        Some((cut, _)) => format!("{}...", &sql[..cut]),
+        None => sql,
+    }
+}
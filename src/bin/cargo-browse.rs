@@ -0,0 +1,119 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+use clap::{Arg, Command};
+use prettytable::{Cell, Row, Table};
+use tiberius_sqlserver::auth::PasswordSource;
+use tiberius_sqlserver::browser::{self, Instance};
+use tiberius_sqlserver::profile::{Profile, Profiles};
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-browse")
+        .about("List SQL Server instances through SQL Browser and save one as a connection profile")
+        .arg(Arg::new("host").help("Server to ask; omit to broadcast on the local subnet"))
+        .arg(
+            Arg::new("wait")
+                .long("wait")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(u64))
+                .default_value("1000")
+                .help("Milliseconds to wait for answers"),
+        )
+        .arg(Arg::new("no-save").long("no-save").help("Only list the instances"))
+        .get_matches();
+
+    let wait = Duration::from_millis(*matches.get_one::<u64>("wait").unwrap());
+    let instances = match matches.get_one::<String>("host") {
+        Some(host) => browser::discover_host(host, wait).await?,
+        None => browser::discover_broadcast(wait).await?,
+    };
+
+    if instances.is_empty() {
+        println!("No instances answered. Is the SQL Server Browser service running and UDP 1434 open?");
+        return Ok(());
+    }
+
+    print_instances(&instances);
+
+    if !matches.contains_id("no-save") {
+        offer_profile(&instances)?;
+    }
+
+    Ok(())
+}
+
+fn print_instances(instances: &[Instance]) {
+    let mut table = Table::new();
+    table.set_titles(Row::new(
+        ["#", "Server", "Instance", "Version", "TCP port", "Named pipe", "Clustered"]
+            .iter()
+            .map(|t| Cell::new(t))
+            .collect(),
+    ));
+    for (i, instance) in instances.iter().enumerate() {
+        table.add_row(Row::new(vec![
+            Cell::new(&(i + 1).to_string()),
+            Cell::new(&instance.server_name),
+            Cell::new(&instance.instance_name),
+            Cell::new(&instance.version),
+            Cell::new(&instance.tcp_port.map(|p| p.to_string()).unwrap_or_else(|| "disabled".to_string())),
+            Cell::new(instance.pipe.as_deref().unwrap_or_default()),
+            Cell::new(if instance.is_clustered { "yes" } else { "no" }),
+        ]));
+    }
+    table.printstd();
+}
+
+fn prompt(question: &str) -> anyhow::Result<String> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+fn offer_profile(instances: &[Instance]) -> anyhow::Result<()> {
+    let choice = prompt("Save an instance as a connection profile? Number, or Enter to skip: ")?;
+    if choice.is_empty() {
+        return Ok(());
+    }
+
+    let instance = choice
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| instances.get(i))
+        .ok_or_else(|| anyhow::anyhow!("No instance number {}", choice))?;
+
+    let default_name = instance.instance_name.to_lowercase();
+    let name = prompt(&format!("Profile name [{}]: ", default_name))?;
+    let name = if name.is_empty() { default_name } else { name };
+
+    let mut profile = Profile::new(&name, &instance.server());
+    let user = prompt("SQL login, or Enter for Windows authentication: ")?;
+    if !user.is_empty() {
+        profile.user = Some(user);
+        let source = prompt("Password from prompt, env:<VAR>, file:<path> or keyring [prompt]: ")?;
+        if !source.is_empty() {
+            profile.password = Some(source.parse()?);
+        }
+        if profile.password == Some(PasswordSource::Keyring) {
+            println!("Run `cargo sqllogin {}` to put the password in the keyring", name);
+        }
+    }
+
+    let path = Profiles::default_path();
+    let mut profiles = Profiles::load(&path)?;
+    let replaced = profiles.get(&name).is_some();
+    profiles.set(profile);
+    profiles.save(&path)?;
+
+    println!(
+        "{} profile {:?} in {}",
+        if replaced { "Updated" } else { "Saved" },
+        name,
+        path.display()
+    );
+    Ok(())
+}
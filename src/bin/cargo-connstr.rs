@@ -0,0 +1,40 @@
+use clap::{Arg, Command};
+use tiberius_sqlserver::connstr::{ConnectionString, Format};
+
+fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-connstr")
+        .about("Check an ADO.NET, JDBC or ODBC connection string and convert it to another format")
+        .arg(Arg::new("connection").required(true).help("The connection string, in any of the three formats"))
+        .arg(
+            Arg::new("to")
+                .long("to")
+                .takes_value(true)
+                .value_parser(["ado", "jdbc", "odbc"])
+                .help("Print the string in this format; defaults to the redacted ADO.NET form"),
+        )
+        .arg(
+            Arg::new("show-password")
+                .long("show-password")
+                .help("Keep the password in the output instead of ***"),
+        )
+        .get_matches();
+
+    let input = matches.get_one::<String>("connection").unwrap();
+    let format = Format::detect(input);
+    let connection = ConnectionString::parse_as(input, format)?;
+    let connection = if matches.contains_id("show-password") {
+        connection
+    } else {
+        connection.redacted()
+    };
+
+    match matches.get_one::<String>("to") {
+        Some(to) => println!("{}", connection.to_format(to.parse()?)?),
+        None => {
+            eprintln!("Valid {} connection string", format);
+            println!("{}", connection.to_ado_string());
+        }
+    }
+
+    Ok(())
+}
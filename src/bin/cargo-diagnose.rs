@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use clap::{Arg, Command};
+use tiberius_sqlserver::diagnose;
+use tiberius_sqlserver::profile;
+use tiberius_sqlserver::sql_client as sc;
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-diagnose")
+        .about("Check each step of connecting to SQL Server and explain what failed")
+        .arg(Arg::new("connection").help("ADO.NET connection string; defaults to the configured server"))
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .conflicts_with("connection")
+                .help("Check a saved profile, with its TLS settings"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(u64))
+                .default_value("5")
+                .help("Seconds allowed for each step"),
+        )
+        .get_matches();
+
+    let timeout = Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap());
+
+    let report = match matches.get_one::<String>("profile") {
+        Some(name) => diagnose::diagnose_profile(&profile::load(name)?, timeout).await?,
+        None => {
+            let connection = matches
+                .get_one::<String>("connection")
+                .cloned()
+                .unwrap_or_else(sc::default_connection_string);
+            diagnose::diagnose(&connection, timeout).await?
+        }
+    };
+    print!("{}", report);
+
+    if !report.passed() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::fs;
+
+use clap::{Arg, Command};
+use colored::Colorize;
+use tiberius_sqlserver::audit;
+use tiberius_sqlserver::backend::{self, Backend, TiberiusBackend};
+use tiberius_sqlserver::plan::{self, Mode, Plan};
+use tiberius_sqlserver::profile;
+use tiberius_sqlserver::sql_client as sc;
+use tiberius_sqlserver::telemetry;
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-explain")
+        .about("Show the execution plan of a query as an operator tree or as JSON")
+        .arg(Arg::new("sql").help("The batch to explain"))
+        .arg(
+            Arg::new("file")
+                .long("file")
+                .takes_value(true)
+                .conflicts_with("sql")
+                .help("Read the batch from a file"),
+        )
+        .arg(
+            Arg::new("plan")
+                .long("plan")
+                .takes_value(true)
+                .conflicts_with_all(&["sql", "file"])
+                .help("Show a saved showplan XML (.sqlplan) file instead of asking the server"),
+        )
+        .arg(
+            Arg::new("actual")
+                .long("actual")
+                .help("Run the batch and show the actual plan with real row counts, not the estimated one"),
+        )
+        .arg(
+            Arg::new("db")
+                .long("db")
+                .takes_value(true)
+                .help("ADO.NET connection string; defaults to the configured server"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .conflicts_with("db")
+                .help("Connect with a saved profile"),
+        )
+        .arg(Arg::new("json").long("json").help("Print the plan as JSON"))
+        .get_matches();
+
+    let plan = if let Some(path) = matches.get_one::<String>("plan") {
+        let bytes = fs::read(path).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        plan::parse(&decode(&bytes).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?)?
+    } else {
+        let sql = match (matches.get_one::<String>("sql"), matches.get_one::<String>("file")) {
+            (Some(sql), _) => sql.clone(),
+            (None, Some(path)) => fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?,
+            (None, None) => anyhow::bail!("Nothing to explain: pass a statement, --file or --plan"),
+        };
+
+        // Spans are written when TIBERIUS_TRACE is set.
+        let _trace = telemetry::init_from_env()?;
+        // Statements are audited unless TIBERIUS_AUDIT_LOG is `off`.
+        audit::init_from_env();
+
+        let mut db: Box<dyn Backend> = match (matches.get_one::<String>("db"), matches.get_one::<String>("profile")) {
+            (Some(target), _) => backend::open(target).await?,
+            (None, Some(name)) => Box::new(TiberiusBackend::connect_profile(&profile::load(name)?).await?),
+            (None, None) => Box::new(TiberiusBackend::connect(sc::default_config()?).await?),
+        };
+        let mode = if matches.contains_id("actual") { Mode::Actual } else { Mode::Estimated };
+        let plan = plan::capture(db.as_mut(), &sql, mode).await;
+        db.close().await?;
+        plan?
+    };
+
+    if matches.contains_id("json") {
+        println!("{}", serde_json::to_string_pretty(&plan.to_json())?);
+    } else {
+        print_plan(&plan);
+    }
+    Ok(())
+}
+
+/// The rendered tree, with statement headers, warnings and index hints
+/// picked out.
+fn print_plan(plan: &Plan) {
+    for line in plan.render().lines() {
+        let body = line.trim_start_matches(|c: char| " │├└─".contains(c));
+        if line.starts_with("Statement ") {
+            println!("{}", line.bold());
+        } else if body.starts_with("! ") {
+            println!("{}", line.yellow());
+        } else if body.starts_with("Missing index") {
+            println!("{}", line.cyan());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+/// SQL Server Management Studio saves plans as UTF-16 with a byte order
+/// mark; copies made elsewhere are usually UTF-8.
+fn decode(bytes: &[u8]) -> anyhow::Result<String> {
+    let utf16 = |bytes: &[u8], unit: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes.chunks_exact(2).map(|c| unit([c[0], c[1]])).collect();
+        String::from_utf16(&units).map_err(anyhow::Error::from)
+    };
+    match bytes {
+        [0xff, 0xfe, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xfe, 0xff, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        _ => Ok(String::from_utf8(bytes.to_vec())?.trim_start_matches('\u{feff}').to_string()),
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::{Arg, Command};
+use tiberius_sqlserver::audit;
+use tiberius_sqlserver::export::{self, ExportFormat, ExportLayout, ExportOptions, SplitMethod};
+use tiberius_sqlserver::pool::Pool;
+use tiberius_sqlserver::sql_client as sc;
+use tiberius_sqlserver::telemetry;
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-export")
+        .about("Export a table in parallel, split by key ranges")
+        .arg(Arg::new("table").required(true).help("Table to export, e.g. Sales.SalesOrderDetail"))
+        .arg(Arg::new("output").short('o').long("output").required(true).takes_value(true))
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .takes_value(true)
+                .value_parser(["csv", "parquet"])
+                .default_value("csv"),
+        )
+        .arg(Arg::new("sharded").long("sharded").help("Write one file per key range"))
+        .arg(Arg::new("ntile").long("ntile").help("Split by NTILE sampling even if partitioned"))
+        .arg(
+            Arg::new("parallel")
+                .short('p')
+                .long("parallel")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("4"),
+        )
+        .arg(
+            Arg::new("splits")
+                .long("splits")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("db")
+                .long("db")
+                .takes_value(true)
+                .help("sqlite:<file> or an ADO.NET connection string; defaults to the configured server"),
+        )
+        .get_matches();
+
+    // Spans are written when TIBERIUS_TRACE is set.
+    let _trace = telemetry::init_from_env()?;
+    // Statements are audited unless TIBERIUS_AUDIT_LOG is `off`.
+    audit::init_from_env();
+
+    let table = matches.get_one::<String>("table").unwrap();
+    let output = PathBuf::from(matches.get_one::<String>("output").unwrap());
+    let parallel = *matches.get_one::<usize>("parallel").unwrap();
+
+    let options = ExportOptions {
+        format: match matches.get_one::<String>("format").map(String::as_str) {
+            Some("parquet") => ExportFormat::Parquet,
+            _ => ExportFormat::Csv,
+        },
+        layout: if matches.contains_id("sharded") {
+            ExportLayout::Sharded
+        } else {
+            ExportLayout::Ordered
+        },
+        split_method: if matches.contains_id("ntile") {
+            SplitMethod::Ntile
+        } else {
+            SplitMethod::Partitions
+        },
+        splits: matches.get_one::<usize>("splits").copied(),
+        on_progress: Some(Arc::new(|p| {
+            print!(
+                "\rRanges {}/{}, rows {}/~{}",
+                p.ranges_done, p.ranges_total, p.rows_written, p.rows_estimated
+            );
+            let _ = io::stdout().flush();
+        })),
+        ..ExportOptions::default()
+    };
+
+    let pool = match matches.get_one::<String>("db") {
+        Some(target) => Pool::open(target, parallel),
+        None => Pool::new(sc::default_config()?, parallel),
+    };
+    let summary = export::export_table(&pool, table, &output, &options).await?;
+    pool.close().await?;
+
+    println!();
+    println!("Exported {} rows to:", summary.rows);
+    for file in summary.files {
+        println!("  {}", file.display());
+    }
+
+    Ok(())
+}
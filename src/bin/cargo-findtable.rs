@@ -1,9 +1,13 @@
+use tiberius_sqlserver::audit;
 use tiberius_sqlserver::sql_client as sc;
 use std::io::{self, Write};
 use std::process::Command;
 
 #[async_std::main]
 async fn main() -> anyhow::Result<()> {
+    // Statements are audited unless TIBERIUS_AUDIT_LOG is `off`.
+    audit::init_from_env();
+
     let runtime = tokio::runtime::Runtime::new().unwrap();
     
     // Run the async function in the runtime
@@ -0,0 +1,103 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, Command};
+use colored::Colorize;
+use tiberius_sqlserver::lint::{self, Linter, Severity};
+
+fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-lint")
+        .about("Check T-SQL in .sql files and in the string literals of .rs files")
+        .arg(
+            Arg::new("paths")
+                .multiple_values(true)
+                .help("Files or directories to check; defaults to the current directory"),
+        )
+        .arg(
+            Arg::new("allow")
+                .long("allow")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Turn a rule off; may be repeated"),
+        )
+        .arg(Arg::new("json").long("json").help("Print the diagnostics as a JSON array"))
+        .arg(Arg::new("strict").long("strict").help("Fail on warnings too, not only on errors"))
+        .arg(Arg::new("rules").long("rules").help("List the rules and exit"))
+        .get_matches();
+
+    if matches.contains_id("rules") {
+        for rule in &lint::RULES {
+            println!("{:<22} {:<8} {}", rule.name, rule.severity, rule.description);
+        }
+        return Ok(());
+    }
+
+    let mut linter = Linter::new();
+    for name in matches.get_many::<String>("allow").into_iter().flatten() {
+        linter = linter.allow(name)?;
+    }
+
+    let roots: Vec<PathBuf> = match matches.get_many::<String>("paths") {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        None => vec![PathBuf::from(".")],
+    };
+    let mut files = Vec::new();
+    for root in &roots {
+        collect(root, &mut files)?;
+    }
+
+    let mut diagnostics = Vec::new();
+    for file in &files {
+        diagnostics.extend(linter.check_file(file)?);
+    }
+
+    if matches.contains_id("json") {
+        println!("{}", lint::to_json(&diagnostics));
+    } else {
+        for diagnostic in &diagnostics {
+            match diagnostic.severity {
+                Severity::Error => println!("{}", diagnostic.to_string().red()),
+                Severity::Warning => println!("{}", diagnostic.to_string().yellow()),
+            }
+        }
+        let errors = diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+        eprintln!(
+            "{} files checked: {} errors, {} warnings",
+            files.len(),
+            errors,
+            diagnostics.len() - errors
+        );
+    }
+
+    let strict = matches.contains_id("strict");
+    if diagnostics.iter().any(|d| strict || d.severity == Severity::Error) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// The `.sql` and `.rs` files under `path`, leaving out `target` and
+/// hidden directories.
+fn collect(path: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    if path.is_file() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)
+        .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+    for entry in entries {
+        let name = entry.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if entry.is_dir() {
+            if !name.starts_with('.') && name != "target" {
+                collect(&entry, files)?;
+            }
+        } else if matches!(entry.extension().and_then(|e| e.to_str()), Some("sql") | Some("rs")) {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
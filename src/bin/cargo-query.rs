@@ -0,0 +1,263 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Arg, Command};
+use colored::Colorize;
+use prettytable::{Cell, Row, Table};
+use tiberius_sqlserver::audit;
+use tiberius_sqlserver::backend::{self, Backend, TiberiusBackend};
+use tiberius_sqlserver::batch::{BatchItem, BatchResult};
+use tiberius_sqlserver::cancel::{self, Interrupted};
+use tiberius_sqlserver::guard::{self, Destructive};
+use tiberius_sqlserver::profile;
+use tiberius_sqlserver::sql_client as sc;
+use tiberius_sqlserver::stats::Statistics;
+use tiberius_sqlserver::telemetry;
+use tiberius_sqlserver::value::{Record, Value};
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-query")
+        .about("Run a query, list tables or describe a table on SQL Server or SQLite")
+        .arg(Arg::new("sql").help("Statement to run"))
+        .arg(
+            Arg::new("db")
+                .long("db")
+                .takes_value(true)
+                .help("sqlite:<file> or an ADO.NET connection string; defaults to the configured server"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .conflicts_with("db")
+                .help("Connect with a saved profile"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .help("Cancel the statement after this many seconds; 0 for none. Overrides the profile's"),
+        )
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .help("Refuse statements that change anything, as a read_only profile does"),
+        )
+        .arg(
+            Arg::new("stats")
+                .long("stats")
+                .help("Show the reads per table and the CPU and elapsed time of each statement"),
+        )
+        .arg(
+            Arg::new("repl")
+                .long("repl")
+                .help("Read statements from the terminal, asking before destructive ones"),
+        )
+        .arg(Arg::new("tables").long("tables").help("List the user tables"))
+        .arg(Arg::new("describe").long("describe").takes_value(true).help("Show the columns of a table"))
+        .arg(Arg::new("csv").long("csv").takes_value(true).help("Write the rows to a CSV file"))
+        .get_matches();
+
+    // Spans are written when TIBERIUS_TRACE is set.
+    let _trace = telemetry::init_from_env()?;
+    // Statements are audited unless TIBERIUS_AUDIT_LOG is `off`.
+    audit::init_from_env();
+
+    let mut db: Box<dyn Backend> = match (matches.get_one::<String>("db"), matches.get_one::<String>("profile")) {
+        (Some(target), _) => backend::open(target).await?,
+        (None, Some(name)) => Box::new(TiberiusBackend::connect_profile(&profile::load(name)?).await?),
+        (None, None) => Box::new(TiberiusBackend::connect(sc::default_config()?).await?),
+    };
+
+    if let Some(seconds) = matches.get_one::<String>("timeout") {
+        let seconds: u64 = seconds
+            .parse()
+            .map_err(|_| anyhow::anyhow!("--timeout takes a number of seconds, got {:?}", seconds))?;
+        db.set_timeout((seconds > 0).then(|| Duration::from_secs(seconds)))?;
+    }
+    if matches.contains_id("read-only") {
+        db.set_read_only(true)?;
+    }
+    if matches.contains_id("stats") {
+        if db.name() != "sqlserver" {
+            anyhow::bail!("--stats needs SQL Server, not {}", db.name());
+        }
+        // The statistics come back as messages, which cannot be read on a
+        // session tiberius encrypts; a profile's session is encrypted by
+        // this crate, so they can be read there.
+        if !db.batch("SET STATISTICS IO, TIME ON").await?.complete {
+            anyhow::bail!(
+                "--stats needs the server's messages, which cannot be read when the driver encrypts the session; \
+                 connect with --profile, whose encrypted sessions expose them"
+            );
+        }
+    }
+    // Ctrl-C cancels the running statement instead of killing the process.
+    if let Some(handle) = db.cancel_handle() {
+        cancel::cancel_on_ctrl_c(&handle)?;
+    }
+
+    if matches.contains_id("repl") {
+        repl(db.as_mut()).await?;
+    } else if matches.contains_id("tables") {
+        for table in db.tables().await? {
+            println!("{}", table);
+        }
+    } else if let Some(table) = matches.get_one::<String>("describe") {
+        let mut out = Table::new();
+        out.set_titles(Row::new(
+            ["Column", "Type", "Nullable", "Key", "Generated"].iter().map(|t| Cell::new(t)).collect(),
+        ));
+        for column in db.columns(table).await? {
+            out.add_row(Row::new(vec![
+                Cell::new(&column.name),
+                Cell::new(&column.type_name),
+                Cell::new(if column.is_nullable { "yes" } else { "no" }),
+                Cell::new(&column.key_ordinal.map(|k| k.to_string()).unwrap_or_default()),
+                Cell::new(if column.is_generated() { "yes" } else { "" }),
+            ]));
+        }
+        out.printstd();
+    } else if let Some(sql) = matches.get_one::<String>("sql") {
+        match matches.get_one::<String>("csv") {
+            Some(path) => {
+                let records = db.query(sql, &[]).await?;
+                write_csv(&PathBuf::from(path), &records)?;
+                println!("Wrote {} rows to {}", records.len(), path);
+            }
+            None => print_batch(&db.batch(sql).await?),
+        }
+    } else {
+        println!("Nothing to do: pass a statement, --repl, --tables or --describe <table>");
+    }
+
+    db.close().await?;
+    Ok(())
+}
+
+/// Read batches until `exit`. A batch ends with a line ending in `;` or
+/// with `GO` on its own line. Errors are printed and the session goes on,
+/// unless it was lost cancelling a statement.
+async fn repl(db: &mut dyn Backend) -> anyhow::Result<()> {
+    println!("Enter statements; end a batch with `;` or GO, and type exit to quit.");
+    let stdin = async_std::io::stdin();
+    let mut batch = String::new();
+    loop {
+        print!("{}", if batch.is_empty() { "sql> " } else { "  -> " });
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line).await? == 0 {
+            break;
+        }
+
+        let trimmed = line.trim();
+        if batch.is_empty() && (trimmed.eq_ignore_ascii_case("exit") || trimmed.eq_ignore_ascii_case("quit")) {
+            break;
+        }
+        let go = trimmed.eq_ignore_ascii_case("go");
+        if !go {
+            batch.push_str(&line);
+        }
+        if !go && !trimmed.ends_with(';') {
+            continue;
+        }
+
+        let sql = std::mem::take(&mut batch);
+        if sql.trim().is_empty() {
+            continue;
+        }
+        if !confirm(&guard::destructive_statements(&sql)).await? {
+            println!("Not run.");
+            continue;
+        }
+        match db.batch(&sql).await {
+            Ok(result) => print_batch(&result),
+            // Nothing more can run on a session ended to cancel a statement.
+            Err(e) if e.downcast_ref::<Interrupted>().is_some_and(|i| i.connection_closed) => {
+                return Err(e.context("The session was lost"));
+            }
+            Err(e) => eprintln!("{}", format!("Error: {:#}", e).red()),
+        }
+    }
+    Ok(())
+}
+
+/// Ask before running drops, truncates, alters and unfiltered updates or
+/// deletes. Anything but `y` or `yes` declines.
+async fn confirm(statements: &[Destructive]) -> anyhow::Result<bool> {
+    if statements.is_empty() {
+        return Ok(true);
+    }
+
+    println!("{}", "This batch drops, alters or changes every row of something:".yellow().bold());
+    for statement in statements {
+        println!("  {}", statement);
+    }
+    print!("Run it? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    async_std::io::stdin().read_line(&mut answer).await?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Result sets, row counts and messages in the order the server sent them.
+/// Statistics are shown after each statement and totalled at the end.
+fn print_batch(result: &BatchResult) {
+    let mut stats = Statistics::default();
+    let mut shown = 0;
+    for item in &result.items {
+        match item {
+            BatchItem::ResultSet(set) => print_records(&set.columns, &set.rows),
+            BatchItem::RowsAffected(count) => println!("({} rows affected)", count),
+            BatchItem::Message(message) if stats.add(message) => {
+                for statement in &stats.completed()[shown..] {
+                    print!("{}", statement.to_string().dimmed());
+                }
+                shown = stats.completed().len();
+            }
+            BatchItem::Message(message) if message.is_warning() => println!("{}", message.to_string().yellow()),
+            BatchItem::Message(message) => println!("{}", message),
+        }
+    }
+    for statement in &stats.statements[shown..] {
+        print!("{}", statement.to_string().dimmed());
+    }
+    if !stats.is_empty() {
+        println!("{}", format!("Total: {}", stats.summary()).bold());
+    }
+    if !result.complete {
+        eprintln!("(row counts and messages are not available on this connection)");
+    }
+}
+
+fn print_records(columns: &[String], records: &[Record]) {
+    if records.is_empty() {
+        println!("(no rows)");
+        return;
+    }
+
+    let mut out = Table::new();
+    out.set_titles(Row::new(columns.iter().map(|c| Cell::new(c)).collect()));
+    for record in records {
+        out.add_row(Row::new(record.values().iter().map(|v| Cell::new(&v.to_string())).collect()));
+    }
+    out.printstd();
+    println!("({} rows)", records.len());
+}
+
+fn write_csv(path: &PathBuf, records: &[Record]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    if let Some(first) = records.first() {
+        writer.write_record(first.columns())?;
+    }
+    for record in records {
+        writer.write_record(record.values().iter().map(|v| match v {
+            Value::Null => String::new(),
+            v => v.to_string(),
+        }))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
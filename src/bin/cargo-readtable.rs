@@ -1,14 +1,38 @@
+use tiberius_sqlserver::audit;
+use tiberius_sqlserver::cancel;
 use tiberius_sqlserver::sql_client as sc;
 use std::io::{self, Write};
 use std::process::Command;
+use std::time::Duration;
+
+use clap::Arg;
+
+/// Read the table on a connection Ctrl-C can cancel.
+async fn read_table(timeout: Option<Duration>) -> anyhow::Result<()> {
+    sc::read_table_cancellable(sc::default_config()?, timeout, cancel::cancel_on_ctrl_c).await
+}
 
 #[async_std::main]
 async fn main() -> anyhow::Result<()> {
+    let matches = clap::Command::new("cargo-readtable")
+        .about("Read HumanResources.Department; Ctrl-C cancels the query")
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(u64))
+                .help("Cancel the query after this many seconds"),
+        )
+        .get_matches();
+    let timeout = matches.get_one::<u64>("timeout").map(|s| Duration::from_secs(*s));
+    // Statements are audited unless TIBERIUS_AUDIT_LOG is `off`.
+    audit::init_from_env();
+
     let runtime = tokio::runtime::Runtime::new().unwrap();
     
     // Run the async function in the runtime
     runtime.block_on(async {
-        match sc::read_table().await {
+        match read_table(timeout).await {
             Ok(_) => {
                 println!("Successfully read table data!");
             },
@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+
+use clap::{Arg, Command};
+use tiberius_sqlserver::replay::Recorder;
+use tiberius_sqlserver::sql_client as sc;
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-record")
+        .about("Record TDS sessions against a dev server into a replayable fixture")
+        .arg(Arg::new("fixture").required(true).help("File to write, e.g. fixtures/read_table.tds"))
+        .arg(
+            Arg::new("server")
+                .long("server")
+                .takes_value(true)
+                .help("host:port to record, defaults to the configured server"),
+        )
+        .get_matches();
+
+    let fixture = PathBuf::from(matches.get_one::<String>("fixture").unwrap());
+    let upstream = match matches.get_one::<String>("server") {
+        Some(server) => server.clone(),
+        None => sc::default_config()?.get_addr(),
+    };
+
+    let recorder = Recorder::start(&upstream).await?;
+    let addr = recorder.addr();
+
+    println!("Recording {} through {}", upstream, addr);
+    println!("Run the code to record with:");
+    println!(
+        "  TIBERIUS_TEST_CONNECTION_STRING=\"server=tcp:{},{};encrypt=DANGER_PLAINTEXT;...\"",
+        addr.ip(),
+        addr.port()
+    );
+    println!("\nPress Enter to stop recording...");
+
+    let mut input = String::new();
+    async_std::io::stdin().read_line(&mut input).await?;
+
+    recorder.save(&fixture)?;
+    println!(
+        "Saved {} session(s) to {}",
+        recorder.fixture().sessions.len(),
+        fixture.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::fs;
+use std::path::PathBuf;
+
+use clap::{Arg, Command};
+use tiberius_sqlserver::audit;
+use tiberius_sqlserver::backend::{self, Backend, TiberiusBackend};
+use tiberius_sqlserver::snapshot::{self, Snapshot};
+use tiberius_sqlserver::sql_client as sc;
+use tiberius_sqlserver::telemetry;
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-snapshot")
+        .about("Snapshot query results as JSON and report how they changed since the last snapshot")
+        .arg(Arg::new("queries").required(true).help("A .sql file of `-- snapshot: <name>` queries"))
+        .arg(
+            Arg::new("dir")
+                .long("dir")
+                .takes_value(true)
+                .default_value("snapshots")
+                .help("Directory holding the snapshots"),
+        )
+        .arg(Arg::new("update").long("update").help("Overwrite snapshots that differ"))
+        .arg(
+            Arg::new("db")
+                .long("db")
+                .takes_value(true)
+                .help("sqlite:<file> or an ADO.NET connection string; defaults to the configured server"),
+        )
+        .get_matches();
+
+    // Spans are written when TIBERIUS_TRACE is set.
+    let _trace = telemetry::init_from_env()?;
+    // Statements are audited unless TIBERIUS_AUDIT_LOG is `off`.
+    audit::init_from_env();
+
+    let queries = snapshot::parse_queries(&fs::read_to_string(matches.get_one::<String>("queries").unwrap())?)?;
+    let dir = PathBuf::from(matches.get_one::<String>("dir").unwrap());
+    let update = matches.contains_id("update");
+
+    let mut db: Box<dyn Backend> = match matches.get_one::<String>("db") {
+        Some(target) => backend::open(target).await?,
+        None => Box::new(TiberiusBackend::connect(sc::default_config()?).await?),
+    };
+
+    let mut changed = 0;
+    for query in &queries {
+        let path = snapshot::path_for(&dir, &query.name);
+        let current = snapshot::take(db.as_mut(), query).await?;
+
+        if !path.exists() {
+            current.save(&path)?;
+            println!("NEW  {} ({} rows) -> {}", query.name, current.rows.len(), path.display());
+            continue;
+        }
+
+        let diff = snapshot::diff(&Snapshot::load(&path)?, &current);
+        if diff.is_empty() {
+            println!("OK   {}", query.name);
+            continue;
+        }
+
+        println!("DIFF {}", query.name);
+        for line in diff.to_string().lines() {
+            println!("     {}", line);
+        }
+        if update {
+            current.save(&path)?;
+            println!("     updated {}", path.display());
+        } else {
+            changed += 1;
+        }
+    }
+
+    db.close().await?;
+
+    if changed > 0 {
+        eprintln!("{} of {} snapshots changed; rerun with --update to accept", changed, queries.len());
+        drop(_trace);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::fs::File;
+
+use clap::{Arg, Command};
+use tiberius::Config;
+use tiberius_sqlserver::audit;
+use tiberius_sqlserver::bench::{self, BenchOptions, ParamSets};
+use tiberius_sqlserver::guard;
+use tiberius_sqlserver::pool::Pool;
+use tiberius_sqlserver::profile;
+use tiberius_sqlserver::sql_client as sc;
+use tiberius_sqlserver::telemetry;
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-sqlbench")
+        .about("Run a query or procedure many times and report throughput, latency percentiles and errors")
+        .arg(Arg::new("sql").help("The statement to run"))
+        .arg(
+            Arg::new("procedure")
+                .long("procedure")
+                .takes_value(true)
+                .conflicts_with("sql")
+                .help("Run this procedure, with the parameters named in the header of --params"),
+        )
+        .arg(
+            Arg::new("params")
+                .long("params")
+                .takes_value(true)
+                .help("CSV file with a row of parameters per run, used in turn"),
+        )
+        .arg(
+            Arg::new("iterations")
+                .short('n')
+                .long("iterations")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("concurrency")
+                .short('c')
+                .long("concurrency")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1")
+                .help("Runs at a time, each on its own pooled connection"),
+        )
+        .arg(
+            Arg::new("warmup")
+                .long("warmup")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("5")
+                .help("Runs before measuring"),
+        )
+        .arg(
+            Arg::new("db")
+                .long("db")
+                .takes_value(true)
+                .help("ADO.NET connection string; defaults to the configured server"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .conflicts_with("db")
+                .help("Connect with a saved profile"),
+        )
+        .arg(Arg::new("json").long("json").help("Print the report as JSON"))
+        .get_matches();
+
+    // Spans are written when TIBERIUS_TRACE is set.
+    let _trace = telemetry::init_from_env()?;
+    // Statements are audited unless TIBERIUS_AUDIT_LOG is `off`.
+    audit::init_from_env();
+
+    let params = match matches.get_one::<String>("params") {
+        Some(path) => ParamSets::from_csv(File::open(path).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?)?,
+        None => ParamSets::default(),
+    };
+    let sql = match (matches.get_one::<String>("sql"), matches.get_one::<String>("procedure")) {
+        (Some(sql), _) => sql.clone(),
+        (None, Some(procedure)) => bench::procedure_call(procedure, &params.names)?,
+        (None, None) => anyhow::bail!("Nothing to run: pass a statement or --procedure"),
+    };
+
+    let concurrency = *matches.get_one::<usize>("concurrency").unwrap();
+    let pool = match (matches.get_one::<String>("db"), matches.get_one::<String>("profile")) {
+        (Some(target), _) => Pool::new(Config::from_ado_string(target)?, concurrency),
+        (None, Some(name)) => {
+            let profile = profile::load(name)?;
+            if profile.read_only {
+                guard::check_read_only(&sql)?;
+            }
+            Pool::with_profile(profile, concurrency)?
+        }
+        (None, None) => Pool::new(sc::default_config()?, concurrency),
+    };
+
+    let options = BenchOptions {
+        iterations: *matches.get_one::<usize>("iterations").unwrap(),
+        warmup: *matches.get_one::<usize>("warmup").unwrap(),
+        params: params.rows,
+    };
+    let report = bench::run(&pool, &sql, &options).await;
+    pool.close().await?;
+    let report = report?;
+
+    if matches.contains_id("json") {
+        println!("{}", serde_json::to_string_pretty(&report.to_json())?);
+    } else {
+        print!("{}", report.render());
+    }
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::fs;
+use std::io::Read;
+
+use clap::{Arg, ArgMatches, Command};
+use colored::Colorize;
+use tiberius_sqlserver::audit;
+use tiberius_sqlserver::backend::{self, Backend, TiberiusBackend};
+use tiberius_sqlserver::format::{self, FormatOptions, Indent};
+use tiberius_sqlserver::profile;
+use tiberius_sqlserver::sql_client as sc;
+use tiberius_sqlserver::value::Value;
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-sqlfmt")
+        .about("Format T-SQL files, standard input or the definition of a database object")
+        .arg(
+            Arg::new("files")
+                .multiple_values(true)
+                .help("Files to format; standard input when none are given"),
+        )
+        .arg(
+            Arg::new("write")
+                .long("write")
+                .help("Rewrite the files in place instead of printing them"),
+        )
+        .arg(
+            Arg::new("check")
+                .long("check")
+                .conflicts_with("write")
+                .help("List the files that are not formatted and fail if there are any"),
+        )
+        .arg(
+            Arg::new("object")
+                .long("object")
+                .takes_value(true)
+                .conflicts_with("files")
+                .help("Format OBJECT_DEFINITION of this view, procedure, function or trigger"),
+        )
+        .arg(
+            Arg::new("db")
+                .long("db")
+                .takes_value(true)
+                .help("ADO.NET connection string for --object; defaults to the configured server"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .conflicts_with("db")
+                .help("Connect with a saved profile for --object"),
+        )
+        .arg(
+            Arg::new("case")
+                .long("case")
+                .takes_value(true)
+                .help("Keyword case: upper (the default), lower or preserve"),
+        )
+        .arg(
+            Arg::new("indent")
+                .long("indent")
+                .takes_value(true)
+                .help("Spaces per level; 4 by default"),
+        )
+        .arg(
+            Arg::new("tabs")
+                .long("tabs")
+                .conflicts_with("indent")
+                .help("Indent with tabs"),
+        )
+        .arg(
+            Arg::new("commas")
+                .long("commas")
+                .takes_value(true)
+                .help("Where list commas go when broken over lines: trailing (the default) or leading"),
+        )
+        .arg(
+            Arg::new("width")
+                .long("width")
+                .takes_value(true)
+                .help("Line width to keep within; 100 by default"),
+        )
+        .get_matches();
+
+    // Statements are audited unless TIBERIUS_AUDIT_LOG is `off`.
+    audit::init_from_env();
+    let options = options(&matches)?;
+
+    if let Some(name) = matches.get_one::<String>("object") {
+        let mut db: Box<dyn Backend> = match (matches.get_one::<String>("db"), matches.get_one::<String>("profile")) {
+            (Some(target), _) => backend::open(target).await?,
+            (None, Some(name)) => Box::new(TiberiusBackend::connect_profile(&profile::load(name)?).await?),
+            (None, None) => Box::new(TiberiusBackend::connect(sc::default_config()?).await?),
+        };
+        let rows = db
+            .query(
+                "SELECT OBJECT_DEFINITION(OBJECT_ID(@P1))",
+                &[Value::String(name.clone())],
+            )
+            .await?;
+        db.close().await?;
+        match rows.first().and_then(|r| r.values().first()) {
+            Some(Value::String(definition)) => print!("{}", format::format_sql(definition, &options)),
+            _ => anyhow::bail!("{} was not found, is not a module or its definition is encrypted", name),
+        }
+        return Ok(());
+    }
+
+    let files: Vec<&String> = matches.get_many::<String>("files").into_iter().flatten().collect();
+    if files.is_empty() {
+        let mut sql = String::new();
+        std::io::stdin().read_to_string(&mut sql)?;
+        print!("{}", format::format_sql(&sql, &options));
+        return Ok(());
+    }
+
+    let mut unformatted = 0;
+    for file in files {
+        let sql = fs::read_to_string(file).map_err(|e| anyhow::anyhow!("{}: {}", file, e))?;
+        let formatted = format::format_sql(&sql, &options);
+        if matches.contains_id("check") {
+            if formatted != sql {
+                println!("{}", file);
+                unformatted += 1;
+            }
+        } else if matches.contains_id("write") {
+            if formatted != sql {
+                fs::write(file, &formatted)?;
+                eprintln!("Formatted {}", file);
+            }
+        } else {
+            print!("{}", formatted);
+        }
+    }
+    if unformatted > 0 {
+        eprintln!("{}", format!("{} files are not formatted", unformatted).red());
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn options(matches: &ArgMatches) -> anyhow::Result<FormatOptions> {
+    let mut options = FormatOptions::default();
+    if let Some(case) = matches.get_one::<String>("case") {
+        options.keyword_case = case.parse()?;
+    }
+    if let Some(commas) = matches.get_one::<String>("commas") {
+        options.commas = commas.parse()?;
+    }
+    if let Some(indent) = matches.get_one::<String>("indent") {
+        let spaces = indent
+            .parse()
+            .map_err(|_| anyhow::anyhow!("--indent takes a number of spaces, got {:?}", indent))?;
+        options.indent = Indent::Spaces(spaces);
+    }
+    if matches.contains_id("tabs") {
+        options.indent = Indent::Tabs;
+    }
+    if let Some(width) = matches.get_one::<String>("width") {
+        options.max_width = width
+            .parse()
+            .map_err(|_| anyhow::anyhow!("--width takes a number of columns, got {:?}", width))?;
+    }
+    Ok(options)
+}
@@ -0,0 +1,59 @@
+use clap::{Arg, Command};
+use tiberius::Config;
+use tiberius_sqlserver::auth::{self, PasswordSource, Secret, SqlLogin};
+use tiberius_sqlserver::profile::{self, Profiles};
+use tiberius_sqlserver::tls;
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-sqllogin")
+        .about("Keep the password of a profile's SQL login in the OS keyring")
+        .arg(Arg::new("profile").required(true).help("A profile with a `user`"))
+        .arg(Arg::new("forget").long("forget").help("Remove the password from the keyring"))
+        .arg(
+            Arg::new("no-check")
+                .long("no-check")
+                .help("Store the password without logging in with it first"),
+        )
+        .get_matches();
+
+    let name = matches.get_one::<String>("profile").unwrap();
+    let mut profile = profile::load(name)?;
+    let user = profile
+        .user
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("Profile {:?} uses Windows authentication; give it a `user` first", name))?;
+    let server = profile.server_key()?;
+
+    if matches.contains_id("forget") {
+        auth::keyring_delete(&user, &server)?;
+        println!("Removed the password of {} on {} from the keyring", user, server);
+        return Ok(());
+    }
+
+    let password = Secret::new(rpassword::prompt_password(format!("Password for {} on {}: ", user, server))?);
+
+    if !matches.contains_id("no-check") {
+        let mut config = Config::from_ado_string(&profile.to_ado_string())?;
+        config.authentication(SqlLogin::new(&user, password.clone()).method());
+        tls::connect(config, &profile.tls)
+            .await
+            .map_err(|e| anyhow::anyhow!("Logging in as {} failed, so the password was not stored: {}", user, e))?
+            .close()
+            .await?;
+    }
+
+    auth::keyring_set(&user, &server, &password)?;
+    println!("Stored the password of {} on {} in the keyring", user, server);
+
+    if profile.password != Some(PasswordSource::Keyring) {
+        let path = Profiles::default_path();
+        let mut profiles = Profiles::load(&path)?;
+        profile.password = Some(PasswordSource::Keyring);
+        profiles.set(profile);
+        profiles.save(&path)?;
+        println!("Profile {:?} now reads its password from the keyring", name);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::PathBuf;
+
+use clap::{Arg, Command};
+use tiberius_sqlserver::audit;
+use tiberius_sqlserver::backend::{self, Backend, TiberiusBackend};
+use tiberius_sqlserver::profile;
+use tiberius_sqlserver::sql_client as sc;
+use tiberius_sqlserver::sqltest::{self, Outcome};
+use tiberius_sqlserver::telemetry;
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let matches = Command::new("cargo-sqltest")
+        .about("Run T-SQL unit tests from .sql files, each in a rolled-back transaction")
+        .arg(Arg::new("path").default_value("tests/sql").help("A .sql file or a directory of them"))
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .takes_value(true)
+                .value_parser(["text", "tap", "junit"])
+                .default_value("text"),
+        )
+        .arg(Arg::new("output").short('o').long("output").takes_value(true).help("Write the report to a file"))
+        .arg(
+            Arg::new("db")
+                .long("db")
+                .takes_value(true)
+                .help("sqlite:<file> or an ADO.NET connection string; defaults to the configured server"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .conflicts_with("db")
+                .help("Connect with a saved profile"),
+        )
+        .get_matches();
+
+    // Spans are written when TIBERIUS_TRACE is set.
+    let _trace = telemetry::init_from_env()?;
+    // Statements are audited unless TIBERIUS_AUDIT_LOG is `off`.
+    audit::init_from_env();
+
+    let path = PathBuf::from(matches.get_one::<String>("path").unwrap());
+    let tests = sqltest::discover(&path)?;
+    if tests.is_empty() {
+        anyhow::bail!("No tests found in {}", path.display());
+    }
+
+    let mut db: Box<dyn Backend> = match (matches.get_one::<String>("db"), matches.get_one::<String>("profile")) {
+        (Some(target), _) => backend::open(target).await?,
+        (None, Some(name)) => Box::new(TiberiusBackend::connect_profile(&profile::load(name)?).await?),
+        (None, None) => Box::new(TiberiusBackend::connect(sc::default_config()?).await?),
+    };
+    let results = sqltest::run_all(&mut *db, &tests).await;
+    db.close().await?;
+
+    let report = match matches.get_one::<String>("format").map(String::as_str) {
+        Some("tap") => sqltest::tap_report(&results),
+        Some("junit") => sqltest::junit_report(&results),
+        _ => {
+            let mut out = String::new();
+            for result in &results {
+                let (status, message) = match &result.outcome {
+                    Outcome::Passed => ("PASS", None),
+                    Outcome::Failed(m) => ("FAIL", Some(m)),
+                    Outcome::Errored(m) => ("ERROR", Some(m)),
+                };
+                out.push_str(&format!("{:<5} {} ({} ms)\n", status, result.test.name, result.duration.as_millis()));
+                if let Some(message) = message {
+                    out.push_str(&format!("      {}:{}: {}\n", result.test.file.display(), result.test.line, message));
+                }
+            }
+            out
+        }
+    };
+
+    match matches.get_one::<String>("output") {
+        Some(output) => fs::write(output, report)?,
+        None => print!("{}", report),
+    }
+
+    let failed = results.iter().filter(|r| !r.passed()).count();
+    eprintln!("{} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        drop(_trace);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
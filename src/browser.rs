@@ -0,0 +1,166 @@
+//! Find SQL Server instances through the SQL Server Browser service
+//! (SSRP, UDP port 1434), on one host or by broadcast on the local subnet.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use async_std::net::{ToSocketAddrs, UdpSocket};
+
+pub const BROWSER_PORT: u16 = 1434;
+
+/// CLNT_BCAST_EX: every instance on every server that hears it.
+const CLNT_BCAST_EX: u8 = 0x02;
+/// CLNT_UCAST_EX: every instance on one server.
+const CLNT_UCAST_EX: u8 = 0x03;
+/// CLNT_UCAST_INST: one named instance on one server.
+const CLNT_UCAST_INST: u8 = 0x04;
+const SVR_RESP: u8 = 0x05;
+
+/// An instance as described by SQL Browser.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instance {
+    pub server_name: String,
+    pub instance_name: String,
+    pub is_clustered: bool,
+    pub version: String,
+    /// `None` when TCP/IP is disabled for the instance.
+    pub tcp_port: Option<u16>,
+    /// The named pipe, e.g. `\\JASON\pipe\MSSQL$SQLEXPRESS\sql\query`.
+    pub pipe: Option<String>,
+    /// Where the answer came from.
+    pub responder: SocketAddr,
+}
+
+impl Instance {
+    /// The `server=` value that reaches this instance over TCP, e.g.
+    /// `JASON\SQLEXPRESS,61521`.
+    pub fn server(&self) -> String {
+        let mut server = self.server_name.clone();
+        if !self.instance_name.eq_ignore_ascii_case("MSSQLSERVER") {
+            server.push('\\');
+            server.push_str(&self.instance_name);
+        }
+        if let Some(port) = self.tcp_port {
+            server.push_str(&format!(",{}", port));
+        }
+        server
+    }
+}
+
+/// Parse an SVR_RESP: 0x05, a little-endian length, then one
+/// `ServerName;X;InstanceName;Y;...;tcp;1433;;` record per instance.
+pub fn parse_response(reply: &[u8], responder: SocketAddr) -> anyhow::Result<Vec<Instance>> {
+    if reply.len() < 3 || reply[0] != SVR_RESP {
+        anyhow::bail!("Invalid SQL Browser reply from {}", responder);
+    }
+
+    let len = u16::from_le_bytes([reply[1], reply[2]]) as usize;
+    let data = reply.get(3..3 + len).unwrap_or(&reply[3..]);
+    let text = String::from_utf8_lossy(data);
+
+    text.split(";;")
+        .filter(|record| !record.trim().is_empty())
+        .map(|record| {
+            let fields: Vec<&str> = record.split(';').collect();
+            let value = |key: &str| {
+                fields
+                    .chunks(2)
+                    .find(|pair| pair[0].eq_ignore_ascii_case(key))
+                    .and_then(|pair| pair.get(1))
+                    .map(|v| v.to_string())
+            };
+
+            Ok(Instance {
+                server_name: value("ServerName").unwrap_or_default(),
+                instance_name: value("InstanceName")
+                    .ok_or_else(|| anyhow::anyhow!("SQL Browser record without an instance name: {}", record))?,
+                is_clustered: value("IsClustered").is_some_and(|v| v.eq_ignore_ascii_case("Yes")),
+                version: value("Version").unwrap_or_default(),
+                tcp_port: value("tcp").map(|p| p.parse()).transpose()?,
+                pipe: value("np"),
+                responder,
+            })
+        })
+        .collect()
+}
+
+async fn socket_for(target: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let local = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    Ok(UdpSocket::bind(local).await?)
+}
+
+/// Collect the replies that arrive within `wait`. A malformed reply is
+/// logged and skipped, so one odd responder does not hide the others.
+async fn collect(socket: &UdpSocket, wait: Duration) -> anyhow::Result<Vec<Instance>> {
+    let deadline = Instant::now() + wait;
+    let mut instances = Vec::new();
+    let mut buf = vec![0u8; 65535];
+
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match async_std::io::timeout(left, socket.recv_from(&mut buf)).await {
+            Ok((len, from)) => match parse_response(&buf[..len], from) {
+                Ok(found) => instances.extend(found),
+                Err(e) => tracing::warn!("Skipping a SQL Browser reply: {:#}", e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(instances)
+}
+
+/// List the instances on `host`, waiting up to `wait` for the answer.
+pub async fn discover_host(host: &str, wait: Duration) -> anyhow::Result<Vec<Instance>> {
+    let target = (host, BROWSER_PORT)
+        .to_socket_addrs()
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No addresses for {}", host))?;
+
+    discover(target, wait).await
+}
+
+/// List the instances known to the browser at `browser`.
+pub async fn discover(browser: SocketAddr, wait: Duration) -> anyhow::Result<Vec<Instance>> {
+    let socket = socket_for(browser).await?;
+    socket.send_to(&[CLNT_UCAST_EX], browser).await?;
+    collect(&socket, wait).await
+}
+
+/// Broadcast on the local subnet and list every instance that answers
+/// within `wait`.
+pub async fn discover_broadcast(wait: Duration) -> anyhow::Result<Vec<Instance>> {
+    let target = SocketAddr::from((Ipv4Addr::BROADCAST, BROWSER_PORT));
+    let socket = socket_for(target).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&[CLNT_BCAST_EX], target).await?;
+    collect(&socket, wait).await
+}
+
+/// The TCP port of `instance` on the server whose browser listens at
+/// `browser`, waiting up to `wait` for the answer.
+pub async fn lookup(browser: SocketAddr, instance: &str, wait: Duration) -> anyhow::Result<u16> {
+    let socket = socket_for(browser).await?;
+    let request = [&[CLNT_UCAST_INST], instance.as_bytes()].concat();
+    socket.send_to(&request, browser).await?;
+
+    let mut buf = vec![0u8; 4096];
+    let (len, from) = match async_std::io::timeout(wait, socket.recv_from(&mut buf)).await {
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+            anyhow::bail!("No reply from SQL Browser at {} within {:?}", browser, wait)
+        }
+        result => result?,
+    };
+    let found = parse_response(&buf[..len], from)?
+        .into_iter()
+        .find(|i| i.instance_name.eq_ignore_ascii_case(instance))
+        .ok_or_else(|| anyhow::anyhow!("SQL Browser does not know instance {}", instance))?;
+
+    found
+        .tcp_port
+        .ok_or_else(|| anyhow::anyhow!("Instance {} does not listen on TCP", instance))
+}
@@ -0,0 +1,473 @@
+//! Statement timeouts and cancellation.
+//!
+//! SQL Server stops a running statement when the client sends an attention
+//! packet, and confirms with a `DONE` token carrying the attention flag.
+//! tiberius cannot send one, so [`CancellableStream`] sits between tiberius
+//! and the socket. When its [`CancelHandle`] is used during [`run`], it
+//! sends the attention packet and passes the rest of the response on to
+//! tiberius, which sees it end early. If the response had already ended,
+//! the confirmation arrives as a message of its own and is dropped, so the
+//! next statement reads its own response and the session stays usable.
+//!
+//! That needs the stream to see plain TDS: a connection opened with TLS
+//! settings, whose TLS this crate does below the stream (see
+//! [`crate::tls::Session`]), `encrypt = off`, or a server without
+//! encryption. When tiberius encrypts the session itself the attention
+//! packet cannot be added to its TLS session. Cancelling then logs in a
+//! second time and ends the session with `KILL`, which stops the statement
+//! on the server and loses the connection: it has to be reopened. When
+//! that login fails the connection is only closed.
+//!
+//! The same stream can keep a copy of a response for the tokens tiberius
+//! reads but does not return, such as row counts and `PRINT` messages; see
+//! [`crate::batch`].
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::Shutdown;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures_util::future::{self, Either};
+use futures_util::io::{AsyncRead, AsyncWrite};
+use futures_util::ready;
+use once_cell::sync::Lazy;
+use tiberius::{Client, Config};
+
+use crate::sql_client;
+use crate::tls::{Session, TlsSettings};
+use crate::tds::{
+    DONE_ATTENTION, HEADER_LEN, PACKET_ATTENTION, PACKET_PRELOGIN, PACKET_TABULAR_RESULT, STATUS_EOM, TOKEN_DONE,
+};
+
+/// How long the server has to confirm a timed out statement's cancel
+/// before the connection is closed.
+const GRACE: Duration = Duration::from_secs(10);
+
+/// A `DONE` token: token, status, current command and row count.
+const DONE_LEN: usize = 13;
+
+/// Why a statement was stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    TimedOut(Duration),
+    Cancelled,
+}
+
+/// The error of a statement stopped by a timeout or a [`CancelHandle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted {
+    pub reason: Reason,
+    /// The session was ended or its connection closed instead of
+    /// cancelling the statement, see the module documentation.
+    pub connection_closed: bool,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Reason::TimedOut(limit) => write!(f, "The statement was cancelled after the {:?} timeout", limit)?,
+            Reason::Cancelled => f.write_str("The statement was cancelled")?,
+        }
+        if self.connection_closed {
+            f.write_str("; the connection was closed and has to be reopened")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[derive(Debug)]
+struct Shared {
+    /// The connection, to close it when the statement cannot be cancelled.
+    socket: async_std::net::TcpStream,
+    /// A statement is running under [`run`].
+    active: AtomicBool,
+    /// Cancelling was asked for and the attention packet is not sent yet.
+    requested: AtomicBool,
+    /// The session is encrypted, so no attention packet can be sent.
+    encrypted: AtomicBool,
+    /// How to end an encrypted session: log in with this configuration
+    /// and `KILL` the session with this id.
+    kill: Mutex<Option<(Config, i16)>>,
+    closed: AtomicBool,
+    reason: Mutex<Option<Reason>>,
+    /// The task reading the response, woken to send the attention packet.
+    reader: Mutex<Option<Waker>>,
+    /// The payload of the response to the latest request, when recording.
+    recording: Mutex<Option<Vec<u8>>>,
+}
+
+/// Cancels the statement running on one connection, from any task or
+/// thread.
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    shared: Arc<Shared>,
+}
+
+impl CancelHandle {
+    /// Stop the running statement. `false` when no statement is running or
+    /// it is already being cancelled.
+    pub fn cancel(&self) -> bool {
+        self.interrupt(Reason::Cancelled)
+    }
+
+    fn interrupt(&self, reason: Reason) -> bool {
+        let shared = &self.shared;
+        if !shared.active.load(Ordering::SeqCst) {
+            return false;
+        }
+        {
+            let mut current = shared.reason.lock().unwrap();
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(reason);
+        }
+
+        if shared.encrypted.load(Ordering::SeqCst) {
+            self.kill();
+        } else {
+            shared.requested.store(true, Ordering::SeqCst);
+            if let Some(reader) = shared.reader.lock().unwrap().take() {
+                reader.wake();
+            }
+        }
+        true
+    }
+
+    fn close(&self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        let _ = self.shared.socket.shutdown(Shutdown::Both);
+    }
+
+    /// End the session from a second connection, then close this one,
+    /// whose reply to the statement will not come.
+    fn kill(&self) {
+        let Some((config, spid)) = self.shared.kill.lock().unwrap().clone() else {
+            return self.close();
+        };
+        self.shared.closed.store(true, Ordering::SeqCst);
+
+        let handle = self.clone();
+        async_std::task::spawn(async move {
+            // Closing is all that is left when the second login fails.
+            let _ = kill_session(config, spid).await;
+            handle.close();
+        });
+    }
+
+    /// Keep a copy of the token stream of the responses to later requests.
+    /// `false` on a session tiberius encrypts, where it cannot be read, so row
+    /// counts and messages are not available; [`crate::batch::run`] reports
+    /// that as an incomplete result.
+    pub(crate) fn start_recording(&self) -> bool {
+        if self.shared.encrypted.load(Ordering::SeqCst) {
+            return false;
+        }
+        *self.shared.recording.lock().unwrap() = Some(Vec::new());
+        true
+    }
+
+    /// Stop recording and return the token stream of the response to the
+    /// latest request.
+    pub(crate) fn stop_recording(&self) -> Vec<u8> {
+        self.shared.recording.lock().unwrap().take().unwrap_or_default()
+    }
+}
+
+/// A connection to SQL Server whose statements can be cancelled through
+/// its [`CancelHandle`].
+pub struct CancellableStream {
+    stream: Session,
+    shared: Arc<Shared>,
+    /// The session is encrypted: bytes are passed on untouched.
+    passthrough: bool,
+    /// The packet being read, how much of it has arrived and how much of
+    /// it tiberius has had.
+    packet: Vec<u8>,
+    filled: usize,
+    delivered: usize,
+    /// The attention packet being sent, and how much of it is sent.
+    attention: Vec<u8>,
+    attention_sent: usize,
+    /// An attention packet was sent and the server has not confirmed it.
+    awaiting_ack: bool,
+    /// The response ended before the confirmation: drop packets until it
+    /// arrives.
+    discarding: bool,
+}
+
+impl CancellableStream {
+    pub fn new(stream: impl Into<Session>) -> (Self, CancelHandle) {
+        let stream = stream.into();
+        let shared = Arc::new(Shared {
+            socket: stream.socket().clone(),
+            active: AtomicBool::new(false),
+            requested: AtomicBool::new(false),
+            encrypted: AtomicBool::new(false),
+            kill: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            reason: Mutex::new(None),
+            reader: Mutex::new(None),
+            recording: Mutex::new(None),
+        });
+        let handle = CancelHandle { shared: shared.clone() };
+
+        let stream = CancellableStream {
+            stream,
+            shared,
+            passthrough: false,
+            packet: Vec::new(),
+            filled: 0,
+            delivered: 0,
+            attention: Vec::new(),
+            attention_sent: 0,
+            awaiting_ack: false,
+            discarding: false,
+        };
+        (stream, handle)
+    }
+
+    /// Whether tiberius gets the packet just read. Watches for the end of
+    /// a response and the server's confirmation of a cancel.
+    fn keep_packet(&mut self) -> bool {
+        let eom = self.packet[1] & STATUS_EOM != 0;
+        let confirmed = eom && self.packet.len() >= HEADER_LEN + DONE_LEN && {
+            let done = &self.packet[self.packet.len() - DONE_LEN..];
+            done[0] == TOKEN_DONE && u16::from_le_bytes([done[1], done[2]]) & DONE_ATTENTION != 0
+        };
+
+        if self.discarding {
+            if confirmed {
+                self.discarding = false;
+                self.awaiting_ack = false;
+            }
+            return false;
+        }
+        if self.awaiting_ack && eom {
+            self.awaiting_ack = false;
+            self.discarding = !confirmed;
+        }
+        true
+    }
+}
+
+impl AsyncRead for CancellableStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        *this.shared.reader.lock().unwrap() = Some(cx.waker().clone());
+        if this.shared.requested.swap(false, Ordering::SeqCst) {
+            this.attention = vec![PACKET_ATTENTION, STATUS_EOM, 0, HEADER_LEN as u8, 0, 0, 1, 0];
+            this.attention_sent = 0;
+            this.awaiting_ack = true;
+        }
+        while this.attention_sent < this.attention.len() {
+            let written = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.attention[this.attention_sent..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.attention_sent += written;
+            if this.attention_sent == this.attention.len() {
+                ready!(Pin::new(&mut this.stream).poll_flush(cx))?;
+            }
+        }
+
+        loop {
+            let complete = this.filled == this.packet.len() && this.filled >= HEADER_LEN;
+            if this.delivered < this.filled && (complete || this.passthrough) {
+                let n = buf.len().min(this.filled - this.delivered);
+                buf[..n].copy_from_slice(&this.packet[this.delivered..this.delivered + n]);
+                this.delivered += n;
+                return Poll::Ready(Ok(n));
+            }
+            if this.passthrough {
+                return Pin::new(&mut this.stream).poll_read(cx, buf);
+            }
+            if complete {
+                this.packet.clear();
+                this.filled = 0;
+                this.delivered = 0;
+            }
+
+            if this.packet.is_empty() {
+                this.packet.resize(HEADER_LEN, 0);
+            }
+            let read = ready!(Pin::new(&mut this.stream).poll_read(cx, &mut this.packet[this.filled..]))?;
+            if read == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            this.filled += read;
+
+            if this.filled == HEADER_LEN && this.packet.len() == HEADER_LEN {
+                if this.packet[0] != PACKET_TABULAR_RESULT && this.packet[0] != PACKET_PRELOGIN {
+                    // A TLS record: the session is encrypted from here on.
+                    this.passthrough = true;
+                    this.shared.encrypted.store(true, Ordering::SeqCst);
+                    continue;
+                }
+                let len = u16::from_be_bytes([this.packet[2], this.packet[3]]) as usize;
+                if len < HEADER_LEN {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid packet length {}", len),
+                    )));
+                }
+                this.packet.resize(len, 0);
+            }
+
+            if this.filled == this.packet.len() {
+                if !this.keep_packet() {
+                    this.delivered = this.filled;
+                } else if let Some(recording) = this.shared.recording.lock().unwrap().as_mut() {
+                    recording.extend_from_slice(&this.packet[HEADER_LEN..]);
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for CancellableStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // A new request: what was read so far answered an earlier one.
+        if let Some(recording) = this.shared.recording.lock().unwrap().as_mut() {
+            recording.clear();
+        }
+        Pin::new(&mut this.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_close(cx)
+    }
+}
+
+/// Log in with `config` over a connection whose statements can be
+/// cancelled.
+pub async fn connect(config: Config) -> anyhow::Result<(Client<CancellableStream>, CancelHandle)> {
+    let (client, handle, _) = connect_traced_at(config.get_addr(), config, None).await?;
+    Ok((client, handle))
+}
+
+/// Like [`connect`], as [`sql_client::connect_traced_at`] does. With
+/// `tls`, the configuration must come from [`crate::tls::prepare`].
+pub(crate) async fn connect_traced_at(
+    addr: String,
+    config: Config,
+    tls: Option<&TlsSettings>,
+) -> anyhow::Result<(Client<CancellableStream>, CancelHandle, Option<i16>)> {
+    let mut handle = None;
+    let side = config.clone();
+    let stream = async {
+        let (stream, cancel) = CancellableStream::new(Session::open(&addr, tls).await?);
+        handle = Some(cancel);
+        Ok(stream)
+    };
+    let (mut client, spid) = sql_client::connect_traced_with(addr.clone(), config, stream).await?;
+
+    let handle = handle.expect("the stream is created before logging in");
+    if handle.shared.encrypted.load(Ordering::SeqCst) {
+        let id = match spid {
+            Some(id) => id,
+            None => sql_client::session_id(&mut client).await?,
+        };
+        *handle.shared.kill.lock().unwrap() = Some((side, id));
+    }
+    Ok((client, handle, spid))
+}
+
+/// Log in with `config` and end the session `spid`.
+async fn kill_session(config: Config, spid: i16) -> anyhow::Result<()> {
+    let mut client = sql_client::connect(config).await?;
+    client.simple_query(format!("KILL {}", spid)).await?.into_results().await?;
+    client.close().await?;
+    Ok(())
+}
+
+/// Run `work`, a statement on the connection of `handle`. It is cancelled
+/// when `timeout` passes or the handle is used, and then fails with
+/// [`Interrupted`] once the server has confirmed. A server that does not
+/// confirm a timed out statement in time has its connection closed.
+pub async fn run<T>(
+    handle: &CancelHandle,
+    timeout: Option<Duration>,
+    work: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let shared = &handle.shared;
+    if shared.closed.load(Ordering::SeqCst) {
+        anyhow::bail!("The connection was closed to cancel an earlier statement; reopen it");
+    }
+    *shared.reason.lock().unwrap() = None;
+    shared.active.store(true, Ordering::SeqCst);
+
+    let result = match future::select(pin!(work), pin!(watchdog(handle, timeout))).await {
+        Either::Left((result, _)) => Some(result),
+        Either::Right(_) => None,
+    };
+
+    shared.active.store(false, Ordering::SeqCst);
+    let unsent = shared.requested.swap(false, Ordering::SeqCst);
+    let reason = shared.reason.lock().unwrap().take();
+
+    match (reason, result) {
+        // Cancelled as the statement finished: nothing was stopped.
+        (Some(_), Some(Ok(value))) if unsent => Ok(value),
+        (Some(reason), _) => Err(Interrupted {
+            reason,
+            connection_closed: shared.closed.load(Ordering::SeqCst),
+        }
+        .into()),
+        (None, Some(result)) => result,
+        (None, None) => unreachable!("the watchdog only returns after interrupting the statement"),
+    }
+}
+
+/// Interrupt the statement after `timeout`, then close the connection if
+/// the server has not confirmed within [`GRACE`].
+async fn watchdog(handle: &CancelHandle, timeout: Option<Duration>) {
+    let Some(limit) = timeout else {
+        return future::pending().await;
+    };
+    async_std::task::sleep(limit).await;
+    handle.interrupt(Reason::TimedOut(limit));
+    async_std::task::sleep(GRACE).await;
+    handle.close();
+}
+
+static CTRL_C: Lazy<Mutex<Option<CancelHandle>>> = Lazy::new(|| Mutex::new(None));
+
+/// Make Ctrl-C cancel the statement running on `handle`. A second Ctrl-C,
+/// or one while no statement runs, exits as usual. On a session tiberius
+/// encrypts Ctrl-C ends the session, and says so.
+pub fn cancel_on_ctrl_c(handle: &CancelHandle) -> anyhow::Result<()> {
+    let installed = CTRL_C.lock().unwrap().replace(handle.clone()).is_some();
+    if !installed {
+        ctrlc::set_handler(|| {
+            let handle = CTRL_C.lock().unwrap().clone();
+            match handle {
+                Some(h) if h.cancel() => {
+                    if h.shared.encrypted.load(Ordering::SeqCst) {
+                        eprintln!(
+                            "The session is encrypted, so the statement is stopped by ending the session, \
+                             which is lost; press Ctrl-C again to quit"
+                        );
+                    } else {
+                        eprintln!("Cancelling the statement; press Ctrl-C again to quit");
+                    }
+                }
+                _ => std::process::exit(130),
+            }
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,542 @@
+//! Read ADO.NET, JDBC and ODBC connection strings into one
+//! [`ConnectionString`], check their keys and values, and write them back
+//! out in any of the three formats:
+//!
+//! ```text
+//! server=tcp:JASON\SQLEXPRESS,61521;database=AdventureWorks2016_EXT;IntegratedSecurity=true
+//! jdbc:sqlserver://JASON\SQLEXPRESS:61521;databaseName=AdventureWorks2016_EXT;integratedSecurity=true
+//! Driver={ODBC Driver 18 for SQL Server};Server=tcp:JASON\SQLEXPRESS,61521;Database=AdventureWorks2016_EXT;Trusted_Connection=yes
+//! ```
+//!
+//! [`ConnectionString::redacted`] hides the password, so the result can go
+//! into logs.
+
+use std::fmt;
+use std::str::FromStr;
+
+use tiberius::Config;
+
+use crate::auth::Secret;
+use crate::diagnose::ServerAddress;
+
+const JDBC_PREFIX: &str = "jdbc:sqlserver://";
+const ODBC_DRIVER: &str = "ODBC Driver 18 for SQL Server";
+const REDACTED: &str = "***";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `server=...;database=...`, as read by [`Config::from_ado_string`].
+    Ado,
+    /// `jdbc:sqlserver://host\instance:port;databaseName=...`
+    Jdbc,
+    /// `Driver={...};Server=...;Database=...`
+    Odbc,
+}
+
+impl Format {
+    /// Guess the format of `s`: JDBC strings start with `jdbc:`, ODBC ones
+    /// name a driver.
+    pub fn detect(s: &str) -> Self {
+        let lower = s.trim_start().to_lowercase();
+        if lower.starts_with("jdbc:") {
+            Format::Jdbc
+        } else if lower.starts_with("driver=") || lower.contains(";driver=") || lower.contains("; driver=") {
+            Format::Odbc
+        } else {
+            Format::Ado
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "ado" | "ado.net" => Ok(Format::Ado),
+            "jdbc" => Ok(Format::Jdbc),
+            "odbc" => Ok(Format::Odbc),
+            _ => anyhow::bail!("Unknown format {:?}; expected ado, jdbc or odbc", s),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Ado => "ADO.NET",
+            Format::Jdbc => "JDBC",
+            Format::Odbc => "ODBC",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encrypt {
+    /// Only the login is encrypted (`encrypt=false`).
+    Off,
+    /// The whole session is encrypted.
+    On,
+    /// Nothing is encrypted, not even the login. Only tiberius knows this
+    /// setting (`encrypt=DANGER_PLAINTEXT`).
+    Plaintext,
+}
+
+/// The settings of a connection string, whatever its format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionString {
+    pub server: ServerAddress,
+    pub database: Option<String>,
+    pub user: Option<String>,
+    /// Printed as `***` by `Debug`; [`Secret::expose`] gives the value.
+    pub password: Option<Secret>,
+    pub integrated_security: bool,
+    pub encrypt: Option<Encrypt>,
+    pub trust_server_certificate: bool,
+    pub application_name: Option<String>,
+}
+
+/// The settings a key can set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Server,
+    Database,
+    User,
+    Password,
+    IntegratedSecurity,
+    Encrypt,
+    TrustServerCertificate,
+    ApplicationName,
+    /// ODBC's driver name, which only ODBC itself needs.
+    Driver,
+    /// JDBC may give the instance and port as keys instead of in the URL.
+    InstanceName,
+    PortNumber,
+}
+
+/// The keys each format knows, lowercased.
+fn keys(format: Format) -> &'static [(&'static str, Key)] {
+    match format {
+        Format::Ado => &[
+            ("server", Key::Server),
+            ("data source", Key::Server),
+            ("address", Key::Server),
+            ("addr", Key::Server),
+            ("network address", Key::Server),
+            ("database", Key::Database),
+            ("initial catalog", Key::Database),
+            ("user id", Key::User),
+            ("uid", Key::User),
+            ("user", Key::User),
+            ("username", Key::User),
+            ("password", Key::Password),
+            ("pwd", Key::Password),
+            ("integrated security", Key::IntegratedSecurity),
+            ("integratedsecurity", Key::IntegratedSecurity),
+            ("trusted_connection", Key::IntegratedSecurity),
+            ("encrypt", Key::Encrypt),
+            ("trustservercertificate", Key::TrustServerCertificate),
+            ("trust server certificate", Key::TrustServerCertificate),
+            ("application name", Key::ApplicationName),
+            ("applicationname", Key::ApplicationName),
+            ("app", Key::ApplicationName),
+        ],
+        Format::Jdbc => &[
+            ("servername", Key::Server),
+            ("server", Key::Server),
+            ("instancename", Key::InstanceName),
+            ("portnumber", Key::PortNumber),
+            ("port", Key::PortNumber),
+            ("databasename", Key::Database),
+            ("database", Key::Database),
+            ("user", Key::User),
+            ("username", Key::User),
+            ("password", Key::Password),
+            ("integratedsecurity", Key::IntegratedSecurity),
+            ("encrypt", Key::Encrypt),
+            ("trustservercertificate", Key::TrustServerCertificate),
+            ("applicationname", Key::ApplicationName),
+        ],
+        Format::Odbc => &[
+            ("driver", Key::Driver),
+            ("server", Key::Server),
+            ("address", Key::Server),
+            ("addr", Key::Server),
+            ("database", Key::Database),
+            ("uid", Key::User),
+            ("pwd", Key::Password),
+            ("trusted_connection", Key::IntegratedSecurity),
+            ("encrypt", Key::Encrypt),
+            ("trustservercertificate", Key::TrustServerCertificate),
+            ("app", Key::ApplicationName),
+        ],
+    }
+}
+
+/// Split `key=value;...` into pairs. Values may be quoted: `"..."` or
+/// `'...'` in ADO.NET, `{...}` in all three formats, with the closing quote
+/// doubled to escape it.
+fn split_pairs(s: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ';').is_some() {}
+        if chars.peek().is_none() {
+            return Ok(pairs);
+        }
+
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ';')).collect();
+        if chars.next_if_eq(&'=').is_none() {
+            anyhow::bail!("`{}` is not a key=value pair", key.trim());
+        }
+        while chars.next_if(|c| *c == ' ').is_some() {}
+
+        let value = match chars.peek().copied() {
+            Some(open @ ('{' | '"' | '\'')) => {
+                chars.next();
+                let close = if open == '{' { '}' } else { open };
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(c) if c == close && chars.next_if_eq(&close).is_some() => value.push(c),
+                        Some(c) if c == close => break,
+                        Some(c) => value.push(c),
+                        None => anyhow::bail!("The value of `{}` has no closing {}", key.trim(), close),
+                    }
+                }
+                while chars.next_if(|c| *c == ' ').is_some() {}
+                if chars.next_if(|c| *c != ';').is_some() {
+                    anyhow::bail!("Unexpected text after the quoted value of `{}`", key.trim());
+                }
+                value
+            }
+            _ => std::iter::from_fn(|| chars.next_if(|c| *c != ';')).collect::<String>().trim().to_string(),
+        };
+
+        pairs.push((key.trim().to_string(), value));
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" => Some(true),
+        "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_encrypt(value: &str) -> Option<Encrypt> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "mandatory" => Some(Encrypt::On),
+        "false" | "no" | "optional" => Some(Encrypt::Off),
+        "danger_plaintext" => Some(Encrypt::Plaintext),
+        _ => None,
+    }
+}
+
+/// Letters to change, add or remove to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let next = (diagonal + usize::from(ca != *cb)).min(row[j] + 1).min(row[j + 1] + 1);
+            diagonal = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+impl ConnectionString {
+    /// Parse `s` in the format [`Format::detect`] finds.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        Self::parse_as(s, Format::detect(s))
+    }
+
+    /// Parse `s` as `format`. Every problem found is listed in the error,
+    /// not just the first.
+    pub fn parse_as(s: &str, format: Format) -> anyhow::Result<Self> {
+        let mut problems = Vec::new();
+        let mut server = None;
+        let mut instance = None;
+        let mut port = None;
+
+        let rest = match format {
+            Format::Jdbc => {
+                let Some(url) = s.trim().get(..JDBC_PREFIX.len()).filter(|p| p.eq_ignore_ascii_case(JDBC_PREFIX))
+                else {
+                    anyhow::bail!("A JDBC connection string starts with {}", JDBC_PREFIX);
+                };
+                let url = &s.trim()[url.len()..];
+                let (address, rest) = url.split_once(';').unwrap_or((url, ""));
+                if !address.is_empty() {
+                    let (host, port_text) = match address.rsplit_once(':') {
+                        Some((host, port)) => (host, Some(port)),
+                        None => (address, None),
+                    };
+                    server = Some(host.to_string());
+                    if let Some(port_text) = port_text {
+                        match port_text.parse::<u16>() {
+                            Ok(p) => port = Some(p),
+                            Err(_) => problems.push(format!("`{}` is not a port number", port_text)),
+                        }
+                    }
+                }
+                rest
+            }
+            Format::Ado | Format::Odbc => s,
+        };
+
+        let mut cs = ConnectionString {
+            server: ServerAddress {
+                host: String::new(),
+                instance: None,
+                port: None,
+            },
+            database: None,
+            user: None,
+            password: None,
+            integrated_security: false,
+            encrypt: None,
+            trust_server_certificate: false,
+            application_name: None,
+        };
+        let mut seen: Vec<Key> = Vec::new();
+
+        for (name, value) in split_pairs(rest)? {
+            let lower = name.to_lowercase();
+            let Some(&(_, key)) = keys(format).iter().find(|(k, _)| *k == lower) else {
+                let suggestion = keys(format)
+                    .iter()
+                    .map(|(k, _)| (edit_distance(&lower, k), *k))
+                    .filter(|(distance, _)| *distance <= 2)
+                    .min();
+                problems.push(match suggestion {
+                    Some((_, k)) => format!("Unknown {} key `{}`; did you mean `{}`?", format, name, k),
+                    None => format!("Unknown {} key `{}`", format, name),
+                });
+                continue;
+            };
+
+            if seen.contains(&key) {
+                problems.push(format!("`{}` is set more than once", name));
+                continue;
+            }
+            seen.push(key);
+
+            let boolean = |problems: &mut Vec<String>| {
+                let parsed = parse_bool(&value).or_else(|| {
+                    (key == Key::IntegratedSecurity && value.eq_ignore_ascii_case("sspi")).then_some(true)
+                });
+                if parsed.is_none() {
+                    problems.push(format!("`{}` must be true or false, not `{}`", name, value));
+                }
+                parsed.unwrap_or_default()
+            };
+
+            match key {
+                Key::Server => server = Some(value.clone()),
+                Key::InstanceName => instance = Some(value.clone()),
+                Key::PortNumber => match value.parse::<u16>() {
+                    Ok(p) => port = Some(p),
+                    Err(_) => problems.push(format!("`{}` must be a port number, not `{}`", name, value)),
+                },
+                Key::Database => cs.database = Some(value.clone()),
+                Key::User => cs.user = Some(value.clone()),
+                Key::Password => cs.password = Some(Secret::new(value.clone())),
+                Key::IntegratedSecurity => cs.integrated_security = boolean(&mut problems),
+                Key::TrustServerCertificate => cs.trust_server_certificate = boolean(&mut problems),
+                Key::Encrypt => match parse_encrypt(&value) {
+                    Some(encrypt) => cs.encrypt = Some(encrypt),
+                    None => {
+                        problems.push(format!("`{}` must be true, false or DANGER_PLAINTEXT, not `{}`", name, value))
+                    }
+                },
+                Key::ApplicationName => cs.application_name = Some(value.clone()),
+                Key::Driver => {}
+            }
+        }
+
+        match server.as_deref().map(ServerAddress::parse) {
+            Some(Ok(address)) => cs.server = address,
+            Some(Err(e)) => problems.push(e.to_string()),
+            None => problems.push("No server is given".to_string()),
+        }
+        if instance.is_some() {
+            cs.server.instance = instance;
+        }
+        if port.is_some() {
+            cs.server.port = port;
+        }
+
+        if cs.password.is_some() && cs.user.is_none() {
+            problems.push("A password is given without a user".to_string());
+        }
+        if cs.user.is_none() && !cs.integrated_security {
+            problems.push("Give either a user or integrated security".to_string());
+        }
+
+        if !problems.is_empty() {
+            anyhow::bail!("Invalid {} connection string:\n  - {}", format, problems.join("\n  - "));
+        }
+
+        Ok(cs)
+    }
+
+    /// A copy with the password, if any, replaced by `***`.
+    pub fn redacted(&self) -> Self {
+        ConnectionString {
+            password: self.password.as_ref().map(|_| Secret::new(REDACTED)),
+            ..self.clone()
+        }
+    }
+
+    /// Write the settings as a `format` connection string. Fails for a
+    /// setting the format has no key for.
+    pub fn to_format(&self, format: Format) -> anyhow::Result<String> {
+        match format {
+            Format::Ado => Ok(self.to_ado_string()),
+            Format::Jdbc => self.to_jdbc_string(),
+            Format::Odbc => self.to_odbc_string(),
+        }
+    }
+
+    pub fn to_ado_string(&self) -> String {
+        let quote = |value: &str| {
+            if value.contains([';', '"', '\'']) || value.trim() != value {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value.to_string()
+            }
+        };
+
+        let mut parts = vec![format!("server=tcp:{}", self.server)];
+        if let Some(database) = &self.database {
+            parts.push(format!("database={}", quote(database)));
+        }
+        if let Some(user) = &self.user {
+            parts.push(format!("user id={}", quote(user)));
+        }
+        if let Some(password) = &self.password {
+            parts.push(format!("password={}", quote(password.expose())));
+        }
+        if self.integrated_security {
+            parts.push("IntegratedSecurity=true".to_string());
+        }
+        match self.encrypt {
+            Some(Encrypt::On) => parts.push("encrypt=true".to_string()),
+            Some(Encrypt::Off) => parts.push("encrypt=false".to_string()),
+            Some(Encrypt::Plaintext) => parts.push("encrypt=DANGER_PLAINTEXT".to_string()),
+            None => {}
+        }
+        if self.trust_server_certificate {
+            parts.push("TrustServerCertificate=true".to_string());
+        }
+        if let Some(application_name) = &self.application_name {
+            parts.push(format!("Application Name={}", quote(application_name)));
+        }
+        parts.join(";")
+    }
+
+    pub fn to_jdbc_string(&self) -> anyhow::Result<String> {
+        let mut url = format!("{}{}", JDBC_PREFIX, self.server.host);
+        if let Some(instance) = &self.server.instance {
+            url.push_str(&format!("\\{}", instance));
+        }
+        if let Some(port) = self.server.port {
+            url.push_str(&format!(":{}", port));
+        }
+
+        let mut parts = vec![url];
+        if let Some(database) = &self.database {
+            parts.push(format!("databaseName={}", braced(database)));
+        }
+        if let Some(user) = &self.user {
+            parts.push(format!("user={}", braced(user)));
+        }
+        if let Some(password) = &self.password {
+            parts.push(format!("password={}", braced(password.expose())));
+        }
+        if self.integrated_security {
+            parts.push("integratedSecurity=true".to_string());
+        }
+        match self.encrypt {
+            Some(Encrypt::On) => parts.push("encrypt=true".to_string()),
+            Some(Encrypt::Off) => parts.push("encrypt=false".to_string()),
+            Some(Encrypt::Plaintext) => anyhow::bail!("JDBC has no equivalent of encrypt=DANGER_PLAINTEXT"),
+            None => {}
+        }
+        if self.trust_server_certificate {
+            parts.push("trustServerCertificate=true".to_string());
+        }
+        if let Some(application_name) = &self.application_name {
+            parts.push(format!("applicationName={}", braced(application_name)));
+        }
+        Ok(parts.join(";"))
+    }
+
+    pub fn to_odbc_string(&self) -> anyhow::Result<String> {
+        let mut parts = vec![
+            format!("Driver={{{}}}", ODBC_DRIVER),
+            format!("Server=tcp:{}", self.server),
+        ];
+        if let Some(database) = &self.database {
+            parts.push(format!("Database={}", braced(database)));
+        }
+        if let Some(user) = &self.user {
+            parts.push(format!("UID={}", braced(user)));
+        }
+        if let Some(password) = &self.password {
+            parts.push(format!("PWD={}", braced(password.expose())));
+        }
+        if self.integrated_security {
+            parts.push("Trusted_Connection=yes".to_string());
+        }
+        match self.encrypt {
+            Some(Encrypt::On) => parts.push("Encrypt=yes".to_string()),
+            Some(Encrypt::Off) => parts.push("Encrypt=no".to_string()),
+            Some(Encrypt::Plaintext) => anyhow::bail!("ODBC has no equivalent of encrypt=DANGER_PLAINTEXT"),
+            None => {}
+        }
+        if self.trust_server_certificate {
+            parts.push("TrustServerCertificate=yes".to_string());
+        }
+        if let Some(application_name) = &self.application_name {
+            parts.push(format!("APP={}", braced(application_name)));
+        }
+        Ok(parts.join(";"))
+    }
+
+    /// The tiberius configuration for these settings.
+    pub fn config(&self) -> anyhow::Result<Config> {
+        Ok(Config::from_ado_string(&self.to_ado_string())?)
+    }
+}
+
+/// Wrap a JDBC or ODBC value in braces when it holds a separator.
+fn braced(value: &str) -> String {
+    if value.contains([';', '{', '}']) || value.trim() != value {
+        format!("{{{}}}", value.replace('}', "}}"))
+    } else {
+        value.to_string()
+    }
+}
+
+/// The redacted ADO.NET form, for logs.
+impl fmt::Display for ConnectionString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.redacted().to_ado_string())
+    }
+}
+
+impl FromStr for ConnectionString {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        ConnectionString::parse(s)
+    }
+}
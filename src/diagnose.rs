@@ -0,0 +1,372 @@
+//! Step-by-step connection checks, so a failed connection says which step
+//! broke and what to try, instead of one driver error.
+//!
+//! The steps are: DNS resolution, the SQL Browser lookup of a named
+//! instance's port, the TCP connect, the PRELOGIN exchange (server version
+//! and encryption), TLS and login, and a trivial query. A step only runs
+//! when the ones before it passed.
+
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use async_std::net::{TcpStream, ToSocketAddrs};
+use tiberius::{Client, Config};
+
+use crate::browser::{self, BROWSER_PORT};
+use crate::sql_client;
+use crate::profile::Profile;
+use crate::tds::{self, ENCRYPT_NOT_SUP, ENCRYPT_OFF, ENCRYPT_ON, ENCRYPT_REQ};
+use crate::tls::{self, Session, TlsFailure, TlsSettings};
+
+/// Where a connection string points: `server=[tcp:]host[\instance][,port]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddress {
+    pub host: String,
+    pub instance: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl ServerAddress {
+    /// Read the `server` (or `data source`) key of an ADO.NET connection
+    /// string.
+    pub fn from_ado_string(s: &str) -> anyhow::Result<Self> {
+        let server = s
+            .split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| {
+                let key = key.trim().to_lowercase();
+                matches!(key.as_str(), "server" | "data source" | "address" | "addr" | "network address")
+            })
+            .map(|(_, value)| value.trim())
+            .ok_or_else(|| anyhow::anyhow!("The connection string has no server"))?;
+
+        ServerAddress::parse(server)
+    }
+
+    /// Parse a `server` value: `[tcp:]host[\instance][,port]`.
+    pub fn parse(server: &str) -> anyhow::Result<Self> {
+        let server = server.strip_prefix("tcp:").unwrap_or(server);
+        let (server, port) = match server.rsplit_once(',') {
+            Some((server, port)) => {
+                let port = port.trim().parse().map_err(|_| anyhow::anyhow!("Invalid port {:?}", port))?;
+                (server, Some(port))
+            }
+            None => (server, None),
+        };
+        let (host, instance) = match server.split_once('\\') {
+            Some((host, instance)) => (host, Some(instance.to_string())),
+            None => (server, None),
+        };
+        let host = match host {
+            "." | "(local)" | "" => "localhost",
+            host => host,
+        };
+
+        Ok(ServerAddress {
+            host: host.to_string(),
+            instance,
+            port,
+        })
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.host)?;
+        if let Some(instance) = &self.instance {
+            write!(f, "\\{}", instance)?;
+        }
+        if let Some(port) = self.port {
+            write!(f, ",{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// Passed, with what was found.
+    Passed(String),
+    Failed {
+        error: String,
+        /// What to check, when the failure has a usual cause.
+        hint: Option<String>,
+    },
+    /// Not run, and why.
+    Skipped(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub name: &'static str,
+    pub duration: Duration,
+    pub status: Status,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub steps: Vec<Step>,
+}
+
+impl Report {
+    /// Whether every step that ran passed.
+    pub fn passed(&self) -> bool {
+        !self.steps.iter().any(|s| matches!(s.status, Status::Failed { .. }))
+    }
+
+    fn failed(&self) -> bool {
+        !self.passed()
+    }
+
+    fn skip(&mut self, name: &'static str, reason: &str) {
+        self.steps.push(Step {
+            name,
+            duration: Duration::ZERO,
+            status: Status::Skipped(reason.to_string()),
+        });
+    }
+
+    /// Run one step with a time limit and record its outcome. `detail`
+    /// describes a success, `hint` explains a failure.
+    async fn run<T, Fut>(
+        &mut self,
+        name: &'static str,
+        timeout: Duration,
+        step: Fut,
+        detail: impl FnOnce(&T) -> String,
+        hint: impl FnOnce(&anyhow::Error) -> Option<String>,
+    ) -> Option<T>
+    where
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        if self.failed() {
+            self.skip(name, "an earlier step failed");
+            return None;
+        }
+
+        let start = Instant::now();
+        let result = match async_std::future::timeout(timeout, step).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("Timed out after {} s", timeout.as_secs_f32())),
+        };
+
+        let (status, value) = match result {
+            Ok(value) => (Status::Passed(detail(&value)), Some(value)),
+            Err(e) => (
+                Status::Failed {
+                    hint: hint(&e),
+                    error: e.to_string(),
+                },
+                None,
+            ),
+        };
+
+        self.steps.push(Step {
+            name,
+            duration: start.elapsed(),
+            status,
+        });
+        value
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            let (label, text) = match &step.status {
+                Status::Passed(detail) => ("OK", detail.as_str()),
+                Status::Failed { error, .. } => ("FAIL", error.as_str()),
+                Status::Skipped(reason) => ("SKIP", reason.as_str()),
+            };
+            writeln!(
+                f,
+                "[{:^4}] {:<16} {:>6} ms  {}",
+                label,
+                step.name,
+                step.duration.as_millis(),
+                text
+            )?;
+            if let Status::Failed { hint: Some(hint), .. } = &step.status {
+                writeln!(f, "       hint: {}", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check each step of connecting with an ADO.NET connection string. Fails
+/// only if the string itself is invalid; connection problems are reported
+/// in the steps. `TIBERIUS_USER` replaces the string's login, as for every
+/// other connection; see [`sql_client::config_from_ado_string`].
+pub async fn diagnose(connection_string: &str, timeout: Duration) -> anyhow::Result<Report> {
+    let config = sql_client::config_from_ado_string(connection_string)?;
+    let server = ServerAddress::from_ado_string(connection_string)?;
+    Ok(run(config, server, None, timeout).await)
+}
+
+/// [`diagnose`] a connection profile, checking the server's certificate as
+/// its TLS settings ask: the pin, the CA bundle and the host name override.
+pub async fn diagnose_profile(profile: &Profile, timeout: Duration) -> anyhow::Result<Report> {
+    let server = ServerAddress::parse(&profile.server)?;
+    Ok(run(profile.config()?, server, Some(&profile.tls), timeout).await)
+}
+
+async fn run(config: Config, server: ServerAddress, tls: Option<&TlsSettings>, timeout: Duration) -> Report {
+    let mut report = Report::default();
+
+    let resolve = async {
+        let addrs: Vec<SocketAddr> = (server.host.as_str(), 0).to_socket_addrs().await?.collect();
+        addrs.first().map(|a| a.ip()).ok_or_else(|| anyhow::anyhow!("No addresses for {}", server.host))
+    };
+    let ip = report
+        .run("DNS resolution", timeout, resolve, |ip| ip.to_string(), |_| {
+            Some(format!("Check the spelling of {:?} and that this machine's DNS can resolve it", server.host))
+        })
+        .await;
+
+    let port = match (&server.instance, server.port) {
+        (Some(instance), None) => {
+            let instance = instance.as_str();
+            let lookup = async { browser::lookup(SocketAddr::new(ip.unwrap(), BROWSER_PORT), instance, timeout).await };
+            report
+                .run("SQL Browser", timeout, lookup, |port| format!("{} listens on port {}", instance, port), |e| {
+                    Some(browser_hint(e, instance))
+                })
+                .await
+        }
+        (_, port) => {
+            report.skip(
+                "SQL Browser",
+                match server.instance {
+                    Some(_) => "the port is given",
+                    None => "not a named instance",
+                },
+            );
+            Some(port.unwrap_or(1433))
+        }
+    };
+
+    let addr = ip.zip(port).map(|(ip, port)| SocketAddr::new(ip, port));
+    let connect = async { Ok(TcpStream::connect(addr.unwrap()).await?) };
+    let tcp = report
+        .run("TCP connect", timeout, connect, |_| addr.unwrap().to_string(), |e| tcp_hint(e, port))
+        .await;
+
+    let prelogin = async { tds::prelogin(&mut tcp.unwrap(), ENCRYPT_OFF).await };
+    let server_info = report
+        .run(
+            "PRELOGIN",
+            timeout,
+            prelogin,
+            |info| format!("SQL Server {}, encryption {}", info.version, encryption_name(info.encryption)),
+            |_| Some("Something other than SQL Server answers on this port".to_string()),
+        )
+        .await;
+    let encryption = server_info.map(|i| i.encryption);
+
+    let login = async {
+        // By name, which the certificate is validated against.
+        let addr = format!("{}:{}", server.host, addr.unwrap().port());
+        let mut config = config;
+        if let Some(settings) = tls {
+            config = tls::prepare(config, settings)?.1;
+        }
+        let session = Session::open(&addr, tls).await?;
+        Ok(Client::connect(config, session).await?)
+    };
+    let client = report
+        .run("TLS and login", timeout, login, |_| "logged in".to_string(), |e| login_hint(e, encryption))
+        .await;
+
+    let query = async {
+        let mut client = client.unwrap();
+        let row = client.simple_query("SELECT @@VERSION").await?.into_row().await?;
+        client.close().await?;
+        Ok(row.and_then(|r| r.get::<&str, _>(0).map(|v| v.lines().next().unwrap_or_default().to_string())))
+    };
+    report
+        .run("Query", timeout, query, |version| version.clone().unwrap_or_default(), |_| None)
+        .await;
+
+    report
+}
+
+fn encryption_name(encryption: u8) -> &'static str {
+    match encryption {
+        ENCRYPT_OFF => "off (login only)",
+        ENCRYPT_ON => "on",
+        ENCRYPT_NOT_SUP => "not supported",
+        ENCRYPT_REQ => "required",
+        _ => "unknown",
+    }
+}
+
+fn io_kind(error: &anyhow::Error) -> Option<std::io::ErrorKind> {
+    match error.downcast_ref::<tiberius::error::Error>() {
+        Some(tiberius::error::Error::Io { kind, .. }) => Some(*kind),
+        _ => error.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+    }
+}
+
+fn browser_hint(error: &anyhow::Error, instance: &str) -> String {
+    let message = error.to_string();
+    if message.starts_with("Timed out") || message.starts_with("No reply from SQL Browser") || io_kind(error).is_some() {
+        return "SQL Browser did not answer on UDP 1434: start the SQL Server Browser service, \
+                open UDP 1434 in the firewall, or give the port as server=host,port"
+            .to_string();
+    }
+    format!(
+        "Check the instance name {:?} and that TCP/IP is enabled for it in SQL Server Configuration Manager",
+        instance
+    )
+}
+
+fn tcp_hint(error: &anyhow::Error, port: Option<u16>) -> Option<String> {
+    let port = port.unwrap_or(1433);
+    match io_kind(error) {
+        Some(std::io::ErrorKind::ConnectionRefused) => Some(format!(
+            "Nothing listens on port {}: check the port, that the server is running and that TCP/IP is enabled",
+            port
+        )),
+        _ if error.to_string().starts_with("Timed out") => Some(format!(
+            "No answer on port {}: a firewall is probably dropping the connection",
+            port
+        )),
+        _ => None,
+    }
+}
+
+fn login_hint(error: &anyhow::Error, encryption: Option<u8>) -> Option<String> {
+    let driver = error.downcast_ref::<tiberius::error::Error>()?;
+
+    match driver {
+        tiberius::error::Error::Tls(message) => Some(TlsFailure::classify(message).to_string()),
+        tiberius::error::Error::Server(token) => match token.code() {
+            18456 => Some(
+                "Login failed. The reason is only in the server's error log, by state: 2 or 5 unknown user, \
+                 8 wrong password, 11 or 12 no access to the server, 38 or 40 default database unavailable, \
+                 58 SQL logins disabled"
+                    .to_string(),
+            ),
+            18452 => Some("The login is from an untrusted domain; use SQL authentication".to_string()),
+            18470 => Some("The login is disabled".to_string()),
+            18487 | 18488 => Some("The password has expired or must be changed".to_string()),
+            4060 => Some(
+                "The database in the connection string does not exist or the login cannot open it".to_string(),
+            ),
+            _ => None,
+        },
+        tiberius::error::Error::Io { .. } | tiberius::error::Error::Protocol(_) => match encryption {
+            Some(ENCRYPT_REQ) => Some(
+                "The server requires encryption but the connection string turns it off".to_string(),
+            ),
+            Some(ENCRYPT_NOT_SUP) => Some(
+                "The server has no certificate for encryption: set encrypt=false (or DANGER_PLAINTEXT)".to_string(),
+            ),
+            _ => Some("The server closed the connection during TLS or login".to_string()),
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,635 @@
+//! Export a large table through several connections at once.
+//!
+//! The table is cut into ranges of its leading key column, either along its
+//! partitions (sizes from `sys.dm_db_partition_stats`) or at boundaries
+//! sampled with `NTILE`. Each range is read with a [`KeysetReader`] on its
+//! own pooled connection and written to its own shard file. In ordered
+//! layout the shards are then joined into one file in key order.
+//!
+//! Connections are [`Backend`]s, so a SQLite file exports the same way; it
+//! has no partitions and counts its rows instead of estimating them.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+
+use crate::backend::Backend;
+use crate::ident::{quote_ident, quote_table};
+use crate::paging::KeysetReader;
+use crate::pool::Pool;
+use crate::schema::TableColumn;
+use crate::value::{Record, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// Needs the `parquet` feature.
+    Parquet,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportLayout {
+    /// One file in key order. Only supported for CSV.
+    #[default]
+    Ordered,
+    /// One file per key range, numbered in key order.
+    Sharded,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SplitMethod {
+    /// Split along the table's partitions, falling back to `NTILE` for
+    /// tables with a single partition.
+    #[default]
+    Partitions,
+    /// Sample the leading key column and cut it into ranges of about the
+    /// same number of rows.
+    Ntile,
+}
+
+/// Reported after every page written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExportProgress {
+    pub ranges_done: usize,
+    pub ranges_total: usize,
+    pub rows_written: u64,
+    /// Row count from `sys.dm_db_partition_stats`, or `COUNT(*)` on
+    /// SQLite.
+    pub rows_estimated: u64,
+}
+
+#[derive(Clone)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    pub layout: ExportLayout,
+    pub split_method: SplitMethod,
+    /// Number of key ranges. Defaults to four per connection.
+    pub splits: Option<usize>,
+    /// Rows per keyset page.
+    pub page_size: usize,
+    pub on_progress: Option<Arc<dyn Fn(ExportProgress) + Send + Sync>>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            format: ExportFormat::default(),
+            layout: ExportLayout::default(),
+            split_method: SplitMethod::default(),
+            splits: None,
+            page_size: 10_000,
+            on_progress: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportSummary {
+    pub rows: u64,
+    pub files: Vec<PathBuf>,
+}
+
+/// A slice of the table, as a predicate on its leading key column.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyRange {
+    /// `lower <= key < upper`, either side open when `None`.
+    Bounds {
+        column: String,
+        lower: Option<Value>,
+        upper: Option<Value>,
+    },
+    /// One partition of a partitioned table.
+    Partition {
+        function: String,
+        column: String,
+        number: i32,
+    },
+}
+
+impl KeyRange {
+    pub fn predicate(&self) -> Option<String> {
+        match self {
+            KeyRange::Bounds { column, lower, upper } => {
+                let column = quote_ident(column);
+                let mut terms = Vec::new();
+                if let Some(lower) = lower {
+                    terms.push(format!("{} >= {}", column, lower.to_sql_literal()));
+                }
+                if let Some(upper) = upper {
+                    terms.push(format!("{} < {}", column, upper.to_sql_literal()));
+                }
+                if terms.is_empty() {
+                    None
+                } else {
+                    Some(terms.join(" AND "))
+                }
+            }
+            KeyRange::Partition { function, column, number } => Some(format!(
+                "$PARTITION.{}({}) = {}",
+                quote_ident(function),
+                quote_ident(column),
+                number
+            )),
+        }
+    }
+}
+
+/// Export `table` to `output`. In sharded layout the shards are written
+/// next to it as `<stem>.part-0001.<ext>`, ...
+pub async fn export_table(
+    pool: &Pool,
+    table: &str,
+    output: &Path,
+    options: &ExportOptions,
+) -> anyhow::Result<ExportSummary> {
+    if options.format == ExportFormat::Parquet && options.layout == ExportLayout::Ordered {
+        anyhow::bail!("Parquet exports are written sharded, use ExportLayout::Sharded");
+    }
+
+    let mut db = pool.get().await?;
+    let metadata = async {
+        let columns = db.columns(table).await?;
+        let key = db.keyset_columns(table).await?;
+        let rows_estimated = estimated_rows(&mut *db, table).await?;
+
+        let splits = options.splits.unwrap_or(pool.max_size() * 4).max(1);
+        let ranges = match options.split_method {
+            SplitMethod::Partitions => match partition_ranges(&mut *db, table).await? {
+                ranges if ranges.len() > 1 => ranges,
+                _ => ntile_ranges(&mut *db, table, &key[0], splits, rows_estimated).await?,
+            },
+            SplitMethod::Ntile => ntile_ranges(&mut *db, table, &key[0], splits, rows_estimated).await?,
+        };
+
+        anyhow::Ok((columns, key, rows_estimated, ranges))
+    }
+    .await;
+    if metadata.is_err() {
+        // The error may have come mid-response; don't hand the connection out again.
+        db.discard();
+    }
+    let (columns, key, rows_estimated, ranges) = metadata?;
+
+    let ranges_total = ranges.len();
+    let rows_written = Arc::new(AtomicU64::new(0));
+    let ranges_done = Arc::new(AtomicUsize::new(0));
+    let report = {
+        let rows_written = rows_written.clone();
+        let ranges_done = ranges_done.clone();
+        let on_progress = options.on_progress.clone();
+        move || {
+            if let Some(on_progress) = &on_progress {
+                on_progress(ExportProgress {
+                    ranges_done: ranges_done.load(Ordering::Relaxed),
+                    ranges_total,
+                    rows_written: rows_written.load(Ordering::Relaxed),
+                    rows_estimated,
+                });
+            }
+        }
+    };
+
+    let shards: Vec<PathBuf> = (0..ranges_total)
+        .map(|i| shard_path(output, i + 1, options.format))
+        .collect();
+
+    stream::iter(ranges.into_iter().zip(shards.iter()))
+        .map(|(range, path)| {
+            let (columns, key, rows_written, ranges_done, report) = (
+                &columns,
+                &key,
+                rows_written.clone(),
+                ranges_done.clone(),
+                &report,
+            );
+            async move {
+                let mut db = pool.get().await?;
+                let mut reader = KeysetReader::with_key(table, key.clone());
+                reader.key_types(columns);
+                reader.page_size(options.page_size);
+                if let Some(predicate) = range.predicate() {
+                    reader.filter(predicate);
+                }
+
+                let mut writer = ShardWriter::create(path, options.format, columns)?;
+                let copied = async {
+                    let rows = reader.into_stream(&mut *db);
+                    futures_util::pin_mut!(rows);
+                    let mut in_page = 0;
+
+                    while let Some(record) = rows.try_next().await? {
+                        writer.write(&record)?;
+                        rows_written.fetch_add(1, Ordering::Relaxed);
+                        in_page += 1;
+                        if in_page == options.page_size {
+                            in_page = 0;
+                            report();
+                        }
+                    }
+                    anyhow::Ok(())
+                }
+                .await;
+                if copied.is_err() {
+                    db.discard();
+                }
+                copied?;
+
+                writer.finish()?;
+                ranges_done.fetch_add(1, Ordering::Relaxed);
+                report();
+
+                anyhow::Ok(())
+            }
+        })
+        .buffer_unordered(pool.max_size())
+        .try_collect::<Vec<()>>()
+        .await?;
+
+    let files = match options.layout {
+        ExportLayout::Sharded => shards,
+        ExportLayout::Ordered => {
+            join_csv_shards(&shards, output)?;
+            vec![output.to_path_buf()]
+        }
+    };
+
+    Ok(ExportSummary {
+        rows: rows_written.load(Ordering::Relaxed),
+        files,
+    })
+}
+
+fn shard_path(output: &Path, number: usize, format: ExportFormat) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "export".to_string());
+
+    output.with_file_name(format!("{}.part-{:04}.{}", stem, number, format.extension()))
+}
+
+/// Concatenate CSV shards, keeping only the first header line.
+fn join_csv_shards(shards: &[PathBuf], output: &Path) -> anyhow::Result<()> {
+    let mut out = BufWriter::new(File::create(output)?);
+
+    for (i, shard) in shards.iter().enumerate() {
+        let mut data = io::BufReader::new(File::open(shard)?);
+        if i > 0 {
+            let mut header = String::new();
+            io::BufRead::read_line(&mut data, &mut header)?;
+        }
+        io::copy(&mut data, &mut out)?;
+        fs::remove_file(shard)?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+async fn estimated_rows(db: &mut dyn Backend, table: &str) -> anyhow::Result<u64> {
+    let rows = match db.name() {
+        "sqlserver" => {
+            db.query(
+                "SELECT CAST(SUM(row_count) AS bigint) FROM sys.dm_db_partition_stats \
+                 WHERE object_id = OBJECT_ID(@P1) AND index_id IN (0, 1)",
+                &[Value::String(table.to_string())],
+            )
+            .await?
+        }
+        _ => db.query(&format!("SELECT COUNT(*) FROM {}", quote_table(table)), &[]).await?,
+    };
+
+    Ok(match rows.first().and_then(|r| r.values().first()) {
+        Some(Value::Int(count)) => (*count).max(0) as u64,
+        _ => 0,
+    })
+}
+
+/// One range per non-empty partition, or a single range if the table is
+/// not partitioned. Only SQL Server has partitions.
+async fn partition_ranges(db: &mut dyn Backend, table: &str) -> anyhow::Result<Vec<KeyRange>> {
+    if db.name() != "sqlserver" {
+        return Ok(Vec::new());
+    }
+
+    let rows = db
+        .query(
+            "SELECT pf.name, c.name, ps.partition_number
+             FROM sys.indexes i
+             JOIN sys.partition_schemes psch ON psch.data_space_id = i.data_space_id
+             JOIN sys.partition_functions pf ON pf.function_id = psch.function_id
+             JOIN sys.index_columns ic
+                 ON ic.object_id = i.object_id AND ic.index_id = i.index_id AND ic.partition_ordinal = 1
+             JOIN sys.columns c ON c.object_id = ic.object_id AND c.column_id = ic.column_id
+             JOIN sys.dm_db_partition_stats ps
+                 ON ps.object_id = i.object_id AND ps.index_id = i.index_id
+             WHERE i.object_id = OBJECT_ID(@P1) AND i.index_id IN (0, 1) AND ps.row_count > 0
+             ORDER BY ps.partition_number",
+            &[Value::String(table.to_string())],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|r| {
+            let text = |i: usize| r.values().get(i).map(Value::to_string).unwrap_or_default();
+            KeyRange::Partition {
+                function: text(0),
+                column: text(1),
+                number: match r.values().get(2) {
+                    Some(Value::Int(number)) => *number as i32,
+                    _ => 1,
+                },
+            }
+        })
+        .collect())
+}
+
+/// Cut the leading key column into `splits` ranges of about equal size,
+/// sampling large SQL Server tables instead of ranking every row.
+async fn ntile_ranges(
+    db: &mut dyn Backend,
+    table: &str,
+    column: &str,
+    splits: usize,
+    rows_estimated: u64,
+) -> anyhow::Result<Vec<KeyRange>> {
+    let sample = if rows_estimated > 1_000_000 && db.name() == "sqlserver" {
+        let percent = (100_000.0 / rows_estimated as f64 * 100.0).clamp(0.01, 100.0);
+        format!(" TABLESAMPLE ({:.4} PERCENT)", percent)
+    } else {
+        String::new()
+    };
+
+    let sql = format!(
+        "SELECT MIN(k) FROM (
+             SELECT {column} AS k, NTILE({splits}) OVER (ORDER BY {column}) AS tile
+             FROM {table}{sample}
+         ) s
+         GROUP BY tile
+         ORDER BY MIN(k)",
+        column = quote_ident(column),
+        splits = splits,
+        table = quote_table(table),
+        sample = sample
+    );
+
+    let rows = db.query(&sql, &[]).await?;
+
+    let mut boundaries: Vec<Value> = rows
+        .into_iter()
+        .skip(1)
+        .filter_map(|r| r.values().first().cloned())
+        .collect();
+    boundaries.dedup();
+
+    let mut ranges = Vec::with_capacity(boundaries.len() + 1);
+    let mut lower = None;
+    for boundary in boundaries {
+        ranges.push(KeyRange::Bounds {
+            column: column.to_string(),
+            lower: lower.take(),
+            upper: Some(boundary.clone()),
+        });
+        lower = Some(boundary);
+    }
+    ranges.push(KeyRange::Bounds {
+        column: column.to_string(),
+        lower,
+        upper: None,
+    });
+
+    Ok(ranges)
+}
+
+enum ShardWriter {
+    Csv(csv::Writer<BufWriter<File>>),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet_shard::ParquetShard),
+}
+
+impl ShardWriter {
+    fn create(path: &Path, format: ExportFormat, columns: &[TableColumn]) -> anyhow::Result<Self> {
+        match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+                writer.write_record(columns.iter().map(|c| c.name.as_str()))?;
+                Ok(ShardWriter::Csv(writer))
+            }
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => Ok(ShardWriter::Parquet(parquet_shard::ParquetShard::create(
+                path, columns,
+            )?)),
+            #[cfg(not(feature = "parquet"))]
+            ExportFormat::Parquet => {
+                anyhow::bail!("Parquet export needs the `parquet` feature")
+            }
+        }
+    }
+
+    fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        match self {
+            ShardWriter::Csv(writer) => {
+                writer.write_record(record.values().iter().map(|v| match v {
+                    Value::Null => String::new(),
+                    v => v.to_string(),
+                }))?;
+            }
+            #[cfg(feature = "parquet")]
+            ShardWriter::Parquet(writer) => writer.write(record)?,
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            ShardWriter::Csv(mut writer) => writer.flush()?,
+            #[cfg(feature = "parquet")]
+            ShardWriter::Parquet(writer) => writer.finish()?,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_shard {
+    //! Parquet output through the low-level column writers. Integers,
+    //! floats and bits keep their type; everything else is written as text
+    //! (binary columns as raw bytes).
+
+    use std::fs::File;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    use crate::schema::TableColumn;
+    use crate::value::{Record, Value};
+
+    const ROW_GROUP_SIZE: usize = 65_536;
+
+    #[derive(Clone, Copy)]
+    enum Physical {
+        Int64,
+        Double,
+        Boolean,
+        Binary,
+        Text,
+    }
+
+    impl Physical {
+        fn for_type(type_name: &str) -> Physical {
+            match type_name {
+                "tinyint" | "smallint" | "int" | "bigint" => Physical::Int64,
+                "real" | "float" => Physical::Double,
+                "bit" => Physical::Boolean,
+                "binary" | "varbinary" | "image" | "timestamp" => Physical::Binary,
+                _ => Physical::Text,
+            }
+        }
+
+        fn schema(self, name: &str) -> String {
+            let name = name.replace(|c: char| !c.is_alphanumeric() && c != '_', "_");
+            match self {
+                Physical::Int64 => format!("OPTIONAL INT64 {};", name),
+                Physical::Double => format!("OPTIONAL DOUBLE {};", name),
+                Physical::Boolean => format!("OPTIONAL BOOLEAN {};", name),
+                Physical::Binary => format!("OPTIONAL BYTE_ARRAY {};", name),
+                Physical::Text => format!("OPTIONAL BYTE_ARRAY {} (UTF8);", name),
+            }
+        }
+    }
+
+    pub struct ParquetShard {
+        writer: SerializedFileWriter<File>,
+        types: Vec<Physical>,
+        buffered: Vec<Vec<Value>>,
+    }
+
+    impl ParquetShard {
+        pub fn create(path: &Path, columns: &[TableColumn]) -> anyhow::Result<Self> {
+            let types: Vec<Physical> = columns
+                .iter()
+                .map(|c| Physical::for_type(&c.type_name))
+                .collect();
+            let fields: String = columns
+                .iter()
+                .zip(&types)
+                .map(|(c, t)| t.schema(&c.name))
+                .collect();
+            let schema = Arc::new(parse_message_type(&format!("message export {{ {} }}", fields))?);
+            let props = Arc::new(WriterProperties::builder().build());
+            let writer = SerializedFileWriter::new(File::create(path)?, schema, props)?;
+
+            Ok(ParquetShard {
+                writer,
+                types,
+                buffered: vec![Vec::new(); columns.len()],
+            })
+        }
+
+        pub fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+            for (column, value) in self.buffered.iter_mut().zip(record.values()) {
+                column.push(value.clone());
+            }
+
+            if self.buffered[0].len() >= ROW_GROUP_SIZE {
+                self.flush()?;
+            }
+
+            Ok(())
+        }
+
+        pub fn finish(mut self) -> anyhow::Result<()> {
+            self.flush()?;
+            self.writer.close()?;
+            Ok(())
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            if self.buffered.first().map(Vec::is_empty).unwrap_or(true) {
+                return Ok(());
+            }
+
+            let mut row_group = self.writer.next_row_group()?;
+            let mut index = 0;
+
+            while let Some(mut column) = row_group.next_column()? {
+                let values = std::mem::take(&mut self.buffered[index]);
+                let levels: Vec<i16> = values.iter().map(|v| i16::from(!v.is_null())).collect();
+                let present = values.iter().filter(|v| !v.is_null());
+
+                match self.types[index] {
+                    Physical::Int64 => {
+                        let data: Vec<i64> = present
+                            .map(|v| match v {
+                                Value::Int(i) => Ok(*i),
+                                other => parse(other, "an integer"),
+                            })
+                            .collect::<anyhow::Result<_>>()?;
+                        column.typed::<Int64Type>().write_batch(&data, Some(&levels), None)?;
+                    }
+                    Physical::Double => {
+                        let data: Vec<f64> = present
+                            .map(|v| match v {
+                                Value::Float(f) => Ok(*f),
+                                other => parse(other, "a number"),
+                            })
+                            .collect::<anyhow::Result<_>>()?;
+                        column.typed::<DoubleType>().write_batch(&data, Some(&levels), None)?;
+                    }
+                    Physical::Boolean => {
+                        let data: Vec<bool> = present
+                            .map(|v| match v {
+                                Value::Bool(b) => Ok(*b),
+                                other => parse(other, "a boolean"),
+                            })
+                            .collect::<anyhow::Result<_>>()?;
+                        column.typed::<BoolType>().write_batch(&data, Some(&levels), None)?;
+                    }
+                    Physical::Binary | Physical::Text => {
+                        let data: Vec<ByteArray> = present
+                            .map(|v| match v {
+                                Value::Binary(b) => ByteArray::from(b.clone()),
+                                other => ByteArray::from(other.to_string().into_bytes()),
+                            })
+                            .collect();
+                        column.typed::<ByteArrayType>().write_batch(&data, Some(&levels), None)?;
+                    }
+                }
+
+                column.close()?;
+                index += 1;
+            }
+
+            row_group.close()?;
+            Ok(())
+        }
+    }
+
+    fn parse<T: std::str::FromStr>(value: &Value, expected: &str) -> anyhow::Result<T> {
+        let text = value.to_string();
+        text.parse()
+            .map_err(|_| anyhow::anyhow!("Cannot write {:?} to a parquet column as {}", text, expected))
+    }
+}
+
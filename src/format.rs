@@ -0,0 +1,1376 @@
+//! A T-SQL formatter for the scripts kept in the repository and the object
+//! definitions scripted out of a server.
+//!
+//! It works on tokens (see [`crate::lexer`]) and a few clause rules rather
+//! than a full parse: each clause starts a line, `BEGIN ... END` blocks and
+//! subqueries are indented, and lists, conditions, parentheses and `CASE`
+//! expressions are broken over lines only when they do not fit. Comments
+//! are kept, on their own line or at the end of one as they were found,
+//! and formatting formatted text changes nothing.
+//!
+//! Only reserved words are recased. Data types, functions and names are
+//! written as found, since on a case-sensitive collation recasing a name
+//! would change what it refers to.
+
+use std::str::FromStr;
+
+use crate::lexer::{self, Token, TokenKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeywordCase {
+    Upper,
+    Lower,
+    /// Keep keywords as they were written.
+    Preserve,
+}
+
+impl FromStr for KeywordCase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "upper" => Ok(KeywordCase::Upper),
+            "lower" => Ok(KeywordCase::Lower),
+            "preserve" => Ok(KeywordCase::Preserve),
+            _ => anyhow::bail!("Keyword case must be upper, lower or preserve, got {:?}", s),
+        }
+    }
+}
+
+/// Where the commas of a list broken over lines go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Commas {
+    /// At the end of each line but the last.
+    Trailing,
+    /// At the start of each line but the first.
+    Leading,
+}
+
+impl FromStr for Commas {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "trailing" => Ok(Commas::Trailing),
+            "leading" => Ok(Commas::Leading),
+            _ => anyhow::bail!("Commas must be trailing or leading, got {:?}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    Spaces(usize),
+    /// One tab per level, counted as four columns against the line width.
+    Tabs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    pub keyword_case: KeywordCase,
+    pub indent: Indent,
+    pub commas: Commas,
+    /// The width lines are kept within where they can be broken.
+    pub max_width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            keyword_case: KeywordCase::Upper,
+            indent: Indent::Spaces(4),
+            commas: Commas::Trailing,
+            max_width: 100,
+        }
+    }
+}
+
+/// Format `sql`, one statement or a whole script with `GO` separators.
+/// The result ends with a newline unless `sql` has no tokens.
+pub fn format_sql(sql: &str, options: &FormatOptions) -> String {
+    let tokens = lexer::tokenize(sql);
+    let mut toks = Vec::with_capacity(tokens.len());
+    let mut previous_end: Option<usize> = None;
+    for token in tokens {
+        toks.push(Tok {
+            token,
+            own_line: previous_end.is_none_or(|end| token.line > end),
+            blank_before: previous_end.is_some_and(|end| token.line > end + 1),
+        });
+        previous_end = Some(token.line + token.text.matches('\n').count());
+    }
+
+    let mut i = 0;
+    let nodes = parse(&toks, &mut i, false, false);
+    let mut writer = Writer::new(options);
+    writer.block(&nodes, 0);
+    writer.finish()
+}
+
+/// T-SQL's reserved keywords, which cannot be names unless quoted and so
+/// are safe to recase.
+const RESERVED: [&str; 185] = [
+    "ADD",
+    "ALL",
+    "ALTER",
+    "AND",
+    "ANY",
+    "AS",
+    "ASC",
+    "AUTHORIZATION",
+    "BACKUP",
+    "BEGIN",
+    "BETWEEN",
+    "BREAK",
+    "BROWSE",
+    "BULK",
+    "BY",
+    "CASCADE",
+    "CASE",
+    "CHECK",
+    "CHECKPOINT",
+    "CLOSE",
+    "CLUSTERED",
+    "COALESCE",
+    "COLLATE",
+    "COLUMN",
+    "COMMIT",
+    "COMPUTE",
+    "CONSTRAINT",
+    "CONTAINS",
+    "CONTAINSTABLE",
+    "CONTINUE",
+    "CONVERT",
+    "CREATE",
+    "CROSS",
+    "CURRENT",
+    "CURRENT_DATE",
+    "CURRENT_TIME",
+    "CURRENT_TIMESTAMP",
+    "CURRENT_USER",
+    "CURSOR",
+    "DATABASE",
+    "DBCC",
+    "DEALLOCATE",
+    "DECLARE",
+    "DEFAULT",
+    "DELETE",
+    "DENY",
+    "DESC",
+    "DISK",
+    "DISTINCT",
+    "DISTRIBUTED",
+    "DOUBLE",
+    "DROP",
+    "DUMP",
+    "ELSE",
+    "END",
+    "ERRLVL",
+    "ESCAPE",
+    "EXCEPT",
+    "EXEC",
+    "EXECUTE",
+    "EXISTS",
+    "EXIT",
+    "EXTERNAL",
+    "FETCH",
+    "FILE",
+    "FILLFACTOR",
+    "FOR",
+    "FOREIGN",
+    "FREETEXT",
+    "FREETEXTTABLE",
+    "FROM",
+    "FULL",
+    "FUNCTION",
+    "GOTO",
+    "GRANT",
+    "GROUP",
+    "HAVING",
+    "HOLDLOCK",
+    "IDENTITY",
+    "IDENTITY_INSERT",
+    "IDENTITYCOL",
+    "IF",
+    "IN",
+    "INDEX",
+    "INNER",
+    "INSERT",
+    "INTERSECT",
+    "INTO",
+    "IS",
+    "JOIN",
+    "KEY",
+    "KILL",
+    "LEFT",
+    "LIKE",
+    "LINENO",
+    "LOAD",
+    "MERGE",
+    "NATIONAL",
+    "NOCHECK",
+    "NONCLUSTERED",
+    "NOT",
+    "NULL",
+    "NULLIF",
+    "OF",
+    "OFF",
+    "OFFSETS",
+    "ON",
+    "OPEN",
+    "OPENDATASOURCE",
+    "OPENQUERY",
+    "OPENROWSET",
+    "OPENXML",
+    "OPTION",
+    "OR",
+    "ORDER",
+    "OUTER",
+    "OVER",
+    "PERCENT",
+    "PIVOT",
+    "PLAN",
+    "PRECISION",
+    "PRIMARY",
+    "PRINT",
+    "PROC",
+    "PROCEDURE",
+    "PUBLIC",
+    "RAISERROR",
+    "READ",
+    "READTEXT",
+    "RECONFIGURE",
+    "REFERENCES",
+    "REPLICATION",
+    "RESTORE",
+    "RESTRICT",
+    "RETURN",
+    "REVERT",
+    "REVOKE",
+    "RIGHT",
+    "ROLLBACK",
+    "ROWCOUNT",
+    "ROWGUIDCOL",
+    "RULE",
+    "SAVE",
+    "SCHEMA",
+    "SECURITYAUDIT",
+    "SELECT",
+    "SEMANTICKEYPHRASETABLE",
+    "SEMANTICSIMILARITYDETAILSTABLE",
+    "SEMANTICSIMILARITYTABLE",
+    "SESSION_USER",
+    "SET",
+    "SETUSER",
+    "SHUTDOWN",
+    "SOME",
+    "STATISTICS",
+    "SYSTEM_USER",
+    "TABLE",
+    "TABLESAMPLE",
+    "TEXTSIZE",
+    "THEN",
+    "TO",
+    "TOP",
+    "TRAN",
+    "TRANSACTION",
+    "TRIGGER",
+    "TRUNCATE",
+    "TRY_CONVERT",
+    "TSEQUAL",
+    "UNION",
+    "UNIQUE",
+    "UNPIVOT",
+    "UPDATE",
+    "UPDATETEXT",
+    "USE",
+    "USER",
+    "VALUES",
+    "VARYING",
+    "VIEW",
+    "WAITFOR",
+    "WHEN",
+    "WHERE",
+    "WHILE",
+    "WITH",
+    "WITHIN",
+    "WRITETEXT",
+];
+
+/// Keywords that are not reserved but are never used as names in practice.
+const KEYWORDS: [&str; 13] = [
+    "APPLY",
+    "CATCH",
+    "GO",
+    "MATCHED",
+    "NOCOUNT",
+    "NOLOCK",
+    "OUTPUT",
+    "PARTITION",
+    "RETURNS",
+    "TIES",
+    "TRY",
+    "USING",
+    "XACT_ABORT",
+];
+
+/// Reserved words that are called like functions, with no space before
+/// their parenthesis.
+const FUNCTIONS: [&str; 16] = [
+    "COALESCE",
+    "CONTAINS",
+    "CONTAINSTABLE",
+    "CONVERT",
+    "FREETEXT",
+    "FREETEXTTABLE",
+    "IDENTITY",
+    "LEFT",
+    "NULLIF",
+    "OPENDATASOURCE",
+    "OPENQUERY",
+    "OPENROWSET",
+    "OPENXML",
+    "RIGHT",
+    "TRY_CONVERT",
+    "UPDATE",
+];
+
+/// Data types with a length, precision or scale in parentheses, which stay
+/// on the line with them.
+const SIZED_TYPES: [&str; 12] = [
+    "BINARY",
+    "CHAR",
+    "DATETIME2",
+    "DATETIMEOFFSET",
+    "DECIMAL",
+    "FLOAT",
+    "NCHAR",
+    "NUMERIC",
+    "NVARCHAR",
+    "TIME",
+    "VARBINARY",
+    "VARCHAR",
+];
+
+/// The words of the query hints in `OPTION (...)`, recased like keywords
+/// there and only there.
+const QUERY_HINTS: [&str; 32] = [
+    "CONCAT",
+    "DISABLE",
+    "EXPAND",
+    "EXTERNALPUSHDOWN",
+    "FAST",
+    "FORCE",
+    "FORCED",
+    "GROUP",
+    "HASH",
+    "HINT",
+    "IGNORE_NONCLUSTERED_COLUMNSTORE_INDEX",
+    "JOIN",
+    "KEEP",
+    "KEEPFIXED",
+    "LOOP",
+    "MAXDOP",
+    "MAXRECURSION",
+    "MERGE",
+    "NO_PERFORMANCE_SPOOL",
+    "OPTIMIZE",
+    "ORDER",
+    "PARAMETERIZATION",
+    "PLAN",
+    "QUERYTRACEON",
+    "RECOMPILE",
+    "ROBUST",
+    "SCALEOUTEXECUTION",
+    "SIMPLE",
+    "UNION",
+    "UNKNOWN",
+    "USE",
+    "VIEWS",
+];
+
+/// Words that begin a statement, unless what comes before shows they are
+/// part of another one, as in `ON DELETE CASCADE` or `GRANT EXECUTE`.
+const STATEMENTS: [&str; 38] = [
+    "ALTER",
+    "BACKUP",
+    "BREAK",
+    "BULK",
+    "CHECKPOINT",
+    "CLOSE",
+    "COMMIT",
+    "CONTINUE",
+    "CREATE",
+    "DBCC",
+    "DEALLOCATE",
+    "DECLARE",
+    "DELETE",
+    "DENY",
+    "DROP",
+    "EXEC",
+    "EXECUTE",
+    "FETCH",
+    "GOTO",
+    "GRANT",
+    "INSERT",
+    "KILL",
+    "MERGE",
+    "OPEN",
+    "PRINT",
+    "RAISERROR",
+    "RECONFIGURE",
+    "RESTORE",
+    "RETURN",
+    "REVERT",
+    "REVOKE",
+    "ROLLBACK",
+    "SAVE",
+    "SHUTDOWN",
+    "THROW",
+    "TRUNCATE",
+    "UPDATE",
+    "USE",
+];
+
+/// The words after `CREATE`, `ALTER` or `DROP` naming what kind of object
+/// follows.
+const OBJECT_TYPES: [&str; 16] = [
+    "DATABASE",
+    "FUNCTION",
+    "INDEX",
+    "LOGIN",
+    "PROC",
+    "PROCEDURE",
+    "ROLE",
+    "SCHEMA",
+    "SEQUENCE",
+    "STATISTICS",
+    "SYNONYM",
+    "TABLE",
+    "TRIGGER",
+    "TYPE",
+    "USER",
+    "VIEW",
+];
+
+fn is_reserved(word: &str) -> bool {
+    RESERVED.iter().any(|k| k.eq_ignore_ascii_case(word))
+}
+
+/// A token and how it sat in the source.
+#[derive(Debug, Clone, Copy)]
+struct Tok<'a> {
+    token: Token<'a>,
+    /// The first token on its line.
+    own_line: bool,
+    /// Preceded by at least one blank line.
+    blank_before: bool,
+}
+
+impl Tok<'_> {
+    fn is_line_comment(&self) -> bool {
+        self.token.kind == TokenKind::Comment && self.token.text.starts_with("--")
+    }
+}
+
+/// Tokens with their parentheses and `CASE ... END` expressions grouped.
+#[derive(Debug)]
+enum Node<'a> {
+    Tok(Tok<'a>),
+    Group {
+        open: Tok<'a>,
+        inner: Vec<Node<'a>>,
+        close: Option<Tok<'a>>,
+    },
+    Case {
+        case: Tok<'a>,
+        inner: Vec<Node<'a>>,
+        end: Option<Tok<'a>>,
+    },
+}
+
+impl<'a> Node<'a> {
+    fn first(&self) -> &Tok<'a> {
+        match self {
+            Node::Tok(tok) => tok,
+            Node::Group { open, .. } => open,
+            Node::Case { case, .. } => case,
+        }
+    }
+
+    fn tok(&self) -> Option<&Token<'a>> {
+        match self {
+            Node::Tok(tok) => Some(&tok.token),
+            _ => None,
+        }
+    }
+
+    fn word(&self) -> Option<String> {
+        self.tok()
+            .filter(|t| t.kind == TokenKind::Word)
+            .map(|t| t.text.to_uppercase())
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.tok().is_some_and(|t| t.is_keyword(keyword))
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        self.tok().is_some_and(|t| t.is_symbol(symbol))
+    }
+
+    fn is_comment(&self) -> bool {
+        self.tok().is_some_and(|t| t.kind == TokenKind::Comment)
+    }
+
+    fn is_group(&self) -> bool {
+        matches!(self, Node::Group { .. })
+    }
+
+    /// A name part: a plain, quoted or `#temp` name, or a variable.
+    fn is_name(&self) -> bool {
+        self.tok().is_some_and(|t| match t.kind {
+            TokenKind::Word => !is_reserved(t.text),
+            TokenKind::QuotedIdentifier | TokenKind::Variable => true,
+            _ => false,
+        })
+    }
+}
+
+/// Group `toks` from `i` until a `)` closing an enclosing group, or an
+/// `END` closing an enclosing `CASE`, which are left for the caller.
+fn parse<'a>(toks: &[Tok<'a>], i: &mut usize, in_group: bool, in_case: bool) -> Vec<Node<'a>> {
+    let mut nodes = Vec::new();
+    while let Some(&tok) = toks.get(*i) {
+        if tok.token.is_symbol(")") && in_group || tok.token.is_keyword("END") && in_case {
+            break;
+        }
+        *i += 1;
+        if tok.token.is_symbol("(") {
+            let inner = parse(toks, i, true, false);
+            let close = toks.get(*i).filter(|t| t.token.is_symbol(")")).copied();
+            *i += close.is_some() as usize;
+            nodes.push(Node::Group {
+                open: tok,
+                inner,
+                close,
+            });
+        } else if tok.token.is_keyword("CASE") {
+            let inner = parse(toks, i, in_group, true);
+            let end = toks.get(*i).filter(|t| t.token.is_keyword("END")).copied();
+            *i += end.is_some() as usize;
+            nodes.push(Node::Case { case: tok, inner, end });
+        } else {
+            nodes.push(Node::Tok(tok));
+        }
+    }
+    nodes
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClauseKind {
+    /// Its body is a comma separated list, one item per line when broken.
+    List,
+    /// `BEGIN`, `BEGIN TRY` or `BEGIN CATCH`: what follows is indented.
+    Begin,
+    /// The `END` of a block.
+    End,
+    /// `IF`, `ELSE` or `WHILE`: a statement other than a block that
+    /// follows is indented.
+    Control,
+    Other,
+}
+
+/// A clause as ranges of the nodes of a block: comments on their own lines
+/// before it from `start`, its keywords from `head`, the rest from `body`
+/// until `end`.
+#[derive(Debug, Clone, Copy)]
+struct Clause {
+    start: usize,
+    head: usize,
+    body: usize,
+    end: usize,
+    kind: ClauseKind,
+    /// It begins a statement rather than continuing one.
+    statement: bool,
+}
+
+/// What the clauses so far say about the one at hand.
+#[derive(Debug, Default)]
+struct Context {
+    /// The first word of the statement.
+    statement: Option<String>,
+    /// The first word of the clause.
+    clause: String,
+    /// A view, procedure, function or trigger whose `AS` has not come yet.
+    awaiting_as: bool,
+    /// The next word begins a statement whatever it is.
+    new_statement: bool,
+}
+
+/// Split the nodes of a block into clauses.
+fn clauses(nodes: &[Node<'_>]) -> Vec<Clause> {
+    let mut found: Vec<Clause> = Vec::new();
+    let mut context = Context {
+        new_statement: true,
+        ..Context::default()
+    };
+
+    let mut i = 0;
+    while i < nodes.len() {
+        if nodes[i].is_comment() || nodes[i].is_symbol(";") {
+            if nodes[i].is_symbol(";") {
+                context = Context {
+                    new_statement: true,
+                    ..Context::default()
+                };
+            }
+            i += 1;
+            continue;
+        }
+
+        let head = head(nodes, i, &context).or_else(|| {
+            let anything = context.new_statement || found.is_empty();
+            anything.then(|| Head {
+                end: name_end(nodes, i).max(i + 1),
+                kind: ClauseKind::Other,
+                awaiting_as: false,
+            })
+        });
+        let Some(head) = head else {
+            i += 1;
+            continue;
+        };
+
+        let mut start = i;
+        match found.last_mut() {
+            Some(last) => {
+                while start > last.body && nodes[start - 1].is_comment() && nodes[start - 1].first().own_line {
+                    start -= 1;
+                }
+                last.end = start;
+            }
+            None => start = 0,
+        }
+        let word = nodes[i].word().unwrap_or_default();
+        let continued = matches!(
+            context.clause.as_str(),
+            "UNION" | "EXCEPT" | "INTERSECT" | "INSERT" | "DECLARE" | "WITH"
+        );
+        found.push(Clause {
+            start,
+            head: i,
+            body: head.end,
+            end: nodes.len(),
+            kind: head.kind,
+            statement: context.new_statement
+                || STATEMENTS.contains(&word.as_str()) && !continued
+                || matches!(head.kind, ClauseKind::Begin | ClauseKind::Control)
+                || matches!(word.as_str(), "SELECT" | "WITH") && !continued,
+        });
+
+        if context.new_statement || STATEMENTS.contains(&word.as_str()) {
+            context.statement = Some(word.clone());
+            context.awaiting_as = head.awaiting_as;
+        }
+        if word == "AS" {
+            context.awaiting_as = false;
+        }
+        context.new_statement = matches!(head.kind, ClauseKind::Begin | ClauseKind::End)
+            || matches!(word.as_str(), "AS" | "GO")
+            || word == "ELSE" && head.end == i + 1;
+        context.clause = word;
+        i = head.end;
+    }
+
+    if found.is_empty() && !nodes.is_empty() {
+        found.push(Clause {
+            start: 0,
+            head: nodes.len(),
+            body: nodes.len(),
+            end: nodes.len(),
+            kind: ClauseKind::Other,
+            statement: true,
+        });
+    }
+    found
+}
+
+struct Head {
+    end: usize,
+    kind: ClauseKind,
+    awaiting_as: bool,
+}
+
+/// The keywords of the clause starting at `i`, if one does.
+fn head(nodes: &[Node<'_>], i: usize, context: &Context) -> Option<Head> {
+    let word = nodes[i].word()?;
+    let word_at = |j: usize| nodes.get(j).and_then(Node::word);
+    let is_at = |j: usize, words: &[&str]| word_at(j).is_some_and(|w| words.contains(&w.as_str()));
+    let previous = nodes[..i].iter().rev().find(|n| !n.is_comment());
+    let after = |words: &[&str]| {
+        previous.is_some_and(|p| p.word().is_some_and(|w| words.contains(&w.as_str())) || p.is_symbol(","))
+    };
+    let clause = |end: usize, kind: ClauseKind| {
+        Some(Head {
+            end,
+            kind,
+            awaiting_as: false,
+        })
+    };
+    // `TOP (10)` or `TOP 10 PERCENT`.
+    let skip_top = |mut j: usize| {
+        if is_at(j, &["TOP"]) {
+            j += 1 + (j + 1 < nodes.len()) as usize;
+            j += is_at(j, &["PERCENT"]) as usize;
+        }
+        j
+    };
+
+    // `GRANT SELECT, INSERT ON ... TO ...` is one clause.
+    let granting = matches!(context.statement.as_deref(), Some("GRANT" | "DENY" | "REVOKE"));
+
+    match word.as_str() {
+        "SELECT" if granting && after(&["GRANT", "DENY", "REVOKE"]) => None,
+        "SELECT" => {
+            let j = i + 1 + is_at(i + 1, &["ALL", "DISTINCT"]) as usize;
+            clause(skip_top(j), ClauseKind::List)
+        }
+        "FROM" if context.clause == "FETCH" || after(&["DISTINCT"]) => None,
+        "FROM" | "WHERE" | "HAVING" | "JOIN" | "EXCEPT" | "INTERSECT" => clause(i + 1, ClauseKind::Other),
+        "GROUP" | "ORDER" if is_at(i + 1, &["BY"]) => clause(i + 2, ClauseKind::List),
+        "UNION" => clause(i + 1 + is_at(i + 1, &["ALL"]) as usize, ClauseKind::Other),
+        "INNER" | "CROSS" | "LEFT" | "RIGHT" | "FULL" | "OUTER" => {
+            let mut j = i + 1;
+            j += is_at(j, &["OUTER"]) as usize;
+            j += is_at(j, &["HASH", "MERGE", "LOOP", "REMOTE"]) as usize;
+            is_at(j, &["JOIN", "APPLY"]).then(|| Head {
+                end: j + 1,
+                kind: ClauseKind::Other,
+                awaiting_as: false,
+            })
+        }
+        "INTO" if context.clause == "SELECT" => clause(i + 1, ClauseKind::Other),
+        "SET" if !after(&["UPDATE", "DELETE"]) => clause(i + 1, ClauseKind::List),
+        "VALUES" if !after(&["DEFAULT"]) => clause(i + 1, ClauseKind::List),
+        "WITH" if is_cte(nodes, i) => clause(i + 1, ClauseKind::List),
+        "BEGIN" if is_at(i + 1, &["TRAN", "TRANSACTION", "DISTRIBUTED", "DIALOG", "CONVERSATION"]) => {
+            clause(i + 2, ClauseKind::Other)
+        }
+        "BEGIN" => clause(i + 1 + is_at(i + 1, &["TRY", "CATCH"]) as usize, ClauseKind::Begin),
+        "END" if is_at(i + 1, &["CONVERSATION"]) => clause(i + 2, ClauseKind::Other),
+        "END" => clause(i + 1 + is_at(i + 1, &["TRY", "CATCH"]) as usize, ClauseKind::End),
+        "ELSE" => clause(i + 1 + is_at(i + 1, &["IF"]) as usize, ClauseKind::Control),
+        "IF" | "WHILE" => clause(i + 1, ClauseKind::Control),
+        "GO" => clause(i + 1, ClauseKind::Other),
+        "WHEN" | "USING" if context.statement.as_deref() == Some("MERGE") => clause(i + 1, ClauseKind::Other),
+        "AS" if context.awaiting_as && !after(&["EXECUTE", "EXEC"]) => clause(i + 1, ClauseKind::Other),
+        _ if STATEMENTS.contains(&word.as_str()) => {
+            let within = after(&[
+                "THEN", "ON", "FOR", "AFTER", "OF", "GRANT", "DENY", "REVOKE", "WITH", "INSTEAD", "TO",
+            ]) || (word == "FETCH" && after(&["ROWS", "ROW"]))
+                || (word == "UPDATE" && nodes.get(i + 1).is_some_and(Node::is_group))
+                || (context.statement.as_deref() == Some("ALTER")
+                    && matches!(word.as_str(), "ALTER" | "DROP")
+                    && !is_at(i + 1, &OBJECT_TYPES));
+            if within && !context.new_statement {
+                return None;
+            }
+            Some(statement_head(nodes, i, &word, skip_top))
+        }
+        _ => None,
+    }
+}
+
+/// The keywords and object name that open a statement, as in `INSERT INTO
+/// dbo.t` or `CREATE OR ALTER PROCEDURE dbo.p`.
+fn statement_head(nodes: &[Node<'_>], i: usize, word: &str, skip_top: impl Fn(usize) -> usize) -> Head {
+    let is_at = |j: usize, words: &[&str]| {
+        nodes
+            .get(j)
+            .and_then(Node::word)
+            .is_some_and(|w| words.contains(&w.as_str()))
+    };
+    let mut j = i + 1;
+    let mut kind = ClauseKind::Other;
+    let mut awaiting_as = false;
+    match word {
+        "INSERT" | "UPDATE" | "DELETE" | "MERGE" | "BULK" => {
+            j += (word == "BULK" && is_at(j, &["INSERT"])) as usize;
+            j = skip_top(j);
+            j += is_at(j, &["INTO", "FROM"]) as usize;
+            j = name_end(nodes, j);
+        }
+        "CREATE" | "ALTER" | "DROP" => {
+            if is_at(j, &["OR"]) && is_at(j + 1, &["ALTER"]) {
+                j += 2;
+            }
+            while is_at(j, &["UNIQUE", "CLUSTERED", "NONCLUSTERED"]) {
+                j += 1;
+            }
+            if is_at(j, &OBJECT_TYPES) {
+                awaiting_as = is_at(j, &["PROC", "PROCEDURE", "FUNCTION", "VIEW", "TRIGGER"]) && word != "DROP";
+                j += 1;
+                if is_at(j, &["IF"]) && is_at(j + 1, &["EXISTS"]) {
+                    j += 2;
+                }
+                j = name_end(nodes, j);
+            }
+            if word != "DROP" {
+                kind = ClauseKind::List;
+            }
+        }
+        "TRUNCATE" => {
+            j += is_at(j, &["TABLE"]) as usize;
+            j = name_end(nodes, j);
+        }
+        "EXEC" | "EXECUTE" => {
+            let returns = nodes
+                .get(j)
+                .and_then(Node::tok)
+                .is_some_and(|t| t.kind == TokenKind::Variable)
+                && nodes.get(j + 1).is_some_and(|n| n.is_symbol("="));
+            j += 2 * returns as usize;
+            j = name_end(nodes, j);
+            kind = ClauseKind::List;
+        }
+        "DECLARE" => kind = ClauseKind::List,
+        _ => {}
+    }
+    Head {
+        end: j,
+        kind,
+        awaiting_as,
+    }
+}
+
+/// The index after the dotted name starting at `j`, `j` when there is none.
+fn name_end(nodes: &[Node<'_>], mut j: usize) -> usize {
+    if !nodes.get(j).is_some_and(Node::is_name) {
+        return j;
+    }
+    j += 1;
+    while nodes.get(j).is_some_and(|n| n.is_symbol(".")) {
+        j += 1;
+        if nodes.get(j).is_some_and(Node::is_name) {
+            j += 1;
+        }
+    }
+    j
+}
+
+/// Whether the `WITH` at `i` begins common table expressions: `WITH name
+/// [(columns)] AS (`.
+fn is_cte(nodes: &[Node<'_>], i: usize) -> bool {
+    let mut j = i + 1;
+    if nodes.get(j).is_some_and(|n| n.is_keyword("XMLNAMESPACES")) {
+        return true;
+    }
+    if !nodes.get(j).is_some_and(Node::is_name) {
+        return false;
+    }
+    j += 1;
+    j += nodes.get(j).is_some_and(Node::is_group) as usize;
+    nodes.get(j).is_some_and(|n| n.is_keyword("AS")) && nodes.get(j + 1).is_some_and(Node::is_group)
+}
+
+/// Whether a group holds a query rather than a list or an expression.
+fn is_subquery(inner: &[Node<'_>]) -> bool {
+    inner
+        .iter()
+        .find(|n| !n.is_comment())
+        .is_some_and(|n| n.is_keyword("SELECT") || n.is_keyword("WITH") || n.is_keyword("VALUES"))
+}
+
+/// The node at `i` and the tokens after it up to the next place a line
+/// could break, as the alias in `CASE ... END AS size`: the node is only
+/// kept on one line if they fit with it.
+fn attached<'n, 'a>(nodes: &'n [Node<'a>], i: usize) -> &'n [Node<'a>] {
+    let end = nodes[i + 1..]
+        .iter()
+        .position(|n| {
+            n.tok().is_none() || n.is_comment() || n.is_symbol(",") || n.is_keyword("AND") || n.is_keyword("OR")
+        })
+        .map_or(nodes.len(), |p| i + 1 + p);
+    &nodes[i..end]
+}
+
+/// An item of a comma separated list.
+struct Item<'n, 'a> {
+    nodes: &'n [Node<'a>],
+    /// The comma after it.
+    comma: Option<&'n Node<'a>>,
+    /// Comments after the comma on the same line, which belong to this
+    /// item rather than the next.
+    trailing: &'n [Node<'a>],
+}
+
+fn split_list<'n, 'a>(nodes: &'n [Node<'a>]) -> Vec<Item<'n, 'a>> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < nodes.len() {
+        if nodes[i].is_symbol(",") {
+            let mut end = i + 1;
+            while nodes.get(end).is_some_and(|n| n.is_comment() && !n.first().own_line) {
+                end += 1;
+            }
+            items.push(Item {
+                nodes: &nodes[start..i],
+                comma: Some(&nodes[i]),
+                trailing: &nodes[i + 1..end],
+            });
+            start = end;
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    items.push(Item {
+        nodes: &nodes[start..],
+        comma: None,
+        trailing: &[],
+    });
+    items
+}
+
+/// Whether a space goes between tokens, given what came before.
+#[derive(Debug, Default, Clone, Copy)]
+struct Spacing<'a> {
+    previous: Option<Token<'a>>,
+    /// The previous token is a sign rather than an operator.
+    sign: bool,
+    /// Put a space before an opening parenthesis even after a name, as in
+    /// `INSERT INTO t (a, b)`.
+    space_paren: bool,
+}
+
+impl<'a> Spacing<'a> {
+    fn space_before(&self, next: &Token<'_>) -> bool {
+        let Some(previous) = self.previous else {
+            return false;
+        };
+        if [",", ";", ")", ".", "::", ":"].iter().any(|s| next.is_symbol(s)) {
+            return false;
+        }
+        if ["(", ".", "::", "~", "$"].iter().any(|s| previous.is_symbol(s)) || self.sign {
+            return false;
+        }
+        if next.is_symbol("(") && !self.space_paren {
+            return match previous.kind {
+                TokenKind::Word => {
+                    is_reserved(previous.text) && !FUNCTIONS.iter().any(|f| previous.text.eq_ignore_ascii_case(f))
+                }
+                TokenKind::QuotedIdentifier => false,
+                _ => true,
+            };
+        }
+        true
+    }
+
+    fn push(&mut self, token: Token<'a>) {
+        let sign = (token.is_symbol("-") || token.is_symbol("+"))
+            && self.previous.is_none_or(|p| match p.kind {
+                TokenKind::Symbol => !p.is_symbol(")"),
+                TokenKind::Word => is_reserved(p.text),
+                _ => false,
+            });
+        if token.kind != TokenKind::Comment {
+            self.sign = sign;
+            self.space_paren = false;
+        }
+        self.previous = Some(token);
+    }
+}
+
+/// The width of `nodes` on one line, `None` when they cannot be put on
+/// one because of a line comment, a comment on its own line or a token
+/// spanning lines.
+fn flat_width<'a>(nodes: &[Node<'a>], spacing: &mut Spacing<'a>) -> Option<usize> {
+    let mut width = 0;
+    for node in nodes {
+        width += match node {
+            Node::Tok(tok) => tok_width(tok, spacing)?,
+            Node::Group { open, inner, close } => {
+                tok_width(open, spacing)?
+                    + flat_width(inner, spacing)?
+                    + close.as_ref().map_or(Some(0), |c| tok_width(c, spacing))?
+            }
+            Node::Case { case, inner, end } => {
+                tok_width(case, spacing)?
+                    + flat_width(inner, spacing)?
+                    + end.as_ref().map_or(Some(0), |e| tok_width(e, spacing))?
+            }
+        };
+    }
+    Some(width)
+}
+
+fn tok_width<'a>(tok: &Tok<'a>, spacing: &mut Spacing<'a>) -> Option<usize> {
+    let comment = tok.token.kind == TokenKind::Comment;
+    if tok.token.text.contains('\n') || tok.is_line_comment() || (comment && tok.own_line) {
+        return None;
+    }
+    let space = spacing.space_before(&tok.token) as usize;
+    spacing.push(tok.token);
+    Some(space + tok.token.text.chars().count())
+}
+
+struct Writer<'o, 'a> {
+    options: &'o FormatOptions,
+    out: String,
+    line: String,
+    /// The width of `line` so far.
+    width: usize,
+    /// The indent level `line` started at.
+    level: usize,
+    /// Whether `line` has anything after its indent.
+    started: bool,
+    spacing: Spacing<'a>,
+    /// A comment ended the line: the next token goes on a new one.
+    pending_break: bool,
+    /// Inside a group that is never broken over lines.
+    unbroken: bool,
+    /// Inside `OPTION (...)`, where query hints are keywords.
+    hints: bool,
+}
+
+impl<'o, 'a> Writer<'o, 'a> {
+    fn new(options: &'o FormatOptions) -> Self {
+        Writer {
+            options,
+            out: String::new(),
+            line: String::new(),
+            width: 0,
+            level: 0,
+            started: false,
+            spacing: Spacing::default(),
+            pending_break: false,
+            unbroken: false,
+            hints: false,
+        }
+    }
+
+    fn indent_width(&self, level: usize) -> usize {
+        match self.options.indent {
+            Indent::Spaces(n) => n * level,
+            Indent::Tabs => 4 * level,
+        }
+    }
+
+    /// Whether `width` more columns fit on the line.
+    fn fits(&self, width: usize) -> bool {
+        self.width + width <= self.options.max_width
+    }
+
+    /// Start a new line at `level`, unless the current one is still empty,
+    /// which is then re-indented.
+    fn newline(&mut self, level: usize) {
+        if self.started {
+            self.out.push_str(self.line.trim_end());
+            self.out.push('\n');
+        }
+        self.line = match self.options.indent {
+            Indent::Spaces(n) => " ".repeat(n * level),
+            Indent::Tabs => "\t".repeat(level),
+        };
+        self.width = self.indent_width(level);
+        self.level = level;
+        self.started = false;
+        self.pending_break = false;
+    }
+
+    fn blank_line(&mut self) {
+        let level = self.level;
+        self.newline(level);
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.newline(0);
+        self.out
+    }
+
+    /// Write a token, starting a new line at `continuation` first if a
+    /// comment ended the last one or the token would overrun the width.
+    fn token(&mut self, tok: &Tok<'a>, continuation: usize) {
+        if self.pending_break {
+            self.newline(continuation);
+        }
+        let comment = tok.token.kind == TokenKind::Comment;
+        if comment && tok.own_line {
+            self.newline(continuation);
+        }
+
+        let text = self.cased(&tok.token);
+        let mut space = self.started && self.spacing.space_before(&tok.token);
+        if space && !comment && !self.unbroken && !self.fits(1 + text.chars().count()) {
+            self.newline(continuation);
+            space = false;
+        }
+
+        // A comment that starts a line stays on its own line.
+        let alone = comment && !self.started;
+        if space {
+            self.line.push(' ');
+            self.width += 1;
+        }
+        self.line.push_str(&text);
+        self.width = match text.rfind('\n') {
+            Some(i) => text[i + 1..].chars().count(),
+            None => self.width + text.chars().count(),
+        };
+        self.started = true;
+        self.spacing.push(tok.token);
+        if tok.is_line_comment() || alone {
+            self.pending_break = true;
+        }
+    }
+
+    fn cased(&self, token: &Token<'_>) -> String {
+        let keyword = token.kind == TokenKind::Word
+            && (is_reserved(token.text)
+                || KEYWORDS.iter().any(|k| k.eq_ignore_ascii_case(token.text))
+                || self.hints && QUERY_HINTS.iter().any(|k| k.eq_ignore_ascii_case(token.text)));
+        match self.options.keyword_case {
+            KeywordCase::Upper if keyword => token.text.to_uppercase(),
+            KeywordCase::Lower if keyword => token.text.to_lowercase(),
+            // Strings keep their line endings, which are part of the value.
+            _ if token.kind == TokenKind::Comment => token.text.replace("\r\n", "\n"),
+            _ => token.text.to_string(),
+        }
+    }
+
+    /// Whether `nodes` fit on the rest of the line.
+    fn fits_flat(&self, nodes: &[Node<'_>]) -> bool {
+        flat_width(nodes, &mut self.spacing.clone()).is_some_and(|w| self.fits(w))
+    }
+
+    /// Write a block of statements at `level`.
+    fn block(&mut self, nodes: &[Node<'a>], level: usize) {
+        let mut level = level;
+        // Levels added for statements under an `IF`, `ELSE` or `WHILE`
+        // without a `BEGIN`.
+        let mut nested = 0;
+        let mut controlled = false;
+        // Where to go back to at the `END` of each open block.
+        let mut blocks = Vec::new();
+        for (n, clause) in clauses(nodes).iter().enumerate() {
+            if clause.kind == ClauseKind::End {
+                if let Some((outer, outer_nested)) = blocks.pop() {
+                    level = outer;
+                    nested = outer_nested;
+                }
+            } else if controlled && clause.kind != ClauseKind::Begin {
+                nested += 1;
+            } else if clause.statement && !controlled {
+                nested = 0;
+            }
+
+            if n > 0 && nodes.get(clause.start).is_some_and(|node| node.first().blank_before) {
+                self.blank_line();
+            }
+            self.clause(&nodes[clause.start..clause.end], clause, level + nested);
+            if clause.kind == ClauseKind::Begin {
+                blocks.push((level, nested));
+                level += nested + 1;
+                nested = 0;
+            }
+            controlled = clause.kind == ClauseKind::Control;
+        }
+    }
+
+    fn clause(&mut self, nodes: &[Node<'a>], clause: &Clause, level: usize) {
+        let (leading, rest) = nodes.split_at(clause.head - clause.start);
+        let (head, body) = rest.split_at(clause.body - clause.head);
+        // Comments on their own lines after the clause, as at the end of a
+        // block, line up with it.
+        let after = body
+            .iter()
+            .rev()
+            .take_while(|n| n.is_comment() && n.first().own_line)
+            .count();
+        let (body, after) = body.split_at(body.len() - after);
+
+        self.newline(level);
+        self.comments(leading, level);
+        if !head.is_empty() {
+            self.newline(level);
+            self.expr(head, level + 1);
+        }
+        if !body.is_empty() {
+            let items = split_list(body);
+            let mut spacing = Spacing::default();
+            let width = flat_width(head, &mut spacing).and_then(|head| {
+                spacing.space_paren = true;
+                Some(head + flat_width(body, &mut spacing)?)
+            });
+            let fits = width.is_some_and(|w| self.indent_width(level) + w <= self.options.max_width);
+            if clause.kind == ClauseKind::List && items.len() > 1 && !fits {
+                self.list(&items, level + 1);
+            } else {
+                self.spacing.space_paren = true;
+                self.expr(body, level + 1);
+            }
+        }
+        self.comments(after, level);
+    }
+
+    /// Write comments each on their own line at `level`.
+    fn comments(&mut self, nodes: &[Node<'a>], level: usize) {
+        for node in nodes {
+            self.newline(level);
+            self.token(node.first(), level);
+            self.pending_break = true;
+        }
+    }
+
+    /// Write a list one item per line at `level`.
+    fn list(&mut self, items: &[Item<'_, 'a>], level: usize) {
+        for (n, item) in items.iter().enumerate() {
+            self.newline(level);
+            let lead = item.nodes.iter().take_while(|n| n.is_comment()).count();
+            let tail = item.nodes[lead..]
+                .iter()
+                .rev()
+                .take_while(|n| n.is_comment() && !n.first().own_line)
+                .count();
+            let (leading, rest) = item.nodes.split_at(lead);
+            let (core, trailing) = rest.split_at(rest.len() - tail);
+            self.comments(leading, level);
+
+            if self.options.commas == Commas::Leading && n > 0 {
+                if let Some(comma) = items[n - 1].comma {
+                    self.token(comma.first(), level + 1);
+                }
+            }
+            self.expr(core, level + 1);
+            if self.options.commas == Commas::Trailing {
+                if let Some(comma) = item.comma {
+                    self.token(comma.first(), level + 1);
+                }
+            }
+            for comment in trailing.iter().chain(item.trailing) {
+                self.token(comment.first(), level + 1);
+            }
+        }
+    }
+
+    /// Write an expression on from the current line, wrapping onto lines
+    /// at `continuation`. When it does not fit, it is broken before each
+    /// `AND` and `OR`.
+    fn expr(&mut self, nodes: &[Node<'a>], continuation: usize) {
+        let fits = self.fits_flat(nodes);
+        let mut between = false;
+        for (i, node) in nodes.iter().enumerate() {
+            match node {
+                Node::Tok(tok) => {
+                    if node.is_keyword("BETWEEN") {
+                        between = true;
+                    }
+                    let and = node.is_keyword("AND") && !std::mem::take(&mut between);
+                    if !fits && (and || node.is_keyword("OR")) {
+                        self.newline(continuation);
+                    }
+                    self.token(tok, continuation);
+                }
+                Node::Group { open, inner, close } => {
+                    let before = i.checked_sub(1).and_then(|j| nodes[j].word());
+                    let option = before.as_deref() == Some("OPTION");
+                    if option || before.is_some_and(|w| SIZED_TYPES.contains(&w.as_str())) {
+                        self.unbroken(open, inner, close.as_ref(), option, continuation);
+                    } else {
+                        let fits = self.unbroken || self.fits_flat(attached(nodes, i));
+                        self.group(open, inner, close.as_ref(), fits, continuation)
+                    }
+                }
+                Node::Case { case, inner, end } => {
+                    let fits = self.unbroken || self.fits_flat(attached(nodes, i));
+                    self.case(case, inner, end.as_ref(), fits, continuation)
+                }
+            }
+        }
+    }
+
+    /// Write a group on the line whatever its width: the length of a data
+    /// type, as in `nvarchar(max)`, or the `hints` of `OPTION (...)`.
+    fn unbroken(
+        &mut self,
+        open: &Tok<'a>,
+        inner: &[Node<'a>],
+        close: Option<&Tok<'a>>,
+        hints: bool,
+        continuation: usize,
+    ) {
+        let unbroken = std::mem::replace(&mut self.unbroken, true);
+        let outer_hints = self.hints;
+        self.hints |= hints;
+        self.group(open, inner, close, true, continuation);
+        self.unbroken = unbroken;
+        self.hints = outer_hints;
+    }
+
+    /// Write a parenthesized group, on the line if it fits and otherwise
+    /// with its contents indented on the lines between the parentheses.
+    fn group(&mut self, open: &Tok<'a>, inner: &[Node<'a>], close: Option<&Tok<'a>>, fits: bool, continuation: usize) {
+        if fits {
+            self.token(open, continuation);
+            self.expr(inner, continuation);
+            if let Some(close) = close {
+                self.token(close, continuation);
+            }
+            return;
+        }
+
+        self.token(open, continuation);
+        let outer = self.level;
+        if is_subquery(inner) {
+            self.block(inner, outer + 1);
+        } else {
+            let items = split_list(inner);
+            if items.len() > 1 {
+                self.list(&items, outer + 1);
+            } else if !inner.is_empty() {
+                self.newline(outer + 1);
+                self.expr(inner, outer + 2);
+            }
+        }
+        if let Some(close) = close {
+            self.newline(outer);
+            self.token(close, continuation);
+        }
+    }
+
+    /// Write a `CASE` expression, on the line if it fits and otherwise with
+    /// each `WHEN` and `ELSE` on a line of its own.
+    fn case(&mut self, case: &Tok<'a>, inner: &[Node<'a>], end: Option<&Tok<'a>>, fits: bool, continuation: usize) {
+        if fits {
+            self.token(case, continuation);
+            self.expr(inner, continuation);
+            if let Some(end) = end {
+                self.token(end, continuation);
+            }
+            return;
+        }
+
+        self.token(case, continuation);
+        let outer = self.level;
+        let mut start = 0;
+        for i in 1..=inner.len() {
+            let arm = i == inner.len() || inner[i].is_keyword("WHEN") || inner[i].is_keyword("ELSE");
+            if arm {
+                if start > 0 || inner.first().is_some_and(|n| n.is_keyword("WHEN")) {
+                    self.newline(outer + 1);
+                }
+                self.expr(&inner[start..i], outer + 2);
+                start = i;
+            }
+        }
+        if let Some(end) = end {
+            self.newline(outer);
+            self.token(end, continuation);
+        }
+    }
+}
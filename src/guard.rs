@@ -0,0 +1,265 @@
+//! Checks on statements before they are sent: refusing writes on a
+//! read-only connection, and finding the statements an interactive session
+//! should confirm first.
+//!
+//! Both work on tokens (see [`crate::lexer`]), so keywords in strings,
+//! comments and quoted names are ignored. They are a safety net against
+//! mistakes, not a security boundary: a read-only guarantee has to come
+//! from the login's permissions or a readable secondary.
+
+use std::fmt;
+
+use crate::lexer::{self, Token, TokenKind};
+
+/// Words that change data, schema, permissions or the server, or that run
+/// code the guard cannot see into.
+const WRITE_KEYWORDS: [&str; 25] = [
+    "INSERT",
+    "UPDATE",
+    "DELETE",
+    "MERGE",
+    "DROP",
+    "TRUNCATE",
+    "ALTER",
+    "CREATE",
+    "EXEC",
+    "EXECUTE",
+    "GRANT",
+    "REVOKE",
+    "DENY",
+    "BACKUP",
+    "RESTORE",
+    "DBCC",
+    "BULK",
+    "KILL",
+    "SHUTDOWN",
+    "RECONFIGURE",
+    "WRITETEXT",
+    "UPDATETEXT",
+    "OPENROWSET",
+    "OPENQUERY",
+    "OPENDATASOURCE",
+];
+
+/// Words that begin a statement. A batch starting with any other name is
+/// an implicit procedure call.
+const STATEMENT_KEYWORDS: [&str; 40] = [
+    "SELECT",
+    "WITH",
+    "DECLARE",
+    "SET",
+    "IF",
+    "ELSE",
+    "BEGIN",
+    "END",
+    "WHILE",
+    "BREAK",
+    "CONTINUE",
+    "RETURN",
+    "PRINT",
+    "RAISERROR",
+    "THROW",
+    "WAITFOR",
+    "USE",
+    "OPEN",
+    "FETCH",
+    "CLOSE",
+    "DEALLOCATE",
+    "COMMIT",
+    "ROLLBACK",
+    "SAVE",
+    "GOTO",
+    "GO",
+    "INSERT",
+    "UPDATE",
+    "DELETE",
+    "MERGE",
+    "DROP",
+    "TRUNCATE",
+    "ALTER",
+    "CREATE",
+    "EXEC",
+    "EXECUTE",
+    "GRANT",
+    "REVOKE",
+    "DENY",
+    "DBCC",
+];
+
+/// Refuse `sql` unless it only reads. Anything the guard is unsure of is
+/// refused: `SELECT ... INTO`, procedure calls, and `CREATE` even of a
+/// temporary table.
+pub fn check_read_only(sql: &str) -> anyhow::Result<()> {
+    let tokens = code_tokens(sql);
+
+    if let Some(first) = tokens.first() {
+        let is_statement = STATEMENT_KEYWORDS.iter().any(|k| first.is_keyword(k));
+        if matches!(first.kind, TokenKind::Word | TokenKind::QuotedIdentifier) && !is_statement {
+            anyhow::bail!(
+                "Read-only connection: {} at line {}, column {} looks like a procedure call",
+                first.text,
+                first.line,
+                first.column
+            );
+        }
+    }
+
+    for (i, token) in tokens.iter().enumerate() {
+        let refused = WRITE_KEYWORDS.iter().any(|k| token.is_keyword(k))
+            || (token.is_keyword("INTO") && tokens.get(i + 1).map(|t| t.kind) != Some(TokenKind::Variable));
+        if refused {
+            anyhow::bail!(
+                "Read-only connection: {} at line {}, column {} is not allowed",
+                token.text.to_uppercase(),
+                token.line,
+                token.column
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestructiveKind {
+    Drop,
+    Truncate,
+    Alter,
+    UpdateWithoutWhere,
+    DeleteWithoutWhere,
+}
+
+/// A statement worth a confirmation before it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destructive {
+    pub kind: DestructiveKind,
+    pub line: usize,
+    pub column: usize,
+    /// The statement, on one line and cut short when long.
+    pub text: String,
+}
+
+impl fmt::Display for Destructive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.text)
+    }
+}
+
+/// The `DROP`, `TRUNCATE` and `ALTER` statements of `sql`, and its
+/// `UPDATE` and `DELETE` statements without a `WHERE` clause.
+pub fn destructive_statements(sql: &str) -> Vec<Destructive> {
+    let tokens = code_tokens(sql);
+    let mut found = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let token = &tokens[i];
+        let previous = i.checked_sub(1).map(|p| &tokens[p]);
+        let next = tokens.get(i + 1);
+
+        let kind = if token.is_keyword("DROP") {
+            Some(DestructiveKind::Drop)
+        } else if token.is_keyword("TRUNCATE") {
+            Some(DestructiveKind::Truncate)
+        } else if token.is_keyword("ALTER") {
+            Some(DestructiveKind::Alter)
+        } else if token.is_keyword("UPDATE") || token.is_keyword("DELETE") {
+            // `ON DELETE CASCADE`, `AFTER UPDATE`, `GRANT UPDATE`,
+            // `WHEN MATCHED THEN DELETE`, `IF UPDATE(col)`, ...
+            let clause = previous.is_some_and(|p| {
+                ["THEN", "ON", "FOR", "AFTER", "OF", "GRANT", "DENY", "REVOKE"]
+                    .iter()
+                    .any(|k| p.is_keyword(k))
+                    || p.is_symbol(",")
+                    || p.is_symbol("(")
+            }) || next.is_some_and(|n| n.is_symbol("(") || n.is_keyword("STATISTICS"));
+            match (clause, token.is_keyword("UPDATE")) {
+                (true, _) => None,
+                (false, true) => Some(DestructiveKind::UpdateWithoutWhere),
+                (false, false) => Some(DestructiveKind::DeleteWithoutWhere),
+            }
+        } else {
+            None
+        };
+
+        let Some(kind) = kind else {
+            i += 1;
+            continue;
+        };
+
+        let end = statement_end(&tokens, i, kind);
+        let has_where = tokens[i + 1..end].iter().any(|t| t.is_keyword("WHERE"));
+        let flagged = match kind {
+            DestructiveKind::UpdateWithoutWhere | DestructiveKind::DeleteWithoutWhere => !has_where,
+            _ => true,
+        };
+        if flagged {
+            found.push(Destructive {
+                kind,
+                line: token.line,
+                column: token.column,
+                text: statement_text(sql, &tokens[i..end]),
+            });
+        }
+        // An `ALTER TABLE ... DROP COLUMN` is one statement.
+        i = if kind == DestructiveKind::Alter { end } else { i + 1 };
+    }
+    found
+}
+
+/// The tokens of `sql` without its comments.
+pub(crate) fn code_tokens(sql: &str) -> Vec<Token<'_>> {
+    lexer::tokenize(sql)
+        .into_iter()
+        .filter(|t| t.kind != TokenKind::Comment)
+        .collect()
+}
+
+/// The index after the last token of the statement starting at `start`:
+/// the next `;` or statement keyword outside parentheses.
+pub(crate) fn statement_end(tokens: &[Token<'_>], start: usize, kind: DestructiveKind) -> usize {
+    let mut depth = 0usize;
+    let mut seen_set = false;
+    for (i, token) in tokens.iter().enumerate().skip(start + 1) {
+        if token.is_symbol("(") {
+            depth += 1;
+        } else if token.is_symbol(")") {
+            depth = depth.saturating_sub(1);
+        }
+        if depth > 0 {
+            continue;
+        }
+        if token.is_symbol(";") {
+            return i;
+        }
+        if kind == DestructiveKind::UpdateWithoutWhere && token.is_keyword("SET") && !seen_set {
+            seen_set = true;
+            continue;
+        }
+        // `WITH (NOLOCK)` is a table hint, not a common table expression.
+        if token.is_keyword("WITH") && tokens.get(i + 1).is_some_and(|t| t.is_symbol("(")) {
+            continue;
+        }
+        if kind == DestructiveKind::Alter && (token.is_keyword("DROP") || token.is_keyword("ALTER")) {
+            continue;
+        }
+        if STATEMENT_KEYWORDS.iter().any(|k| token.is_keyword(k)) {
+            return i;
+        }
+    }
+    tokens.len()
+}
+
+const MAX_TEXT: usize = 80;
+
+fn statement_text(sql: &str, tokens: &[Token<'_>]) -> String {
+    let (Some(first), Some(last)) = (tokens.first(), tokens.last()) else {
+        return String::new();
+    };
+    let start = first.text.as_ptr() as usize - sql.as_ptr() as usize;
+    let end = last.text.as_ptr() as usize - sql.as_ptr() as usize + last.text.len();
+    let text = sql[start..end].split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(MAX_TEXT) {
+        Some((cut, _)) => format!("{}...", &text[..cut]),
+        None => text,
+    }
+}
@@ -0,0 +1,58 @@
+//! Quoting of identifiers that end up in generated T-SQL.
+
+/// Quote a single identifier with square brackets.
+pub fn quote_ident(name: &str) -> String {
+    let name = name
+        .strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .map(|n| n.replace("]]", "]"))
+        .unwrap_or_else(|| name.to_string());
+
+    format!("[{}]", name.replace(']', "]]"))
+}
+
+/// Quote a possibly schema-qualified name such as `dbo.rabbit_births`.
+pub fn quote_table(name: &str) -> String {
+    split_name(name)
+        .iter()
+        .map(|part| quote_ident(part))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Split a multi-part name on the dots that are not inside brackets.
+pub fn split_name(name: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut in_brackets = false;
+
+    let mut chars = name.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '[' => {
+                in_brackets = true;
+                current.push(c);
+            }
+            ']' if in_brackets && chars.peek() == Some(&']') => {
+                // An escaped bracket inside a quoted name.
+                chars.next();
+                current.push_str("]]");
+            }
+            ']' => {
+                in_brackets = false;
+                current.push(c);
+            }
+            '.' if !in_brackets => parts.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    parts.push(current);
+
+    parts
+}
+
+/// Quote a string literal.
+pub fn quote_literal(value: &str) -> String {
+    format!("N'{}'", value.replace('\'', "''"))
+}
@@ -0,0 +1,203 @@
+//! Inserts that hand back what the server generated for the new rows:
+//! identity values, rowversions, defaults (including `NEXT VALUE FOR`
+//! sequence defaults) and computed columns.
+//!
+//! This replaces the `select @new_id=max(id)+1` pattern used by
+//! `register_rabbit_birth`, which hands out the same id to two concurrent
+//! callers.
+
+use std::sync::Arc;
+
+use async_std::net::TcpStream;
+use futures_util::stream::TryStreamExt;
+use tiberius::{Client, ColumnData, QueryItem, ToSql};
+
+use crate::ident::{quote_ident, quote_table};
+use crate::schema;
+use crate::telemetry::{self, Operation};
+use crate::value::{column_names, Record, Value};
+
+/// SQL Server accepts at most 2100 parameters per request.
+const MAX_PARAMS: usize = 2000;
+
+/// A table value constructor is limited to 1000 rows.
+const MAX_ROWS: usize = 1000;
+
+/// What to read back from the inserted rows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Returning {
+    /// Every column of every inserted row (`OUTPUT INSERTED.*`).
+    All,
+    /// The named columns of every inserted row.
+    Columns(Vec<String>),
+    /// Only the identity value, through `SCOPE_IDENTITY()`. Unlike `OUTPUT`
+    /// this also works on tables with triggers, but only for a single row.
+    Identity,
+}
+
+/// Insert one row and return what the server generated for it.
+pub async fn insert_one(
+    client: &mut Client<TcpStream>,
+    table: &str,
+    columns: &[&str],
+    values: Vec<Value>,
+    returning: &Returning,
+) -> anyhow::Result<Record> {
+    let mut records = insert_returning(client, table, columns, vec![values], returning).await?;
+
+    records
+        .pop()
+        .ok_or_else(|| anyhow::anyhow!("The insert into {} returned no row", table))
+}
+
+/// Insert a batch of rows and return what the server generated for each,
+/// in the order the server reports them. Large batches are split into
+/// several statements to stay within the parameter limit.
+pub async fn insert_returning(
+    client: &mut Client<TcpStream>,
+    table: &str,
+    columns: &[&str],
+    rows: Vec<Vec<Value>>,
+    returning: &Returning,
+) -> anyhow::Result<Vec<Record>> {
+    if columns.is_empty() {
+        anyhow::bail!("An insert needs at least one column");
+    }
+
+    if let Some(row) = rows.iter().find(|r| r.len() != columns.len()) {
+        anyhow::bail!(
+            "Expected {} values per row but got a row with {}",
+            columns.len(),
+            row.len()
+        );
+    }
+
+    if *returning == Returning::Identity && rows.len() > 1 {
+        anyhow::bail!("Returning::Identity only supports single-row inserts, use Returning::All");
+    }
+
+    // Value::Null is an nvarchar NULL, which binary columns refuse: type
+    // NULLs as their column, looked up only when there are any.
+    let nulls: Vec<TypedNull> = match rows.iter().flatten().any(Value::is_null) {
+        true => {
+            let table_columns = schema::table_columns(client, table).await?;
+            columns
+                .iter()
+                .map(|name| {
+                    let declared = table_columns.iter().find(|c| c.name.eq_ignore_ascii_case(name));
+                    TypedNull(typed_null(declared.map_or("", |c| c.type_name.as_str())))
+                })
+                .collect()
+        }
+        false => Vec::new(),
+    };
+
+    let rows_per_statement = (MAX_PARAMS / columns.len()).clamp(1, MAX_ROWS);
+    let mut records = Vec::with_capacity(rows.len());
+
+    for chunk in rows.chunks(rows_per_statement) {
+        let sql = insert_sql(table, columns, chunk.len(), returning);
+        let params: Vec<&dyn ToSql> = chunk
+            .iter()
+            .flat_map(|row| row.iter().enumerate())
+            .map(|(i, v)| match v {
+                Value::Null => &nulls[i] as &dyn ToSql,
+                v => v as &dyn ToSql,
+            })
+            .collect();
+        let span = telemetry::statement_span(Operation::Query, &sql, &params);
+
+        let read = async {
+            let mut stream = client.query(sql.as_str(), &params).await?;
+            let mut names: Option<Arc<[String]>> = None;
+            let mut chunk_records = Vec::with_capacity(chunk.len());
+
+            while let Some(item) = stream.try_next().await? {
+                if let QueryItem::Row(row) = item {
+                    let names = names.get_or_insert_with(|| column_names(&row)).clone();
+                    chunk_records.push(Record::new(names, Value::from_row(row)));
+                }
+            }
+
+            anyhow::Ok(chunk_records)
+        };
+
+        records.extend(telemetry::traced(span, read, |r| Some(r.len() as u64)).await?);
+    }
+
+    Ok(records)
+}
+
+/// A NULL parameter of the type of the column it is inserted into.
+struct TypedNull(ColumnData<'static>);
+
+impl ToSql for TypedNull {
+    fn to_sql(&self) -> ColumnData<'_> {
+        self.0.clone()
+    }
+}
+
+/// A NULL of a type that converts implicitly to `type_name`, a column's
+/// system type name.
+pub(crate) fn typed_null(type_name: &str) -> ColumnData<'static> {
+    match type_name {
+        "tinyint" | "smallint" | "int" | "bigint" => ColumnData::I64(None),
+        "bit" => ColumnData::Bit(None),
+        "real" | "float" => ColumnData::F64(None),
+        "binary" | "varbinary" | "image" => ColumnData::Binary(None),
+        "uniqueidentifier" => ColumnData::Guid(None),
+        "date" => ColumnData::Date(None),
+        "time" => ColumnData::Time(None),
+        "datetime" | "smalldatetime" | "datetime2" => ColumnData::DateTime2(None),
+        "datetimeoffset" => ColumnData::DateTimeOffset(None),
+        // Strings, xml and exact numerics, as Value::Null is.
+        _ => ColumnData::String(None),
+    }
+}
+
+/// Build the parameterised insert statement for `row_count` rows.
+pub fn insert_sql(table: &str, columns: &[&str], row_count: usize, returning: &Returning) -> String {
+    let column_list = columns
+        .iter()
+        .map(|c| quote_ident(c))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let values = (0..row_count)
+        .map(|r| {
+            let params = (0..columns.len())
+                .map(|c| format!("@P{}", r * columns.len() + c + 1))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("({})", params)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let output = match returning {
+        Returning::All => " OUTPUT INSERTED.*".to_string(),
+        Returning::Columns(names) => format!(
+            " OUTPUT {}",
+            names
+                .iter()
+                .map(|n| format!("INSERTED.{}", quote_ident(n)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Returning::Identity => String::new(),
+    };
+
+    let mut sql = format!(
+        "INSERT INTO {} ({}){} VALUES {}",
+        quote_table(table),
+        column_list,
+        output,
+        values
+    );
+
+    if *returning == Returning::Identity {
+        sql.push_str("; SELECT CAST(SCOPE_IDENTITY() AS bigint) AS [identity]");
+    }
+
+    sql
+}
@@ -0,0 +1,207 @@
+//! A T-SQL tokenizer, for the checks and tools that look at statements
+//! before they are sent. It knows about comments, strings and quoted names,
+//! so keywords inside them are not mistaken for real ones, but it does not
+//! parse.
+
+/// What a token is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A keyword or a plain name, including `#temp` tables.
+    Word,
+    /// `[name]` or `"name"`.
+    QuotedIdentifier,
+    /// `'text'` or `N'text'`.
+    String,
+    /// An integer, decimal, float or `0x` binary literal.
+    Number,
+    /// `@name` or `@@name`.
+    Variable,
+    /// `-- ...` or `/* ... */`.
+    Comment,
+    /// An operator or punctuation.
+    Symbol,
+}
+
+/// A token and where it starts. Lines and columns count from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Token<'_> {
+    /// Whether this is the word `keyword`, in any case.
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        self.kind == TokenKind::Word && self.text.eq_ignore_ascii_case(keyword)
+    }
+
+    pub fn is_symbol(&self, symbol: &str) -> bool {
+        self.kind == TokenKind::Symbol && self.text == symbol
+    }
+
+    /// Whether a string, quoted name or block comment is missing its
+    /// closing delimiter.
+    pub fn is_unterminated(&self) -> bool {
+        let text = self.text;
+        match self.kind {
+            TokenKind::String => {
+                let body = text.strip_prefix(['N', 'n']).unwrap_or(text);
+                quoted_close(body, 1, '\'').is_none()
+            }
+            TokenKind::QuotedIdentifier => {
+                let close = if text.starts_with('[') { ']' } else { '"' };
+                quoted_close(text, 1, close).is_none()
+            }
+            TokenKind::Comment => text.starts_with("/*") && block_comment_close(text, 0).is_none(),
+            _ => false,
+        }
+    }
+}
+
+const TWO_CHAR_SYMBOLS: [&str; 16] = [
+    "<=", ">=", "<>", "!=", "!<", "!>", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "::", "=>",
+];
+
+/// Split `sql` into tokens, leaving out whitespace. Never fails: an
+/// unterminated string or comment runs to the end of the text.
+pub fn tokenize(sql: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    let (mut line, mut column) = (1, 1);
+
+    while let Some(&(start, c)) = chars.peek() {
+        let (token_line, token_column) = (line, column);
+        let next = sql[start + c.len_utf8()..].chars().next();
+
+        let (kind, end) = if c.is_whitespace() {
+            (None, start + c.len_utf8())
+        } else if c == '-' && next == Some('-') {
+            let end = sql[start..].find('\n').map_or(sql.len(), |i| start + i);
+            (Some(TokenKind::Comment), end)
+        } else if c == '/' && next == Some('*') {
+            (Some(TokenKind::Comment), block_comment_close(sql, start).unwrap_or(sql.len()))
+        } else if c == '\'' {
+            (Some(TokenKind::String), quoted_close(sql, start + 1, '\'').unwrap_or(sql.len()))
+        } else if (c == 'N' || c == 'n') && next == Some('\'') {
+            (Some(TokenKind::String), quoted_close(sql, start + 2, '\'').unwrap_or(sql.len()))
+        } else if c == '[' {
+            (Some(TokenKind::QuotedIdentifier), quoted_close(sql, start + 1, ']').unwrap_or(sql.len()))
+        } else if c == '"' {
+            (Some(TokenKind::QuotedIdentifier), quoted_close(sql, start + 1, '"').unwrap_or(sql.len()))
+        } else if c == '@' {
+            (Some(TokenKind::Variable), word_end(sql, start + 1))
+        } else if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+            (Some(TokenKind::Number), number_end(sql, start))
+        } else if c.is_alphabetic() || c == '_' || c == '#' {
+            (Some(TokenKind::Word), word_end(sql, start + c.len_utf8()))
+        } else {
+            let two = sql.get(start..start + 2).filter(|s| TWO_CHAR_SYMBOLS.contains(s));
+            (Some(TokenKind::Symbol), start + two.map_or(c.len_utf8(), |s| s.len()))
+        };
+
+        if let Some(kind) = kind {
+            tokens.push(Token {
+                kind,
+                text: &sql[start..end],
+                line: token_line,
+                column: token_column,
+            });
+        }
+
+        while chars.peek().is_some_and(|&(i, _)| i < end) {
+            let (_, c) = chars.next().unwrap();
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+    }
+
+    tokens
+}
+
+/// The end of a quoted string or name whose body starts at `from`, `None`
+/// when it is not closed. A doubled closing character is part of the text.
+fn quoted_close(sql: &str, from: usize, close: char) -> Option<usize> {
+    let mut chars = sql[from..].char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == close {
+            if chars.peek().is_some_and(|&(_, next)| next == close) {
+                chars.next();
+            } else {
+                return Some(from + i + 1);
+            }
+        }
+    }
+    None
+}
+
+/// The end of the block comment at `start`, which may nest.
+fn block_comment_close(sql: &str, start: usize) -> Option<usize> {
+    let bytes = sql.as_bytes();
+    let mut depth = 0;
+    let mut i = start;
+    while i + 1 < bytes.len() {
+        match &bytes[i..i + 2] {
+            b"/*" => {
+                depth += 1;
+                i += 2;
+            }
+            b"*/" => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+fn word_end(sql: &str, from: usize) -> usize {
+    sql[from..]
+        .char_indices()
+        .find(|&(_, c)| !(c.is_alphanumeric() || matches!(c, '_' | '#' | '@' | '$')))
+        .map_or(sql.len(), |(i, _)| from + i)
+}
+
+fn number_end(sql: &str, start: usize) -> usize {
+    let rest = &sql[start..];
+    if rest.len() > 1 && (rest.starts_with("0x") || rest.starts_with("0X")) {
+        return rest[2..]
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .map_or(sql.len(), |i| start + 2 + i);
+    }
+
+    let mut end = 0;
+    let mut seen_dot = false;
+    for (i, c) in rest.char_indices() {
+        if c.is_ascii_digit() {
+            end = i + 1;
+        } else if c == '.' && !seen_dot {
+            seen_dot = true;
+            end = i + 1;
+        } else if c == 'e' || c == 'E' {
+            let mut exponent = i + 1;
+            if rest[exponent..].starts_with(['+', '-']) {
+                exponent += 1;
+            }
+            if rest[exponent..].starts_with(|d: char| d.is_ascii_digit()) {
+                end = exponent
+                    + rest[exponent..]
+                        .find(|d: char| !d.is_ascii_digit())
+                        .unwrap_or(rest.len() - exponent);
+            }
+            break;
+        } else {
+            break;
+        }
+    }
+    start + end
+}
@@ -0,0 +1,871 @@
+//! A T-SQL linter for `.sql` files and for the SQL embedded in Rust string
+//! literals.
+//!
+//! The rules work on tokens (see [`crate::lexer`]) and know T-SQL's
+//! shapes, not the database: `implicit-conversion` only sees the types of
+//! variables, parameters and columns declared in the same text, and
+//! `missing-schema` cannot tell a table alias from a table in every case.
+//! Rules can be turned off with [`Linter::allow`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value as Json};
+
+use crate::guard::{self, DestructiveKind};
+use crate::lexer::{Token, TokenKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub name: &'static str,
+    pub severity: Severity,
+    pub description: &'static str,
+}
+
+pub const RULES: [Rule; 7] = [
+    Rule {
+        name: "select-star",
+        severity: Severity::Warning,
+        description: "SELECT * returns whatever columns the table has today",
+    },
+    Rule {
+        name: "update-without-where",
+        severity: Severity::Error,
+        description: "UPDATE without WHERE changes every row",
+    },
+    Rule {
+        name: "delete-without-where",
+        severity: Severity::Error,
+        description: "DELETE without WHERE removes every row",
+    },
+    Rule {
+        name: "max-plus-one",
+        severity: Severity::Error,
+        description: "MAX(id) + 1 hands the same key to concurrent callers",
+    },
+    Rule {
+        name: "missing-schema",
+        severity: Severity::Warning,
+        description: "Objects named without a schema are looked up per user and recompile",
+    },
+    Rule {
+        name: "non-sargable",
+        severity: Severity::Warning,
+        description: "Predicates that wrap a column in a function or start LIKE with % cannot seek",
+    },
+    Rule {
+        name: "implicit-conversion",
+        severity: Severity::Warning,
+        description: "Comparing or assigning values of different types converts one of them",
+    },
+];
+
+/// The rule called `name`.
+pub fn rule(name: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|r| r.name == name)
+}
+
+/// A problem found at a place in a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// `None` for SQL not read from a file.
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn to_json(&self) -> Json {
+        json!({
+            "file": self.file.as_ref().map(|f| f.display().to_string()),
+            "line": self.line,
+            "column": self.column,
+            "rule": self.rule,
+            "severity": self.severity.to_string(),
+            "message": self.message,
+        })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:", file.display())?,
+            None => f.write_str("<sql>:")?,
+        }
+        write!(
+            f,
+            "{}:{}: {}[{}]: {}",
+            self.line, self.column, self.severity, self.rule, self.message
+        )
+    }
+}
+
+/// Diagnostics as a JSON array.
+pub fn to_json(diagnostics: &[Diagnostic]) -> String {
+    let json = Json::Array(diagnostics.iter().map(Diagnostic::to_json).collect());
+    serde_json::to_string_pretty(&json).expect("JSON values always serialize")
+}
+
+/// Runs the rules that are not allowed.
+#[derive(Debug, Clone, Default)]
+pub struct Linter {
+    allowed: Vec<&'static str>,
+}
+
+impl Linter {
+    pub fn new() -> Self {
+        Linter::default()
+    }
+
+    /// Turn the rule called `name` off.
+    pub fn allow(mut self, name: &str) -> anyhow::Result<Self> {
+        let rule = rule(name).ok_or_else(|| {
+            let names: Vec<_> = RULES.iter().map(|r| r.name).collect();
+            anyhow::anyhow!("No lint rule {:?}; the rules are {}", name, names.join(", "))
+        })?;
+        self.allowed.push(rule.name);
+        Ok(self)
+    }
+
+    /// Lint SQL text, e.g. one batch.
+    pub fn check_sql(&self, sql: &str) -> Vec<Diagnostic> {
+        self.findings(sql)
+            .into_iter()
+            .map(|f| f.diagnostic(f.line, f.column))
+            .collect()
+    }
+
+    /// Lint a `.sql` file, or the SQL string literals of a `.rs` file.
+    pub fn check_file(&self, path: &Path) -> anyhow::Result<Vec<Diagnostic>> {
+        let text = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let mut diagnostics = match path.extension().and_then(|e| e.to_str()) {
+            Some("rs") => self.check_rust(&text),
+            _ => self.check_sql(&text),
+        };
+        for diagnostic in &mut diagnostics {
+            diagnostic.file = Some(path.to_path_buf());
+        }
+        Ok(diagnostics)
+    }
+
+    /// Lint the string literals of Rust source that hold SQL, reporting
+    /// lines and columns of the source.
+    pub fn check_rust(&self, source: &str) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for literal in string_literals(source) {
+            if !looks_like_sql(&literal.text) {
+                continue;
+            }
+            for finding in self.findings(&literal.text) {
+                let (line, column) = literal.source_position(finding.line, finding.column);
+                diagnostics.push(finding.diagnostic(line, column));
+            }
+        }
+        diagnostics
+    }
+
+    fn findings(&self, sql: &str) -> Vec<Finding> {
+        let tokens = guard::code_tokens(sql);
+        let mut findings = Vec::new();
+        select_star(&tokens, &mut findings);
+        without_where(sql, &mut findings);
+        max_plus_one(&tokens, &mut findings);
+        missing_schema(&tokens, &mut findings);
+        non_sargable(&tokens, &mut findings);
+        implicit_conversion(&tokens, &mut findings);
+
+        findings.retain(|f| !self.allowed.contains(&f.rule.name));
+        findings.sort_by_key(|f| (f.line, f.column));
+        findings
+    }
+}
+
+/// A diagnostic before it is placed in a file.
+struct Finding {
+    rule: &'static Rule,
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+impl Finding {
+    fn new(name: &str, token: &Token<'_>, message: String) -> Self {
+        Finding {
+            rule: rule(name).expect("findings name known rules"),
+            line: token.line,
+            column: token.column,
+            message,
+        }
+    }
+
+    fn diagnostic(&self, line: usize, column: usize) -> Diagnostic {
+        Diagnostic {
+            file: None,
+            line,
+            column,
+            rule: self.rule.name,
+            severity: self.rule.severity,
+            message: self.message.clone(),
+        }
+    }
+}
+
+fn select_star(tokens: &[Token<'_>], findings: &mut Vec<Finding>) {
+    for (i, token) in tokens.iter().enumerate() {
+        if !token.is_symbol("*") || i == 0 {
+            continue;
+        }
+        let previous = &tokens[i - 1];
+        let in_select_list = ["SELECT", "DISTINCT", "ALL"].iter().any(|k| previous.is_keyword(k))
+            || previous.is_symbol(",")
+            || previous.is_symbol(".")
+            || (previous.kind == TokenKind::Number && i >= 2 && tokens[i - 2].is_keyword("TOP"));
+        if !in_select_list {
+            continue;
+        }
+
+        // `EXISTS (SELECT * ...)` reads no columns.
+        let select = tokens[..i].iter().rposition(|t| t.is_keyword("SELECT"));
+        let in_exists =
+            select.is_some_and(|s| s >= 2 && tokens[s - 1].is_symbol("(") && tokens[s - 2].is_keyword("EXISTS"));
+        if !in_exists {
+            let message = "SELECT * returns whatever columns the table has today; list the columns".to_string();
+            findings.push(Finding::new("select-star", token, message));
+        }
+    }
+}
+
+fn without_where(sql: &str, findings: &mut Vec<Finding>) {
+    for statement in guard::destructive_statements(sql) {
+        let (name, verb) = match statement.kind {
+            DestructiveKind::UpdateWithoutWhere => ("update-without-where", "changes"),
+            DestructiveKind::DeleteWithoutWhere => ("delete-without-where", "removes"),
+            _ => continue,
+        };
+        findings.push(Finding {
+            rule: rule(name).expect("a known rule"),
+            line: statement.line,
+            column: statement.column,
+            message: format!("{} has no WHERE clause and {} every row", statement.text, verb),
+        });
+    }
+}
+
+fn max_plus_one(tokens: &[Token<'_>], findings: &mut Vec<Finding>) {
+    for (i, token) in tokens.iter().enumerate() {
+        if !token.is_keyword("MAX") || !tokens.get(i + 1).is_some_and(|t| t.is_symbol("(")) {
+            continue;
+        }
+        // Past `MAX(id)`, closing parentheses and the `, 0)` of an
+        // `ISNULL(MAX(id), 0)`.
+        let mut next = closing_paren(tokens, i + 1) + 1;
+        loop {
+            let at = |k: usize| tokens.get(next + k);
+            if at(0).is_some_and(|t| t.is_symbol(")")) {
+                next += 1;
+            } else if at(0).is_some_and(|t| t.is_symbol(","))
+                && at(1).is_some_and(|t| t.kind == TokenKind::Number)
+                && at(2).is_some_and(|t| t.is_symbol(")"))
+            {
+                next += 3;
+            } else {
+                break;
+            }
+        }
+        let plus = tokens.get(next).is_some_and(|t| t.is_symbol("+"));
+        if plus && tokens.get(next + 1).is_some_and(|t| t.kind == TokenKind::Number) {
+            let message = "MAX(...) + 1 gives concurrent callers the same key; use an IDENTITY column or a SEQUENCE";
+            findings.push(Finding::new("max-plus-one", token, message.to_string()));
+        }
+    }
+}
+
+/// Words after which an object name follows.
+const OBJECT_KEYWORDS: [&str; 13] = [
+    "FROM",
+    "JOIN",
+    "INTO",
+    "UPDATE",
+    "DELETE",
+    "TABLE",
+    "VIEW",
+    "PROCEDURE",
+    "PROC",
+    "FUNCTION",
+    "EXEC",
+    "EXECUTE",
+    "MERGE",
+];
+
+fn missing_schema(tokens: &[Token<'_>], findings: &mut Vec<Finding>) {
+    // Common table expressions: `name AS (`.
+    let ctes: Vec<String> = tokens
+        .windows(3)
+        .filter(|w| w[0].kind == TokenKind::Word && w[1].is_keyword("AS") && w[2].is_symbol("("))
+        .map(|w| w[0].text.to_lowercase())
+        .collect();
+
+    for (i, token) in tokens.iter().enumerate() {
+        if !OBJECT_KEYWORDS.iter().any(|k| token.is_keyword(k)) {
+            continue;
+        }
+        let previous = i.checked_sub(1).map(|p| &tokens[p]);
+        // `ON DELETE CASCADE`, `GRANT UPDATE`, `IF UPDATE(col)`, ...
+        if token.is_keyword("UPDATE") || token.is_keyword("DELETE") {
+            let clause = previous.is_some_and(|p| {
+                ["THEN", "ON", "FOR", "AFTER", "OF", "GRANT", "DENY", "REVOKE"]
+                    .iter()
+                    .any(|k| p.is_keyword(k))
+                    || p.is_symbol(",")
+            });
+            if clause {
+                continue;
+            }
+        }
+
+        // `FETCH NEXT FROM cursor`, `FETCH ABSOLUTE 5 FROM cursor`
+        let fetch_direction = |t: &Token<'_>| {
+            ["FETCH", "NEXT", "PRIOR", "FIRST", "LAST"]
+                .iter()
+                .any(|k| t.is_keyword(k))
+        };
+        let cursor = token.is_keyword("FROM")
+            && (previous.is_some_and(fetch_direction)
+                || (i >= 2
+                    && tokens[i - 1].kind == TokenKind::Number
+                    && (tokens[i - 2].is_keyword("ABSOLUTE") || tokens[i - 2].is_keyword("RELATIVE"))));
+        if cursor {
+            continue;
+        }
+
+        let mut name = i + 1;
+        while tokens.get(name).is_some_and(|t| {
+            ["INTO", "IF", "EXISTS", "TOP"].iter().any(|k| t.is_keyword(k))
+                || (t.is_keyword("FROM") && token.is_keyword("DELETE"))
+        }) {
+            name += 1;
+        }
+        if tokens.get(name).is_some_and(|t| t.is_symbol("(")) && tokens[i + 1].is_keyword("TOP") {
+            name = closing_paren(tokens, name) + 1;
+        }
+        // `EXEC @status = dbo.proc`
+        if tokens.get(name).is_some_and(|t| t.kind == TokenKind::Variable)
+            && tokens.get(name + 1).is_some_and(|t| t.is_symbol("="))
+        {
+            name += 2;
+        }
+        let Some(object) = tokens.get(name) else {
+            continue;
+        };
+        let after = tokens.get(name + 1);
+        if !matches!(object.kind, TokenKind::Word | TokenKind::QuotedIdentifier)
+            || after.is_some_and(|t| t.is_symbol("."))
+        {
+            continue;
+        }
+
+        let bare = object.text.trim_matches(['[', ']', '"']).to_lowercase();
+        let table_function =
+            (token.is_keyword("FROM") || token.is_keyword("JOIN")) && after.is_some_and(|t| t.is_symbol("("));
+        let system = bare.starts_with('#')
+            || bare.starts_with("sp_")
+            || bare.starts_with("xp_")
+            || bare == "inserted"
+            || bare == "deleted"
+            || OBJECT_KEYWORDS.iter().any(|k| object.is_keyword(k))
+            || ["SET", "SELECT", "VALUES", "OUTPUT", "WHERE"]
+                .iter()
+                .any(|k| object.is_keyword(k));
+        // `UPDATE o SET ... FROM dbo.Orders o` names an alias.
+        let alias = (token.is_keyword("UPDATE") || token.is_keyword("DELETE")) && {
+            let kind = if token.is_keyword("UPDATE") {
+                DestructiveKind::UpdateWithoutWhere
+            } else {
+                DestructiveKind::DeleteWithoutWhere
+            };
+            let end = guard::statement_end(tokens, i, kind);
+            tokens[name + 1..end.max(name + 1)].iter().any(|t| t.is_keyword("FROM"))
+        };
+        if table_function || system || alias || ctes.contains(&bare) {
+            continue;
+        }
+        findings.push(Finding::new(
+            "missing-schema",
+            object,
+            format!(
+                "{} has no schema; name it with one, e.g. dbo.{}",
+                object.text, object.text
+            ),
+        ));
+    }
+}
+
+/// Functions that hide a column from an index when wrapped around it.
+const WRAPPING_FUNCTIONS: [&str; 20] = [
+    "YEAR",
+    "MONTH",
+    "DAY",
+    "UPPER",
+    "LOWER",
+    "LTRIM",
+    "RTRIM",
+    "TRIM",
+    "CONVERT",
+    "CAST",
+    "ISNULL",
+    "COALESCE",
+    "DATEPART",
+    "DATEADD",
+    "DATEDIFF",
+    "DATENAME",
+    "SUBSTRING",
+    "LEFT",
+    "RIGHT",
+    "LEN",
+];
+
+/// Functions whose first argument is a type or date part, not a value.
+const KIND_FIRST: [&str; 5] = ["CONVERT", "DATEPART", "DATEADD", "DATEDIFF", "DATENAME"];
+
+const COMPARISONS: [&str; 9] = ["=", "<", ">", "<=", ">=", "<>", "!=", "!<", "!>"];
+
+/// Words that end a `WHERE` or `ON` condition.
+const CONDITION_END: [&str; 16] = [
+    "GROUP", "ORDER", "HAVING", "SELECT", "INSERT", "UPDATE", "DELETE", "SET", "DECLARE", "RETURN", "BEGIN", "END",
+    "JOIN", "UNION", "OPTION", "EXEC",
+];
+
+fn non_sargable(tokens: &[Token<'_>], findings: &mut Vec<Finding>) {
+    let mut in_condition = false;
+    for (i, token) in tokens.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|p| &tokens[p]);
+        if token.is_keyword("WHERE") {
+            in_condition = true;
+        } else if token.is_keyword("ON") {
+            // Not `SET NOCOUNT ON`, `ON DELETE`, `(STATISTICS_NORECOMPUTE = ON)`.
+            in_condition = tokens
+                .get(i + 1)
+                .is_some_and(|t| t.kind != TokenKind::Symbol && !t.is_keyword("DELETE") && !t.is_keyword("UPDATE"))
+                && previous.is_some_and(|p| p.kind == TokenKind::Word || p.kind == TokenKind::QuotedIdentifier)
+                && !previous.is_some_and(|p| p.is_keyword("NOCOUNT") || p.is_keyword("XACT_ABORT"));
+        } else if token.is_symbol(";") || CONDITION_END.iter().any(|k| token.is_keyword(k)) {
+            in_condition = false;
+        }
+        if !in_condition {
+            continue;
+        }
+
+        if token.is_keyword("LIKE") {
+            if let Some(pattern) = tokens.get(i + 1).filter(|t| t.kind == TokenKind::String) {
+                let body = pattern.text.trim_start_matches(['N', 'n']);
+                if body.starts_with("'%") {
+                    findings.push(Finding::new(
+                        "non-sargable",
+                        pattern,
+                        format!("LIKE {} starts with a wildcard, so every row is read", pattern.text),
+                    ));
+                }
+            }
+            continue;
+        }
+
+        let wrapping = WRAPPING_FUNCTIONS.iter().find(|f| token.is_keyword(f));
+        let Some(function) = wrapping.filter(|_| tokens.get(i + 1).is_some_and(|t| t.is_symbol("("))) else {
+            continue;
+        };
+        let close = closing_paren(tokens, i + 1);
+        let mut arguments = &tokens[(i + 2).min(close)..close];
+        if KIND_FIRST.contains(function) {
+            let comma = arguments.iter().position(|t| t.is_symbol(","));
+            arguments = comma.map_or(&[][..], |c| &arguments[c + 1..]);
+        }
+        let column = arguments.iter().enumerate().find(|(a, t)| {
+            let call = arguments.get(a + 1).is_some_and(|n| n.is_symbol("("));
+            let cast_type = a.checked_sub(1).is_some_and(|p| arguments[p].is_keyword("AS"));
+            (t.kind == TokenKind::Word || t.kind == TokenKind::QuotedIdentifier)
+                && !call
+                && !cast_type
+                && !t.is_keyword("AS")
+                && !t.is_keyword("NULL")
+        });
+        let compared = tokens
+            .get(close + 1)
+            .is_some_and(|t| is_comparison(t) || ["IN", "LIKE", "BETWEEN", "IS"].iter().any(|k| t.is_keyword(k)))
+            || previous.is_some_and(is_comparison);
+        if let (Some((_, column)), true) = (column, compared) {
+            findings.push(Finding::new(
+                "non-sargable",
+                token,
+                format!(
+                    "{}() around {} keeps an index on it from being used; compare the bare column instead",
+                    function, column.text
+                ),
+            ));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Category {
+    Number,
+    /// `true` for the national types.
+    String(bool),
+    Date,
+}
+
+impl Category {
+    fn of_type(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "int" | "bigint" | "smallint" | "tinyint" | "decimal" | "numeric" | "float" | "real" | "money"
+            | "smallmoney" | "bit" => Some(Category::Number),
+            "varchar" | "char" | "text" => Some(Category::String(false)),
+            "nvarchar" | "nchar" | "ntext" | "sysname" => Some(Category::String(true)),
+            "date" | "datetime" | "datetime2" | "smalldatetime" | "datetimeoffset" | "time" => Some(Category::Date),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Category::Number => "a number",
+            Category::String(false) => "a varchar",
+            Category::String(true) => "an nvarchar",
+            Category::Date => "a date",
+        }
+    }
+
+    /// Whether comparing a value of this category with one of `other`
+    /// converts either side in a way that can fail or scan.
+    fn converts_with(self, other: Category) -> bool {
+        match (self, other) {
+            (Category::String(a), Category::String(b)) => a != b,
+            // Date strings are the usual way to write date literals.
+            (Category::Date, Category::String(_)) | (Category::String(_), Category::Date) => false,
+            (a, b) => a != b,
+        }
+    }
+}
+
+fn implicit_conversion(tokens: &[Token<'_>], findings: &mut Vec<Finding>) {
+    // Variables, parameters and columns declared in the text: a name
+    // followed by its type, as in `DECLARE @id int`, `@id AS int` or
+    // `name varchar(20)`. `CAST(x AS int)` declares nothing.
+    let mut declared: HashMap<String, Category> = HashMap::new();
+    for pair in tokens.windows(3) {
+        let (name, ty) = if pair[1].is_keyword("AS") && pair[0].kind == TokenKind::Variable {
+            (&pair[0], &pair[2])
+        } else {
+            (&pair[0], &pair[1])
+        };
+        if matches!(
+            name.kind,
+            TokenKind::Variable | TokenKind::Word | TokenKind::QuotedIdentifier
+        ) && ty.kind == TokenKind::Word
+        {
+            if let Some(category) = Category::of_type(ty.text) {
+                declared.entry(name_key(name.text)).or_insert(category);
+            }
+        }
+    }
+
+    let category = |token: &Token<'_>| match token.kind {
+        TokenKind::Number => Some(Category::Number),
+        TokenKind::String => Some(Category::String(token.text.starts_with(['N', 'n']))),
+        TokenKind::Variable | TokenKind::Word | TokenKind::QuotedIdentifier => {
+            declared.get(&name_key(token.text)).copied()
+        }
+        _ => None,
+    };
+
+    for (i, token) in tokens.iter().enumerate() {
+        if !is_comparison(token) || i == 0 {
+            continue;
+        }
+        let (left, right) = (&tokens[i - 1], tokens.get(i + 1));
+        let Some(right) = right else {
+            continue;
+        };
+        // `@id int = '5'` compares nothing; it assigns a default.
+        let left = match Category::of_type(left.text) {
+            Some(_) if i >= 2 && tokens[i - 2].kind == TokenKind::Variable => &tokens[i - 2],
+            _ => left,
+        };
+        let named = |t: &Token<'_>| t.kind != TokenKind::Number && t.kind != TokenKind::String;
+        if !named(left) && !named(right) {
+            continue;
+        }
+        if let (Some(a), Some(b)) = (category(left), category(right)) {
+            if a.converts_with(b) {
+                findings.push(Finding::new(
+                    "implicit-conversion",
+                    token,
+                    format!(
+                        "{} is {} but {} is {}; SQL Server converts one of them, which can fail or scan",
+                        left.text,
+                        a.name(),
+                        right.text,
+                        b.name()
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+fn name_key(name: &str) -> String {
+    name.trim_matches(['[', ']', '"']).to_lowercase()
+}
+
+fn is_comparison(token: &Token<'_>) -> bool {
+    COMPARISONS.iter().any(|c| token.is_symbol(c))
+}
+
+/// The index of the `)` closing the `(` at `open`, or the end.
+fn closing_paren(tokens: &[Token<'_>], open: usize) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        if token.is_symbol("(") {
+            depth += 1;
+        } else if token.is_symbol(")") {
+            depth -= 1;
+            if depth == 0 {
+                return i;
+            }
+        }
+    }
+    tokens.len()
+}
+
+/// Whether a string literal holds a SQL statement rather than a message
+/// or a fragment: it starts with a statement keyword written in one case
+/// and has the clause that statement needs.
+fn looks_like_sql(text: &str) -> bool {
+    let tokens = guard::code_tokens(text);
+    let Some(first) = tokens.first() else {
+        return false;
+    };
+    if first.text != first.text.to_uppercase() && first.text != first.text.to_lowercase() {
+        return false;
+    }
+    let second = tokens.get(1);
+    let has = |keyword: &str| tokens.iter().skip(1).any(|t| t.is_keyword(keyword));
+
+    if first.is_keyword("SELECT") {
+        has("FROM") || tokens.len() <= 6
+    } else if first.is_keyword("INSERT") {
+        has("INTO") || has("VALUES") || has("SELECT")
+    } else if first.is_keyword("UPDATE") {
+        has("SET") && second.is_some_and(|t| !t.is_keyword("SET"))
+    } else if first.is_keyword("DELETE") {
+        // `DELETE FROM t` or `DELETE dbo.t WHERE ...`
+        has("FROM") || tokens.get(2).is_some_and(|t| t.is_symbol("."))
+    } else if first.is_keyword("MERGE") {
+        has("USING")
+    } else if ["CREATE", "ALTER", "DROP"].iter().any(|k| first.is_keyword(k)) {
+        second.is_some_and(|t| OBJECT_TYPES.iter().any(|k| t.is_keyword(k)))
+    } else if first.is_keyword("TRUNCATE") {
+        second.is_some_and(|t| t.is_keyword("TABLE"))
+    } else if first.is_keyword("DECLARE") {
+        second.is_some_and(|t| t.kind == TokenKind::Variable)
+    } else if first.is_keyword("WITH") {
+        has("AS") && has("SELECT")
+    } else if first.is_keyword("EXEC") || first.is_keyword("EXECUTE") {
+        tokens.len() >= 2
+    } else {
+        false
+    }
+}
+
+/// What `CREATE`, `ALTER` and `DROP` are followed by.
+const OBJECT_TYPES: [&str; 20] = [
+    "TABLE",
+    "VIEW",
+    "PROCEDURE",
+    "PROC",
+    "FUNCTION",
+    "TRIGGER",
+    "INDEX",
+    "UNIQUE",
+    "CLUSTERED",
+    "NONCLUSTERED",
+    "SCHEMA",
+    "TYPE",
+    "SEQUENCE",
+    "SYNONYM",
+    "DATABASE",
+    "LOGIN",
+    "USER",
+    "ROLE",
+    "STATISTICS",
+    "OR",
+];
+
+/// A Rust string literal with its escapes decoded, and where each of its
+/// characters came from.
+struct Literal {
+    text: String,
+    /// Source line and column of each character of `text`.
+    positions: Vec<(usize, usize)>,
+}
+
+impl Literal {
+    /// The source position of the character at `line` and `column` of
+    /// `text`, both counting from 1 as the lexer does.
+    fn source_position(&self, line: usize, column: usize) -> (usize, usize) {
+        let mut index = 0;
+        let mut current = 1;
+        for c in self.text.chars() {
+            if current == line {
+                break;
+            }
+            index += 1;
+            if c == '\n' {
+                current += 1;
+            }
+        }
+        index += column.saturating_sub(1);
+        self.positions
+            .get(index)
+            .or(self.positions.last())
+            .copied()
+            .unwrap_or((1, 1))
+    }
+}
+
+/// The string literals of Rust source, skipping comments and characters.
+fn string_literals(source: &str) -> Vec<Literal> {
+    let chars: Vec<char> = source.chars().collect();
+    // Source line and column of each character.
+    let mut positions = Vec::with_capacity(chars.len());
+    let (mut line, mut column) = (1, 1);
+    for &c in &chars {
+        positions.push((line, column));
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+
+    let mut literals = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let identifier_before = i > 0 && (chars[i - 1].is_alphanumeric() || chars[i - 1] == '_');
+
+        if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i += 2;
+        } else if c == 'r' && !identifier_before && matches!(next, Some('"') | Some('#')) {
+            let hashes = chars[i + 1..].iter().take_while(|&&h| h == '#').count();
+            if chars.get(i + 1 + hashes) != Some(&'"') {
+                i += 1;
+                continue;
+            }
+            let start = i + hashes + 2;
+            let mut end = start;
+            while end < chars.len()
+                && !(chars[end] == '"' && chars[end + 1..].iter().take(hashes).filter(|&&h| h == '#').count() == hashes)
+            {
+                end += 1;
+            }
+            literals.push(Literal {
+                text: chars[start..end.min(chars.len())].iter().collect(),
+                positions: positions[start..end.min(chars.len())].to_vec(),
+            });
+            i = end + hashes + 1;
+        } else if c == '"' {
+            let mut literal = Literal {
+                text: String::new(),
+                positions: Vec::new(),
+            };
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                let at = positions[i];
+                if chars[i] != '\\' {
+                    literal.text.push(chars[i]);
+                    literal.positions.push(at);
+                    i += 1;
+                    continue;
+                }
+                let escaped = chars.get(i + 1).copied().unwrap_or('\\');
+                i += 2;
+                let decoded = match escaped {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    '0' => '\0',
+                    '\n' => {
+                        // A line continuation skips the next line's indent.
+                        while i < chars.len() && chars[i].is_whitespace() {
+                            i += 1;
+                        }
+                        continue;
+                    }
+                    'x' => {
+                        i += 2;
+                        '?'
+                    }
+                    'u' => {
+                        while i < chars.len() && chars[i] != '}' {
+                            i += 1;
+                        }
+                        i += 1;
+                        '?'
+                    }
+                    other => other,
+                };
+                literal.text.push(decoded);
+                literal.positions.push(at);
+            }
+            i += 1;
+            literals.push(literal);
+        } else if c == '\'' {
+            // A character literal, or the quote of a lifetime.
+            if next == Some('\\') {
+                i += 2;
+                while i < chars.len() && chars[i] != '\'' {
+                    i += 1;
+                }
+                i += 1;
+            } else if chars.get(i + 2) == Some(&'\'') {
+                i += 3;
+            } else {
+                i += 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    literals
+}
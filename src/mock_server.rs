@@ -0,0 +1,774 @@
+//! A minimal TDS server on localhost, so connection, query and decoding
+//! logic can be tested without a real SQL Server.
+//!
+//! The server speaks just enough of the protocol for tiberius: PRELOGIN
+//! (without TLS, or with TLS for the whole session when started with
+//! [`MockServer::start_tls`]), LOGIN7 with SQL authentication, SQL batches
+//! and `sp_executesql` RPC calls. Unless scripted otherwise, `SELECT
+//! @@SPID` returns the connection's session id and `KILL <spid>` closes
+//! that connection. Responses are scripted per test:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use tiberius_sqlserver::mock_server::{MockResponse, MockServer, MockType};
+//! use tiberius_sqlserver::value::Value;
+//!
+//! let server = MockServer::start().await?;
+//! server.on(
+//!     "FROM HumanResources.Department",
+//!     MockResponse::new().result_set(
+//!         &[("DepartmentID", MockType::SmallInt), ("Name", MockType::NVarChar)],
+//!         vec![vec![Value::Int(1), Value::String("Engineering".into())]],
+//!     ),
+//! );
+//!
+//! let mut client = tiberius_sqlserver::sql_client::connect(server.config()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use async_native_tls::TlsAcceptor;
+use async_std::io::{Read, Write};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use tiberius::{AuthMethod, Config, EncryptionLevel};
+
+use crate::tds::{
+    self, epoch, parse_batch, parse_rpc, read_message, reorder_guid, utf16, Reader, DONE_ATTENTION, DONE_COUNT,
+    DONE_ERROR, DONE_MORE, ENCRYPT_NOT_SUP, ENCRYPT_ON, PACKET_ATTENTION, PACKET_BULK_LOAD, PACKET_LOGIN7,
+    PACKET_PRELOGIN, PACKET_RPC, PACKET_SQL_BATCH, PACKET_TABULAR_RESULT, TOKEN_COLMETADATA, TOKEN_DONE,
+    TOKEN_DONEINPROC, TOKEN_DONEPROC, TOKEN_ENVCHANGE, TOKEN_ERROR, TOKEN_INFO, TOKEN_LOGINACK, TOKEN_RETURN_STATUS,
+    TOKEN_ROW,
+};
+use crate::tls::PreloginFraming;
+use crate::value::Value;
+
+const LOGIN_FAILED: u32 = 18456;
+pub(crate) const NO_SCRIPTED_RESPONSE: u32 = 50000;
+
+/// Column types a scripted result set can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockType {
+    Bit,
+    TinyInt,
+    SmallInt,
+    Int,
+    BigInt,
+    Float,
+    /// decimal(precision, scale)
+    Decimal(u8, u8),
+    NVarChar,
+    VarBinary,
+    UniqueIdentifier,
+    Date,
+    Time,
+    DateTime2,
+    DateTimeOffset,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum MockItem {
+    ResultSet {
+        columns: Vec<(String, MockType)>,
+        rows: Vec<Vec<Value>>,
+    },
+    RowsAffected(u64),
+    Error { code: u32, message: String },
+    Info { code: u32, class: u8, message: String },
+    Procedure(Vec<MockItem>),
+}
+
+/// The tokens the server sends back for one request, in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MockResponse {
+    items: Vec<MockItem>,
+    delay: Option<std::time::Duration>,
+}
+
+impl MockResponse {
+    /// An empty response: the statement succeeds without rows.
+    pub fn new() -> Self {
+        MockResponse::default()
+    }
+
+    /// Add a result set. Values must match their column's type; `Null` is
+    /// allowed in every column.
+    pub fn result_set(mut self, columns: &[(&str, MockType)], rows: Vec<Vec<Value>>) -> Self {
+        let columns = columns.iter().map(|(name, ty)| (name.to_string(), *ty)).collect();
+        self.items.push(MockItem::ResultSet { columns, rows });
+        self
+    }
+
+    /// Add a statement that affected `count` rows.
+    pub fn rows_affected(mut self, count: u64) -> Self {
+        self.items.push(MockItem::RowsAffected(count));
+        self
+    }
+
+    /// Add a severity 16 error, e.g. 1205 for a deadlock victim.
+    pub fn error(mut self, code: u32, message: &str) -> Self {
+        self.items.push(MockItem::Error {
+            code,
+            message: message.to_string(),
+        });
+        self
+    }
+
+    /// Add a stored procedure run by `EXEC` in a batch, whose statements
+    /// send what `body` does. As the server does, they end in `DONEINPROC`
+    /// and the procedure in `DONEPROC`, which repeats the row count of the
+    /// last statement.
+    pub fn procedure(mut self, body: MockResponse) -> Self {
+        self.items.push(MockItem::Procedure(body.items));
+        self
+    }
+
+    /// Answer after `delay`, like a slow statement. An attention packet
+    /// arriving meanwhile is confirmed instead.
+    pub fn delay(mut self, delay: std::time::Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Add an informational message, as sent by `PRINT`.
+    pub fn info(mut self, code: u32, message: &str) -> Self {
+        self.items.push(MockItem::Info {
+            code,
+            class: 0,
+            message: message.to_string(),
+        });
+        self
+    }
+
+    /// Add a severity 10 warning, as sent by `RAISERROR(..., 10, 1)`.
+    pub fn warning(mut self, code: u32, message: &str) -> Self {
+        self.items.push(MockItem::Info {
+            code,
+            class: 10,
+            message: message.to_string(),
+        });
+        self
+    }
+
+    /// Encode the token stream. RPC responses end with a return status and
+    /// `DONEPROC`, batches with `DONE`.
+    fn encode(&self, rpc: bool) -> anyhow::Result<Vec<u8>> {
+        let statement_done = if rpc { TOKEN_DONEINPROC } else { TOKEN_DONE };
+        let mut buf = Vec::new();
+        let mut dones = Vec::new();
+        put_items(&mut buf, &mut dones, &self.items, statement_done)?;
+
+        if rpc {
+            for &status in &dones {
+                buf[status] |= DONE_MORE as u8;
+            }
+            buf.push(TOKEN_RETURN_STATUS);
+            buf.extend_from_slice(&0i32.to_le_bytes());
+            put_done(&mut buf, TOKEN_DONEPROC, 0, 0);
+        } else {
+            // Every statement but the last tells the client more follows.
+            if dones.last().is_none_or(|&status| status + 12 != buf.len()) {
+                dones.push(put_done(&mut buf, TOKEN_DONE, 0, 0));
+            }
+            for &status in &dones[..dones.len() - 1] {
+                buf[status] |= DONE_MORE as u8;
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+/// Encode `items`, ending each statement with `statement_done`. The
+/// positions of the status of each `DONE` token are added to `dones`.
+fn put_items(buf: &mut Vec<u8>, dones: &mut Vec<usize>, items: &[MockItem], statement_done: u8) -> anyhow::Result<()> {
+    for item in items {
+        match item {
+            MockItem::ResultSet { columns, rows } => {
+                put_colmetadata(buf, columns);
+                for row in rows {
+                    if row.len() != columns.len() {
+                        anyhow::bail!(
+                            "Mock row has {} values for {} columns",
+                            row.len(),
+                            columns.len()
+                        );
+                    }
+                    buf.push(TOKEN_ROW);
+                    for ((name, ty), value) in columns.iter().zip(row) {
+                        put_value(buf, *ty, value).map_err(|e| {
+                            anyhow::anyhow!("Mock column {}: {}", name, e)
+                        })?;
+                    }
+                }
+                dones.push(put_done(buf, statement_done, DONE_COUNT, rows.len() as u64));
+            }
+            MockItem::RowsAffected(count) => {
+                dones.push(put_done(buf, statement_done, DONE_COUNT, *count));
+            }
+            MockItem::Error { code, message } => {
+                put_message(buf, TOKEN_ERROR, *code, 16, message);
+                dones.push(put_done(buf, statement_done, DONE_ERROR, 0));
+            }
+            MockItem::Info { code, class, message } => {
+                put_message(buf, TOKEN_INFO, *code, *class, message);
+            }
+            MockItem::Procedure(body) => {
+                put_items(buf, dones, body, TOKEN_DONEINPROC)?;
+                let last = body.iter().rev().find_map(|item| match item {
+                    MockItem::ResultSet { rows, .. } => Some(rows.len() as u64),
+                    MockItem::RowsAffected(count) => Some(*count),
+                    _ => None,
+                });
+                buf.push(TOKEN_RETURN_STATUS);
+                buf.extend_from_slice(&0i32.to_le_bytes());
+                let status = if last.is_some() { DONE_COUNT } else { 0 };
+                dones.push(put_done(buf, TOKEN_DONEPROC, status, last.unwrap_or(0)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What kind of message a request arrived in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Batch,
+    Rpc,
+    BulkLoad,
+}
+
+/// A request received by the server, for assertions in tests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockRequest {
+    pub kind: RequestKind,
+    /// The statement text. Empty for bulk load data.
+    pub sql: String,
+    /// The parameter values of an `sp_executesql` call, `@P1` first.
+    pub params: Vec<Value>,
+}
+
+struct Rule {
+    pattern: String,
+    response: MockResponse,
+    once: bool,
+}
+
+#[derive(Default)]
+struct State {
+    login: Option<(String, String)>,
+    rules: Vec<Rule>,
+    requests: Vec<MockRequest>,
+    logins: usize,
+    /// The connections, by session id less [`FIRST_SPID`].
+    sessions: Vec<TcpStream>,
+}
+
+/// The session id of the first connection; lower ids are the server's own.
+const FIRST_SPID: i16 = 51;
+
+impl State {
+    /// The response of the first rule whose pattern the statement contains,
+    /// on the connection with session id `spid`.
+    fn respond(&mut self, spid: i16, request: MockRequest) -> MockResponse {
+        let sql = request.sql.to_lowercase();
+        self.requests.push(request);
+
+        match self.rules.iter().position(|r| sql.contains(&r.pattern)) {
+            Some(i) if self.rules[i].once => self.rules.remove(i).response,
+            Some(i) => self.rules[i].response.clone(),
+            None if sql.trim() == "select @@spid" => {
+                MockResponse::new().result_set(&[("", MockType::SmallInt)], vec![vec![Value::Int(spid.into())]])
+            }
+            None => match sql.trim().strip_prefix("kill ").and_then(|id| id.trim().parse().ok()) {
+                Some(killed) => self.kill(killed),
+                None => MockResponse::new().error(
+                    NO_SCRIPTED_RESPONSE,
+                    &format!("No scripted response for: {}", sql),
+                ),
+            },
+        }
+    }
+
+    /// End the session `spid` by closing its connection.
+    fn kill(&self, spid: i16) -> MockResponse {
+        let index = usize::try_from(i32::from(spid) - i32::from(FIRST_SPID)).ok();
+        match index.and_then(|i| self.sessions.get(i)) {
+            Some(tcp) => {
+                let _ = tcp.shutdown(Shutdown::Both);
+                MockResponse::new()
+            }
+            None => MockResponse::new().error(6106, &format!("Process ID {} is not an active process ID.", spid)),
+        }
+    }
+}
+
+/// A scripted TDS server listening on a random localhost port. The server
+/// runs until the test process exits.
+#[derive(Clone)]
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    /// Start listening on `127.0.0.1` and accept connections in the
+    /// background.
+    pub async fn start() -> anyhow::Result<Self> {
+        Self::listen(None).await
+    }
+
+    /// Like [`start`](MockServer::start), encrypting every session with
+    /// the certificate of `acceptor` from the handshake on, as SQL Server
+    /// does with `encrypt = on`.
+    pub async fn start_tls(acceptor: TlsAcceptor) -> anyhow::Result<Self> {
+        Self::listen(Some(acceptor)).await
+    }
+
+    async fn listen(tls: Option<TlsAcceptor>) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let shared = state.clone();
+        task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = shared.clone();
+                let tls = tls.clone();
+                task::spawn(async move {
+                    // A broken connection only affects the test using it.
+                    let _ = accept(stream, state, tls).await;
+                });
+            }
+        });
+
+        Ok(MockServer { addr, state })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A client configuration that reaches this server without TLS.
+    pub fn config(&self) -> Config {
+        let (user, password) = self
+            .state
+            .lock()
+            .unwrap()
+            .login
+            .clone()
+            .unwrap_or_else(|| ("sa".to_string(), "mock".to_string()));
+
+        let mut config = Config::new();
+        config.host(self.addr.ip().to_string());
+        config.port(self.addr.port());
+        config.authentication(AuthMethod::sql_server(user, password));
+        config.encryption(EncryptionLevel::NotSupported);
+        config
+    }
+
+    /// Reject logins with any other user name or password. By default
+    /// every login is accepted.
+    pub fn expect_login(&self, user: &str, password: &str) {
+        self.state.lock().unwrap().login = Some((user.to_string(), password.to_string()));
+    }
+
+    /// Answer every statement containing `pattern` (case-insensitive) with
+    /// `response`. Rules are tried in the order they were added.
+    pub fn on(&self, pattern: &str, response: MockResponse) {
+        self.add_rule(pattern, response, false);
+    }
+
+    /// Like [`on`](MockServer::on), but the rule is used only once, e.g. to
+    /// fail the first attempt of a retried statement.
+    pub fn on_once(&self, pattern: &str, response: MockResponse) {
+        self.add_rule(pattern, response, true);
+    }
+
+    fn add_rule(&self, pattern: &str, response: MockResponse, once: bool) {
+        self.state.lock().unwrap().rules.push(Rule {
+            pattern: pattern.to_lowercase(),
+            response,
+            once,
+        });
+    }
+
+    /// Every request received so far, across all connections.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// How many logins were attempted so far, accepted or not.
+    pub fn logins(&self) -> usize {
+        self.state.lock().unwrap().logins
+    }
+}
+
+/// Serve a connection, after answering PRELOGIN and doing the TLS
+/// handshake when the server encrypts.
+async fn accept(mut tcp: TcpStream, state: Arc<Mutex<State>>, tls: Option<TlsAcceptor>) -> anyhow::Result<()> {
+    let spid = {
+        let mut state = state.lock().unwrap();
+        state.sessions.push(tcp.clone());
+        FIRST_SPID + state.sessions.len() as i16 - 1
+    };
+    let Some(acceptor) = tls else {
+        return serve(tcp, state, spid).await;
+    };
+
+    match read_message(&mut tcp).await? {
+        Some((PACKET_PRELOGIN, _)) => (),
+        Some((other, _)) => anyhow::bail!("Expected PRELOGIN, got packet type {}", other),
+        None => return Ok(()),
+    }
+    tds::write_message(&mut tcp, PACKET_TABULAR_RESULT, &prelogin_reply(ENCRYPT_ON)).await?;
+    let mut stream = acceptor.accept(PreloginFraming::new(tcp)).await?;
+    stream.get_mut().finish_handshake();
+    serve(stream, state, spid).await
+}
+
+async fn serve<S: Read + Write + Unpin>(mut stream: S, state: Arc<Mutex<State>>, spid: i16) -> anyhow::Result<()> {
+    while let Some((packet_type, body)) = read_message(&mut stream).await? {
+        let reply = match packet_type {
+            PACKET_PRELOGIN => prelogin_reply(ENCRYPT_NOT_SUP),
+            PACKET_LOGIN7 => {
+                state.lock().unwrap().logins += 1;
+                login_reply(&state, &body)?
+            }
+            PACKET_SQL_BATCH => {
+                let request = MockRequest {
+                    kind: RequestKind::Batch,
+                    sql: parse_batch(&body)?,
+                    params: Vec::new(),
+                };
+                let response = state.lock().unwrap().respond(spid, request);
+                match delayed_reply(&mut stream, &response, false).await? {
+                    Some(reply) => reply,
+                    None => return Ok(()),
+                }
+            }
+            PACKET_RPC => {
+                let (sql, params) = parse_rpc(&body)?;
+                let request = MockRequest {
+                    kind: RequestKind::Rpc,
+                    sql,
+                    params,
+                };
+                let response = state.lock().unwrap().respond(spid, request);
+                match delayed_reply(&mut stream, &response, true).await? {
+                    Some(reply) => reply,
+                    None => return Ok(()),
+                }
+            }
+            PACKET_BULK_LOAD => {
+                state.lock().unwrap().requests.push(MockRequest {
+                    kind: RequestKind::BulkLoad,
+                    sql: String::new(),
+                    params: Vec::new(),
+                });
+                done_only(0)
+            }
+            PACKET_ATTENTION => done_only(DONE_ATTENTION),
+            other => anyhow::bail!("Unsupported packet type {}", other),
+        };
+
+        tds::write_message(&mut stream, PACKET_TABULAR_RESULT, &reply).await?;
+    }
+
+    Ok(())
+}
+
+/// The reply to a request, after the response's delay unless the client
+/// cancels first. `None` when the client closed the connection.
+async fn delayed_reply<S: Read + Unpin>(
+    stream: &mut S,
+    response: &MockResponse,
+    rpc: bool,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(delay) = response.delay else {
+        return Ok(Some(encode_or_error(response, rpc)));
+    };
+
+    match async_std::future::timeout(delay, read_message(stream)).await {
+        Err(_) => Ok(Some(encode_or_error(response, rpc))),
+        Ok(Ok(Some((PACKET_ATTENTION, _)))) => Ok(Some(done_only(DONE_ATTENTION))),
+        Ok(Ok(Some((other, _)))) => anyhow::bail!("Unexpected packet type {} while answering a request", other),
+        Ok(Ok(None)) => Ok(None),
+        Ok(Err(e)) => Err(e),
+    }
+}
+
+/// A scripted response that cannot be encoded is reported to the client
+/// as a server error, so the failing test shows why.
+pub(crate) fn encode_or_error(response: &MockResponse, rpc: bool) -> Vec<u8> {
+    response.encode(rpc).unwrap_or_else(|e| {
+        MockResponse::new()
+            .error(NO_SCRIPTED_RESPONSE, &e.to_string())
+            .encode(rpc)
+            .expect("error responses always encode")
+    })
+}
+
+fn done_only(status: u16) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_done(&mut buf, TOKEN_DONE, status, 0);
+    buf
+}
+
+/// Version, encryption, instance, thread id and MARS.
+fn prelogin_reply(encryption: u8) -> Vec<u8> {
+    tds::prelogin_body(&[
+        (0, &[15, 0, 0x07, 0xD0, 0, 0]),
+        (1, &[encryption]),
+        (2, &[0]),
+        (3, &[]),
+        (4, &[0]),
+    ])
+}
+
+fn login_reply(state: &Mutex<State>, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let field = |at: usize| -> anyhow::Result<&[u8]> {
+        let mut r = Reader::new(body);
+        r.take(at)?;
+        let offset = r.u16()? as usize;
+        let chars = r.u16()? as usize;
+        body.get(offset..offset + chars * 2)
+            .ok_or_else(|| anyhow::anyhow!("LOGIN7 field at {} is out of bounds", at))
+    };
+
+    let user = utf16(field(40)?);
+    // Passwords are obfuscated by swapping nibbles and xoring with 0xA5.
+    let password: Vec<u8> = field(44)?
+        .iter()
+        .map(|b| (b ^ 0xA5).rotate_left(4))
+        .collect();
+    let password = utf16(&password);
+    let database = match utf16(field(68)?) {
+        db if db.is_empty() => "master".to_string(),
+        db => db,
+    };
+
+    let mut buf = Vec::new();
+
+    if let Some((expected_user, expected_password)) = &state.lock().unwrap().login {
+        if *expected_user != user || *expected_password != password {
+            let message = format!("Login failed for user '{}'.", user);
+            put_message(&mut buf, TOKEN_ERROR, LOGIN_FAILED, 14, &message);
+            put_done(&mut buf, TOKEN_DONE, DONE_ERROR, 0);
+            return Ok(buf);
+        }
+    }
+
+    let program = "Mock SQL Server";
+    buf.push(TOKEN_LOGINACK);
+    buf.extend_from_slice(&((1 + 4 + 1 + program.len() * 2 + 4) as u16).to_le_bytes());
+    buf.push(1);
+    buf.extend_from_slice(&0x7400_0004u32.to_be_bytes());
+    put_b_varchar(&mut buf, program);
+    buf.extend_from_slice(&[15, 0, 0x07, 0xD0]);
+
+    let mut change = vec![1];
+    put_b_varchar(&mut change, &database);
+    put_b_varchar(&mut change, "master");
+    buf.push(TOKEN_ENVCHANGE);
+    buf.extend_from_slice(&(change.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&change);
+
+    put_done(&mut buf, TOKEN_DONE, 0, 0);
+    Ok(buf)
+}
+
+
+fn put_b_varchar(buf: &mut Vec<u8>, value: &str) {
+    let units: Vec<u16> = value.encode_utf16().collect();
+    buf.push(units.len() as u8);
+    units.iter().for_each(|u| buf.extend_from_slice(&u.to_le_bytes()));
+}
+
+fn put_us_varchar(buf: &mut Vec<u8>, value: &str) {
+    let units: Vec<u16> = value.encode_utf16().collect();
+    buf.extend_from_slice(&(units.len() as u16).to_le_bytes());
+    units.iter().for_each(|u| buf.extend_from_slice(&u.to_le_bytes()));
+}
+
+/// Write a DONE-family token and return the position of its status, so
+/// the "more results" bit can be set afterwards.
+fn put_done(buf: &mut Vec<u8>, token: u8, status: u16, rows: u64) -> usize {
+    buf.push(token);
+    let position = buf.len();
+    buf.extend_from_slice(&status.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes());
+    buf.extend_from_slice(&rows.to_le_bytes());
+    position
+}
+
+/// An ERROR or INFO token.
+fn put_message(buf: &mut Vec<u8>, token: u8, code: u32, class: u8, message: &str) {
+    let mut body = Vec::new();
+    body.extend_from_slice(&code.to_le_bytes());
+    body.push(1);
+    body.push(class);
+    put_us_varchar(&mut body, message);
+    put_b_varchar(&mut body, "mock");
+    put_b_varchar(&mut body, "");
+    body.extend_from_slice(&1u32.to_le_bytes());
+
+    buf.push(token);
+    buf.extend_from_slice(&(body.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&body);
+}
+
+fn put_colmetadata(buf: &mut Vec<u8>, columns: &[(String, MockType)]) {
+    buf.push(TOKEN_COLMETADATA);
+    buf.extend_from_slice(&(columns.len() as u16).to_le_bytes());
+
+    for (name, ty) in columns {
+        // User type, then flags: nullable.
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&1u16.to_le_bytes());
+
+        match ty {
+            MockType::Bit => buf.extend_from_slice(&[0x68, 1]),
+            MockType::TinyInt => buf.extend_from_slice(&[0x26, 1]),
+            MockType::SmallInt => buf.extend_from_slice(&[0x26, 2]),
+            MockType::Int => buf.extend_from_slice(&[0x26, 4]),
+            MockType::BigInt => buf.extend_from_slice(&[0x26, 8]),
+            MockType::Float => buf.extend_from_slice(&[0x6D, 8]),
+            MockType::Decimal(precision, scale) => {
+                buf.extend_from_slice(&[0x6A, decimal_len(*precision) + 1, *precision, *scale])
+            }
+            MockType::NVarChar => {
+                buf.push(0xE7);
+                buf.extend_from_slice(&8000u16.to_le_bytes());
+                // Latin1_General_CI_AS
+                buf.extend_from_slice(&[0x09, 0x04, 0xD0, 0x00, 0x34]);
+            }
+            MockType::VarBinary => {
+                buf.push(0xA5);
+                buf.extend_from_slice(&8000u16.to_le_bytes());
+            }
+            MockType::UniqueIdentifier => buf.extend_from_slice(&[0x24, 16]),
+            MockType::Date => buf.push(0x28),
+            MockType::Time => buf.extend_from_slice(&[0x29, 7]),
+            MockType::DateTime2 => buf.extend_from_slice(&[0x2A, 7]),
+            MockType::DateTimeOffset => buf.extend_from_slice(&[0x2B, 7]),
+        }
+
+        put_b_varchar(buf, name);
+    }
+}
+
+/// Bytes of the magnitude of a decimal with the given precision.
+fn decimal_len(precision: u8) -> u8 {
+    match precision {
+        0..=9 => 4,
+        10..=19 => 8,
+        20..=28 => 12,
+        _ => 16,
+    }
+}
+
+fn put_value(buf: &mut Vec<u8>, ty: MockType, value: &Value) -> anyhow::Result<()> {
+    if value.is_null() {
+        match ty {
+            MockType::NVarChar | MockType::VarBinary => buf.extend_from_slice(&[0xFF, 0xFF]),
+            _ => buf.push(0),
+        }
+        return Ok(());
+    }
+
+    match (ty, value) {
+        (MockType::Bit, Value::Bool(v)) => buf.extend_from_slice(&[1, u8::from(*v)]),
+        (MockType::TinyInt, Value::Int(v)) => {
+            buf.extend_from_slice(&[1, u8::try_from(*v)?]);
+        }
+        (MockType::SmallInt, Value::Int(v)) => {
+            buf.push(2);
+            buf.extend_from_slice(&i16::try_from(*v)?.to_le_bytes());
+        }
+        (MockType::Int, Value::Int(v)) => {
+            buf.push(4);
+            buf.extend_from_slice(&i32::try_from(*v)?.to_le_bytes());
+        }
+        (MockType::BigInt, Value::Int(v)) => {
+            buf.push(8);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        (MockType::Float, Value::Float(v)) => {
+            buf.push(8);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        (MockType::Decimal(precision, scale), Value::Decimal(_) | Value::Int(_)) => {
+            let (digits, from_scale) = match value {
+                Value::Decimal(n) => (n.value(), n.scale()),
+                Value::Int(v) => (*v as i128, 0),
+                _ => unreachable!(),
+            };
+            if from_scale > scale {
+                anyhow::bail!("{} has more than {} decimal places", value, scale);
+            }
+            let digits = digits * 10i128.pow((scale - from_scale) as u32);
+            let len = decimal_len(precision) as usize;
+
+            buf.push(len as u8 + 1);
+            buf.push(u8::from(digits >= 0));
+            buf.extend_from_slice(&digits.unsigned_abs().to_le_bytes()[..len]);
+        }
+        (MockType::NVarChar, Value::String(v)) => {
+            let units: Vec<u16> = v.encode_utf16().collect();
+            buf.extend_from_slice(&u16::try_from(units.len() * 2)?.to_le_bytes());
+            units.iter().for_each(|u| buf.extend_from_slice(&u.to_le_bytes()));
+        }
+        (MockType::VarBinary, Value::Binary(v)) => {
+            buf.extend_from_slice(&u16::try_from(v.len())?.to_le_bytes());
+            buf.extend_from_slice(v);
+        }
+        (MockType::UniqueIdentifier, Value::Guid(v)) => {
+            let mut bytes = *v.as_bytes();
+            reorder_guid(&mut bytes);
+            buf.push(16);
+            buf.extend_from_slice(&bytes);
+        }
+        (MockType::Date, Value::Date(v)) => {
+            buf.push(3);
+            put_date(buf, *v);
+        }
+        (MockType::Time, Value::Time(v)) => {
+            buf.push(5);
+            put_time(buf, *v);
+        }
+        (MockType::DateTime2, Value::DateTime(v)) => {
+            buf.push(8);
+            put_datetime(buf, *v);
+        }
+        (MockType::DateTimeOffset, Value::DateTimeOffset(v)) => {
+            buf.push(10);
+            put_datetime(buf, v.naive_utc());
+            let minutes = (v.offset().local_minus_utc() / 60) as i16;
+            buf.extend_from_slice(&minutes.to_le_bytes());
+        }
+        (ty, value) => anyhow::bail!("{:?} cannot hold {:?}", ty, value),
+    }
+
+    Ok(())
+}
+
+fn put_date(buf: &mut Vec<u8>, date: NaiveDate) {
+    let days = (date - epoch()).num_days() as u32;
+    buf.extend_from_slice(&days.to_le_bytes()[..3]);
+}
+
+/// Time of day in 100ns units (scale 7), in five bytes.
+fn put_time(buf: &mut Vec<u8>, time: NaiveTime) {
+    let ticks = time.num_seconds_from_midnight() as u64 * 10_000_000 + time.nanosecond() as u64 / 100;
+    buf.extend_from_slice(&ticks.to_le_bytes()[..5]);
+}
+
+fn put_datetime(buf: &mut Vec<u8>, datetime: NaiveDateTime) {
+    put_time(buf, datetime.time());
+    put_date(buf, datetime.date());
+}
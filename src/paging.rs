@@ -0,0 +1,346 @@
+//! Read very large tables page by page, walking the table's unique key
+//! (keyset pagination) instead of holding one long-running `select *` open.
+//!
+//! Each page is a short `SELECT TOP (n) ... WHERE key > @last ORDER BY key`,
+//! so locks are released between pages and a read can be resumed from a
+//! [`Checkpoint`] after a failure.
+
+use std::fmt;
+use std::str::FromStr;
+
+use futures_util::stream::{self, Stream, TryStreamExt};
+use tiberius::numeric::Numeric;
+
+use crate::backend::Backend;
+use crate::ident::{quote_ident, quote_table};
+use crate::schema::TableColumn;
+use crate::value::{Record, Value};
+
+/// Walks a table in key order, one page at a time.
+#[derive(Debug, Clone)]
+pub struct KeysetReader {
+    table: String,
+    key: Vec<String>,
+    /// The declared type of each key column, where known.
+    key_types: Vec<Option<String>>,
+    columns: Option<Vec<String>>,
+    filter: Option<String>,
+    page_size: usize,
+    start: Option<Checkpoint>,
+}
+
+impl KeysetReader {
+    /// Prepare a reader for `table`, looking up its primary or unique key
+    /// and the key's types.
+    pub async fn new(db: &mut dyn Backend, table: &str) -> anyhow::Result<Self> {
+        let key = db.keyset_columns(table).await?;
+        let mut reader = Self::with_key(table, key);
+        reader.key_types(&db.columns(table).await?);
+        Ok(reader)
+    }
+
+    /// Prepare a reader that walks the given key columns. They must be
+    /// unique and not nullable, or rows will be skipped.
+    pub fn with_key(table: &str, key: Vec<String>) -> Self {
+        KeysetReader {
+            table: table.to_string(),
+            key,
+            key_types: Vec::new(),
+            columns: None,
+            filter: None,
+            page_size: 10_000,
+            start: None,
+        }
+    }
+
+    /// Take the declared types of the key columns from `columns`, the
+    /// table's, so a checkpoint's strings are compared with `varchar` keys
+    /// as `varchar`: as `nvarchar` parameters they would convert the
+    /// column and scan the index.
+    pub fn key_types(&mut self, columns: &[TableColumn]) {
+        self.key_types = self
+            .key
+            .iter()
+            .map(|k| {
+                columns
+                    .iter()
+                    .find(|c| c.name.eq_ignore_ascii_case(k))
+                    .map(|c| c.type_name.to_lowercase())
+            })
+            .collect();
+    }
+
+    /// Number of rows fetched per query. Defaults to 10 000.
+    pub fn page_size(&mut self, page_size: usize) {
+        self.page_size = page_size.max(1);
+    }
+
+    /// Only read these columns. The key columns are always read.
+    pub fn columns(&mut self, columns: Vec<String>) {
+        self.columns = Some(columns);
+    }
+
+    /// Only read rows matching this predicate, e.g. one key range of a
+    /// parallel export.
+    pub fn filter(&mut self, predicate: String) {
+        self.filter = Some(predicate);
+    }
+
+    /// Continue after the row the checkpoint was taken from.
+    pub fn resume_from(&mut self, checkpoint: Checkpoint) {
+        self.start = Some(checkpoint);
+    }
+
+    pub fn key(&self) -> &[String] {
+        &self.key
+    }
+
+    /// The checkpoint of a row returned by this reader. Resuming from it
+    /// continues with the row after it.
+    pub fn checkpoint(&self, record: &Record) -> anyhow::Result<Checkpoint> {
+        let values = self
+            .key
+            .iter()
+            .map(|k| {
+                record
+                    .get(k)
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("Row has no key column {}", k))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Checkpoint(values))
+    }
+
+    /// The query for the page after `after`, or the first page.
+    pub fn page_sql(&self, after: Option<&Checkpoint>) -> String {
+        self.page_sql_for("sqlserver", after)
+    }
+
+    /// [`page_sql`](Self::page_sql) for the backend named `backend`, see
+    /// [`Backend::name`]: SQLite limits the page with `LIMIT` instead of
+    /// `TOP`.
+    pub fn page_sql_for(&self, backend: &str, after: Option<&Checkpoint>) -> String {
+        let select = match &self.columns {
+            Some(columns) => {
+                let mut names: Vec<&String> = self.key.iter().collect();
+                names.extend(columns.iter().filter(|c| !self.key.contains(c)));
+                names
+                    .iter()
+                    .map(|c| quote_ident(c))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+            None => "*".to_string(),
+        };
+
+        let order = self
+            .key
+            .iter()
+            .map(|k| quote_ident(k))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let filter = match (&self.filter, after) {
+            (Some(filter), Some(_)) => format!(" WHERE ({}) AND ({})", filter, self.after_predicate(backend)),
+            (Some(filter), None) => format!(" WHERE {}", filter),
+            (None, Some(_)) => format!(" WHERE {}", self.after_predicate(backend)),
+            (None, None) => String::new(),
+        };
+
+        match backend {
+            "sqlite" => format!(
+                "SELECT {} FROM {}{} ORDER BY {} LIMIT {}",
+                select,
+                quote_table(&self.table),
+                filter,
+                order,
+                self.page_size
+            ),
+            _ => format!(
+                "SELECT TOP ({}) {} FROM {}{} ORDER BY {}",
+                self.page_size,
+                select,
+                quote_table(&self.table),
+                filter,
+                order
+            ),
+        }
+    }
+
+    /// `(a > @P1) OR (a = @P1 AND b > @P2) OR ...` for the key `(a, b, ...)`.
+    fn after_predicate(&self, backend: &str) -> String {
+        (0..self.key.len())
+            .map(|i| {
+                let mut terms: Vec<String> = (0..i)
+                    .map(|j| format!("{} = {}", quote_ident(&self.key[j]), self.param(j, backend)))
+                    .collect();
+                terms.push(format!("{} > {}", quote_ident(&self.key[i]), self.param(i, backend)));
+                format!("({})", terms.join(" AND "))
+            })
+            .collect::<Vec<_>>()
+            .join(" OR ")
+    }
+
+    /// The parameter holding the checkpoint's value of key column `i`.
+    /// Strings are bound as `nvarchar`, so they are cast for `char` and
+    /// `varchar` keys on SQL Server.
+    fn param(&self, i: usize, backend: &str) -> String {
+        let declared = self.key_types.get(i).and_then(Option::as_deref);
+        match (backend, declared) {
+            ("sqlserver", Some("char" | "varchar")) => format!("CAST(@P{} AS varchar(8000))", i + 1),
+            _ => format!("@P{}", i + 1),
+        }
+    }
+
+    async fn fetch_page(&self, db: &mut dyn Backend, after: Option<&Checkpoint>) -> anyhow::Result<Vec<Record>> {
+        let sql = self.page_sql_for(db.name(), after);
+        let params = after.map(|checkpoint| checkpoint.0.as_slice()).unwrap_or_default();
+        db.query(&sql, params).await
+    }
+
+    /// Stream every row after the starting checkpoint in key order.
+    pub fn into_stream(self, db: &mut dyn Backend) -> impl Stream<Item = anyhow::Result<Record>> + '_ {
+        let after = self.start.clone();
+        let state = (self, db, after, false);
+
+        stream::try_unfold(state, |(reader, db, after, done)| async move {
+            if done {
+                return anyhow::Ok(None);
+            }
+
+            let page = reader.fetch_page(db, after.as_ref()).await?;
+            let done = page.len() < reader.page_size;
+            let after = match page.last() {
+                Some(last) => Some(reader.checkpoint(last)?),
+                None => after,
+            };
+
+            Ok(Some((stream::iter(page.into_iter().map(anyhow::Ok)), (reader, db, after, done))))
+        })
+        .try_flatten()
+    }
+}
+
+/// The key of the last row read, as an opaque string token that can be
+/// stored and parsed back with [`str::parse`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint(Vec<Value>);
+
+impl Checkpoint {
+    pub fn values(&self) -> &[Value] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items = self
+            .0
+            .iter()
+            .map(|v| {
+                let (tag, payload) = match v {
+                    Value::Null => ("n", String::new()),
+                    Value::Bool(b) => ("b", u8::from(*b).to_string()),
+                    Value::Int(i) => ("i", i.to_string()),
+                    Value::Float(x) => ("f", x.to_string()),
+                    Value::Decimal(d) => ("d", d.to_string()),
+                    Value::String(s) => ("s", s.clone()),
+                    Value::Binary(_) => ("x", v.to_string()),
+                    Value::Guid(g) => ("g", g.to_string()),
+                    Value::Date(d) => ("D", d.to_string()),
+                    Value::Time(t) => ("T", t.to_string()),
+                    Value::DateTime(dt) => ("t", dt.format("%Y-%m-%dT%H:%M:%S%.f").to_string()),
+                    Value::DateTimeOffset(dt) => ("o", dt.to_rfc3339()),
+                };
+                format!("{}{}", tag, escape(&payload))
+            })
+            .collect::<Vec<_>>();
+
+        write!(f, "v1,{}", items.join(","))
+    }
+}
+
+impl FromStr for Checkpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(token: &str) -> anyhow::Result<Self> {
+        let rest = token
+            .strip_prefix("v1,")
+            .ok_or_else(|| anyhow::anyhow!("Unrecognised checkpoint token {:?}", token))?;
+
+        let values = rest
+            .split(',')
+            .map(|item| {
+                let mut chars = item.chars();
+                let tag = chars.next().ok_or_else(|| anyhow::anyhow!("Empty checkpoint item"))?;
+                let payload = unescape(chars.as_str())?;
+
+                let value = match tag {
+                    'n' => Value::Null,
+                    'b' => Value::Bool(payload == "1"),
+                    'i' => Value::Int(payload.parse()?),
+                    'f' => Value::Float(payload.parse()?),
+                    'd' => Value::Decimal(parse_numeric(&payload)?),
+                    's' => Value::String(payload),
+                    'x' => Value::Binary(parse_hex(&payload)?),
+                    'g' => Value::Guid(payload.parse()?),
+                    'D' => Value::Date(payload.parse()?),
+                    'T' => Value::Time(payload.parse()?),
+                    't' => Value::DateTime(payload.parse()?),
+                    'o' => Value::DateTimeOffset(chrono::DateTime::parse_from_rfc3339(&payload)?),
+                    other => anyhow::bail!("Unknown checkpoint value type {:?}", other),
+                };
+                Ok(value)
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Checkpoint(values))
+    }
+}
+
+/// Percent-escape the separator so any string can be part of a token.
+fn escape(value: &str) -> String {
+    value.replace('%', "%25").replace(',', "%2C")
+}
+
+fn unescape(value: &str) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(i) = rest.find('%') {
+        out.push_str(&rest[..i]);
+        match rest.get(i + 1..i + 3) {
+            Some("25") => out.push('%'),
+            Some("2C") => out.push(','),
+            _ => anyhow::bail!("Invalid escape in checkpoint token {:?}", value),
+        }
+        rest = &rest[i + 3..];
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+pub(crate) fn parse_hex(value: &str) -> anyhow::Result<Vec<u8>> {
+    let digits = value.trim_start_matches("0x");
+    if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        anyhow::bail!("Not a hex string: {:?}", value);
+    }
+    if !digits.len().is_multiple_of(2) {
+        anyhow::bail!("Odd number of hex digits in {:?}", value);
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&digits[i..i + 2], 16)?))
+        .collect()
+}
+
+fn parse_numeric(value: &str) -> anyhow::Result<Numeric> {
+    let (int_part, frac_part) = value.split_once('.').unwrap_or((value, ""));
+    let digits: i128 = format!("{}{}", int_part.trim_start_matches('-'), frac_part).parse()?;
+    let digits = if value.starts_with('-') { -digits } else { digits };
+
+    Ok(Numeric::new_with_scale(digits, frac_part.len() as u8))
+}
@@ -0,0 +1,471 @@
+//! Execution plans: capturing the showplan XML SQL Server produces for a
+//! batch, reading it into a tree of operators, and rendering that tree for
+//! the terminal or as JSON.
+//!
+//! The estimated plan (`SET SHOWPLAN_XML ON`) compiles the batch without
+//! running it. The actual plan (`SET STATISTICS XML ON`) runs it, so it
+//! adds the rows each operator really produced and the time taken.
+
+use std::fmt::Write;
+
+use serde_json::{json, Value as Json};
+
+use crate::backend::Backend;
+use crate::value::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Compile only.
+    Estimated,
+    /// Run the batch and collect run-time counters.
+    Actual,
+}
+
+impl Mode {
+    fn option(self) -> &'static str {
+        match self {
+            Mode::Estimated => "SHOWPLAN_XML",
+            Mode::Actual => "STATISTICS XML",
+        }
+    }
+}
+
+/// The plans of the statements of a batch.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Plan {
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Statement {
+    pub text: String,
+    /// `SELECT`, `UPDATE`, `ASSIGN`, ...
+    pub kind: String,
+    /// The optimizer's estimate, in its own units.
+    pub cost: f64,
+    pub estimated_rows: f64,
+    /// From actual plans only.
+    pub elapsed_ms: Option<u64>,
+    pub cpu_ms: Option<u64>,
+    pub warnings: Vec<String>,
+    pub missing_indexes: Vec<MissingIndex>,
+    /// `None` for statements without a plan of their own, such as `IF`.
+    pub root: Option<Operator>,
+}
+
+/// An operator of the plan tree.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Operator {
+    pub node_id: u32,
+    /// What runs, e.g. `Clustered Index Scan`.
+    pub physical_op: String,
+    /// What it does, e.g. `Inner Join`.
+    pub logical_op: String,
+    /// The table or index read or written, as `[schema].[table].[index]`.
+    pub object: Option<String>,
+    /// Per execution.
+    pub estimated_rows: f64,
+    /// From actual plans only: the rows of all executions.
+    pub actual_rows: Option<u64>,
+    pub actual_executions: Option<u64>,
+    /// The cost of this operator and everything under it.
+    pub subtree_cost: f64,
+    /// The cost of this operator alone.
+    pub cost: f64,
+    /// `cost` as a share of the statement's.
+    pub cost_percent: f64,
+    pub warnings: Vec<String>,
+    pub children: Vec<Operator>,
+}
+
+/// An index the optimizer would have used had it existed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MissingIndex {
+    /// The estimated improvement in percent of the statement's cost.
+    pub impact: f64,
+    /// `[database].[schema].[table]`.
+    pub table: String,
+    pub equality: Vec<String>,
+    pub inequality: Vec<String>,
+    pub include: Vec<String>,
+}
+
+impl MissingIndex {
+    pub fn to_json(&self) -> Json {
+        json!({
+            "impact": self.impact,
+            "table": self.table,
+            "equality": self.equality,
+            "inequality": self.inequality,
+            "include": self.include,
+            "sql": self.to_sql(),
+        })
+    }
+
+    /// A `CREATE INDEX` statement for it, keyed on the equality columns
+    /// then the inequality ones.
+    pub fn to_sql(&self) -> String {
+        let keys: Vec<&str> = self.equality.iter().chain(&self.inequality).map(String::as_str).collect();
+        let table = self.table.rsplit('.').next().unwrap_or_default();
+        let name: Vec<&str> = std::iter::once(table)
+            .chain(keys.iter().copied())
+            .map(|part| part.trim_matches(['[', ']']))
+            .collect();
+        let mut sql = format!("CREATE INDEX [IX_{}] ON {} ({})", name.join("_"), self.table, keys.join(", "));
+        if !self.include.is_empty() {
+            write!(sql, " INCLUDE ({})", self.include.join(", ")).unwrap();
+        }
+        sql
+    }
+}
+
+/// Capture the plan of `sql`. The option is turned off again afterwards,
+/// also when the batch fails, so the connection can be used as before.
+pub async fn capture(db: &mut dyn Backend, sql: &str, mode: Mode) -> anyhow::Result<Plan> {
+    db.batch(&format!("SET {} ON", mode.option())).await?;
+    let result = db.batch(sql).await;
+    let reset = db.batch(&format!("SET {} OFF", mode.option())).await;
+
+    // The batch's own error matters more than the one turning the option off.
+    let result = match (result, reset) {
+        (Ok(result), reset) => reset.map(|_| result)?,
+        (Err(e), Ok(_)) => return Err(e),
+        (Err(e), Err(reset)) => {
+            return Err(e.context(format!("Turning {} off again failed too: {:#}", mode.option(), reset)))
+        }
+    };
+
+    let mut plan = Plan::default();
+    for set in result.result_sets() {
+        if !set.columns.iter().any(|c| c.contains("Showplan")) {
+            continue;
+        }
+        for row in &set.rows {
+            if let Some(Value::String(xml)) = row.values().first() {
+                plan.statements.extend(parse(xml)?.statements);
+            }
+        }
+    }
+    if plan.statements.is_empty() {
+        anyhow::bail!("The server returned no plan for this batch");
+    }
+    Ok(plan)
+}
+
+/// Read a showplan XML document, as captured or saved in a `.sqlplan` file.
+pub fn parse(xml: &str) -> anyhow::Result<Plan> {
+    let document = roxmltree::Document::parse(xml).map_err(|e| anyhow::anyhow!("Not a showplan: {}", e))?;
+    let root = document.root_element();
+    if root.tag_name().name() != "ShowPlanXML" {
+        anyhow::bail!("Not a showplan: the document is a <{}>", root.tag_name().name());
+    }
+
+    let statements = root
+        .descendants()
+        .filter(|n| n.tag_name().name().starts_with("Stmt") && n.has_attribute("StatementText"))
+        .map(statement)
+        .collect();
+    Ok(Plan { statements })
+}
+
+type Node<'a, 'input> = roxmltree::Node<'a, 'input>;
+
+fn statement(node: Node<'_, '_>) -> Statement {
+    let query_plan = child(node, "QueryPlan");
+    let cost = number(node, "StatementSubTreeCost");
+    let times = query_plan.and_then(|p| child(p, "QueryTimeStats"));
+
+    let mut warnings = query_plan.map(warnings).unwrap_or_default();
+    match node.attribute("StatementOptmEarlyAbortReason") {
+        Some("TimeOut") => warnings.push("The optimizer timed out before finding a good plan".to_string()),
+        Some("MemoryLimitExceeded") => warnings.push("The optimizer ran out of memory".to_string()),
+        _ => {}
+    }
+
+    let missing_indexes = query_plan
+        .and_then(|p| child(p, "MissingIndexes"))
+        .map(|m| elements(m, "MissingIndexGroup").flat_map(missing_indexes).collect())
+        .unwrap_or_default();
+
+    Statement {
+        text: node.attribute("StatementText").unwrap_or_default().trim().to_string(),
+        kind: node.attribute("StatementType").unwrap_or_default().to_string(),
+        cost,
+        estimated_rows: number(node, "StatementEstRows"),
+        elapsed_ms: times.and_then(|t| t.attribute("ElapsedTime")?.parse().ok()),
+        cpu_ms: times.and_then(|t| t.attribute("CpuTime")?.parse().ok()),
+        warnings,
+        missing_indexes,
+        root: query_plan.and_then(|p| child(p, "RelOp")).map(|r| operator(r, cost)),
+    }
+}
+
+fn operator(node: Node<'_, '_>, statement_cost: f64) -> Operator {
+    let children: Vec<Operator> = own(node, "RelOp").into_iter().map(|r| operator(r, statement_cost)).collect();
+    let subtree_cost = number(node, "EstimatedTotalSubtreeCost");
+    let cost = (subtree_cost - children.iter().map(|c| c.subtree_cost).sum::<f64>()).max(0.0);
+
+    let counters: Vec<Node<'_, '_>> = child(node, "RunTimeInformation")
+        .map(|r| elements(r, "RunTimeCountersPerThread").collect())
+        .unwrap_or_default();
+    let total = |name: &str| {
+        (!counters.is_empty()).then(|| counters.iter().filter_map(|c| c.attribute(name)?.parse::<u64>().ok()).sum())
+    };
+
+    let object = own(node, "Object").first().map(|o| {
+        ["Schema", "Table", "Index"]
+            .iter()
+            .filter_map(|a| o.attribute(*a))
+            .collect::<Vec<_>>()
+            .join(".")
+    });
+
+    Operator {
+        node_id: node.attribute("NodeId").and_then(|n| n.parse().ok()).unwrap_or_default(),
+        physical_op: node.attribute("PhysicalOp").unwrap_or_default().to_string(),
+        logical_op: node.attribute("LogicalOp").unwrap_or_default().to_string(),
+        object: object.filter(|o| !o.is_empty()),
+        estimated_rows: number(node, "EstimateRows"),
+        actual_rows: total("ActualRows"),
+        actual_executions: total("ActualExecutions"),
+        subtree_cost,
+        cost,
+        cost_percent: if statement_cost > 0.0 { 100.0 * cost / statement_cost } else { 0.0 },
+        warnings: warnings(node),
+        children,
+    }
+}
+
+fn missing_indexes(group: Node<'_, '_>) -> Vec<MissingIndex> {
+    let impact = number(group, "Impact");
+    elements(group, "MissingIndex")
+        .map(|index| {
+            let columns = |usage: &str| -> Vec<String> {
+                elements(index, "ColumnGroup")
+                    .filter(|g| g.attribute("Usage") == Some(usage))
+                    .flat_map(|g| elements(g, "Column").filter_map(|c| c.attribute("Name").map(str::to_string)))
+                    .collect()
+            };
+            MissingIndex {
+                impact,
+                table: ["Database", "Schema", "Table"]
+                    .iter()
+                    .filter_map(|a| index.attribute(*a))
+                    .collect::<Vec<_>>()
+                    .join("."),
+                equality: columns("EQUALITY"),
+                inequality: columns("INEQUALITY"),
+                include: columns("INCLUDE"),
+            }
+        })
+        .collect()
+}
+
+/// The `<Warnings>` of an operator or a query plan, in words.
+fn warnings(node: Node<'_, '_>) -> Vec<String> {
+    let Some(warnings) = child(node, "Warnings") else {
+        return Vec::new();
+    };
+    let mut found: Vec<String> = warnings
+        .attributes()
+        .filter(|a| matches!(a.value(), "true" | "1"))
+        .map(|a| words(a.name()))
+        .collect();
+
+    for warning in warnings.children().filter(Node::is_element) {
+        let attribute = |name: &str| warning.attribute(name).unwrap_or("?");
+        found.push(match warning.tag_name().name() {
+            "ColumnsWithNoStatistics" => {
+                let columns: Vec<String> = elements(warning, "ColumnReference")
+                    .map(|c| {
+                        ["Table", "Column"]
+                            .iter()
+                            .filter_map(|a| c.attribute(*a))
+                            .collect::<Vec<_>>()
+                            .join(".")
+                    })
+                    .collect();
+                format!("No statistics on {}", columns.join(", "))
+            }
+            "SpillToTempDb" => format!("Spilled to tempdb at level {}", attribute("SpillLevel")),
+            "PlanAffectingConvert" => format!(
+                "Type conversion in {} may affect the {}",
+                attribute("Expression"),
+                attribute("ConvertIssue").to_lowercase()
+            ),
+            "Wait" => format!("Waited {} ms for {}", attribute("WaitTime"), attribute("WaitType")),
+            "MemoryGrantWarning" => format!(
+                "{}: requested {} KB, granted {} KB, used {} KB",
+                words(attribute("GrantWarningKind")),
+                attribute("RequestedMemory"),
+                attribute("GrantedMemory"),
+                attribute("MaxUsedMemory")
+            ),
+            other => words(other),
+        });
+    }
+    found
+}
+
+/// `NoJoinPredicate` as `No join predicate`.
+fn words(name: &str) -> String {
+    let mut text = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if i > 0 && c.is_uppercase() {
+            text.push(' ');
+            text.extend(c.to_lowercase());
+        } else {
+            text.push(c);
+        }
+    }
+    text
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|c| c.tag_name().name() == name)
+}
+
+fn elements<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |c| c.tag_name().name() == name)
+}
+
+/// The `name` elements under `node` that belong to it rather than to an
+/// operator nested in it.
+fn own<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Vec<Node<'a, 'input>> {
+    let mut found = Vec::new();
+    for c in node.children().filter(Node::is_element) {
+        if c.tag_name().name() == name {
+            found.push(c);
+        } else if c.tag_name().name() != "RelOp" {
+            found.extend(own(c, name));
+        }
+    }
+    found
+}
+
+fn number(node: Node<'_, '_>, attribute: &str) -> f64 {
+    node.attribute(attribute).and_then(|v| v.parse().ok()).unwrap_or_default()
+}
+
+impl Plan {
+    pub fn to_json(&self) -> Json {
+        json!({ "statements": self.statements.iter().map(Statement::to_json).collect::<Vec<_>>() })
+    }
+
+    /// The statements with their operators as an indented tree.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (n, statement) in self.statements.iter().enumerate() {
+            if n > 0 {
+                out.push('\n');
+            }
+            statement.render(n + 1, &mut out);
+        }
+        out
+    }
+}
+
+const MAX_TEXT: usize = 100;
+
+impl Statement {
+    pub fn to_json(&self) -> Json {
+        json!({
+            "text": self.text,
+            "type": self.kind,
+            "cost": self.cost,
+            "estimated_rows": self.estimated_rows,
+            "elapsed_ms": self.elapsed_ms,
+            "cpu_ms": self.cpu_ms,
+            "warnings": self.warnings,
+            "missing_indexes": self.missing_indexes.iter().map(MissingIndex::to_json).collect::<Vec<_>>(),
+            "plan": self.root.as_ref().map(Operator::to_json),
+        })
+    }
+
+    fn render(&self, number: usize, out: &mut String) {
+        let text = self.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let text = match text.char_indices().nth(MAX_TEXT) {
+            Some((cut, _)) => format!("{}...", &text[..cut]),
+            None => text,
+        };
+        write!(out, "Statement {}", number).unwrap();
+        if !self.kind.is_empty() {
+            write!(out, " ({})", self.kind).unwrap();
+        }
+        write!(out, ": cost {:.4}, {} rows estimated", self.cost, rows(self.estimated_rows)).unwrap();
+        if let (Some(elapsed), Some(cpu)) = (self.elapsed_ms, self.cpu_ms) {
+            write!(out, ", {} ms elapsed, {} ms CPU", elapsed, cpu).unwrap();
+        }
+        writeln!(out, "\n  {}", text).unwrap();
+        for warning in &self.warnings {
+            writeln!(out, "  ! {}", warning).unwrap();
+        }
+        for index in &self.missing_indexes {
+            writeln!(out, "  Missing index ({:.1}% less cost): {}", index.impact, index.to_sql()).unwrap();
+        }
+        if let Some(root) = &self.root {
+            root.render("", "", out);
+        }
+    }
+}
+
+impl Operator {
+    pub fn to_json(&self) -> Json {
+        json!({
+            "node_id": self.node_id,
+            "physical_op": self.physical_op,
+            "logical_op": self.logical_op,
+            "object": self.object,
+            "estimated_rows": self.estimated_rows,
+            "actual_rows": self.actual_rows,
+            "actual_executions": self.actual_executions,
+            "subtree_cost": self.subtree_cost,
+            "cost": self.cost,
+            "cost_percent": self.cost_percent,
+            "warnings": self.warnings,
+            "children": self.children.iter().map(Operator::to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    /// This operator's line after `first`, and its warnings and children
+    /// each after `rest`.
+    fn render(&self, first: &str, rest: &str, out: &mut String) {
+        write!(out, "{}{}", first, self.physical_op).unwrap();
+        if !self.logical_op.is_empty() && self.logical_op != self.physical_op {
+            write!(out, " ({})", self.logical_op).unwrap();
+        }
+        if let Some(object) = &self.object {
+            write!(out, " {}", object).unwrap();
+        }
+        write!(out, "  {:.0}%  rows {}", self.cost_percent, rows(self.estimated_rows)).unwrap();
+        if let Some(actual) = self.actual_rows {
+            write!(out, " est, {} actual", actual).unwrap();
+            if let Some(executions) = self.actual_executions.filter(|&e| e != 1) {
+                write!(out, " in {} executions", executions).unwrap();
+            }
+        }
+        out.push('\n');
+
+        let bar = if self.children.is_empty() { "   " } else { "│  " };
+        for warning in &self.warnings {
+            writeln!(out, "{}{}! {}", rest, bar, warning).unwrap();
+        }
+        for (i, child) in self.children.iter().enumerate() {
+            let (first, next) = if i + 1 == self.children.len() {
+                ("└─ ", "   ")
+            } else {
+                ("├─ ", "│  ")
+            };
+            child.render(&format!("{}{}", rest, first), &format!("{}{}", rest, next), out);
+        }
+    }
+}
+
+/// An estimate of rows, whole when it is.
+fn rows(estimate: f64) -> String {
+    if estimate.fract() == 0.0 {
+        format!("{:.0}", estimate)
+    } else {
+        format!("{:.1}", estimate)
+    }
+}
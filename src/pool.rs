@@ -0,0 +1,167 @@
+//! A small connection pool, so concurrent work can reuse logged-in
+//! connections instead of paying for a new login each time.
+//!
+//! Connections are [`Backend`]s, so a pool can hold SQL Server or SQLite
+//! connections alike.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use tiberius::Config;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::backend::{self, Backend, TiberiusBackend};
+use crate::profile::Profile;
+use crate::retry::{Idempotency, RetryPolicy};
+
+/// What the pool opens connections to.
+#[derive(Clone)]
+enum Target {
+    Config(Config),
+    /// A profile, with its configuration made once.
+    Profile(Box<Profile>, Config),
+    /// A [`backend::open`] target.
+    Open(String),
+}
+
+/// Hands out at most `max_size` connections at a time.
+#[derive(Clone)]
+pub struct Pool {
+    target: Target,
+    retry: RetryPolicy,
+    max_size: usize,
+    idle: Arc<Mutex<Vec<Box<dyn Backend>>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl Pool {
+    /// SQL Server connections from `config`.
+    pub fn new(config: Config, max_size: usize) -> Self {
+        Self::with_target(Target::Config(config), max_size)
+    }
+
+    /// SQL Server connections with a profile: its TLS checks, statement
+    /// timeout and read-only setting. Its password is fetched now, once.
+    pub fn with_profile(profile: Profile, max_size: usize) -> anyhow::Result<Self> {
+        let config = profile.config()?;
+        Ok(Self::with_target(Target::Profile(Box::new(profile), config), max_size))
+    }
+
+    /// Connections to a [`backend::open`] target, e.g. `sqlite:dev.db`.
+    /// Every connection to `sqlite::memory:` is a database of its own.
+    pub fn open(target: &str, max_size: usize) -> Self {
+        Self::with_target(Target::Open(target.to_string()), max_size)
+    }
+
+    fn with_target(target: Target, max_size: usize) -> Self {
+        let max_size = max_size.max(1);
+
+        Pool {
+            target,
+            retry: RetryPolicy::default(),
+            max_size,
+            idle: Arc::new(Mutex::new(Vec::new())),
+            permits: Arc::new(Semaphore::new(max_size)),
+        }
+    }
+
+    /// The policy used when opening new connections.
+    pub fn retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Wait for a free slot and return an idle connection, or open a new
+    /// one.
+    pub async fn get(&self) -> anyhow::Result<PooledBackend> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let idle = self.idle.lock().unwrap().pop();
+
+        let backend = match idle {
+            Some(backend) => backend,
+            None => self.connect().await?,
+        };
+
+        Ok(PooledBackend {
+            backend: Some(backend),
+            idle: self.idle.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// Open a connection, retrying refused connections, drops and
+    /// throttling.
+    async fn connect(&self) -> anyhow::Result<Box<dyn Backend>> {
+        let backend: Box<dyn Backend> = match &self.target {
+            Target::Config(config) => Box::new(
+                self.retry
+                    .run(Idempotency::Idempotent, || TiberiusBackend::connect(config.clone()))
+                    .await?,
+            ),
+            Target::Profile(profile, config) => Box::new(
+                self.retry
+                    .run(Idempotency::Idempotent, || {
+                        TiberiusBackend::connect_profile_with(profile, config.clone())
+                    })
+                    .await?,
+            ),
+            Target::Open(target) => {
+                self.retry
+                    .run(Idempotency::Idempotent, || backend::open(target))
+                    .await?
+            }
+        };
+        Ok(backend)
+    }
+
+    /// Close every idle connection.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        let idle = std::mem::take(&mut *self.idle.lock().unwrap());
+        for backend in idle {
+            backend.close().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A connection borrowed from a [`Pool`]. It goes back to the pool when
+/// dropped, unless it is [discarded](PooledBackend::discard).
+pub struct PooledBackend {
+    backend: Option<Box<dyn Backend>>,
+    idle: Arc<Mutex<Vec<Box<dyn Backend>>>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledBackend {
+    /// Drop the connection instead of returning it, e.g. after an I/O
+    /// error left it in an unknown state.
+    pub fn discard(mut self) {
+        self.backend.take();
+    }
+}
+
+impl Deref for PooledBackend {
+    type Target = dyn Backend;
+
+    fn deref(&self) -> &Self::Target {
+        self.backend.as_deref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBackend {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.backend.as_deref_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBackend {
+    fn drop(&mut self) {
+        if let Some(backend) = self.backend.take() {
+            self.idle.lock().unwrap().push(backend);
+        }
+    }
+}
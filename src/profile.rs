@@ -0,0 +1,313 @@
+//! Named connection profiles, kept in a small INI-style file so a server
+//! can be picked by name instead of pasting a connection string:
+//!
+//! ```text
+//! [dev]
+//! server = JASON\SQLEXPRESS,61521
+//! database = AdventureWorks2016_EXT
+//! trust_server_certificate = true
+//!
+//! [ci]
+//! server = 10.0.4.12,1433
+//! user = ci_reader
+//! password = env:CI_DB_PASSWORD
+//! encrypt = strict
+//! ca_bundle = /etc/ssl/corp-root.pem
+//! tls_hostname = build-db.corp.example
+//! fingerprint = 3F:2A:...:9C
+//! query_timeout = 30
+//! read_only = true
+//! ```
+//!
+//! Passwords are never stored here; `password` names where to fetch one
+//! (see [`PasswordSource`]) and defaults to a prompt. The TLS keys are those
+//! of [`TlsSettings`]. `query_timeout` cancels statements running longer
+//! than that many seconds. `read_only` connects with
+//! `ApplicationIntent=ReadOnly`, so an availability group routes to a
+//! readable secondary, and refuses statements that change anything (see
+//! [`crate::guard::check_read_only`]).
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tiberius::Config;
+
+use crate::auth::{PasswordSource, SqlLogin};
+use crate::diagnose::ServerAddress;
+use crate::tls::{self, TlsMode, TlsSettings};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    /// As in a connection string: `host[\instance][,port]`.
+    pub server: String,
+    pub database: Option<String>,
+    /// A SQL login; Windows authentication when `None`.
+    pub user: Option<String>,
+    /// Where the password of `user` comes from; a prompt when `None`.
+    pub password: Option<PasswordSource>,
+    pub tls: TlsSettings,
+    /// The default statement timeout of connections made with
+    /// [`crate::backend::TiberiusBackend::connect_profile`].
+    pub query_timeout: Option<Duration>,
+    pub read_only: bool,
+}
+
+impl Profile {
+    pub fn new(name: &str, server: &str) -> Self {
+        Profile {
+            name: name.to_string(),
+            server: server.to_string(),
+            database: None,
+            user: None,
+            password: None,
+            tls: TlsSettings::default(),
+            query_timeout: None,
+            read_only: false,
+        }
+    }
+
+    /// The ADO.NET connection string for this profile, without a password.
+    pub fn to_ado_string(&self) -> String {
+        let mut parts = vec![format!("server=tcp:{}", self.server)];
+        if let Some(database) = &self.database {
+            parts.push(format!("database={}", database));
+        }
+        match &self.user {
+            Some(user) => parts.push(format!("user id={}", user)),
+            None => parts.push("IntegratedSecurity=true".to_string()),
+        }
+        if self.tls.trust_server_certificate {
+            parts.push("TrustServerCertificate=true".to_string());
+        }
+        if self.read_only {
+            parts.push("ApplicationIntent=ReadOnly".to_string());
+        }
+        parts.join(";")
+    }
+
+    /// The configuration to connect with. For a SQL login the password is
+    /// fetched now, from [`Profile::password`].
+    pub fn config(&self) -> anyhow::Result<Config> {
+        let mut config = Config::from_ado_string(&self.to_ado_string())?;
+        self.tls.apply(&mut config)?;
+        if let Some(login) = self.login()? {
+            config.authentication(login.method());
+        }
+        Ok(config)
+    }
+
+    /// Log in with this profile, checking the certificate as its TLS
+    /// settings ask.
+    pub async fn connect(&self) -> anyhow::Result<tiberius::Client<tls::Session>> {
+        tls::connect(self.config()?, &self.tls).await
+    }
+
+    /// The SQL login of this profile with its password, or `None` for
+    /// Windows authentication.
+    pub fn login(&self) -> anyhow::Result<Option<SqlLogin>> {
+        let Some(user) = &self.user else {
+            return Ok(None);
+        };
+        let source = self.password.clone().unwrap_or(PasswordSource::Prompt);
+        let password = source.resolve(user, &self.server_key()?)?;
+        Ok(Some(SqlLogin::new(user, password)))
+    }
+
+    /// The server as keyring entries and prompts name it.
+    pub fn server_key(&self) -> anyhow::Result<String> {
+        Ok(ServerAddress::parse(&self.server)?.to_string())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        match key {
+            "server" => self.server = value.to_string(),
+            "database" => self.database = Some(value.to_string()),
+            "user" => self.user = Some(value.to_string()),
+            "password" => self.password = Some(value.parse()?),
+            "trust_server_certificate" => self.tls.trust_server_certificate = parse_bool(value)?,
+            "encrypt" => self.tls.mode = value.parse()?,
+            "ca_bundle" => self.tls.ca_bundle = Some(PathBuf::from(value)),
+            "fingerprint" => self.tls.fingerprint = Some(value.parse()?),
+            "tls_hostname" => self.tls.hostname = Some(value.to_string()),
+            "query_timeout" => self.query_timeout = parse_timeout(value)?,
+            "read_only" => self.read_only = parse_bool(value)?,
+            _ => anyhow::bail!("unknown setting {:?}", key),
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> anyhow::Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => anyhow::bail!("expected true or false, got {:?}", value),
+    }
+}
+
+/// Whole seconds; `0` means no timeout.
+fn parse_timeout(value: &str) -> anyhow::Result<Option<Duration>> {
+    match value.parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(seconds) => Ok(Some(Duration::from_secs(seconds))),
+        Err(_) => anyhow::bail!("expected a number of seconds, got {:?}", value),
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[{}]", self.name)?;
+        writeln!(f, "server = {}", self.server)?;
+        if let Some(database) = &self.database {
+            writeln!(f, "database = {}", database)?;
+        }
+        if let Some(user) = &self.user {
+            writeln!(f, "user = {}", user)?;
+        }
+        if let Some(password) = &self.password {
+            writeln!(f, "password = {}", password)?;
+        }
+        if self.tls.mode != TlsMode::default() {
+            writeln!(f, "encrypt = {}", self.tls.mode)?;
+        }
+        if let Some(ca_bundle) = &self.tls.ca_bundle {
+            writeln!(f, "ca_bundle = {}", ca_bundle.display())?;
+        }
+        if let Some(hostname) = &self.tls.hostname {
+            writeln!(f, "tls_hostname = {}", hostname)?;
+        }
+        if let Some(fingerprint) = &self.tls.fingerprint {
+            writeln!(f, "fingerprint = {}", fingerprint)?;
+        }
+        if self.tls.trust_server_certificate {
+            writeln!(f, "trust_server_certificate = true")?;
+        }
+        if let Some(timeout) = self.query_timeout {
+            writeln!(f, "query_timeout = {}", timeout.as_secs())?;
+        }
+        if self.read_only {
+            writeln!(f, "read_only = true")?;
+        }
+        Ok(())
+    }
+}
+
+/// This crate's directory in the user's configuration directory, for
+/// profiles and the audit log.
+pub fn config_dir() -> PathBuf {
+    let config_dir = std::env::var("APPDATA")
+        .or_else(|_| std::env::var("XDG_CONFIG_HOME"))
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| Path::new(&home).join(".config")))
+        .unwrap_or_else(|_| PathBuf::from("."));
+
+    config_dir.join(env!("CARGO_PKG_NAME"))
+}
+
+/// The profiles of one file, in file order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profiles {
+    pub profiles: Vec<Profile>,
+}
+
+impl Profiles {
+    /// `TIBERIUS_PROFILES` if set, otherwise `profiles.ini` in the user's
+    /// configuration directory.
+    pub fn default_path() -> PathBuf {
+        if let Ok(path) = std::env::var("TIBERIUS_PROFILES") {
+            return PathBuf::from(path);
+        }
+
+        config_dir().join("profiles.ini")
+    }
+
+    /// Read a profiles file; a missing file has no profiles.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => text.parse().map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Profiles::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Add a profile, replacing one of the same name.
+    pub fn set(&mut self, profile: Profile) {
+        match self.profiles.iter_mut().find(|p| p.name.eq_ignore_ascii_case(&profile.name)) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+}
+
+/// Load the profile called `name` from the default profiles file.
+pub fn load(name: &str) -> anyhow::Result<Profile> {
+    let path = Profiles::default_path();
+    Profiles::load(&path)?
+        .get(name)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("No profile named {:?} in {}", name, path.display()))
+}
+
+impl FromStr for Profiles {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut profiles: Vec<Profile> = Vec::new();
+
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                profiles.push(Profile::new(name.trim(), ""));
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("line {}: expected `key = value`", number + 1))?;
+            let profile = profiles
+                .last_mut()
+                .ok_or_else(|| anyhow::anyhow!("line {}: setting before the first [profile]", number + 1))?;
+            profile
+                .set(&key.trim().to_lowercase(), value.trim())
+                .map_err(|e| anyhow::anyhow!("line {}: {}", number + 1, e))?;
+        }
+
+        if let Some(profile) = profiles.iter().find(|p| p.server.is_empty()) {
+            anyhow::bail!("profile {:?} has no server", profile.name);
+        }
+
+        Ok(Profiles { profiles })
+    }
+}
+
+impl fmt::Display for Profiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", profile)?;
+        }
+        Ok(())
+    }
+}
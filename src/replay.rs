@@ -0,0 +1,471 @@
+//! Record TDS sessions against a real server once, then replay them in
+//! tests that run anywhere.
+//!
+//! A [`Recorder`] is a proxy on localhost that forwards every connection to
+//! the real server and keeps the server's packets. A [`Replayer`] listens on
+//! localhost and answers each connection from a recorded session, checking
+//! that the client sends the same statements in the same order.
+//!
+//! Sessions are recorded without TLS, so the client must connect with
+//! `encrypt=DANGER_PLAINTEXT` and the server must not force encryption.
+//! Client messages are stored as their kind and statement text only, so a
+//! fixture never contains the login or its password.
+//!
+//! Functions that build their own connection, like `sql_client::read_table`,
+//! are replayed through their `_with(config)` variant, e.g.
+//! `sql_client::read_table_with`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use tiberius::{Config, EncryptionLevel};
+
+use crate::mock_server::{self, MockResponse, NO_SCRIPTED_RESPONSE};
+use crate::tds::{
+    self, ENCRYPT_NOT_SUP, HEADER_LEN, PACKET_ATTENTION, PACKET_BULK_LOAD, PACKET_LOGIN7, PACKET_PRELOGIN,
+    PACKET_RPC, PACKET_SQL_BATCH, PACKET_TABULAR_RESULT, STATUS_EOM,
+};
+use crate::paging::parse_hex;
+
+const FIXTURE_HEADER: &str = "# tds-fixture v1";
+
+/// One step of a recorded session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// A message from the client: its packet type and, for batches and
+    /// RPC calls, the statement text.
+    Client { kind: u8, sql: Option<String> },
+    /// One packet from the server, header included.
+    Server(Vec<u8>),
+}
+
+/// Recorded sessions, one per connection in the order they were opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fixture {
+    pub sessions: Vec<Vec<Entry>>,
+}
+
+impl Fixture {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|e: anyhow::Error| e.context(format!("Invalid fixture {}", path.display())))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+}
+
+/// A line-based text format, so fixtures diff well in review:
+///
+/// ```text
+/// # tds-fixture v1
+/// session
+/// > prelogin
+/// < 0401002B00000100...
+/// > rpc SELECT * FROM HumanResources.Department WHERE Name = @P1
+/// < 04010045003A0100...
+/// ```
+impl fmt::Display for Fixture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", FIXTURE_HEADER)?;
+
+        for session in &self.sessions {
+            writeln!(f, "session")?;
+            for entry in session {
+                match entry {
+                    Entry::Client { kind, sql: Some(sql) } => {
+                        writeln!(f, "> {} {}", kind_name(*kind), escape(sql))?
+                    }
+                    Entry::Client { kind, sql: None } => writeln!(f, "> {}", kind_name(*kind))?,
+                    Entry::Server(packet) => {
+                        write!(f, "< ")?;
+                        for byte in packet {
+                            write!(f, "{:02X}", byte)?;
+                        }
+                        writeln!(f)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Fixture {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+
+        match lines.next() {
+            Some((_, FIXTURE_HEADER)) => (),
+            _ => anyhow::bail!("Missing {:?} header", FIXTURE_HEADER),
+        }
+
+        let mut fixture = Fixture::default();
+        for (number, line) in lines {
+            let at = || format!("line {}", number + 1);
+
+            if line.starts_with('#') {
+                continue;
+            } else if line == "session" {
+                fixture.sessions.push(Vec::new());
+                continue;
+            }
+
+            let session = fixture
+                .sessions
+                .last_mut()
+                .ok_or_else(|| anyhow::anyhow!("Entry before the first session at {}", at()))?;
+
+            let entry = if let Some(rest) = line.strip_prefix("> ") {
+                let (kind, sql) = match rest.split_once(' ') {
+                    Some((kind, sql)) => (kind, Some(unescape(sql))),
+                    None => (rest, None),
+                };
+                Entry::Client {
+                    kind: parse_kind(kind).map_err(|e| e.context(at()))?,
+                    sql,
+                }
+            } else if let Some(hex) = line.strip_prefix("< ") {
+                Entry::Server(parse_hex(hex).map_err(|e| e.context(at()))?)
+            } else {
+                anyhow::bail!("Unrecognised fixture entry at {}: {:?}", at(), line);
+            };
+            session.push(entry);
+        }
+
+        Ok(fixture)
+    }
+}
+
+fn kind_name(kind: u8) -> String {
+    match kind {
+        PACKET_SQL_BATCH => "batch".to_string(),
+        PACKET_RPC => "rpc".to_string(),
+        PACKET_ATTENTION => "attention".to_string(),
+        PACKET_BULK_LOAD => "bulk".to_string(),
+        PACKET_LOGIN7 => "login7".to_string(),
+        PACKET_PRELOGIN => "prelogin".to_string(),
+        other => format!("packet-{}", other),
+    }
+}
+
+fn parse_kind(name: &str) -> anyhow::Result<u8> {
+    let kind = match name {
+        "batch" => PACKET_SQL_BATCH,
+        "rpc" => PACKET_RPC,
+        "attention" => PACKET_ATTENTION,
+        "bulk" => PACKET_BULK_LOAD,
+        "login7" => PACKET_LOGIN7,
+        "prelogin" => PACKET_PRELOGIN,
+        other => match other.strip_prefix("packet-") {
+            Some(number) => number.parse()?,
+            None => anyhow::bail!("Unknown message kind {:?}", other),
+        },
+    };
+    Ok(kind)
+}
+
+fn escape(sql: &str) -> String {
+    sql.replace('\\', "\\\\").replace('\r', "\\r").replace('\n', "\\n")
+}
+
+fn unescape(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => out.push('\n'),
+            ('\\', Some('r')) => out.push('\r'),
+            ('\\', Some('\\')) => out.push('\\'),
+            _ => {
+                out.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+
+    out
+}
+
+/// The statement text of a batch or RPC message.
+fn statement(kind: u8, body: &[u8]) -> Option<String> {
+    match kind {
+        PACKET_SQL_BATCH => tds::parse_batch(body).ok(),
+        PACKET_RPC => tds::parse_rpc(body).ok().map(|(sql, _)| sql),
+        _ => None,
+    }
+}
+
+/// Point a client configuration at a local recorder or replayer.
+fn redirect(config: &mut Config, addr: SocketAddr) {
+    config.host(addr.ip().to_string());
+    config.port(addr.port());
+    config.encryption(EncryptionLevel::NotSupported);
+}
+
+/// Read packets up to the end of a message, headers included. `None` when
+/// the peer closed the connection.
+async fn read_packets(stream: &mut TcpStream) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+    let mut packets = Vec::new();
+
+    loop {
+        let mut packet = vec![0u8; HEADER_LEN];
+        match stream.read_exact(&mut packet).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && packets.is_empty() => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if len < HEADER_LEN {
+            anyhow::bail!("Invalid packet length {}", len);
+        }
+        packet.resize(len, 0);
+        stream.read_exact(&mut packet[HEADER_LEN..]).await?;
+
+        let last = packet[1] & STATUS_EOM != 0;
+        packets.push(packet);
+        if last {
+            return Ok(Some(packets));
+        }
+    }
+}
+
+fn message_body(packets: &[Vec<u8>]) -> Vec<u8> {
+    packets.iter().flat_map(|p| p[HEADER_LEN..].iter().copied()).collect()
+}
+
+/// The encryption option of a PRELOGIN message.
+fn prelogin_encryption(body: &[u8]) -> Option<u8> {
+    let mut options = body.chunks(5).take_while(|o| o[0] != 0xFF);
+    let option = options.find(|o| o.len() == 5 && o[0] == 1)?;
+    let offset = u16::from_be_bytes([option[1], option[2]]) as usize;
+    body.get(offset).copied()
+}
+
+/// A proxy that records the sessions passing through it.
+#[derive(Clone)]
+pub struct Recorder {
+    addr: SocketAddr,
+    sessions: Arc<Mutex<Vec<Vec<Entry>>>>,
+}
+
+impl Recorder {
+    /// Listen on a random localhost port and forward connections to
+    /// `upstream`, e.g. the address of `Config::get_addr`.
+    pub async fn start(upstream: &str) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let sessions = Arc::new(Mutex::new(Vec::new()));
+
+        let upstream = upstream.to_string();
+        let shared = sessions.clone();
+        task::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let index = {
+                    let mut sessions = shared.lock().unwrap();
+                    sessions.push(Vec::new());
+                    sessions.len() - 1
+                };
+                let sessions = shared.clone();
+                let upstream = upstream.clone();
+                task::spawn(async move {
+                    if let Err(e) = record(client, &upstream, index, &sessions).await {
+                        tracing::warn!("Recording of session {} stopped: {}", index + 1, e);
+                    }
+                });
+            }
+        });
+
+        Ok(Recorder { addr, sessions })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send a client's connections through this recorder, without TLS.
+    pub fn redirect(&self, config: &mut Config) {
+        redirect(config, self.addr);
+    }
+
+    /// Everything recorded so far.
+    pub fn fixture(&self) -> Fixture {
+        Fixture {
+            sessions: self.sessions.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        self.fixture().save(path)
+    }
+}
+
+async fn record(
+    mut client: TcpStream,
+    upstream: &str,
+    index: usize,
+    sessions: &Mutex<Vec<Vec<Entry>>>,
+) -> anyhow::Result<()> {
+    let mut server = TcpStream::connect(upstream).await?;
+    server.set_nodelay(true)?;
+    let push = |entry: Entry| sessions.lock().unwrap()[index].push(entry);
+
+    // TDS is request/response: forward one client message, then the
+    // server's whole reply.
+    while let Some(request) = read_packets(&mut client).await? {
+        let kind = request[0][0];
+        push(Entry::Client {
+            kind,
+            sql: statement(kind, &message_body(&request)),
+        });
+        for packet in &request {
+            server.write_all(packet).await?;
+        }
+        server.flush().await?;
+
+        let reply = read_packets(&mut server)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Server closed the connection"))?;
+
+        if kind == PACKET_PRELOGIN && prelogin_encryption(&message_body(&reply)) != Some(ENCRYPT_NOT_SUP) {
+            anyhow::bail!(
+                "The session would be encrypted; connect with encrypt=DANGER_PLAINTEXT to a server that does not force encryption"
+            );
+        }
+
+        // Record before forwarding, so the fixture is complete by the time
+        // the client sees the reply.
+        for packet in reply {
+            push(Entry::Server(packet.clone()));
+            client.write_all(&packet).await?;
+        }
+        client.flush().await?;
+    }
+
+    Ok(())
+}
+
+/// A server that answers connections from recorded sessions.
+#[derive(Clone)]
+pub struct Replayer {
+    addr: SocketAddr,
+    mismatches: Arc<Mutex<Vec<String>>>,
+}
+
+impl Replayer {
+    /// Listen on a random localhost port. Each connection replays the next
+    /// session of the fixture.
+    pub async fn start(fixture: Fixture) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let mismatches = Arc::new(Mutex::new(Vec::new()));
+
+        let mut sessions: VecDeque<Vec<Entry>> = fixture.sessions.into();
+        let shared = mismatches.clone();
+        task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mismatches = shared.clone();
+                match sessions.pop_front() {
+                    Some(session) => {
+                        task::spawn(async move {
+                            let _ = replay(stream, session, &mismatches).await;
+                        });
+                    }
+                    None => mismatches
+                        .lock()
+                        .unwrap()
+                        .push("Connection opened after the last recorded session".to_string()),
+                }
+            }
+        });
+
+        Ok(Replayer { addr, mismatches })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send a client's connections to this replayer.
+    pub fn redirect(&self, config: &mut Config) {
+        redirect(config, self.addr);
+    }
+
+    /// Requests that did not match the recording. Each was answered with a
+    /// server error.
+    pub fn mismatches(&self) -> Vec<String> {
+        self.mismatches.lock().unwrap().clone()
+    }
+}
+
+async fn replay(
+    mut stream: TcpStream,
+    session: Vec<Entry>,
+    mismatches: &Mutex<Vec<String>>,
+) -> anyhow::Result<()> {
+    let mut entries = session.into_iter().peekable();
+
+    while let Some((kind, body)) = tds::read_message(&mut stream).await? {
+        let sql = statement(kind, &body);
+
+        match entries.next() {
+            Some(Entry::Client { kind: expected_kind, sql: expected_sql })
+                if expected_kind == kind && expected_sql == sql =>
+            {
+                while let Some(Entry::Server(packet)) =
+                    entries.next_if(|e| matches!(e, Entry::Server(_)))
+                {
+                    stream.write_all(&packet).await?;
+                }
+                stream.flush().await?;
+            }
+            expected => {
+                let expected = match expected {
+                    Some(Entry::Client { kind, sql }) => describe(kind, sql.as_deref()),
+                    _ => "the end of the session".to_string(),
+                };
+                let message = format!(
+                    "Replay mismatch: expected {}, got {}",
+                    expected,
+                    describe(kind, sql.as_deref())
+                );
+                mismatches.lock().unwrap().push(message.clone());
+
+                let response = MockResponse::new().error(NO_SCRIPTED_RESPONSE, &message);
+                let reply = mock_server::encode_or_error(&response, kind == PACKET_RPC);
+                tds::write_message(&mut stream, PACKET_TABULAR_RESULT, &reply).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn describe(kind: u8, sql: Option<&str>) -> String {
+    match sql {
+        Some(sql) => format!("{} {:?}", kind_name(kind), sql),
+        None => kind_name(kind),
+    }
+}
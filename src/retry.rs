@@ -0,0 +1,344 @@
+//! Retry of transient failures: deadlock victims, lock timeouts, Azure
+//! throttling and dropped connections.
+//!
+//! Every operation handed to a [`RetryPolicy`] carries an [`Idempotency`]
+//! marker. Idempotent operations are retried on any transient failure, while
+//! non-idempotent ones are only retried when the server guarantees the
+//! statement had no effect (deadlock victim, lock timeout, throttled request).
+//! Whole transactions can be retried with [`run_transaction`].
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use async_std::net::TcpStream;
+use async_std::task;
+use futures_util::future::BoxFuture;
+use tiberius::error::{Error, IoErrorKind};
+use tiberius::{Client, Config};
+
+use crate::sql_client;
+
+/// How a failure is treated by the retry loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Chosen as a deadlock victim (1205). The transaction was rolled back.
+    Deadlock,
+    /// Timed out waiting for a lock (1222). The statement was cancelled.
+    LockTimeout,
+    /// The server is busy or the database is moving (Azure 40501, 40613, ...).
+    Throttled,
+    /// The connection dropped; the statement may or may not have run.
+    Connection,
+    /// Anything else. Never retried.
+    Fatal,
+}
+
+impl ErrorClass {
+    /// True if the failure is worth another attempt.
+    pub fn is_transient(self) -> bool {
+        self != ErrorClass::Fatal
+    }
+
+    /// True if the server guarantees the failed statement had no effect.
+    pub fn is_rejected(self) -> bool {
+        matches!(
+            self,
+            ErrorClass::Deadlock | ErrorClass::LockTimeout | ErrorClass::Throttled
+        )
+    }
+}
+
+/// Whether an operation may safely run more than once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idempotency {
+    /// Running it twice has the same effect as running it once
+    /// (a read, a connect, an upsert, a whole transaction).
+    Idempotent,
+    /// Running it twice could duplicate its effect (a plain insert).
+    NonIdempotent,
+}
+
+/// Classify a server error number.
+pub fn classify_code(code: u32) -> ErrorClass {
+    match code {
+        1205 => ErrorClass::Deadlock,
+        1222 => ErrorClass::LockTimeout,
+        // Azure SQL: service busy, database unavailable, resource limits,
+        // elastic pool limits and reconfiguration.
+        40501 | 40613 | 40197 | 40540 | 10928 | 10929 | 49918 | 49919 | 49920 => {
+            ErrorClass::Throttled
+        }
+        // Transport-level errors reported by the server.
+        233 | 64 | 10053 | 10054 | 10060 => ErrorClass::Connection,
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// Classify a driver error.
+pub fn classify_error(err: &Error) -> ErrorClass {
+    match err {
+        Error::Server(token) => classify_code(token.code()),
+        Error::Io {
+            kind:
+                IoErrorKind::ConnectionReset
+                | IoErrorKind::ConnectionAborted
+                | IoErrorKind::ConnectionRefused
+                | IoErrorKind::BrokenPipe
+                | IoErrorKind::TimedOut
+                | IoErrorKind::UnexpectedEof
+                | IoErrorKind::NotConnected,
+            ..
+        } => ErrorClass::Connection,
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// Classify any error returned from this crate.
+pub fn classify(err: &anyhow::Error) -> ErrorClass {
+    if let Some(e) = err.downcast_ref::<Error>() {
+        return classify_error(e);
+    }
+
+    if let Some(e) = err.downcast_ref::<std::io::Error>() {
+        let kind = e.kind();
+        return classify_error(&Error::Io {
+            kind,
+            message: e.to_string(),
+        });
+    }
+
+    ErrorClass::Fatal
+}
+
+/// Counters shared by every retry loop in the process.
+#[derive(Debug, Default)]
+pub struct RetryMetrics {
+    operations: AtomicU64,
+    attempts: AtomicU64,
+    retries: AtomicU64,
+    recovered: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+/// A point-in-time copy of [`RetryMetrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryStats {
+    /// Operations started.
+    pub operations: u64,
+    /// Attempts made, including the first one.
+    pub attempts: u64,
+    /// Attempts that were followed by another attempt.
+    pub retries: u64,
+    /// Operations that succeeded after at least one retry.
+    pub recovered: u64,
+    /// Operations that failed with a transient error on their last attempt.
+    pub exhausted: u64,
+}
+
+impl RetryMetrics {
+    pub const fn new() -> Self {
+        RetryMetrics {
+            operations: AtomicU64::new(0),
+            attempts: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            recovered: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    pub fn snapshot(&self) -> RetryStats {
+        RetryStats {
+            operations: self.operations.load(Ordering::Relaxed),
+            attempts: self.attempts.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            recovered: self.recovered.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Process-wide retry counters.
+pub static RETRY_METRICS: RetryMetrics = RetryMetrics::new();
+
+/// Exponential backoff with jitter.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry. Doubles on every further retry.
+    pub base_delay: Duration,
+    /// Upper bound for a single delay.
+    pub max_delay: Duration,
+    /// Randomise each delay between half and all of its nominal value.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that makes a single attempt.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// The delay to wait after the given failed attempt (starting at 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        if self.jitter {
+            let half = delay / 2;
+            half + delay.mul_f64(fastrand::f64()) / 2
+        } else {
+            delay
+        }
+    }
+
+    /// True if an operation with the given idempotency should be retried
+    /// after failing with `class`.
+    pub fn should_retry(&self, class: ErrorClass, idempotency: Idempotency) -> bool {
+        match idempotency {
+            Idempotency::Idempotent => class.is_transient(),
+            Idempotency::NonIdempotent => class.is_rejected(),
+        }
+    }
+
+    /// Decide what to do after `attempt` failed with `err`: the delay before
+    /// the next attempt, or `None` to give up.
+    fn next_delay(
+        &self,
+        attempt: u32,
+        err: &anyhow::Error,
+        idempotency: Idempotency,
+    ) -> Option<Duration> {
+        let class = classify(err);
+
+        if !self.should_retry(class, idempotency) {
+            return None;
+        }
+
+        if attempt >= self.max_attempts {
+            RETRY_METRICS.exhausted.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        RETRY_METRICS.retries.fetch_add(1, Ordering::Relaxed);
+        Some(self.backoff(attempt))
+    }
+
+    /// Run `op` until it succeeds, fails with an error that may not be
+    /// retried, or the attempts run out.
+    pub async fn run<T, F, Fut>(&self, idempotency: Idempotency, mut op: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        RETRY_METRICS.operations.fetch_add(1, Ordering::Relaxed);
+        let mut attempt = 1;
+
+        loop {
+            RETRY_METRICS.attempts.fetch_add(1, Ordering::Relaxed);
+
+            match op().await {
+                Ok(value) => {
+                    if attempt > 1 {
+                        RETRY_METRICS.recovered.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return Ok(value);
+                }
+                Err(err) => match self.next_delay(attempt, &err, idempotency) {
+                    Some(delay) => task::sleep(delay).await,
+                    None => return Err(err),
+                },
+            }
+
+            attempt += 1;
+        }
+    }
+}
+
+/// Connect, retrying refused connections, drops and throttling.
+pub async fn connect_with_retry(
+    config: &Config,
+    policy: &RetryPolicy,
+) -> anyhow::Result<Client<TcpStream>> {
+    policy
+        .run(Idempotency::Idempotent, || sql_client::connect(config.clone()))
+        .await
+}
+
+/// Run `body` inside a transaction on a fresh connection, committing on
+/// success. On a transient failure the transaction is rolled back and the
+/// whole body runs again from scratch, so it must not have side effects
+/// outside the database.
+pub async fn run_transaction<T, F>(
+    config: &Config,
+    policy: &RetryPolicy,
+    mut body: F,
+) -> anyhow::Result<T>
+where
+    F: for<'c> FnMut(&'c mut Client<TcpStream>) -> BoxFuture<'c, anyhow::Result<T>>,
+{
+    RETRY_METRICS.operations.fetch_add(1, Ordering::Relaxed);
+    let mut attempt = 1;
+
+    loop {
+        RETRY_METRICS.attempts.fetch_add(1, Ordering::Relaxed);
+
+        match transaction_attempt(config, &mut body).await {
+            Ok(value) => {
+                if attempt > 1 {
+                    RETRY_METRICS.recovered.fetch_add(1, Ordering::Relaxed);
+                }
+                return Ok(value);
+            }
+            Err(err) => match policy.next_delay(attempt, &err, Idempotency::Idempotent) {
+                Some(delay) => task::sleep(delay).await,
+                None => return Err(err),
+            },
+        }
+
+        attempt += 1;
+    }
+}
+
+async fn transaction_attempt<T, F>(config: &Config, body: &mut F) -> anyhow::Result<T>
+where
+    F: for<'c> FnMut(&'c mut Client<TcpStream>) -> BoxFuture<'c, anyhow::Result<T>>,
+{
+    let mut client = sql_client::connect(config.clone()).await?;
+    client.simple_query("BEGIN TRAN").await?.into_results().await?;
+
+    match body(&mut client).await {
+        Ok(value) => {
+            client.simple_query("COMMIT").await?.into_results().await?;
+            client.close().await?;
+            Ok(value)
+        }
+        Err(err) => {
+            // The connection may already be gone; the server rolls back on
+            // disconnect in that case.
+            if let Ok(stream) = client.simple_query("IF @@TRANCOUNT > 0 ROLLBACK").await {
+                let _ = stream.into_results().await;
+            }
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Table metadata read from the catalog views.
+
+use futures_util::io::{AsyncRead, AsyncWrite};
+use tiberius::Client;
+
+/// A column of a table, as described by `sys.columns`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableColumn {
+    pub name: String,
+    /// The system type name, e.g. `int` or `nvarchar`.
+    pub type_name: String,
+    pub is_nullable: bool,
+    pub is_identity: bool,
+    pub is_computed: bool,
+    /// Position in the primary key, starting at 1.
+    pub key_ordinal: Option<u8>,
+}
+
+impl TableColumn {
+    /// True if values for this column cannot be supplied by an insert or
+    /// update: identity, computed and rowversion columns.
+    pub fn is_generated(&self) -> bool {
+        self.is_identity || self.is_computed || self.type_name == "timestamp"
+    }
+}
+
+const TABLE_COLUMNS_SQL: &str = "
+SELECT
+    c.name,
+    TYPE_NAME(c.system_type_id),
+    c.is_nullable,
+    c.is_identity,
+    c.is_computed,
+    CAST(ic.key_ordinal AS tinyint)
+FROM sys.columns c
+LEFT JOIN sys.indexes i
+    ON i.object_id = c.object_id AND i.is_primary_key = 1
+LEFT JOIN sys.index_columns ic
+    ON ic.object_id = i.object_id AND ic.index_id = i.index_id AND ic.column_id = c.column_id
+WHERE c.object_id = OBJECT_ID(@P1)
+ORDER BY c.column_id";
+
+/// Read the columns of a table in their declared order.
+pub async fn table_columns<S>(
+    client: &mut Client<S>,
+    table: &str,
+) -> anyhow::Result<Vec<TableColumn>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let rows = client
+        .query(TABLE_COLUMNS_SQL, &[&table])
+        .await?
+        .into_first_result()
+        .await?;
+
+    if rows.is_empty() {
+        anyhow::bail!("Table {} does not exist or has no visible columns", table);
+    }
+
+    let columns = rows
+        .iter()
+        .map(|r| TableColumn {
+            name: r.get::<&str, _>(0).unwrap_or_default().to_string(),
+            type_name: r.get::<&str, _>(1).unwrap_or_default().to_string(),
+            is_nullable: r.get(2).unwrap_or(true),
+            is_identity: r.get(3).unwrap_or(false),
+            is_computed: r.get(4).unwrap_or(false),
+            key_ordinal: r.get(5),
+        })
+        .collect();
+
+    Ok(columns)
+}
+
+/// The primary key columns in key order.
+pub fn primary_key(columns: &[TableColumn]) -> Vec<&TableColumn> {
+    let mut key: Vec<&TableColumn> = columns.iter().filter(|c| c.key_ordinal.is_some()).collect();
+    key.sort_by_key(|c| c.key_ordinal);
+    key
+}
+
+const KEYSET_COLUMNS_SQL: &str = "
+WITH best AS (
+    SELECT TOP 1 i.object_id, i.index_id
+    FROM sys.indexes i
+    WHERE i.object_id = OBJECT_ID(@P1)
+      AND i.is_unique = 1
+      AND i.has_filter = 0
+      AND i.is_disabled = 0
+      AND NOT EXISTS (
+          SELECT 1
+          FROM sys.index_columns ic
+          JOIN sys.columns c ON c.object_id = ic.object_id AND c.column_id = ic.column_id
+          WHERE ic.object_id = i.object_id AND ic.index_id = i.index_id
+            AND ic.key_ordinal > 0 AND c.is_nullable = 1)
+    ORDER BY i.is_primary_key DESC, CASE WHEN i.type = 1 THEN 0 ELSE 1 END, i.index_id
+)
+SELECT c.name
+FROM best
+JOIN sys.index_columns ic ON ic.object_id = best.object_id AND ic.index_id = best.index_id
+JOIN sys.columns c ON c.object_id = ic.object_id AND c.column_id = ic.column_id
+WHERE ic.key_ordinal > 0
+ORDER BY ic.key_ordinal";
+
+/// The columns of the best unique, non-nullable key for walking a table in
+/// order: the primary key, else a unique clustered index, else any unique
+/// index.
+pub async fn keyset_columns<S>(
+    client: &mut Client<S>,
+    table: &str,
+) -> anyhow::Result<Vec<String>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let rows = client
+        .query(KEYSET_COLUMNS_SQL, &[&table])
+        .await?
+        .into_first_result()
+        .await?;
+
+    if rows.is_empty() {
+        anyhow::bail!(
+            "Table {} has no primary key or unique index without nullable columns",
+            table
+        );
+    }
+
+    Ok(rows
+        .iter()
+        .filter_map(|r| r.get::<&str, _>(0).map(str::to_string))
+        .collect())
+}
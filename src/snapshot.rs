@@ -0,0 +1,422 @@
+//! Snapshots of query results, to check that refactoring a view or a
+//! procedure leaves its results unchanged.
+//!
+//! Queries are listed in a `.sql` file, each introduced by a marker:
+//!
+//! ```text
+//! -- snapshot: active rabbits
+//! -- key: id
+//! SELECT id, name, date_of_birth FROM dbo.active_rabbits;
+//! ```
+//!
+//! `-- key:` names the columns that identify a row, so a row whose other
+//! columns differ is reported as changed rather than as removed and added.
+//! It defaults to the first column.
+//!
+//! A snapshot is stored as JSON with sorted keys and its rows sorted by key,
+//! so it only changes when the results do and diffs well under version
+//! control.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value as Json};
+
+use crate::backend::Backend;
+use crate::value::{Record, Value};
+
+/// A query to snapshot, as read from a queries file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotQuery {
+    pub name: String,
+    pub sql: String,
+    /// Empty to use the first column.
+    pub key: Vec<String>,
+}
+
+/// Parse the `-- snapshot:` blocks of a queries file.
+pub fn parse_queries(text: &str) -> anyhow::Result<Vec<SnapshotQuery>> {
+    let mut queries: Vec<SnapshotQuery> = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let marker = line.trim().strip_prefix("--").map(str::trim);
+
+        if let Some(name) = marker.and_then(|m| strip_prefix_ignore_case(m, "snapshot:")) {
+            queries.push(SnapshotQuery {
+                name: name.trim().to_string(),
+                sql: String::new(),
+                key: Vec::new(),
+            });
+            continue;
+        }
+
+        let Some(query) = queries.last_mut() else {
+            if line.trim().is_empty() || marker.is_some() {
+                continue;
+            }
+            anyhow::bail!("line {}: SQL before the first `-- snapshot:`", number + 1);
+        };
+
+        match marker.and_then(|m| strip_prefix_ignore_case(m, "key:")) {
+            Some(key) => {
+                query.key = key.split(',').map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect();
+            }
+            None => {
+                query.sql.push_str(line);
+                query.sql.push('\n');
+            }
+        }
+    }
+
+    for query in &mut queries {
+        query.sql = query.sql.trim().to_string();
+        if query.sql.is_empty() {
+            anyhow::bail!("Snapshot {:?} has no query", query.name);
+        }
+    }
+
+    Ok(queries)
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    text.get(..prefix.len())
+        .filter(|start| start.eq_ignore_ascii_case(prefix))
+        .map(|_| &text[prefix.len()..])
+}
+
+/// The results of one query at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub sql: String,
+    pub columns: Vec<String>,
+    pub key: Vec<String>,
+    /// Sorted by key, then by the whole row.
+    pub rows: Vec<Vec<Json>>,
+}
+
+impl Snapshot {
+    /// Build a snapshot from the rows of `query`. Without rows the columns
+    /// are unknown and left empty.
+    pub fn from_records(query: &SnapshotQuery, records: &[Record]) -> anyhow::Result<Self> {
+        let columns: Vec<String> = records.first().map(|r| r.columns().to_vec()).unwrap_or_default();
+
+        let key = match (query.key.is_empty(), columns.first()) {
+            (_, None) => Vec::new(),
+            (true, Some(first)) => vec![first.clone()],
+            (false, Some(_)) => {
+                if let Some(missing) = query.key.iter().find(|k| !columns.iter().any(|c| c.eq_ignore_ascii_case(k))) {
+                    anyhow::bail!("Snapshot {:?}: key column {} is not in the results", query.name, missing);
+                }
+                query.key.clone()
+            }
+        };
+
+        let mut snapshot = Snapshot {
+            name: query.name.clone(),
+            sql: query.sql.clone(),
+            columns,
+            key,
+            rows: records.iter().map(|r| r.values().iter().map(to_json).collect()).collect(),
+        };
+        snapshot.sort();
+        Ok(snapshot)
+    }
+
+    fn sort(&mut self) {
+        let key = positions(&self.columns, &self.key);
+        self.rows.sort_by(|a, b| {
+            key.iter()
+                .map(|&i| compare_json(&a[i], &b[i]))
+                .find(|o| o.is_ne())
+                .unwrap_or_else(|| compare_rows(a, b))
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        let snapshot = json!({
+            "name": self.name,
+            "query": self.sql,
+            "columns": self.columns,
+            "key": self.key,
+            "rows": self.rows,
+        });
+        let mut text = serde_json::to_string_pretty(&snapshot).expect("JSON values always serialize");
+        text.push('\n');
+        text
+    }
+
+    pub fn from_json(text: &str) -> anyhow::Result<Self> {
+        let json: Json = serde_json::from_str(text)?;
+        let string = |field: &str| match &json[field] {
+            Json::String(s) => Ok(s.clone()),
+            _ => Err(anyhow::anyhow!("Snapshot has no {:?}", field)),
+        };
+        let strings = |field: &str| match &json[field] {
+            Json::Array(items) => items
+                .iter()
+                .map(|i| i.as_str().map(str::to_string).ok_or_else(|| anyhow::anyhow!("{:?} must hold strings", field)))
+                .collect::<anyhow::Result<Vec<_>>>(),
+            _ => Err(anyhow::anyhow!("Snapshot has no {:?}", field)),
+        };
+
+        let columns = strings("columns")?;
+        let rows = match &json["rows"] {
+            Json::Array(rows) => rows
+                .iter()
+                .map(|row| match row {
+                    Json::Array(values) if values.len() == columns.len() => Ok(values.clone()),
+                    _ => Err(anyhow::anyhow!("Every row must be an array of {} values", columns.len())),
+                })
+                .collect::<anyhow::Result<_>>()?,
+            _ => anyhow::bail!("Snapshot has no \"rows\""),
+        };
+
+        Ok(Snapshot {
+            name: string("name")?,
+            sql: string("query")?,
+            columns,
+            key: strings("key")?,
+            rows,
+        })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Snapshot::from_json(&fs::read_to_string(path)?)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_json())?;
+        Ok(())
+    }
+}
+
+/// Run `query` and snapshot its results.
+pub async fn take(db: &mut dyn Backend, query: &SnapshotQuery) -> anyhow::Result<Snapshot> {
+    let records = db.query(&query.sql, &[]).await?;
+    Snapshot::from_records(query, &records)
+}
+
+/// Where the snapshot named `name` is stored in `dir`.
+pub fn path_for(dir: &Path, name: &str) -> PathBuf {
+    let file: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    dir.join(format!("{}.json", file))
+}
+
+/// Numbers and booleans keep their JSON types; everything else is stored
+/// in its text form, which is exact for decimals and dates.
+fn to_json(value: &Value) -> Json {
+    match value {
+        Value::Null => Json::Null,
+        Value::Bool(v) => Json::Bool(*v),
+        Value::Int(v) => Json::from(*v),
+        Value::Float(v) if v.is_finite() => Json::from(*v),
+        v => Json::String(v.to_string()),
+    }
+}
+
+fn compare_json(a: &Json, b: &Json) -> Ordering {
+    fn rank(value: &Json) -> u8 {
+        match value {
+            Json::Null => 0,
+            Json::Bool(_) => 1,
+            Json::Number(_) => 2,
+            Json::String(_) => 3,
+            Json::Array(_) => 4,
+            Json::Object(_) => 5,
+        }
+    }
+
+    match (a, b) {
+        (Json::Bool(a), Json::Bool(b)) => a.cmp(b),
+        (Json::Number(a), Json::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => a.as_f64().partial_cmp(&b.as_f64()).unwrap_or(Ordering::Equal),
+        },
+        (Json::String(a), Json::String(b)) => a.cmp(b),
+        (a, b) if rank(a) != rank(b) => rank(a).cmp(&rank(b)),
+        (a, b) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+fn compare_rows(a: &[Json], b: &[Json]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| compare_json(a, b))
+        .find(|o| o.is_ne())
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+/// The positions of `names` in `columns`, skipping any that are missing.
+fn positions(columns: &[String], names: &[String]) -> Vec<usize> {
+    names
+        .iter()
+        .filter_map(|n| columns.iter().position(|c| c.eq_ignore_ascii_case(n)))
+        .collect()
+}
+
+/// A column whose value differs between two versions of a row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnChange {
+    pub column: String,
+    pub old: Json,
+    pub new: Json,
+}
+
+/// A row found under the same key in both snapshots, with other columns
+/// changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowChange {
+    /// `column=value` pairs of the key.
+    pub key: Vec<(String, Json)>,
+    pub changes: Vec<ColumnChange>,
+}
+
+/// The differences between an old and a new snapshot of the same query.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SnapshotDiff {
+    pub columns_added: Vec<String>,
+    pub columns_removed: Vec<String>,
+    /// Rows of the new snapshot, with its columns.
+    pub added: Vec<Vec<(String, Json)>>,
+    /// Rows of the old snapshot, with its columns.
+    pub removed: Vec<Vec<(String, Json)>>,
+    pub changed: Vec<RowChange>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.columns_added.is_empty()
+            && self.columns_removed.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+/// Compare two snapshots. Rows are matched by the old snapshot's key;
+/// repeated keys are matched in sorted order. Columns are compared by name
+/// and only when both snapshots have rows.
+pub fn diff(old: &Snapshot, new: &Snapshot) -> SnapshotDiff {
+    let has = |columns: &[String], name: &String| columns.iter().any(|c| c.eq_ignore_ascii_case(name));
+    let mut result = SnapshotDiff::default();
+
+    if !old.columns.is_empty() && !new.columns.is_empty() {
+        result.columns_added = new.columns.iter().filter(|c| !has(&old.columns, c)).cloned().collect();
+        result.columns_removed = old.columns.iter().filter(|c| !has(&new.columns, c)).cloned().collect();
+    }
+
+    // Without a key present on both sides, rows can only match as a whole.
+    let key: Vec<String> = match old.key.iter().all(|k| has(&new.columns, k)) {
+        true => old.key.clone(),
+        false => old.columns.iter().filter(|c| has(&new.columns, c)).cloned().collect(),
+    };
+    let old_key = positions(&old.columns, &key);
+    let new_key = positions(&new.columns, &key);
+    let common: Vec<(String, usize, usize)> = old
+        .columns
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| new.columns.iter().position(|n| n.eq_ignore_ascii_case(c)).map(|j| (c.clone(), i, j)))
+        .collect();
+
+    let key_text = |row: &[Json], key: &[usize]| Json::Array(key.iter().map(|&i| row[i].clone()).collect()).to_string();
+    let mut unmatched: HashMap<String, VecDeque<usize>> = HashMap::new();
+    for (i, row) in old.rows.iter().enumerate() {
+        unmatched.entry(key_text(row, &old_key)).or_default().push_back(i);
+    }
+
+    let mut matched = vec![false; old.rows.len()];
+    for row in &new.rows {
+        let Some(i) = unmatched.get_mut(&key_text(row, &new_key)).and_then(VecDeque::pop_front) else {
+            result.added.push(labelled(&new.columns, row));
+            continue;
+        };
+        matched[i] = true;
+
+        let changes: Vec<ColumnChange> = common
+            .iter()
+            .filter(|(_, o, n)| old.rows[i][*o] != row[*n])
+            .map(|(column, o, n)| ColumnChange {
+                column: column.clone(),
+                old: old.rows[i][*o].clone(),
+                new: row[*n].clone(),
+            })
+            .collect();
+        if !changes.is_empty() {
+            result.changed.push(RowChange {
+                key: key.iter().cloned().zip(new_key.iter().map(|&k| row[k].clone())).collect(),
+                changes,
+            });
+        }
+    }
+
+    for (i, row) in old.rows.iter().enumerate() {
+        if !matched[i] {
+            result.removed.push(labelled(&old.columns, row));
+        }
+    }
+
+    result
+}
+
+fn labelled(columns: &[String], row: &[Json]) -> Vec<(String, Json)> {
+    columns.iter().cloned().zip(row.iter().cloned()).collect()
+}
+
+fn write_pairs(f: &mut fmt::Formatter<'_>, pairs: &[(String, Json)]) -> fmt::Result {
+    for (i, (column, value)) in pairs.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}={}", column, value)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.columns_added.is_empty() {
+            writeln!(f, "columns added: {}", self.columns_added.join(", "))?;
+        }
+        if !self.columns_removed.is_empty() {
+            writeln!(f, "columns removed: {}", self.columns_removed.join(", "))?;
+        }
+        for row in &self.removed {
+            write!(f, "- ")?;
+            write_pairs(f, row)?;
+            writeln!(f)?;
+        }
+        for row in &self.added {
+            write!(f, "+ ")?;
+            write_pairs(f, row)?;
+            writeln!(f)?;
+        }
+        for row in &self.changed {
+            write!(f, "~ ")?;
+            write_pairs(f, &row.key)?;
+            writeln!(f)?;
+            for change in &row.changes {
+                writeln!(f, "    {}: {} -> {}", change.column, change.old, change.new)?;
+            }
+        }
+        write!(
+            f,
+            "{} removed, {} added, {} changed",
+            self.removed.len(),
+            self.added.len(),
+            self.changed.len()
+        )
+    }
+}
@@ -1,31 +1,36 @@
 use anyhow::Ok;
 use async_std::net::TcpStream;
+use futures_util::io::{AsyncRead, AsyncWrite};
 
-use tiberius::{Client, Config};
+use tiberius::{Client, Config, ToSql};
 use tiberius::SqlBrowser;
 use once_cell::sync::Lazy;
 use std::env;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::audit::{self, AuditLog};
+use crate::auth;
+use crate::cancel::{self, CancelHandle};
+use crate::diagnose::ServerAddress;
+use crate::telemetry::{self, Operation};
 
 
 static CONN_STR_PORT: Lazy<String> = Lazy::new(|| {
     env::var("TIBERIUS_TEST_CONNECTION_STRING").unwrap_or_else(|_| {
-        "server=JASON\\SQLEXPRESS,61521;database=AdventureWorks2016_EXT;IntegratedSecurity=true;TrustServerCertificate=true".to_owned()
+        "server=JASON\\SQLEXPRESS,61521;database=AdventureWorks2016_EXT;IntegratedSecurity=true".to_owned()
     })
 });
 
 /// Connect to an SQL Server instance using the hostname and port number.
 pub async fn connect_through_port() -> anyhow::Result<()> {
 
-    let config = Config::from_ado_string(&CONN_STR_PORT)?;
+    let config = default_config()?;
     
-    // Create a `TCPStream` from the `async-std` library with 
-    // a address that contains the hostname/IP and port number.
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-
-    tcp.set_nodelay(true)?;
-
-    // Connect to SQL Server
-    let client = Client::connect(config, tcp).await?;
+    // Open a `TCPStream` from the `async-std` library to the hostname/IP
+    // and port number, and log in to SQL Server
+    let client = connect(config).await?;
     println!("Successfully connected to server.");
 
     let _ = read_table().await;
@@ -35,6 +40,139 @@ pub async fn connect_through_port() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The configuration of the default server, taken from
+/// `TIBERIUS_TEST_CONNECTION_STRING` when set. The server's certificate is
+/// validated unless that string sets `TrustServerCertificate=true`. `TIBERIUS_USER` replaces its
+/// authentication with a SQL login; see [`auth::SqlLogin::from_env`].
+pub fn default_config() -> anyhow::Result<Config> {
+    config_from_ado_string(&CONN_STR_PORT)
+}
+
+/// Parse an ADO.NET connection string, with its authentication replaced
+/// by the SQL login in `TIBERIUS_USER` when that is set.
+pub fn config_from_ado_string(connection_string: &str) -> anyhow::Result<Config> {
+    let mut config = Config::from_ado_string(connection_string)?;
+    let server = ServerAddress::from_ado_string(connection_string)?;
+    if let Some(login) = auth::SqlLogin::from_env(&server.to_string())? {
+        config.authentication(login.method());
+    }
+    Ok(config)
+}
+
+/// The connection string of the default server.
+pub fn default_connection_string() -> String {
+    CONN_STR_PORT.clone()
+}
+
+/// Open a TCP connection to the address in `config` and log in.
+pub async fn connect(config: Config) -> anyhow::Result<Client<TcpStream>> {
+    let (client, _) = connect_traced(config).await?;
+    Ok(client)
+}
+
+/// The audit log and session the statements of a connection are written
+/// to, when auditing is on.
+type Audit = Option<(AuditLog, audit::Session)>;
+
+/// [`connect`], with what [`statement`] needs to audit its statements.
+async fn connect_audited(config: Config) -> anyhow::Result<(Client<TcpStream>, Audit)> {
+    let server = config.get_addr();
+    let mut client = connect(config).await?;
+    let audit = audit::session(&mut client, &server).await?;
+    Ok((client, audit))
+}
+
+/// Run `work`, the statement `sql`, inside a span and write it to the
+/// audit log, if any.
+async fn statement<T, E, Fut>(
+    audit: &Audit,
+    operation: Operation,
+    sql: &str,
+    params: &[&dyn ToSql],
+    work: Fut,
+    rows: impl Fn(&T) -> Option<u64>,
+) -> Result<T, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+    E: fmt::Display + 'static,
+{
+    let span = telemetry::statement_span(operation, sql, params);
+    let result = telemetry::traced(span, work, &rows).await;
+
+    if let Some((log, session)) = audit {
+        let name = match operation {
+            Operation::Query => "query",
+            Operation::Execute => "execute",
+            Operation::BulkLoad => "bulk_load",
+        };
+        log.record_result(session, name, sql, params, &result, rows);
+    }
+    result
+}
+
+/// Like [`connect`], inside a `sql.connect` span. When the span is being
+/// recorded the session id is looked up too, and returned for the spans of
+/// later statements.
+pub(crate) async fn connect_traced(config: Config) -> anyhow::Result<(Client<TcpStream>, Option<i16>)> {
+    connect_traced_at(config.get_addr(), config).await
+}
+
+/// Like [`connect_traced`], but open the TCP connection to `addr` instead
+/// of the host in `config`, which then only names the server for TLS
+/// validation.
+pub(crate) async fn connect_traced_at(
+    addr: String,
+    config: Config,
+) -> anyhow::Result<(Client<TcpStream>, Option<i16>)> {
+    let tcp = async {
+        let tcp = TcpStream::connect(&addr).await?;
+        tcp.set_nodelay(true)?;
+        Ok(tcp)
+    };
+    connect_traced_with(addr.clone(), config, tcp).await
+}
+
+/// Like [`connect_traced_at`], logging in over the connection `stream`
+/// opens to `addr`.
+pub(crate) async fn connect_traced_with<S>(
+    addr: String,
+    config: Config,
+    stream: impl Future<Output = anyhow::Result<S>>,
+) -> anyhow::Result<(Client<S>, Option<i16>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let span = telemetry::connect_span(&addr);
+    let recording = !span.is_disabled();
+
+    let login = async move {
+        let mut client = Client::connect(config, stream.await?).await?;
+        let spid = match recording {
+            true => session_id(&mut client).await.ok(),
+            false => None,
+        };
+
+        Ok((client, spid))
+    };
+
+    let result = telemetry::traced(span.clone(), login, |_| None).await;
+    if let Some(spid) = result.as_ref().ok().and_then(|(_, spid)| *spid) {
+        span.record("spid", spid);
+    }
+
+    result
+}
+
+/// The server process id (`@@SPID`) of this connection.
+pub async fn session_id<S>(client: &mut Client<S>) -> anyhow::Result<i16>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let row = client.simple_query("SELECT @@SPID").await?.into_row().await?;
+    row.and_then(|r| r.get::<i16, _>(0))
+        .ok_or_else(|| anyhow::anyhow!("@@SPID returned no value"))
+}
+
 #[allow(dead_code)]
 /// Connect to a named instance of SQL Server through SQL Server Browser.
 /// Make sure that SQL Server Browser is installed and running, otherwise
@@ -42,8 +180,9 @@ pub async fn connect_through_port() -> anyhow::Result<()> {
 pub async fn connect_through_sql_browser() -> anyhow::Result<()> {
     let mut config = Config::new();
 
-    // Use Windows Authentication
-    config.authentication(tiberius::AuthMethod::Integrated);
+    // Use Windows Authentication, or the SQL login in TIBERIUS_USER where
+    // Windows Authentication is not available
+    config.authentication(auth::default_method("JASON\\SQLEXPRESS")?);
 
     config.host("JASON");
 
@@ -53,9 +192,6 @@ pub async fn connect_through_sql_browser() -> anyhow::Result<()> {
     // The name of the database server instance.
     config.instance_name("SQLEXPRESS");
 
-    // it is not a good idea to do this in production
-    config.trust_cert();
-
     // This will create a new `TcpStream` from `async-std`, connected to the
     // right port of the named instance.
     let tcp = TcpStream::connect_named(&config).await?;
@@ -73,7 +209,7 @@ pub async fn connect_through_sql_browser() -> anyhow::Result<()> {
 #[allow(dead_code)]
 static CONN_STR: Lazy<String> = Lazy::new(|| {
     env::var("TIBERIUS_TEST_CONNECTION_STRING").unwrap_or_else(|_| {
-        "server=JASON\\SQLEXPRESS;IntegratedSecurity=true;TrustServerCertificate=true".to_owned()
+        "server=JASON\\SQLEXPRESS;IntegratedSecurity=true".to_owned()
     })
 });
 
@@ -81,7 +217,7 @@ static CONN_STR: Lazy<String> = Lazy::new(|| {
 /// Connect to a named instance of SQL Server without specifing the port number.
 /// SQL Server Browser must be running and will automatically choose the right port.
 pub async fn connect_to_named_instance() -> anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR)?;
+    let config = config_from_ado_string(&CONN_STR)?;
 
     let tcp = TcpStream::connect_named(&config).await?;
     tcp.set_nodelay(true)?;
@@ -103,7 +239,7 @@ pub async fn connect_to_named_instance() -> anyhow::Result<()> {
 #[allow(dead_code)]
 static JDBC_CONN_STR_NAMED_INSTANCE: Lazy<String> = Lazy::new(|| {
     env::var("TIBERIUS_TEST_JDBC_CONNECTION_STRING").unwrap_or_else(|_| {
-        "jdbc:sqlserver://JASON\\SQLEXPRESS;integratedSecurity=true;trustServerCertificate=true".to_owned()
+        "jdbc:sqlserver://JASON\\SQLEXPRESS;integratedSecurity=true".to_owned()
     })
 });
 
@@ -131,15 +267,10 @@ use tiberius::Query;
 
 #[allow(dead_code)]
 async fn create_table()-> anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR)?;
-
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-
-    tcp.set_nodelay(true)?;
-
-    let mut client = Client::connect(config, tcp).await?;
+    let config = config_from_ado_string(&CONN_STR)?;
+    let (mut client, audit) = connect_audited(config).await?;
 
-    let select = Query::new("
+    let sql = "
     drop table if exists dbo.rabbit_births
     create table dbo.rabbit_births
     (
@@ -147,9 +278,11 @@ async fn create_table()-> anyhow::Result<()> {
         name varchar(max),
         date_of_birth datetime
     )
-    ");
+    ";
+    let select = Query::new(sql);
     
-    let result=select.execute(&mut client).await?;
+    let work = select.execute(&mut client);
+    let result = statement(&audit, Operation::Execute, sql, &[], work, |r| Some(r.rows_affected().iter().sum())).await?;
 
     // Print the total number of rows affected
     println!("Rows affected: {}",result.total());
@@ -164,18 +297,16 @@ async fn create_table()-> anyhow::Result<()> {
 
 // to insert data into a table of SQL Server
 async fn insert_data()->anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR)?;
-
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-
-    tcp.set_nodelay(true)?;
-
-    let mut client = Client::connect(config, tcp).await?;
-
-    let result = client.execute(
-        "INSERT INTO rabbit_births (id, name, date_of_birth) VALUES (@P1, @P2, @P3)", 
-        &[&1i32, &"Bugs Bunny", &"2023-08-01"],
-    ).await?;
+    let config = config_from_ado_string(&CONN_STR)?;
+    let (mut client, audit) = connect_audited(config).await?;
+
+    let sql = "INSERT INTO rabbit_births (id, name, date_of_birth) VALUES (@P1, @P2, @P3)";
+    let params: [&dyn ToSql; 3] = [&1i32, &"Bugs Bunny", &"2023-08-01"];
+    let work = client.execute(sql, &params);
+    let result = statement(&audit, Operation::Execute, sql, &params, work, |r| {
+        Some(r.rows_affected().iter().sum())
+    })
+    .await?;
     
     
     println!("Rows affected: {}",result.total());
@@ -191,86 +322,94 @@ use tiberius::QueryItem;
 
 
 pub async fn read_table() -> anyhow::Result<()> {
+    read_table_with(default_config()?).await
+}
 
+/// [`read_table`] against the server in `config`, e.g. a
+/// [`Replayer`](crate::replay::Replayer).
+pub async fn read_table_with(config: Config) -> anyhow::Result<()> {
+    read_table_cancellable(config, None, |_| Ok(())).await
+}
 
-    let config = Config::from_ado_string(&CONN_STR_PORT)?;
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    let mut client = Client::connect(config, tcp).await?;
-    let select = Query::new("select * from HumanResources.Department");
-    let mut stream=select.query(&mut client).await?;
- 
-
-    //Read each row as long as ther arrive from the stream
-
-    while let Some(row)=stream.try_next().await? {
-        
-        match row {
-
-            // Metadata of the result set
-            QueryItem::Metadata(meta) => {
-                println!("{:?}",meta);
-            },
-
-            // Actual data rows
-            QueryItem::Row(r) => {
-                
-                // Break line to separate each row
-                println!();
-
-                // Create variables with an explicit type annotation.
-                let department_id: Option<i16> =r.get(0);
-                let name: Option<&str> =r.get(1);
-                let group_name: Option<&str> =r.get(2);
-                
-                // The complete list of SQL Server data types
-                // matching with Rust data types can be found in:
-                // https://docs.rs/tiberius/latest/tiberius/trait.FromSql.html#tymethod.from_sql
-
-
-                // Print an INT column
-                if let Some(value) = department_id {
-                    println!("{:?}", value);
-                }else{
-                    println!("NULL");
-                }
-                
-                // Print an VARCHAR column
-                if let Some(value) =name {
-                    println!("{:?}", value);
-                }else{
-                    println!("NULL");
+/// [`read_table_with`], on a connection whose query is cancelled after
+/// `timeout`, or through the handle given to `on_connect`, e.g.
+/// [`cancel::cancel_on_ctrl_c`].
+pub async fn read_table_cancellable(
+    config: Config,
+    timeout: Option<Duration>,
+    on_connect: impl FnOnce(&CancelHandle) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let server = config.get_addr();
+    let (mut client, cancel) = cancel::connect(config).await?;
+    let audit = audit::session(&mut client, &server).await?;
+    on_connect(&cancel)?;
+    let sql = "select * from HumanResources.Department";
+
+    let read = async {
+        let select = Query::new(sql);
+        let mut stream = select.query(&mut client).await?;
+        let mut rows = 0;
+
+        //Read each row as long as ther arrive from the stream
+
+        while let Some(row) = stream.try_next().await? {
+            match row {
+                // Metadata of the result set
+                QueryItem::Metadata(meta) => {
+                    println!("{:?}", meta);
                 }
 
-                // Print an VARCHAR column
-                if let Some(value) =group_name {
-                    println!("{:?}", value);
-                }else{
-                    println!("NULL");
+                // Actual data rows
+                QueryItem::Row(r) => {
+                    rows += 1;
+
+                    // Break line to separate each row
+                    println!();
+
+                    // Create variables with an explicit type annotation.
+                    let department_id: Option<i16> = r.get(0);
+                    let name: Option<&str> = r.get(1);
+                    let group_name: Option<&str> = r.get(2);
+
+                    // The complete list of SQL Server data types
+                    // matching with Rust data types can be found in:
+                    // https://docs.rs/tiberius/latest/tiberius/trait.FromSql.html#tymethod.from_sql
+
+                    // Print an INT column
+                    if let Some(value) = department_id {
+                        println!("{:?}", value);
+                    } else {
+                        println!("NULL");
+                    }
+
+                    // Print an VARCHAR column
+                    if let Some(value) = name {
+                        println!("{:?}", value);
+                    } else {
+                        println!("NULL");
+                    }
+
+                    // Print an VARCHAR column
+                    if let Some(value) = group_name {
+                        println!("{:?}", value);
+                    } else {
+                        println!("NULL");
+                    }
                 }
-
-                // Print a DATE column
-                // if let Some(value) = modified_date {
-                //     println!("{:?}", value);
-                // }else{
-                //     println!("NULL");
-                // }
-                
-
-            },
+            }
         }
-    }
 
+        Ok(rows)
+    };
+
+    let work = cancel::run(&cancel, timeout, read);
+    statement(&audit, Operation::Query, sql, &[], work, |&rows| Some(rows)).await?;
     Ok(())
 }
 
 pub async fn create_view(view_name: &str, query: &str) -> anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR_PORT)?;
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    let mut client = Client::connect(config, tcp).await?;
+    let config = default_config()?;
+    let (mut client, audit) = connect_audited(config).await?;
 
     // Construct the CREATE VIEW statement
     let create_view_sql = format!(
@@ -280,7 +419,8 @@ pub async fn create_view(view_name: &str, query: &str) -> anyhow::Result<()> {
     );
 
     // Execute the CREATE VIEW statement
-    let _result = client.execute(&create_view_sql, &[]).await?;
+    let work = client.execute(&create_view_sql, &[]);
+    let _result = statement(&audit, Operation::Execute, &create_view_sql, &[], work, |_| None).await?;
 
     println!("View '{}' created successfully", view_name);
     
@@ -289,56 +429,66 @@ pub async fn create_view(view_name: &str, query: &str) -> anyhow::Result<()> {
 }
 
 pub async fn find_table_all() -> anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR_PORT)?;
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
+    find_table_all_with(default_config()?).await
+}
 
-    let mut client = Client::connect(config, tcp).await?;
-    let select = Query::new("SELECT 
+/// [`find_table_all`] against the server in `config`.
+pub async fn find_table_all_with(config: Config) -> anyhow::Result<()> {
+    let (mut client, audit) = connect_audited(config).await?;
+    let sql = "SELECT 
                             TABLE_SCHEMA,
                             TABLE_NAME,
                             TABLE_TYPE
                             FROM INFORMATION_SCHEMA.TABLES
                             WHERE TABLE_TYPE = 'BASE TABLE'
-                            ORDER BY TABLE_SCHEMA, TABLE_NAME;");
-    let mut stream = select.query(&mut client).await?;
-
-    while let Some(row) = stream.try_next().await? {
-        match row {
-            QueryItem::Metadata(meta) => {
-                println!("Metadata: {:?}", meta);
-            },
-            QueryItem::Row(r) => {
-                // Get values from each column
-                let schema: Option<&str> = r.get(0);
-                let table_name: Option<&str> = r.get(1);
-                let table_type: Option<&str> = r.get(2);
-
-                // Print the values
-                println!("Schema: {}, Table: {}, Type: {}", 
-                    schema.unwrap_or("NULL"),
-                    table_name.unwrap_or("NULL"),
-                    table_type.unwrap_or("NULL")
-                );
+                            ORDER BY TABLE_SCHEMA, TABLE_NAME;";
+
+    let read = async {
+        let select = Query::new(sql);
+        let mut stream = select.query(&mut client).await?;
+        let mut rows = 0;
+
+        while let Some(row) = stream.try_next().await? {
+            match row {
+                QueryItem::Metadata(meta) => {
+                    println!("Metadata: {:?}", meta);
+                }
+                QueryItem::Row(r) => {
+                    rows += 1;
+
+                    // Get values from each column
+                    let schema: Option<&str> = r.get(0);
+                    let table_name: Option<&str> = r.get(1);
+                    let table_type: Option<&str> = r.get(2);
+
+                    // Print the values
+                    println!(
+                        "Schema: {}, Table: {}, Type: {}",
+                        schema.unwrap_or("NULL"),
+                        table_name.unwrap_or("NULL"),
+                        table_type.unwrap_or("NULL")
+                    );
+                }
             }
         }
-    }
 
-    
+        Ok(rows)
+    };
+
+    statement(&audit, Operation::Query, sql, &[], read, |&rows| Some(rows)).await?;
     Ok(())
 }
 
 
 //to create a stored procedure for SQL Server
+// Note: `max(id)+1` hands out the same id to concurrent callers,
+// use `insert::insert_one` with an identity column instead.
 
 async fn create_stored_procedure()->anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR)?;
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    let mut client = Client::connect(config, tcp).await?;
+    let config = config_from_ado_string(&CONN_STR)?;
+    let (mut client, audit) = connect_audited(config).await?;
     
-    let _=client.simple_query("CREATE or alter procedure register_rabbit_birth\r
+    let sql = "CREATE or alter procedure register_rabbit_birth\r
     @birth_date date,\r
     @name varchar(max)\r
 \r
@@ -349,7 +499,9 @@ as\r
       
     insert dbo.rabbit_births(id,name,date_of_birth)\r
     values(@new_id,@name,@birth_date)\r
-    ").await?;
+    ";
+    let create = async { client.simple_query(sql).await?.into_results().await };
+    let _ = statement(&audit, Operation::Execute, sql, &[], create, |_| None).await?;
    
     println!("Stored procedure created or altered");
 
@@ -359,16 +511,16 @@ as\r
 
 //to execute a stored procedure of SQL Server
 async fn execute_stored_procedure()->anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR)?;
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    let mut client = Client::connect(config, tcp).await?;
+    let config = config_from_ado_string(&CONN_STR)?;
+    let (mut client, audit) = connect_audited(config).await?;
  
-    let result = client.execute(
-        "exec dbo.register_rabbit_birth @birth_date= @P1, @name=@P2", 
-        &[&"2023-08-24", &"Lola Bunny"],
-    ).await?;
+    let sql = "exec dbo.register_rabbit_birth @birth_date= @P1, @name=@P2";
+    let params: [&dyn ToSql; 2] = [&"2023-08-24", &"Lola Bunny"];
+    let work = client.execute(sql, &params);
+    let result = statement(&audit, Operation::Execute, sql, &params, work, |r| {
+        Some(r.rows_affected().iter().sum())
+    })
+    .await?;
     
     
     println!("Rows affected: {}",result.total());
@@ -381,30 +533,34 @@ async fn execute_stored_procedure()->anyhow::Result<()> {
 //to execute a stored procedure with an output parameter
 
 async fn execute_stored_procedure_with_output_parameter()->anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR)?;
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    let mut client = Client::connect(config, tcp).await?;
+    let config = config_from_ado_string(&CONN_STR)?;
+    let (mut client, audit) = connect_audited(config).await?;
 
     //let results=client.execute("dbo.register_rabbit_birth_and_get_id @birth_date= @P1, @name=@P2, @id=@P3 OUTPUT",
     //    &[&"2023-08-24", &"Clyde Bunny", &0i32]).await?;
 
-    let mut results=client.query("
+    let sql = "
     declare @id int
 exec dbo.register_rabbit_birth_and_get_id @birth_date= @P1, @name=@P2, @id=@id OUTPUT
 select @id
-    ",
-    &[&"2023-08-24", &"Clyde Bunny"]).await?;
-
-    while let Some(row)=results.try_next().await? {
-        if let QueryItem::Row(r)=row{
-            let new_id:Option<i32>=r.get(0);
-            if let Some(value)=new_id{
-                println!("New id={:?}",value);
+    ";
+    let params: [&dyn ToSql; 2] = [&"2023-08-24", &"Clyde Bunny"];
+
+    let read = async {
+        let mut results = client.query(sql, &params).await?;
+        let mut rows = 0;
+        while let Some(row) = results.try_next().await? {
+            if let QueryItem::Row(r) = row {
+                rows += 1;
+                let new_id: Option<i32> = r.get(0);
+                if let Some(value) = new_id {
+                    println!("New id={:?}", value);
+                }
             }
         }
-    }
+        Ok(rows)
+    };
+    statement(&audit, Operation::Query, sql, &params, read, |&rows| Some(rows)).await?;
     
     Ok(())
     
@@ -413,32 +569,35 @@ select @id
 //to execute an stored procedure with a return value?
 
 async fn execute_stored_procedure_with_return_value()->anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR)?;
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    let mut client = Client::connect(config, tcp).await?;
+    let config = config_from_ado_string(&CONN_STR)?;
+    let (mut client, audit) = connect_audited(config).await?;
 
-    let mut select = Query::new("
+    let sql = "
     declare @return_value int
     exec @return_value= dbo.register_rabbit_birth_return_id @birth_date= @P1, @name=@P2
-    select @return_value");
-    select.bind("2023-08-24");
-    select.bind("Clyde Bunny");
-    
-    let mut stream=select.query(&mut client).await?;
-
-    while let Some(row)=stream.try_next().await? {
-        if let QueryItem::Row(r)=row{
-            let return_value:Option<i32>=r.get(0);
-            if let Some(value)=return_value{
-                println!("Return value: {:?}",value);
+    select @return_value";
+    let (birth_date, name) = ("2023-08-24", "Clyde Bunny");
+    let mut select = Query::new(sql);
+    select.bind(birth_date);
+    select.bind(name);
+
+    let read = async {
+        let mut stream = select.query(&mut client).await?;
+        let mut rows = 0;
+        while let Some(row) = stream.try_next().await? {
+            if let QueryItem::Row(r) = row {
+                rows += 1;
+                let return_value: Option<i32> = r.get(0);
+                if let Some(value) = return_value {
+                    println!("Return value: {:?}", value);
+                }
+            } else {
+                println!("Nothing as return value: {:?}", row);
             }
         }
-        else{
-            println!("Nothing as return value: {:?}",row);
-        }
-    }
+        Ok(rows)
+    };
+    statement(&audit, Operation::Query, sql, &[&birth_date, &name], read, |&rows| Some(rows)).await?;
     
     Ok(())
 }
@@ -460,12 +619,11 @@ async fn execute_stored_procedure_with_return_value()->anyhow::Result<()> {
 
 //to create an scalar function for SQL Server
 async fn create_scalar_function()->anyhow::Result<()> {
-    let config = Config::from_ado_string(&CONN_STR)?;
-    let tcp = TcpStream::connect(config.get_addr()).await?;
-    tcp.set_nodelay(true)?;
-
-    let mut client = Client::connect(config, tcp).await?;
-    let mut result=client.simple_query("
+    let config = config_from_ado_string(&CONN_STR)?;
+    let server = config.get_addr();
+    let (mut client, cancel) = crate::cancel::connect(config).await?;
+    let audit = audit::session(&mut client, &server).await?;
+    let sql = "
 create or alter function dbo.reverse_words\r\n
 (\r
     @original_value varchar(100)\r
@@ -507,17 +665,19 @@ begin\r
     end\r
 \r 
     return @new_value\r
-end").await?;
+end";
+    let work = crate::batch::run(&mut client, &cancel, sql);
+    let result = statement(&audit, Operation::Execute, sql, &[], work, |_| None).await?;
 
     println!("Function created or altered");
 
-    // No results
-    while let Some(row)=result.try_next().await? {
-
-        println!("Row: {:?}",row);
-        if let QueryItem::Row(value)=row{
-            println!("{:?}",value);
-        }
+    // No result sets, but there may be warnings, which an encrypted
+    // session does not let us read
+    if !result.complete {
+        eprintln!("Warnings are not shown: the session is encrypted, so messages cannot be read");
+    }
+    for message in result.messages() {
+        println!("{}", message);
     }
 
     Ok(())
@@ -0,0 +1,228 @@
+//! A SQLite [`Backend`] for local development without SQL Server.
+//!
+//! SQLite calls are synchronous; they run on the calling task, which is
+//! fine for a local file but not for a busy server.
+
+use std::sync::Arc;
+
+use futures_util::future::{self, BoxFuture};
+use futures_util::FutureExt;
+use rusqlite::types::{Value as SqliteValue, ValueRef};
+use rusqlite::{Batch, Connection, Statement};
+
+use crate::backend::Backend;
+use crate::batch::{BatchItem, BatchResult, ResultSet};
+use crate::schema::TableColumn;
+use crate::value::{Record, Value};
+
+pub struct SqliteBackend {
+    conn: Connection,
+}
+
+impl SqliteBackend {
+    /// Open or create a database file. `:memory:` opens a private
+    /// in-memory database.
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let conn = match path {
+            ":memory:" => Connection::open_in_memory()?,
+            path => Connection::open(path)?,
+        };
+        Ok(SqliteBackend { conn })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    fn prepare(&self, sql: &str, params: &[Value]) -> anyhow::Result<Statement<'_>> {
+        let mut stmt = self.conn.prepare(sql)?;
+
+        // Parameters are bound by name, so `@P2` may appear before `@P1`
+        // and unused ones are ignored, as with sp_executesql.
+        for (i, value) in params.iter().enumerate() {
+            if let Some(index) = stmt.parameter_index(&format!("@P{}", i + 1))? {
+                stmt.raw_bind_parameter(index, to_sqlite(value))?;
+            }
+        }
+
+        Ok(stmt)
+    }
+
+    fn query_sync(&self, sql: &str, params: &[Value]) -> anyhow::Result<Vec<Record>> {
+        let mut stmt = self.prepare(sql, params)?;
+        let columns: Arc<[String]> = stmt.column_names().into_iter().map(str::to_string).collect();
+        let mut rows = stmt.raw_query();
+        let mut records = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            let values = (0..columns.len())
+                .map(|i| Ok(from_sqlite(row.get_ref(i)?)))
+                .collect::<anyhow::Result<_>>()?;
+            records.push(Record::new(columns.clone(), values));
+        }
+
+        Ok(records)
+    }
+
+    /// Each statement in turn, with the rows of those that return any and
+    /// the changes of the others. SQLite has no messages, so the result
+    /// is complete.
+    fn batch_sync(&self, sql: &str) -> anyhow::Result<BatchResult> {
+        let mut statements = Batch::new(&self.conn, sql);
+        let mut items = Vec::new();
+
+        while let Some(mut stmt) = statements.next()? {
+            if stmt.column_count() == 0 {
+                items.push(BatchItem::RowsAffected(stmt.raw_execute()? as u64));
+                continue;
+            }
+
+            let columns: Arc<[String]> = stmt.column_names().into_iter().map(str::to_string).collect();
+            let mut rows = stmt.raw_query();
+            let mut records = Vec::new();
+            while let Some(row) = rows.next()? {
+                let values = (0..columns.len())
+                    .map(|i| Ok(from_sqlite(row.get_ref(i)?)))
+                    .collect::<anyhow::Result<_>>()?;
+                records.push(Record::new(columns.clone(), values));
+            }
+            items.push(BatchItem::ResultSet(ResultSet {
+                columns: columns.to_vec(),
+                rows: records,
+            }));
+        }
+
+        Ok(BatchResult { items, complete: true })
+    }
+
+    fn execute_sync(&self, sql: &str, params: &[Value]) -> anyhow::Result<u64> {
+        if params.is_empty() {
+            // Allows scripts with several statements, e.g. schema setup.
+            let before = self.conn.total_changes();
+            self.conn.execute_batch(sql)?;
+            return Ok(self.conn.total_changes() - before);
+        }
+
+        Ok(self.prepare(sql, params)?.raw_execute()? as u64)
+    }
+
+    fn columns_sync(&self, table: &str) -> anyhow::Result<Vec<TableColumn>> {
+        let rows = self.query_sync(
+            "SELECT name, type, \"notnull\", pk, hidden FROM pragma_table_xinfo(@P1)",
+            &[Value::String(table.to_string())],
+        )?;
+
+        if rows.is_empty() {
+            anyhow::bail!("Table {} does not exist", table);
+        }
+
+        let int = |record: &Record, column: &str| match record.get(column) {
+            Some(Value::Int(v)) => *v,
+            _ => 0,
+        };
+        let key_columns = rows.iter().filter(|r| int(r, "pk") > 0).count();
+
+        Ok(rows
+            .iter()
+            .map(|r| {
+                let type_name = match r.get("type") {
+                    Some(Value::String(t)) => t.to_lowercase(),
+                    _ => String::new(),
+                };
+                let key_ordinal = u8::try_from(int(r, "pk")).ok().filter(|k| *k > 0);
+
+                TableColumn {
+                    name: r.get("name").map(|v| v.to_string()).unwrap_or_default(),
+                    // A single INTEGER PRIMARY KEY is an alias of the rowid
+                    // and is generated like an identity column.
+                    is_identity: type_name == "integer" && key_ordinal.is_some() && key_columns == 1,
+                    type_name,
+                    is_nullable: int(r, "notnull") == 0 && key_ordinal.is_none(),
+                    // Generated columns are hidden 2 (virtual) or 3 (stored).
+                    is_computed: matches!(int(r, "hidden"), 2 | 3),
+                    key_ordinal,
+                }
+            })
+            .collect())
+    }
+}
+
+impl Backend for SqliteBackend {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn query<'a>(
+        &'a mut self,
+        sql: &'a str,
+        params: &'a [Value],
+    ) -> BoxFuture<'a, anyhow::Result<Vec<Record>>> {
+        future::ready(self.query_sync(sql, params)).boxed()
+    }
+
+    fn execute<'a>(&'a mut self, sql: &'a str, params: &'a [Value]) -> BoxFuture<'a, anyhow::Result<u64>> {
+        future::ready(self.execute_sync(sql, params)).boxed()
+    }
+
+    fn batch<'a>(&'a mut self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<BatchResult>> {
+        future::ready(self.batch_sync(sql)).boxed()
+    }
+
+    fn tables(&mut self) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+        let tables = self
+            .query_sync(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+                &[],
+            )
+            .map(|rows| rows.iter().filter_map(|r| r.get("name")).map(|v| v.to_string()).collect());
+        future::ready(tables).boxed()
+    }
+
+    fn columns<'a>(&'a mut self, table: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<TableColumn>>> {
+        future::ready(self.columns_sync(table)).boxed()
+    }
+
+    fn begin(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        future::ready(self.conn.execute_batch("BEGIN").map_err(Into::into)).boxed()
+    }
+
+    fn rollback(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        let result = match self.conn.is_autocommit() {
+            true => Ok(()),
+            false => self.conn.execute_batch("ROLLBACK").map_err(Into::into),
+        };
+        future::ready(result).boxed()
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, anyhow::Result<()>> {
+        let result = self.conn.close().map_err(|(_, e)| e.into());
+        future::ready(result).boxed()
+    }
+}
+
+/// SQLite has no date, decimal or GUID types; those are stored as text in
+/// the formats SQLite's date functions understand.
+fn to_sqlite(value: &Value) -> SqliteValue {
+    match value {
+        Value::Null => SqliteValue::Null,
+        Value::Bool(v) => SqliteValue::Integer((*v).into()),
+        Value::Int(v) => SqliteValue::Integer(*v),
+        Value::Float(v) => SqliteValue::Real(*v),
+        Value::String(v) => SqliteValue::Text(v.clone()),
+        Value::Binary(v) => SqliteValue::Blob(v.clone()),
+        Value::DateTimeOffset(v) => SqliteValue::Text(v.to_rfc3339()),
+        Value::Decimal(_) | Value::Guid(_) | Value::Date(_) | Value::Time(_) | Value::DateTime(_) => {
+            SqliteValue::Text(value.to_string())
+        }
+    }
+}
+
+fn from_sqlite(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(v) => Value::Int(v),
+        ValueRef::Real(v) => Value::Float(v),
+        ValueRef::Text(v) => Value::String(String::from_utf8_lossy(v).into_owned()),
+        ValueRef::Blob(v) => Value::Binary(v.to_vec()),
+    }
+}
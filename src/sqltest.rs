@@ -0,0 +1,459 @@
+//! Unit tests for procedures and functions, written as `.sql` files.
+//!
+//! A file holds one or more tests. Each starts with `-- test: <name>` and
+//! is made of blocks introduced by marker comments:
+//!
+//! ```text
+//! -- test: register_rabbit_birth stores the rabbit
+//! -- setup
+//! DELETE FROM dbo.rabbit_births;
+//! -- action
+//! EXEC dbo.register_rabbit_birth @birth_date = '2023-08-24', @name = 'Lola Bunny';
+//! -- verify
+//! SELECT name, date_of_birth FROM dbo.rabbit_births;
+//! -- expect
+//! | name       | date_of_birth |
+//! | Lola Bunny | 2023-08-24    |
+//! ```
+//!
+//! - `-- setup` runs first and is optional.
+//! - `-- action` is the code under test.
+//! - `-- verify` optionally runs after the action; its rows are checked
+//!   instead of the action's, e.g. to read a table a procedure changed.
+//! - `-- expect` is followed by the expected rows as a `|`-separated table,
+//!   `-- expect csv` by the rows as CSV with a header line, and
+//!   `-- expect error [code]` expects the action to fail.
+//!
+//! Rows are compared in order against the last result set, by their text
+//! form, with `NULL` for nulls. Every test runs in a transaction that is
+//! rolled back, so tests cannot see each other's changes.
+//!
+//! Tests run on any [`Backend`]; error numbers are only known on SQL
+//! Server, so elsewhere `-- expect error` should not give one.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::backend::Backend;
+use crate::telemetry;
+use crate::value::{Record, Value};
+
+/// What a test expects the action to produce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expectation {
+    /// The last result set, in order.
+    Rows {
+        columns: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+    /// An error, with this server error number if given.
+    Error(Option<u32>),
+    /// Only that nothing fails.
+    Success,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqlTest {
+    pub name: String,
+    pub file: PathBuf,
+    /// Line of the `-- test:` marker, starting at 1.
+    pub line: usize,
+    pub setup: String,
+    pub action: String,
+    pub verify: Option<String>,
+    pub expect: Expectation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Setup,
+    Action,
+    Verify,
+    Table,
+    Csv,
+}
+
+/// Parse the tests in the text of one file.
+pub fn parse_tests(file: &Path, text: &str) -> anyhow::Result<Vec<SqlTest>> {
+    let mut tests: Vec<SqlTest> = Vec::new();
+    let mut blocks: Vec<(Block, String)> = Vec::new();
+    let mut current: Option<(String, usize)> = None;
+    let mut error: Option<Option<u32>> = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let marker = line.trim().strip_prefix("--").map(|m| m.trim().trim_end_matches(':'));
+
+        let block = match marker {
+            Some(m) if m.to_lowercase().starts_with("test:") => {
+                if let Some((name, at)) = current.take() {
+                    tests.push(build_test(file, name, at, &blocks, error.take())?);
+                }
+                blocks.clear();
+                current = Some((m[5..].trim().to_string(), number + 1));
+                continue;
+            }
+            Some(m) => match m.to_lowercase().as_str() {
+                "setup" => Some(Block::Setup),
+                "action" => Some(Block::Action),
+                "verify" => Some(Block::Verify),
+                "expect" => Some(Block::Table),
+                "expect csv" => Some(Block::Csv),
+                m if m.starts_with("expect error") => {
+                    let code = m["expect error".len()..].trim();
+                    error = Some(match code {
+                        "" => None,
+                        code => Some(code.parse().map_err(|_| {
+                            anyhow::anyhow!("{}:{}: invalid error number {:?}", file.display(), number + 1, code)
+                        })?),
+                    });
+                    continue;
+                }
+                _ => None,
+            },
+            None => None,
+        };
+
+        match (block, blocks.last_mut()) {
+            (Some(block), _) => {
+                if current.is_none() {
+                    anyhow::bail!("{}:{}: block before the first `-- test:`", file.display(), number + 1);
+                }
+                blocks.push((block, String::new()));
+            }
+            (None, Some((_, body))) => {
+                body.push_str(line);
+                body.push('\n');
+            }
+            (None, None) => (),
+        }
+    }
+
+    if let Some((name, at)) = current {
+        tests.push(build_test(file, name, at, &blocks, error)?);
+    }
+
+    Ok(tests)
+}
+
+fn build_test(
+    file: &Path,
+    name: String,
+    line: usize,
+    blocks: &[(Block, String)],
+    error: Option<Option<u32>>,
+) -> anyhow::Result<SqlTest> {
+    let block = |kind: Block| {
+        blocks
+            .iter()
+            .filter(|(b, _)| *b == kind)
+            .map(|(_, body)| body.as_str())
+            .collect::<String>()
+    };
+
+    let action = block(Block::Action);
+    if action.trim().is_empty() {
+        anyhow::bail!("{}:{}: test {:?} has no action", file.display(), line, name);
+    }
+    let verify = Some(block(Block::Verify)).filter(|v| !v.trim().is_empty());
+
+    let has = |kind: Block| blocks.iter().any(|(b, _)| *b == kind);
+    let expect = match error {
+        Some(code) => Expectation::Error(code),
+        None if has(Block::Csv) => parse_csv(&block(Block::Csv))?,
+        None if has(Block::Table) => parse_table(&block(Block::Table)),
+        None => Expectation::Success,
+    };
+
+    Ok(SqlTest {
+        name,
+        file: file.to_path_buf(),
+        line,
+        setup: block(Block::Setup),
+        action,
+        verify,
+        expect,
+    })
+}
+
+/// `| a | b |` lines; the first is the header, `|---|` separators are
+/// skipped.
+fn parse_table(text: &str) -> Expectation {
+    let mut lines = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.trim_matches(|c| c == '|' || c == '-' || c == '+' || c == ' ').is_empty())
+        .map(|l| {
+            l.trim_matches('|')
+                .split('|')
+                .map(|cell| cell.trim().to_string())
+                .collect::<Vec<_>>()
+        });
+
+    Expectation::Rows {
+        columns: lines.next().unwrap_or_default(),
+        rows: lines.collect(),
+    }
+}
+
+fn parse_csv(text: &str) -> anyhow::Result<Expectation> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(text.trim().as_bytes());
+
+    let columns = reader.headers()?.iter().map(str::to_string).collect();
+    let rows = reader
+        .records()
+        .map(|r| Ok(r?.iter().map(str::to_string).collect()))
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(Expectation::Rows { columns, rows })
+}
+
+/// Every test in the `.sql` files under `dir`, in path order.
+pub fn discover(dir: &Path) -> anyhow::Result<Vec<SqlTest>> {
+    let mut files = Vec::new();
+    collect_sql_files(dir, &mut files)?;
+    files.sort();
+
+    let mut tests = Vec::new();
+    for file in files {
+        tests.extend(parse_tests(&file, &fs::read_to_string(&file)?)?);
+    }
+
+    Ok(tests)
+}
+
+fn collect_sql_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    if dir.is_file() {
+        files.push(dir.to_path_buf());
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_sql_files(&path, files)?;
+        } else if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("sql")) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// The code ran but did not produce what the test expects.
+    Failed(String),
+    /// The test could not run, e.g. its setup failed.
+    Errored(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub test: SqlTest,
+    pub outcome: Outcome,
+    pub duration: Duration,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Passed
+    }
+}
+
+/// Run one test inside a transaction and roll it back.
+pub async fn run_test(db: &mut dyn Backend, test: &SqlTest) -> TestResult {
+    let started = Instant::now();
+
+    let outcome = match db.begin().await {
+        Ok(()) => run_in_transaction(db, test).await,
+        Err(e) => Outcome::Errored(format!("Starting the transaction failed: {}", e)),
+    };
+
+    let rollback = db.rollback().await;
+    let outcome = match (outcome, rollback) {
+        (Outcome::Passed, Err(e)) => Outcome::Errored(format!("Rollback failed: {}", e)),
+        (outcome, _) => outcome,
+    };
+
+    TestResult {
+        test: test.clone(),
+        outcome,
+        duration: started.elapsed(),
+    }
+}
+
+async fn run_in_transaction(db: &mut dyn Backend, test: &SqlTest) -> Outcome {
+    if !test.setup.trim().is_empty() {
+        if let Err(e) = last_result_set(db, &test.setup).await {
+            return Outcome::Errored(format!("Setup failed: {}", e));
+        }
+    }
+
+    let action = last_result_set(db, &test.action).await;
+    let rows = match (&test.expect, action) {
+        (Expectation::Error(expected), Err(e)) => {
+            return match (expected, telemetry::error_code(&e)) {
+                (Some(expected), Some(code)) if *expected != code => {
+                    Outcome::Failed(format!("Expected error {}, got {}: {}", expected, code, e))
+                }
+                (Some(_), None) => Outcome::Failed(format!("Expected a server error, got: {}", e)),
+                _ => Outcome::Passed,
+            };
+        }
+        (Expectation::Error(expected), Ok(_)) => {
+            let expected = expected.map(|c| format!(" {}", c)).unwrap_or_default();
+            return Outcome::Failed(format!("Expected error{} but the action succeeded", expected));
+        }
+        (_, Err(e)) => return Outcome::Errored(format!("Action failed: {}", e)),
+        (_, Ok(rows)) => rows,
+    };
+
+    let rows = match &test.verify {
+        Some(verify) => match last_result_set(db, verify).await {
+            Ok(rows) => rows,
+            Err(e) => return Outcome::Errored(format!("Verify failed: {}", e)),
+        },
+        None => rows,
+    };
+
+    match &test.expect {
+        Expectation::Rows { columns, rows: expected } => compare_rows(columns, expected, &rows),
+        _ => Outcome::Passed,
+    }
+}
+
+/// Run a batch and return the rows of its last result set.
+async fn last_result_set(db: &mut dyn Backend, sql: &str) -> anyhow::Result<Vec<Record>> {
+    let result = db.batch(sql).await?;
+    Ok(result.result_sets().last().map(|set| set.rows.clone()).unwrap_or_default())
+}
+
+fn compare_rows(columns: &[String], expected: &[Vec<String>], actual: &[Record]) -> Outcome {
+    if let Some(first) = actual.first() {
+        let names = first.columns();
+        if names.len() != columns.len() || names.iter().zip(columns).any(|(a, e)| !a.eq_ignore_ascii_case(e)) {
+            return Outcome::Failed(format!("Expected columns {:?}, got {:?}", columns, names));
+        }
+    }
+
+    for (i, (want, got)) in expected.iter().zip(actual).enumerate() {
+        let got: Vec<String> = got.values().iter().map(Value::to_string).collect();
+        if *want != got {
+            return Outcome::Failed(format!("Row {}: expected {:?}, got {:?}", i + 1, want, got));
+        }
+    }
+
+    if expected.len() != actual.len() {
+        return Outcome::Failed(format!("Expected {} rows, got {}", expected.len(), actual.len()));
+    }
+
+    Outcome::Passed
+}
+
+/// Run tests one after another on one connection.
+pub async fn run_all(db: &mut dyn Backend, tests: &[SqlTest]) -> Vec<TestResult> {
+    let mut results = Vec::with_capacity(tests.len());
+    for test in tests {
+        results.push(run_test(db, test).await);
+    }
+    results
+}
+
+/// Test Anything Protocol, version 13.
+pub fn tap_report(results: &[TestResult]) -> String {
+    let mut out = format!("TAP version 13\n1..{}\n", results.len());
+
+    for (i, result) in results.iter().enumerate() {
+        let message = match &result.outcome {
+            Outcome::Passed => {
+                let _ = writeln!(out, "ok {} - {}", i + 1, result.test.name);
+                continue;
+            }
+            Outcome::Failed(m) | Outcome::Errored(m) => m,
+        };
+
+        let _ = writeln!(out, "not ok {} - {}", i + 1, result.test.name);
+        let _ = writeln!(out, "  ---");
+        let _ = writeln!(out, "  message: {:?}", message);
+        let _ = writeln!(out, "  severity: {}", if matches!(result.outcome, Outcome::Errored(_)) { "error" } else { "fail" });
+        let _ = writeln!(out, "  at: \"{}:{}\"", result.test.file.display(), result.test.line);
+        let _ = writeln!(out, "  ...");
+    }
+
+    out
+}
+
+/// JUnit XML, one test suite per file.
+pub fn junit_report(results: &[TestResult]) -> String {
+    let count = |f: fn(&Outcome) -> bool| results.iter().filter(|r| f(&r.outcome)).count();
+    let total: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        out,
+        "<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+        results.len(),
+        count(|o| matches!(o, Outcome::Failed(_))),
+        count(|o| matches!(o, Outcome::Errored(_))),
+        total
+    );
+
+    let mut files: Vec<&PathBuf> = results.iter().map(|r| &r.test.file).collect();
+    files.dedup();
+
+    for file in files {
+        let suite: Vec<&TestResult> = results.iter().filter(|r| &r.test.file == file).collect();
+        let suite_name = file.display().to_string();
+        let _ = writeln!(
+            out,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+            xml_escape(&suite_name),
+            suite.len(),
+            suite.iter().filter(|r| matches!(r.outcome, Outcome::Failed(_))).count(),
+            suite.iter().filter(|r| matches!(r.outcome, Outcome::Errored(_))).count(),
+            suite.iter().map(|r| r.duration.as_secs_f64()).sum::<f64>()
+        );
+
+        for result in suite {
+            let _ = write!(
+                out,
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                xml_escape(&result.test.name),
+                xml_escape(&suite_name),
+                result.duration.as_secs_f64()
+            );
+            match &result.outcome {
+                Outcome::Passed => out.push_str("/>\n"),
+                Outcome::Failed(m) | Outcome::Errored(m) => {
+                    let tag = if matches!(result.outcome, Outcome::Failed(_)) { "failure" } else { "error" };
+                    let _ = writeln!(
+                        out,
+                        ">\n      <{} message=\"{}\">{}:{}</{}>\n    </testcase>",
+                        tag,
+                        xml_escape(m),
+                        xml_escape(&suite_name),
+                        result.test.line,
+                        tag
+                    );
+                }
+            }
+        }
+
+        out.push_str("  </testsuite>\n");
+    }
+
+    out.push_str("</testsuites>\n");
+    out
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,249 @@
+//! `SET STATISTICS IO, TIME ON` output: the info messages the server sends
+//! after each statement, read into reads per table and CPU and elapsed
+//! time per statement.
+//!
+//! The messages are told apart by number, and times are the numbers in
+//! the text, so they are read in any language. The names of the read
+//! counts are only known in English.
+//!
+//! Messages only arrive with batches, see [`crate::batch`].
+
+use std::fmt;
+
+use tracing::Span;
+
+use crate::batch::Message;
+
+/// `Table 'x'. Scan count 1, logical reads 2, ...`
+pub const TABLE_IO: u32 = 3615;
+/// `SQL Server Execution Times: CPU time = 1 ms, elapsed time = 2 ms.`
+pub const EXECUTION_TIMES: u32 = 3612;
+/// `SQL Server parse and compile time: CPU time = 1 ms, elapsed time = 2 ms.`
+pub const COMPILE_TIME: u32 = 3613;
+
+/// The reads of one statement from one table, including worktables.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableIo {
+    pub table: String,
+    pub scan_count: u64,
+    pub logical_reads: u64,
+    pub physical_reads: u64,
+    pub read_ahead_reads: u64,
+    pub lob_logical_reads: u64,
+    pub lob_physical_reads: u64,
+    pub lob_read_ahead_reads: u64,
+}
+
+impl TableIo {
+    fn parse(text: &str) -> Option<TableIo> {
+        let start = text.find('\'')? + 1;
+        let end = start + text[start..].rfind("'.")?;
+        let mut io = TableIo {
+            table: text[start..end].to_string(),
+            ..TableIo::default()
+        };
+        for part in text[end + 2..].split(',') {
+            let part = part.trim().trim_end_matches('.');
+            let Some((name, count)) = part.rsplit_once(' ') else {
+                continue;
+            };
+            let Ok(count) = count.parse() else {
+                continue;
+            };
+            match name.to_lowercase().as_str() {
+                "scan count" => io.scan_count = count,
+                "logical reads" => io.logical_reads = count,
+                "physical reads" => io.physical_reads = count,
+                "read-ahead reads" => io.read_ahead_reads = count,
+                "lob logical reads" => io.lob_logical_reads = count,
+                "lob physical reads" => io.lob_physical_reads = count,
+                "lob read-ahead reads" => io.lob_read_ahead_reads = count,
+                _ => {}
+            }
+        }
+        Some(io)
+    }
+}
+
+impl fmt::Display for TableIo {
+    /// `LOB` reads only when there are some.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} scans, {} logical reads, {} physical reads, {} read-ahead reads",
+            self.table, self.scan_count, self.logical_reads, self.physical_reads, self.read_ahead_reads
+        )?;
+        if self.lob_logical_reads + self.lob_physical_reads + self.lob_read_ahead_reads > 0 {
+            write!(
+                f,
+                ", {} lob logical reads, {} lob physical reads, {} lob read-ahead reads",
+                self.lob_logical_reads, self.lob_physical_reads, self.lob_read_ahead_reads
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The statistics of one statement. A statement's reads are sent before
+/// its times; either is missing when only the other option is on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatementStats {
+    pub tables: Vec<TableIo>,
+    pub cpu_ms: Option<u64>,
+    pub elapsed_ms: Option<u64>,
+}
+
+impl StatementStats {
+    pub fn logical_reads(&self) -> u64 {
+        self.tables.iter().map(|t| t.logical_reads).sum()
+    }
+
+    pub fn physical_reads(&self) -> u64 {
+        self.tables.iter().map(|t| t.physical_reads).sum()
+    }
+}
+
+impl fmt::Display for StatementStats {
+    /// A line per table, then the times.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for table in &self.tables {
+            writeln!(f, "{}", table)?;
+        }
+        if let (Some(cpu), Some(elapsed)) = (self.cpu_ms, self.elapsed_ms) {
+            writeln!(f, "CPU time {} ms, elapsed time {} ms", cpu, elapsed)?;
+        }
+        Ok(())
+    }
+}
+
+/// The statistics of a batch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// Summed over the batch and any recompiles in it.
+    pub compile_cpu_ms: Option<u64>,
+    pub compile_elapsed_ms: Option<u64>,
+    pub statements: Vec<StatementStats>,
+    /// Whether the last statement may still get its times.
+    open: bool,
+}
+
+impl Statistics {
+    pub fn from_messages<'a>(messages: impl IntoIterator<Item = &'a Message>) -> Statistics {
+        let mut stats = Statistics::default();
+        for message in messages {
+            stats.add(message);
+        }
+        stats
+    }
+
+    /// Take in `message` if it is a statistics message, and say whether
+    /// it was.
+    pub fn add(&mut self, message: &Message) -> bool {
+        match message.number {
+            TABLE_IO => {
+                let Some(io) = TableIo::parse(&message.text) else {
+                    return false;
+                };
+                if !self.open {
+                    self.statements.push(StatementStats::default());
+                    self.open = true;
+                }
+                self.statements.last_mut().unwrap().tables.push(io);
+            }
+            EXECUTION_TIMES | COMPILE_TIME => {
+                let [cpu, elapsed] = numbers(&message.text)[..] else {
+                    return false;
+                };
+                if message.number == COMPILE_TIME {
+                    self.compile_cpu_ms = Some(self.compile_cpu_ms.unwrap_or(0) + cpu);
+                    self.compile_elapsed_ms = Some(self.compile_elapsed_ms.unwrap_or(0) + elapsed);
+                    return true;
+                }
+                if !self.open {
+                    self.statements.push(StatementStats::default());
+                }
+                let statement = self.statements.last_mut().unwrap();
+                statement.cpu_ms = Some(cpu);
+                statement.elapsed_ms = Some(elapsed);
+                self.open = false;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The statements that have had their times. With only
+    /// `STATISTICS IO` on, the last one never does.
+    pub fn completed(&self) -> &[StatementStats] {
+        &self.statements[..self.statements.len() - self.open as usize]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty() && self.compile_cpu_ms.is_none()
+    }
+
+    pub fn logical_reads(&self) -> u64 {
+        self.statements.iter().map(StatementStats::logical_reads).sum()
+    }
+
+    pub fn physical_reads(&self) -> u64 {
+        self.statements.iter().map(StatementStats::physical_reads).sum()
+    }
+
+    /// Execution time, without parse and compile time.
+    pub fn cpu_ms(&self) -> u64 {
+        self.statements.iter().filter_map(|s| s.cpu_ms).sum()
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        self.statements.iter().filter_map(|s| s.elapsed_ms).sum()
+    }
+
+    /// One line for the whole batch, to compare variants of a query by.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} logical reads, {} physical reads, CPU time {} ms, elapsed time {} ms",
+            self.logical_reads(),
+            self.physical_reads(),
+            self.cpu_ms(),
+            self.elapsed_ms()
+        );
+        if let (Some(cpu), Some(elapsed)) = (self.compile_cpu_ms, self.compile_elapsed_ms) {
+            summary.push_str(&format!(
+                " (parse and compile: CPU time {} ms, elapsed time {} ms)",
+                cpu, elapsed
+            ));
+        }
+        summary
+    }
+
+    /// Record the totals on a statement span, see [`crate::telemetry`],
+    /// with an event per table read.
+    pub fn record(&self, span: &Span) {
+        if self.is_empty() || span.is_disabled() {
+            return;
+        }
+        span.record("db.logical_reads", self.logical_reads());
+        span.record("db.physical_reads", self.physical_reads());
+        span.record("db.cpu_ms", self.cpu_ms());
+        span.record("db.elapsed_ms", self.elapsed_ms());
+        span.in_scope(|| {
+            for table in self.statements.iter().flat_map(|s| &s.tables) {
+                tracing::info!(
+                    table = %table.table,
+                    scan_count = table.scan_count,
+                    logical_reads = table.logical_reads,
+                    physical_reads = table.physical_reads,
+                    read_ahead_reads = table.read_ahead_reads,
+                    "table io"
+                );
+            }
+        });
+    }
+}
+
+fn numbers(text: &str) -> Vec<u64> {
+    text.split(|c: char| !c.is_ascii_digit())
+        .filter_map(|n| n.parse().ok())
+        .collect()
+}
@@ -0,0 +1,462 @@
+//! The pieces of the TDS wire protocol this crate reads and writes itself:
+//! packet and token types, message framing, PRELOGIN and the decoding of
+//! batches and RPC calls.
+//!
+//! tiberius speaks the protocol for every connection; this module is for
+//! what it does not expose, such as cancelling a statement, reading row
+//! counts and messages, checking a server step by step, and the test
+//! servers in [`crate::mock_server`] and [`crate::replay`].
+
+use std::ops::Range;
+
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use chrono::{Duration, FixedOffset, NaiveDate, NaiveTime, TimeZone};
+use tiberius::numeric::Numeric;
+use tiberius::Uuid;
+
+use crate::value::Value;
+
+pub(crate) const HEADER_LEN: usize = 8;
+/// The largest packet sent before the server agrees on a packet size.
+pub(crate) const PACKET_SIZE: usize = 4096;
+pub(crate) const STATUS_EOM: u8 = 0x01;
+
+// Packet types.
+pub(crate) const PACKET_SQL_BATCH: u8 = 1;
+pub(crate) const PACKET_RPC: u8 = 3;
+pub(crate) const PACKET_TABULAR_RESULT: u8 = 4;
+pub(crate) const PACKET_ATTENTION: u8 = 6;
+pub(crate) const PACKET_BULK_LOAD: u8 = 7;
+pub(crate) const PACKET_LOGIN7: u8 = 16;
+pub(crate) const PACKET_PRELOGIN: u8 = 18;
+
+// Token types.
+pub(crate) const TOKEN_RETURN_STATUS: u8 = 0x79;
+pub(crate) const TOKEN_COLMETADATA: u8 = 0x81;
+pub(crate) const TOKEN_ERROR: u8 = 0xAA;
+pub(crate) const TOKEN_INFO: u8 = 0xAB;
+pub(crate) const TOKEN_LOGINACK: u8 = 0xAD;
+pub(crate) const TOKEN_ROW: u8 = 0xD1;
+pub(crate) const TOKEN_ENVCHANGE: u8 = 0xE3;
+pub(crate) const TOKEN_DONE: u8 = 0xFD;
+pub(crate) const TOKEN_DONEPROC: u8 = 0xFE;
+pub(crate) const TOKEN_DONEINPROC: u8 = 0xFF;
+// Tokens the mock server does not send.
+pub(crate) const TOKEN_TABNAME: u8 = 0xA4;
+pub(crate) const TOKEN_COLINFO: u8 = 0xA5;
+pub(crate) const TOKEN_ORDER: u8 = 0xA9;
+pub(crate) const TOKEN_RETURNVALUE: u8 = 0xAC;
+pub(crate) const TOKEN_NBCROW: u8 = 0xD2;
+pub(crate) const TOKEN_SESSIONSTATE: u8 = 0xE4;
+pub(crate) const TOKEN_SSPI: u8 = 0xED;
+
+// DONE status bits.
+pub(crate) const DONE_MORE: u16 = 0x01;
+pub(crate) const DONE_ERROR: u16 = 0x02;
+pub(crate) const DONE_COUNT: u16 = 0x10;
+pub(crate) const DONE_ATTENTION: u16 = 0x20;
+
+// PRELOGIN encryption settings.
+pub(crate) const ENCRYPT_OFF: u8 = 0x00;
+pub(crate) const ENCRYPT_ON: u8 = 0x01;
+pub(crate) const ENCRYPT_NOT_SUP: u8 = 0x02;
+pub(crate) const ENCRYPT_REQ: u8 = 0x03;
+
+const SP_EXECUTESQL: u16 = 10;
+
+/// Read packets until the end of a message. `None` when the client closed
+/// the connection.
+pub(crate) async fn read_message<S: Read + Unpin>(stream: &mut S) -> anyhow::Result<Option<(u8, Vec<u8>)>> {
+    let mut body = Vec::new();
+
+    loop {
+        let mut header = [0u8; HEADER_LEN];
+        match stream.read_exact(&mut header).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && body.is_empty() => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let payload = len
+            .checked_sub(HEADER_LEN)
+            .ok_or_else(|| anyhow::anyhow!("Invalid packet length {}", len))?;
+
+        let start = body.len();
+        body.resize(start + payload, 0);
+        stream.read_exact(&mut body[start..]).await?;
+
+        if header[1] & STATUS_EOM != 0 {
+            return Ok(Some((header[0], body)));
+        }
+    }
+}
+
+/// Send `body` as a message of `packet_type`, split into packets.
+pub(crate) async fn write_message<S: Write + Unpin>(
+    stream: &mut S,
+    packet_type: u8,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let chunks: Vec<&[u8]> = if body.is_empty() {
+        vec![body]
+    } else {
+        body.chunks(PACKET_SIZE - HEADER_LEN).collect()
+    };
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let status = if i + 1 == chunks.len() { STATUS_EOM } else { 0 };
+        let len = (chunk.len() + HEADER_LEN) as u16;
+
+        let mut packet = Vec::with_capacity(len as usize);
+        packet.extend_from_slice(&[packet_type, status]);
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, (i + 1) as u8, 0]);
+        packet.extend_from_slice(chunk);
+
+        stream.write_all(&packet).await?;
+    }
+
+    stream.flush().await?;
+    Ok(())
+}
+
+/// What a server said about itself in its PRELOGIN reply.
+pub(crate) struct ServerInfo {
+    pub version: String,
+    pub encryption: u8,
+    /// The reply itself.
+    pub reply: Vec<u8>,
+}
+
+/// Encode PRELOGIN options, `(token, value)` in order.
+pub(crate) fn prelogin_body(options: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut data = Vec::new();
+    let mut offset = options.len() * 5 + 1;
+
+    for (token, value) in options {
+        body.push(*token);
+        body.extend_from_slice(&(offset as u16).to_be_bytes());
+        body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        data.extend_from_slice(value);
+        offset += value.len();
+    }
+    body.push(0xFF);
+    body.extend_from_slice(&data);
+
+    body
+}
+
+/// The value of the PRELOGIN option `token`, if the message has it.
+pub(crate) fn prelogin_option(body: &[u8], token: u8) -> Option<&[u8]> {
+    prelogin_range(body, token).and_then(|range| body.get(range))
+}
+
+/// Where the value of the PRELOGIN option `token` is in the message.
+pub(crate) fn prelogin_range(body: &[u8], token: u8) -> Option<Range<usize>> {
+    body.chunks(5)
+        .take_while(|o| o[0] != 0xFF)
+        .find(|o| o.len() == 5 && o[0] == token)
+        .map(|o| {
+            let offset = u16::from_be_bytes([o[1], o[2]]) as usize;
+            let len = u16::from_be_bytes([o[3], o[4]]) as usize;
+            offset..offset + len
+        })
+}
+
+/// Send a PRELOGIN offering `encryption` and read the server's version and
+/// encryption setting.
+pub(crate) async fn prelogin<S: Read + Write + Unpin>(stream: &mut S, encryption: u8) -> anyhow::Result<ServerInfo> {
+    let body = prelogin_body(&[(0, &[0, 0, 0, 0, 0, 0]), (1, &[encryption]), (2, &[0]), (4, &[0])]);
+    write_message(stream, PACKET_PRELOGIN, &body).await?;
+
+    let (_, reply) = read_message(stream)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("The server closed the connection"))?;
+
+    let version = match prelogin_option(&reply, 0) {
+        Some([major, minor, build_hi, build_lo, ..]) => {
+            format!("{}.{}.{}", major, minor, u16::from_be_bytes([*build_hi, *build_lo]))
+        }
+        _ => anyhow::bail!("The PRELOGIN reply has no version"),
+    };
+    let encryption = match prelogin_option(&reply, 1) {
+        Some([encryption]) => *encryption,
+        _ => anyhow::bail!("The PRELOGIN reply has no encryption setting"),
+    };
+
+    Ok(ServerInfo { version, encryption, reply })
+}
+
+pub(crate) fn parse_batch(body: &[u8]) -> anyhow::Result<String> {
+    let mut r = Reader::new(body);
+    r.skip_all_headers()?;
+    Ok(utf16(r.rest()))
+}
+
+/// The statement and parameter values of an `sp_executesql` call. Other
+/// procedures are recorded by name with all of their parameters.
+pub(crate) fn parse_rpc(body: &[u8]) -> anyhow::Result<(String, Vec<Value>)> {
+    let mut r = Reader::new(body);
+    r.skip_all_headers()?;
+
+    let name_len = r.u16()?;
+    let procedure = if name_len == 0xFFFF {
+        match r.u16()? {
+            SP_EXECUTESQL => None,
+            id => Some(format!("procedure {}", id)),
+        }
+    } else {
+        Some(utf16(r.take(name_len as usize * 2)?))
+    };
+    let _flags = r.u16()?;
+
+    // Parameters are decoded on a best-effort basis: types the mock does
+    // not know end the list.
+    let mut params = Vec::new();
+    while !r.rest().is_empty() {
+        match r.param() {
+            Ok(value) => params.push(value),
+            Err(_) => break,
+        }
+    }
+
+    match procedure {
+        Some(name) => Ok((name, params)),
+        None => {
+            let mut params = params.into_iter();
+            let sql = match params.next() {
+                Some(Value::String(sql)) => sql,
+                _ => anyhow::bail!("sp_executesql call without a statement"),
+            };
+            // Skip the parameter declarations.
+            params.next();
+            Ok((sql, params.collect()))
+        }
+    }
+}
+
+/// Reads the little-endian fields of a TDS message.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    pub(crate) fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| anyhow::anyhow!("Message ends early at byte {}", self.pos))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+
+    pub(crate) fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    /// Little-endian unsigned integer of up to 8 bytes.
+    pub(crate) fn uint(&mut self, n: usize) -> anyhow::Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes[..n].copy_from_slice(self.take(n)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn skip_all_headers(&mut self) -> anyhow::Result<()> {
+        let total = self.u32()? as usize;
+        self.take(total.saturating_sub(4))?;
+        Ok(())
+    }
+
+    pub(crate) fn b_varchar(&mut self) -> anyhow::Result<String> {
+        let chars = self.u8()? as usize;
+        Ok(utf16(self.take(chars * 2)?))
+    }
+
+    pub(crate) fn us_varchar(&mut self) -> anyhow::Result<String> {
+        let chars = self.u16()? as usize;
+        Ok(utf16(self.take(chars * 2)?))
+    }
+
+    /// A length-prefixed value, or a PLP stream when the type was declared
+    /// with a maximum length of 0xFFFF.
+    fn var_bytes(&mut self, max_len: u16) -> anyhow::Result<Option<Vec<u8>>> {
+        if max_len != 0xFFFF {
+            return match self.u16()? {
+                0xFFFF => Ok(None),
+                len => Ok(Some(self.take(len as usize)?.to_vec())),
+            };
+        }
+
+        if self.u64()? == u64::MAX {
+            return Ok(None);
+        }
+        let mut bytes = Vec::new();
+        loop {
+            match self.u32()? {
+                0 => return Ok(Some(bytes)),
+                len => bytes.extend_from_slice(self.take(len as usize)?),
+            }
+        }
+    }
+
+    /// One RPC parameter: name, status flags, TYPE_INFO and value.
+    fn param(&mut self) -> anyhow::Result<Value> {
+        let _name = self.b_varchar()?;
+        let _status = self.u8()?;
+        let ty = self.u8()?;
+
+        let value = match ty {
+            // Untyped null.
+            0x1F => Value::Null,
+            // INTN, BITN, FLTN
+            0x26 | 0x68 | 0x6D => {
+                let _max_len = self.u8()?;
+                match (ty, self.u8()?) {
+                    (_, 0) => Value::Null,
+                    (0x68, _) => Value::Bool(self.u8()? != 0),
+                    (0x6D, 4) => Value::Float(f32::from_bits(self.u32()?).into()),
+                    (0x6D, _) => Value::Float(f64::from_bits(self.u64()?)),
+                    (_, 1) => Value::Int(self.u8()?.into()),
+                    (_, 2) => Value::Int((self.u16()? as i16).into()),
+                    (_, 4) => Value::Int((self.u32()? as i32).into()),
+                    (_, _) => Value::Int(self.u64()? as i64),
+                }
+            }
+            // NVARCHAR, NCHAR, BIGVARCHAR, BIGCHAR
+            0xE7 | 0xEF | 0xA7 | 0xAF => {
+                let max_len = self.u16()?;
+                let _collation = self.take(5)?;
+                match self.var_bytes(max_len)? {
+                    None => Value::Null,
+                    Some(bytes) if ty == 0xE7 || ty == 0xEF => Value::String(utf16(&bytes)),
+                    Some(bytes) => Value::String(String::from_utf8_lossy(&bytes).into_owned()),
+                }
+            }
+            // BIGVARBIN, BIGBINARY
+            0xA5 | 0xAD => {
+                let max_len = self.u16()?;
+                self.var_bytes(max_len)?.map_or(Value::Null, Value::Binary)
+            }
+            // GUID
+            0x24 => {
+                let _max_len = self.u8()?;
+                match self.u8()? {
+                    0 => Value::Null,
+                    _ => {
+                        let mut bytes: [u8; 16] = self.take(16)?.try_into()?;
+                        reorder_guid(&mut bytes);
+                        Value::Guid(Uuid::from_bytes(bytes))
+                    }
+                }
+            }
+            // DATEN
+            0x28 => match self.u8()? {
+                0 => Value::Null,
+                _ => Value::Date(self.date()?),
+            },
+            // TIMEN, DATETIME2N, DATETIMEOFFSETN
+            0x29..=0x2B => {
+                let scale = self.u8()?;
+                let len = self.u8()? as usize;
+                match ty {
+                    _ if len == 0 => Value::Null,
+                    0x29 => Value::Time(self.time(len, scale)?),
+                    0x2A => {
+                        let time = self.time(len - 3, scale)?;
+                        Value::DateTime(self.date()?.and_time(time))
+                    }
+                    _ => {
+                        let time = self.time(len - 5, scale)?;
+                        let utc = self.date()?.and_time(time);
+                        let minutes = self.u16()? as i16;
+                        let offset = FixedOffset::east_opt(minutes as i32 * 60)
+                            .ok_or_else(|| anyhow::anyhow!("Invalid offset {}", minutes))?;
+                        Value::DateTimeOffset(offset.from_utc_datetime(&utc))
+                    }
+                }
+            }
+            // DECIMALN, NUMERICN
+            0x6A | 0x6C => {
+                let _max_len = self.u8()?;
+                let _precision = self.u8()?;
+                let scale = self.u8()?;
+                match self.u8()? as usize {
+                    0 => Value::Null,
+                    len => {
+                        let positive = self.u8()? == 1;
+                        let mut bytes = [0u8; 16];
+                        bytes[..len - 1].copy_from_slice(self.take(len - 1)?);
+                        let value = u128::from_le_bytes(bytes) as i128;
+                        let value = if positive { value } else { -value };
+                        Value::Decimal(Numeric::new_with_scale(value, scale))
+                    }
+                }
+            }
+            other => anyhow::bail!("Unsupported parameter type 0x{:02X}", other),
+        };
+
+        Ok(value)
+    }
+
+    /// Days since 0001-01-01 in three bytes.
+    fn date(&mut self) -> anyhow::Result<NaiveDate> {
+        let days = self.uint(3)?;
+        Ok(epoch() + Duration::days(days as i64))
+    }
+
+    /// Time of day in units of 10^-scale seconds.
+    fn time(&mut self, len: usize, scale: u8) -> anyhow::Result<NaiveTime> {
+        let increments = self.uint(len)?;
+        let ticks = increments * 10u64.pow(7u32.saturating_sub(scale as u32));
+        NaiveTime::from_num_seconds_from_midnight_opt(
+            (ticks / 10_000_000) as u32,
+            (ticks % 10_000_000) as u32 * 100,
+        )
+        .ok_or_else(|| anyhow::anyhow!("Invalid time {}", increments))
+    }
+}
+
+pub(crate) fn epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1, 1, 1).unwrap()
+}
+
+/// SQL Server stores the first three fields of a GUID little-endian.
+pub(crate) fn reorder_guid(bytes: &mut [u8; 16]) {
+    bytes.swap(0, 3);
+    bytes.swap(1, 2);
+    bytes.swap(4, 5);
+    bytes.swap(6, 7);
+}
+
+pub(crate) fn utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
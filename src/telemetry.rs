@@ -0,0 +1,284 @@
+//! `tracing` spans for the statements this crate runs, and a subscriber
+//! to collect them.
+//!
+//! Every connect, query, execute and bulk load opens a span named
+//! `sql.connect`, `sql.query`, `sql.execute` or `sql.bulk_load` with these
+//! fields, filled in as they become known:
+//!
+//! - `db.statement`: the SQL text, with passwords hidden by
+//!   [`auth::redact`](crate::auth::redact)
+//! - `db.param_count`, and `db.params` when parameter logging is on
+//! - `spid`: the server session, where the connection knows it
+//! - `rows`: rows returned or affected
+//! - `duration_ms`
+//! - `error.code`: the server error number of a failure
+//! - `db.logical_reads`, `db.physical_reads`, `db.cpu_ms` and
+//!   `db.elapsed_ms`: the totals of a batch run with
+//!   `SET STATISTICS IO, TIME ON`, with a `table io` event per table
+//!   read (see [`crate::stats`])
+//!
+//! Parameter values often hold personal data, so they are left out unless
+//! [`set_log_parameters`] turns them on. Without a subscriber the spans cost
+//! next to nothing.
+
+use std::any::Any;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tiberius::ToSql;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+static LOG_PARAMETERS: AtomicBool = AtomicBool::new(false);
+
+/// Record parameter values in `db.params`. Off by default.
+pub fn set_log_parameters(enabled: bool) {
+    LOG_PARAMETERS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn log_parameters() -> bool {
+    LOG_PARAMETERS.load(Ordering::Relaxed)
+}
+
+/// The kind of statement a span covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// A statement whose rows are read.
+    Query,
+    /// A statement run for its rows affected.
+    Execute,
+    /// A bulk load into a table; the statement is the table name.
+    BulkLoad,
+}
+
+macro_rules! statement_span {
+    ($name:literal, $sql:expr, $count:expr) => {
+        tracing::info_span!(
+            $name,
+            db.statement = %crate::auth::redact($sql),
+            db.param_count = $count,
+            db.params = Empty,
+            spid = Empty,
+            rows = Empty,
+            duration_ms = Empty,
+            error.code = Empty,
+            db.logical_reads = Empty,
+            db.physical_reads = Empty,
+            db.cpu_ms = Empty,
+            db.elapsed_ms = Empty,
+        )
+    };
+}
+
+/// Open a span for a statement. Parameter values are only recorded when
+/// [`log_parameters`] is on.
+pub fn statement_span(operation: Operation, sql: &str, params: &[&dyn ToSql]) -> Span {
+    let span = match operation {
+        Operation::Query => statement_span!("sql.query", sql, params.len()),
+        Operation::Execute => statement_span!("sql.execute", sql, params.len()),
+        Operation::BulkLoad => statement_span!("sql.bulk_load", sql, params.len()),
+    };
+
+    if log_parameters() && !params.is_empty() && !span.is_disabled() {
+        let values = params
+            .iter()
+            .enumerate()
+            .map(|(i, p)| format!("@P{}={:?}", i + 1, p.to_sql()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        span.record("db.params", values.as_str());
+    }
+
+    span
+}
+
+/// Open a span for logging in to the server at `addr`.
+pub fn connect_span(addr: &str) -> Span {
+    tracing::info_span!(
+        "sql.connect",
+        server.address = %addr,
+        spid = Empty,
+        duration_ms = Empty,
+        error.code = Empty,
+    )
+}
+
+/// Run `fut` inside `span`, then record how long it took and either the
+/// rows counted by `rows`, if any, or the error.
+pub async fn traced<T, E, Fut>(span: Span, fut: Fut, rows: impl FnOnce(&T) -> Option<u64>) -> Result<T, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+    E: fmt::Display + 'static,
+{
+    let start = Instant::now();
+    let result = fut.instrument(span.clone()).await;
+    span.record("duration_ms", start.elapsed().as_millis() as u64);
+
+    match &result {
+        Ok(value) => {
+            if let Some(rows) = rows(value) {
+                span.record("rows", rows);
+            }
+        }
+        Err(e) => {
+            if let Some(code) = error_code(e) {
+                span.record("error.code", code);
+            }
+            let error = crate::auth::redact(&e.to_string());
+            span.in_scope(|| tracing::error!(error = %error, "statement failed"));
+        }
+    }
+
+    result
+}
+
+/// The server error number behind a driver or `anyhow` error.
+pub fn error_code(error: &dyn Any) -> Option<u32> {
+    let driver = match error.downcast_ref::<anyhow::Error>() {
+        Some(e) => e.downcast_ref::<tiberius::error::Error>(),
+        None => error.downcast_ref::<tiberius::error::Error>(),
+    };
+
+    match driver {
+        Some(tiberius::error::Error::Server(token)) => Some(token.code()),
+        _ => None,
+    }
+}
+
+/// Where spans are written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceOutput {
+    /// Human-readable lines on stderr, one per closed span.
+    Stderr,
+    /// One JSON object per line, appended to a file.
+    JsonFile(PathBuf),
+    /// An OTLP/HTTP collector, e.g. `http://localhost:4318/v1/traces`.
+    #[cfg(feature = "otel")]
+    OpenTelemetry(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceConfig {
+    pub output: TraceOutput,
+    /// An `EnvFilter` directive, e.g. `tiberius_sqlserver=debug`.
+    pub filter: String,
+    pub log_parameters: bool,
+}
+
+impl TraceConfig {
+    /// Read the configuration from the environment, or `None` when tracing
+    /// is not asked for:
+    ///
+    /// - `TIBERIUS_TRACE`: `stderr`, `json:<file>` or `otlp:<endpoint>`
+    /// - `TIBERIUS_TRACE_FILTER`: defaults to `tiberius_sqlserver=info`
+    /// - `TIBERIUS_TRACE_PARAMS=1` records parameter values
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(output) = std::env::var("TIBERIUS_TRACE") else {
+            return Ok(None);
+        };
+
+        Ok(Some(TraceConfig {
+            output: output.parse()?,
+            filter: std::env::var("TIBERIUS_TRACE_FILTER").unwrap_or_else(|_| "tiberius_sqlserver=info".to_string()),
+            log_parameters: std::env::var("TIBERIUS_TRACE_PARAMS").is_ok_and(|v| v == "1" || v == "true"),
+        }))
+    }
+}
+
+impl std::str::FromStr for TraceOutput {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.eq_ignore_ascii_case("stderr") {
+            return Ok(TraceOutput::Stderr);
+        }
+        if let Some(path) = s.strip_prefix("json:") {
+            return Ok(TraceOutput::JsonFile(PathBuf::from(path)));
+        }
+        if let Some(endpoint) = s.strip_prefix("otlp:") {
+            #[cfg(feature = "otel")]
+            return Ok(TraceOutput::OpenTelemetry(endpoint.to_string()));
+            #[cfg(not(feature = "otel"))]
+            anyhow::bail!("Exporting to {} requires building with `--features otel`", endpoint);
+        }
+        anyhow::bail!("Unknown trace output {:?}; expected stderr, json:<file> or otlp:<endpoint>", s)
+    }
+}
+
+/// Keeps the exporter alive; dropping it flushes any spans not yet sent.
+pub struct TraceGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// Install a global subscriber. Fails if one is already installed.
+pub fn init(config: &TraceConfig) -> anyhow::Result<TraceGuard> {
+    set_log_parameters(config.log_parameters);
+    let filter = EnvFilter::try_new(&config.filter)?;
+
+    let layer = match &config.output {
+        TraceOutput::Stderr => tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_span_events(FmtSpan::CLOSE)
+            .boxed(),
+        TraceOutput::JsonFile(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(Mutex::new(file))
+                .with_span_events(FmtSpan::CLOSE)
+                .boxed()
+        }
+        #[cfg(feature = "otel")]
+        TraceOutput::OpenTelemetry(endpoint) => {
+            use opentelemetry::trace::TracerProvider as _;
+            use opentelemetry_otlp::WithExportConfig;
+
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()?;
+            let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+                .with_simple_exporter(exporter)
+                .with_resource(opentelemetry_sdk::Resource::new([opentelemetry::KeyValue::new(
+                    "service.name",
+                    env!("CARGO_PKG_NAME"),
+                )]))
+                .build();
+            let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")));
+
+            tracing_subscriber::registry().with(layer.with_filter(filter)).try_init()?;
+            return Ok(TraceGuard { provider: Some(provider) });
+        }
+    };
+
+    tracing_subscriber::registry().with(layer.with_filter(filter)).try_init()?;
+    Ok(TraceGuard {
+        #[cfg(feature = "otel")]
+        provider: None,
+    })
+}
+
+/// Install the subscriber described by the environment, if any; see
+/// [`TraceConfig::from_env`].
+pub fn init_from_env() -> anyhow::Result<Option<TraceGuard>> {
+    TraceConfig::from_env()?.map(|config| init(&config)).transpose()
+}
@@ -0,0 +1,623 @@
+//! TLS settings for a connection, and the certificate checks tiberius does
+//! not do itself.
+//!
+//! When a connection is opened with [`TlsSettings`] and the whole session
+//! is encrypted, this crate does the TLS handshake itself, in [`Session`],
+//! and tiberius writes plain TDS that the session encrypts. tiberius does
+//! not expose its own TLS session, and this way:
+//!
+//! - the certificate's SHA-256 fingerprint is pinned on the session's own
+//!   handshake, on top of the chain and host name checks
+//! - the certificate can be validated against another host name than the
+//!   one connected to, e.g. when connecting through an IP address or a
+//!   tunnel
+//! - a failed check says which one, instead of OpenSSL's message
+//! - statements can be cancelled and their row counts and messages read,
+//!   see [`crate::cancel`] and [`crate::batch`]
+//!
+//! With `encrypt = off` only the login is encrypted, and tiberius does it.
+//!
+//! tiberius speaks TDS 7.4, so TLS always starts after PRELOGIN. `strict`
+//! therefore cannot be TDS 8.0 strict encryption; it is `required` with
+//! every certificate check on, and refuses `trust_server_certificate`.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use async_native_tls::{Certificate, TlsConnector, TlsStream};
+use async_std::net::TcpStream;
+use futures_util::io::{AsyncRead, AsyncWrite};
+use futures_util::ready;
+use sha2::{Digest, Sha256};
+use tiberius::{Client, Config, EncryptionLevel};
+
+use crate::sql_client;
+use crate::tds::{
+    self, ENCRYPT_NOT_SUP, ENCRYPT_ON, HEADER_LEN, PACKET_PRELOGIN, PACKET_SIZE, PACKET_TABULAR_RESULT, STATUS_EOM,
+};
+
+/// How much of the session is encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TlsMode {
+    /// Only the login packet.
+    Off,
+    /// Everything, if the server supports it.
+    #[default]
+    On,
+    /// Everything; fail if the server cannot encrypt.
+    Required,
+    /// Like `Required`, with every certificate check enforced.
+    Strict,
+}
+
+impl FromStr for TlsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(TlsMode::Off),
+            "on" => Ok(TlsMode::On),
+            "required" => Ok(TlsMode::Required),
+            "strict" => Ok(TlsMode::Strict),
+            _ => anyhow::bail!("Unknown encryption mode {:?}; expected off, on, required or strict", s),
+        }
+    }
+}
+
+impl fmt::Display for TlsMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TlsMode::Off => "off",
+            TlsMode::On => "on",
+            TlsMode::Required => "required",
+            TlsMode::Strict => "strict",
+        })
+    }
+}
+
+/// The SHA-256 digest of a certificate's DER encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint(pub [u8; 32]);
+
+impl Fingerprint {
+    pub fn of(der: &[u8]) -> Self {
+        Fingerprint(Sha256::digest(der).into())
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = anyhow::Error;
+
+    /// Hex digits, optionally separated by colons and prefixed with
+    /// `sha256:`, as printed by `openssl x509 -fingerprint -sha256`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let hex: String = s
+            .strip_prefix("sha256:")
+            .unwrap_or(s)
+            .chars()
+            .filter(|c| *c != ':')
+            .collect();
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("A SHA-256 fingerprint is 64 hex digits, got {:?}", s);
+        }
+
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(Fingerprint(bytes))
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex: Vec<String> = self.0.iter().map(|b| format!("{:02X}", b)).collect();
+        f.write_str(&hex.join(":"))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsSettings {
+    pub mode: TlsMode,
+    /// A CA certificate (`.pem`, `.crt` or `.der`) to trust besides the
+    /// system roots.
+    pub ca_bundle: Option<PathBuf>,
+    /// The certificate the server must present.
+    pub fingerprint: Option<Fingerprint>,
+    /// The name the certificate must be issued for, when it is not the host
+    /// connected to.
+    pub hostname: Option<String>,
+    /// Skip chain and host name validation. Not allowed with a pin, or in
+    /// strict mode.
+    pub trust_server_certificate: bool,
+}
+
+impl TlsSettings {
+    /// Reject combinations that cannot work or would weaken a check
+    /// silently.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.mode == TlsMode::Strict && self.trust_server_certificate {
+            anyhow::bail!("Strict encryption validates the server certificate: remove trust_server_certificate");
+        }
+        if self.fingerprint.is_some() && self.trust_server_certificate {
+            anyhow::bail!(
+                "A pinned certificate is validated too: remove trust_server_certificate, and set ca_bundle \
+                 to the certificate's CA, or to the certificate itself when it is self-signed"
+            );
+        }
+        if self.fingerprint.is_some() && self.mode == TlsMode::Off {
+            anyhow::bail!(
+                "With encrypt = off only the login is encrypted, by the driver, so the certificate cannot be \
+                 pinned; set encrypt = on"
+            );
+        }
+        if self.trust_server_certificate && (self.ca_bundle.is_some() || self.hostname.is_some()) {
+            anyhow::bail!("trust_server_certificate skips validation, so a CA bundle or host name would be ignored");
+        }
+        if let Some(path) = &self.ca_bundle {
+            let extension = path.extension().and_then(|e| e.to_str()).map(str::to_lowercase);
+            if !matches!(extension.as_deref(), Some("pem" | "crt" | "der")) {
+                anyhow::bail!("The CA bundle {} must be a .pem, .crt or .der file", path.display());
+            }
+            if !path.is_file() {
+                anyhow::bail!("The CA bundle {} does not exist", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the encryption level and certificate trust of `config`, for
+    /// tiberius to do TLS. The fingerprint is only checked by connections
+    /// opened with [`connect`] and [`Session::open`].
+    pub fn apply(&self, config: &mut Config) -> anyhow::Result<()> {
+        self.validate()?;
+
+        config.encryption(match self.mode {
+            TlsMode::Off => EncryptionLevel::Off,
+            TlsMode::On => EncryptionLevel::On,
+            TlsMode::Required | TlsMode::Strict => EncryptionLevel::Required,
+        });
+        if let Some(path) = &self.ca_bundle {
+            config.trust_cert_ca(path.to_string_lossy());
+        }
+        if self.trust_server_certificate {
+            config.trust_cert();
+        }
+        Ok(())
+    }
+
+    fn connector(&self) -> anyhow::Result<TlsConnector> {
+        let mut connector = TlsConnector::new();
+        if let Some(path) = &self.ca_bundle {
+            let bytes = std::fs::read(path)?;
+            let certificate = match path.extension().and_then(|e| e.to_str()) {
+                Some(e) if e.eq_ignore_ascii_case("der") => Certificate::from_der(&bytes)?,
+                _ => Certificate::from_pem(&bytes)?,
+            };
+            connector = connector.add_root_certificate(certificate);
+        }
+        if self.trust_server_certificate {
+            connector = connector
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true)
+                .use_sni(false);
+        }
+        Ok(connector)
+    }
+}
+
+/// Which check a TLS connection failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsFailure {
+    /// The server has no certificate to encrypt with.
+    NotSupported,
+    /// The certificate chain does not lead to a trusted CA.
+    UntrustedIssuer(String),
+    /// The certificate signed itself and is not trusted.
+    SelfSigned,
+    /// The certificate was not issued for the host name validated.
+    HostnameMismatch(String),
+    /// The certificate has expired or is not valid yet.
+    Expired,
+    /// The certificate is not the pinned one.
+    FingerprintMismatch {
+        expected: Fingerprint,
+        actual: Fingerprint,
+    },
+    Other(String),
+}
+
+impl TlsFailure {
+    /// Classify a TLS library error message.
+    pub fn classify(message: &str) -> Self {
+        let lower = message.to_lowercase();
+        let name_mismatch = ["hostname mismatch", "ip address mismatch", "host name", "cn_no_match"];
+        if name_mismatch.iter().any(|m| lower.contains(m)) {
+            TlsFailure::HostnameMismatch(message.to_string())
+        } else if lower.contains("self-signed") || lower.contains("self signed") {
+            TlsFailure::SelfSigned
+        } else if lower.contains("expired") || lower.contains("not yet valid") {
+            TlsFailure::Expired
+        } else if lower.contains("issuer") || lower.contains("untrusted") || lower.contains("unknown ca") {
+            TlsFailure::UntrustedIssuer(message.to_string())
+        } else {
+            TlsFailure::Other(message.to_string())
+        }
+    }
+}
+
+impl fmt::Display for TlsFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsFailure::NotSupported => write!(
+                f,
+                "Encryption check failed: the server has no certificate to encrypt with; \
+                 install one on the server, or set encrypt = off"
+            ),
+            TlsFailure::UntrustedIssuer(message) => write!(
+                f,
+                "Certificate chain check failed: the issuer is not trusted ({}); \
+                 set ca_bundle to the CA certificate that issued it",
+                message
+            ),
+            TlsFailure::SelfSigned => write!(
+                f,
+                "Certificate chain check failed: the certificate is self-signed; \
+                 set ca_bundle to the certificate itself"
+            ),
+            TlsFailure::HostnameMismatch(message) => write!(
+                f,
+                "Host name check failed: the certificate was not issued for this server ({}); \
+                 connect with the name on the certificate, or set tls_hostname to it",
+                message
+            ),
+            TlsFailure::Expired => write!(
+                f,
+                "Validity check failed: the certificate has expired or is not valid yet; \
+                 renew it on the server, or check this machine's clock"
+            ),
+            TlsFailure::FingerprintMismatch { expected, actual } => write!(
+                f,
+                "Pinning check failed: the server presented certificate {} but {} is pinned; \
+                 if the certificate was renewed, update the fingerprint",
+                actual, expected
+            ),
+            TlsFailure::Other(message) => write!(f, "TLS handshake failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for TlsFailure {}
+
+/// Turn a driver TLS error into the [`TlsFailure`] it stands for.
+pub fn explain(error: anyhow::Error) -> anyhow::Error {
+    match error.downcast_ref::<tiberius::error::Error>() {
+        Some(tiberius::error::Error::Tls(message)) => TlsFailure::classify(message).into(),
+        _ => error,
+    }
+}
+
+/// Do a TLS handshake with the server at `addr` and return the fingerprint
+/// of its certificate, after checking it as `settings` ask. `host` is the
+/// name validated when `settings` has no host name override.
+pub async fn inspect(addr: &str, host: &str, settings: &TlsSettings) -> anyhow::Result<Fingerprint> {
+    let tcp = TcpStream::connect(addr).await?;
+    let (_, fingerprint, _) = handshake(tcp, host, settings).await?;
+    Ok(fingerprint)
+}
+
+/// Send PRELOGIN asking for encryption, then do the TLS handshake and check
+/// the certificate. Returns the stream, ready for TDS packets, the
+/// certificate's fingerprint and the server's PRELOGIN reply.
+async fn handshake(
+    mut tcp: TcpStream,
+    host: &str,
+    settings: &TlsSettings,
+) -> anyhow::Result<(TlsStream<PreloginFraming>, Fingerprint, Vec<u8>)> {
+    let server = tds::prelogin(&mut tcp, ENCRYPT_ON).await?;
+    if server.encryption == ENCRYPT_NOT_SUP {
+        return Err(TlsFailure::NotSupported.into());
+    }
+
+    let name = settings.hostname.as_deref().unwrap_or(host);
+    let mut stream = settings
+        .connector()?
+        .connect(name, PreloginFraming::new(tcp))
+        .await
+        .map_err(|e| TlsFailure::classify(&e.to_string()))?;
+
+    let certificate = stream
+        .peer_certificate()?
+        .ok_or_else(|| anyhow::anyhow!("The server presented no certificate"))?;
+    let actual = Fingerprint::of(&certificate.to_der()?);
+    if let Some(expected) = settings.fingerprint.filter(|expected| *expected != actual) {
+        return Err(TlsFailure::FingerprintMismatch { expected, actual }.into());
+    }
+
+    stream.get_mut().finish_handshake();
+    Ok((stream, actual, server.reply))
+}
+
+/// Log in with `config` under `settings`, checking the certificate as they
+/// ask.
+pub async fn connect(config: Config, settings: &TlsSettings) -> anyhow::Result<Client<Session>> {
+    let (addr, config) = prepare(config, settings)?;
+    let (client, _) = sql_client::connect_traced_with(addr.clone(), config, Session::open(&addr, Some(settings)))
+        .await
+        .map_err(explain)?;
+    Ok(client)
+}
+
+/// Apply `settings` to `config`. Returns the address to connect to, which
+/// differs from the host in the returned configuration when the
+/// certificate is validated against another name. Unless only the login is
+/// encrypted the configuration leaves TLS to [`Session::open`], which must
+/// be given the same settings.
+pub(crate) fn prepare(mut config: Config, settings: &TlsSettings) -> anyhow::Result<(String, Config)> {
+    settings.apply(&mut config)?;
+    let addr = config.get_addr();
+
+    if settings.mode != TlsMode::Off {
+        config.encryption(EncryptionLevel::NotSupported);
+    }
+    if let Some(hostname) = &settings.hostname {
+        config.host(hostname);
+    }
+
+    Ok((addr, config))
+}
+
+/// The connection to the server under tiberius.
+///
+/// [`Session::open`] with TLS settings sends PRELOGIN and does the TLS
+/// handshake before tiberius logs in. tiberius' own PRELOGIN is then
+/// answered with the server's reply, changed to say the server cannot
+/// encrypt, so tiberius writes plain TDS and this stream encrypts it.
+pub struct Session {
+    socket: TcpStream,
+    tls: Option<TlsStream<PreloginFraming>>,
+    /// tiberius' PRELOGIN, which is not sent on: the server has had one.
+    /// `None` once it is complete.
+    request: Option<Vec<u8>>,
+    /// The PRELOGIN reply for tiberius, and how much of it was read.
+    reply: Vec<u8>,
+    replied: usize,
+}
+
+impl Session {
+    /// Connect to `addr`. With `settings`, unless only the login is to be
+    /// encrypted, do the TLS handshake and check the certificate; the
+    /// configuration then has to come from [`prepare`].
+    pub async fn open(addr: &str, settings: Option<&TlsSettings>) -> anyhow::Result<Self> {
+        let tcp = TcpStream::connect(addr).await?;
+        tcp.set_nodelay(true)?;
+        let Some(settings) = settings.filter(|s| s.mode != TlsMode::Off) else {
+            return Ok(Session::from(tcp));
+        };
+
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let (stream, _, mut body) = handshake(tcp.clone(), host, settings).await?;
+        if let Some(range) = tds::prelogin_range(&body, 1) {
+            body[range].fill(ENCRYPT_NOT_SUP);
+        }
+
+        let mut reply = vec![PACKET_TABULAR_RESULT, STATUS_EOM];
+        reply.extend_from_slice(&((body.len() + HEADER_LEN) as u16).to_be_bytes());
+        reply.extend_from_slice(&[0, 0, 1, 0]);
+        reply.extend_from_slice(&body);
+
+        Ok(Session {
+            socket: tcp,
+            tls: Some(stream),
+            request: Some(Vec::new()),
+            reply,
+            replied: 0,
+        })
+    }
+
+    /// Whether this crate encrypts the session.
+    pub fn is_encrypted(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// The TCP connection, e.g. to shut it down from another task.
+    pub(crate) fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+}
+
+impl From<TcpStream> for Session {
+    /// A connection left to tiberius as it is.
+    fn from(socket: TcpStream) -> Self {
+        Session {
+            socket,
+            tls: None,
+            request: None,
+            reply: Vec::new(),
+            replied: 0,
+        }
+    }
+}
+
+/// Whether `request` holds a whole message.
+fn is_message(request: &[u8]) -> bool {
+    let mut rest = request;
+    while rest.len() >= HEADER_LEN {
+        let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+        if rest.len() < len {
+            return false;
+        }
+        if rest[1] & STATUS_EOM != 0 {
+            return true;
+        }
+        rest = &rest[len.max(HEADER_LEN)..];
+    }
+    false
+}
+
+impl AsyncRead for Session {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.replied < this.reply.len() {
+            let n = buf.len().min(this.reply.len() - this.replied);
+            buf[..n].copy_from_slice(&this.reply[this.replied..this.replied + n]);
+            this.replied += n;
+            return Poll::Ready(Ok(n));
+        }
+        match &mut this.tls {
+            Some(tls) => Pin::new(tls).poll_read(cx, buf),
+            None => Pin::new(&mut this.socket).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Session {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if let Some(request) = &mut this.request {
+            request.extend_from_slice(buf);
+            if is_message(request) {
+                this.request = None;
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
+        match &mut this.tls {
+            Some(tls) => Pin::new(tls).poll_write(cx, buf),
+            None => Pin::new(&mut this.socket).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match &mut this.tls {
+            Some(tls) => Pin::new(tls).poll_flush(cx),
+            None => Pin::new(&mut this.socket).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match &mut this.tls {
+            Some(tls) => Pin::new(tls).poll_close(cx),
+            None => Pin::new(&mut this.socket).poll_close(cx),
+        }
+    }
+}
+
+/// Carries the TLS handshake inside PRELOGIN packets, as TDS 7.x does.
+/// After the handshake TLS records are sent as they are.
+pub(crate) struct PreloginFraming {
+    stream: TcpStream,
+    handshake_done: bool,
+    header: [u8; HEADER_LEN],
+    header_read: usize,
+    /// Payload bytes left in the packet being read.
+    remaining: usize,
+    /// Bytes written by TLS since the last flush.
+    pending: Vec<u8>,
+    /// Framed packets being sent, and how much of them is sent.
+    out: Vec<u8>,
+    sent: usize,
+}
+
+impl PreloginFraming {
+    pub(crate) fn new(stream: TcpStream) -> Self {
+        PreloginFraming {
+            stream,
+            handshake_done: false,
+            header: [0; HEADER_LEN],
+            header_read: 0,
+            remaining: 0,
+            pending: Vec::new(),
+            out: Vec::new(),
+            sent: 0,
+        }
+    }
+
+    pub(crate) fn finish_handshake(&mut self) {
+        self.handshake_done = true;
+    }
+}
+
+impl AsyncRead for PreloginFraming {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.handshake_done {
+            return Pin::new(&mut this.stream).poll_read(cx, buf);
+        }
+
+        while this.remaining == 0 {
+            while this.header_read < HEADER_LEN {
+                let read = ready!(Pin::new(&mut this.stream).poll_read(cx, &mut this.header[this.header_read..]))?;
+                if read == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+                this.header_read += read;
+            }
+            this.header_read = 0;
+
+            if this.header[0] != PACKET_PRELOGIN {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Expected a PRELOGIN packet during the TLS handshake, got type {}", this.header[0]),
+                )));
+            }
+            let len = u16::from_be_bytes([this.header[2], this.header[3]]) as usize;
+            this.remaining = len.saturating_sub(HEADER_LEN);
+        }
+
+        let max = this.remaining.min(buf.len());
+        let read = ready!(Pin::new(&mut this.stream).poll_read(cx, &mut buf[..max]))?;
+        this.remaining -= read;
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl AsyncWrite for PreloginFraming {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.handshake_done {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        }
+        this.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.sent == this.out.len() && !this.pending.is_empty() {
+            this.out.clear();
+            this.sent = 0;
+            let chunks: Vec<&[u8]> = this.pending.chunks(PACKET_SIZE - HEADER_LEN).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let status = if i + 1 == chunks.len() { STATUS_EOM } else { 0 };
+                this.out.extend_from_slice(&[PACKET_PRELOGIN, status]);
+                this.out.extend_from_slice(&((chunk.len() + HEADER_LEN) as u16).to_be_bytes());
+                this.out.extend_from_slice(&[0, 0, (i + 1) as u8, 0]);
+                this.out.extend_from_slice(chunk);
+            }
+            this.pending.clear();
+        }
+
+        while this.sent < this.out.len() {
+            let written = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.out[this.sent..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.sent += written;
+        }
+
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_close(cx)
+    }
+}
@@ -0,0 +1,466 @@
+//! Sync a batch of records into a table keyed on its primary key.
+//!
+//! The rows are bulk loaded into a temp table first, then applied with a
+//! single `MERGE` (or an `UPDATE` + `INSERT` pair in one transaction).
+//! Matched rows are only updated when at least one value differs.
+
+use std::borrow::Cow;
+
+use async_std::net::TcpStream;
+use tiberius::{Client, ColumnData, IntoSql, TokenRow};
+
+use crate::ident::{quote_ident, quote_table};
+use crate::schema::{self, TableColumn};
+use crate::telemetry::{self, Operation};
+use crate::value::Value;
+
+const STAGE_TABLE: &str = "#upsert_stage";
+
+/// How the staged rows are applied to the target table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UpsertStrategy {
+    /// One `MERGE` statement.
+    #[default]
+    Merge,
+    /// Separate `UPDATE`, `INSERT` (and `DELETE`) statements in one
+    /// transaction, for teams that avoid `MERGE`.
+    UpdateInsert,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UpsertOptions {
+    pub strategy: UpsertStrategy,
+    /// Delete target rows whose key is not in the batch.
+    pub delete_missing: bool,
+    /// Columns that are staged but never written, e.g. audit columns the
+    /// target fills in itself.
+    pub exclude: Vec<String>,
+    /// Match on these columns instead of the primary key.
+    pub key: Option<Vec<String>>,
+}
+
+/// Rows affected by each action.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpsertCounts {
+    pub inserted: i64,
+    pub updated: i64,
+    pub deleted: i64,
+}
+
+/// Insert or update `rows` in `table`, matching on the primary key.
+pub async fn upsert(
+    client: &mut Client<TcpStream>,
+    table: &str,
+    columns: &[&str],
+    rows: Vec<Vec<Value>>,
+    options: &UpsertOptions,
+) -> anyhow::Result<UpsertCounts> {
+    if let Some(row) = rows.iter().find(|r| r.len() != columns.len()) {
+        anyhow::bail!(
+            "Expected {} values per row but got a row with {}",
+            columns.len(),
+            row.len()
+        );
+    }
+
+    let table_columns = schema::table_columns(client, table).await?;
+    let plan = UpsertPlan::new(table, &table_columns, columns, options)?;
+
+    let create_stage_sql = plan.create_stage_sql();
+    telemetry::traced(
+        telemetry::statement_span(Operation::Execute, &create_stage_sql, &[]),
+        async { client.simple_query(create_stage_sql.as_str()).await?.into_results().await },
+        |_| None,
+    )
+    .await?;
+
+    let load = async {
+        let mut bulk = client.bulk_insert(STAGE_TABLE).await?;
+        for row in rows {
+            let mut token_row = TokenRow::new();
+            for (value, column) in row.into_iter().zip(&plan.staged) {
+                token_row.push(stage_data(value, column)?);
+            }
+            bulk.send(token_row).await?;
+        }
+        anyhow::Ok(bulk.finalize().await?)
+    };
+    telemetry::traced(
+        telemetry::statement_span(Operation::BulkLoad, STAGE_TABLE, &[]),
+        load,
+        |r| Some(r.rows_affected().iter().sum()),
+    )
+    .await?;
+
+    let apply_sql = plan.apply_sql();
+    let results = telemetry::traced(
+        telemetry::statement_span(Operation::Execute, &apply_sql, &[]),
+        async { client.simple_query(apply_sql.as_str()).await?.into_results().await },
+        |_| None,
+    )
+    .await?;
+
+    let counts = results
+        .iter()
+        .flatten()
+        .last()
+        .map(|r| UpsertCounts {
+            inserted: r.get(0).unwrap_or(0),
+            updated: r.get(1).unwrap_or(0),
+            deleted: r.get(2).unwrap_or(0),
+        })
+        .unwrap_or_default();
+
+    Ok(counts)
+}
+
+/// The type a value is staged as. Bulk load needs the value to match the
+/// column type exactly, so every target type maps to one staging type that
+/// converts implicitly to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StageType {
+    BigInt,
+    Bit,
+    Float,
+    NVarChar,
+    VarBinary,
+    Guid,
+    Date,
+    Time,
+    DateTime2,
+    DateTimeOffset,
+}
+
+impl StageType {
+    fn for_type(type_name: &str) -> StageType {
+        match type_name {
+            "tinyint" | "smallint" | "int" | "bigint" => StageType::BigInt,
+            "bit" => StageType::Bit,
+            "real" | "float" => StageType::Float,
+            "binary" | "varbinary" | "image" | "timestamp" => StageType::VarBinary,
+            "uniqueidentifier" => StageType::Guid,
+            "date" => StageType::Date,
+            "time" => StageType::Time,
+            "datetime" | "smalldatetime" | "datetime2" => StageType::DateTime2,
+            "datetimeoffset" => StageType::DateTimeOffset,
+            // Strings, xml and exact numerics (staged as text so no
+            // precision is lost).
+            _ => StageType::NVarChar,
+        }
+    }
+
+    fn sql(self) -> &'static str {
+        match self {
+            StageType::BigInt => "bigint",
+            StageType::Bit => "bit",
+            StageType::Float => "float",
+            StageType::NVarChar => "nvarchar(max)",
+            StageType::VarBinary => "varbinary(max)",
+            StageType::Guid => "uniqueidentifier",
+            StageType::Date => "date",
+            StageType::Time => "time(7)",
+            StageType::DateTime2 => "datetime2(7)",
+            StageType::DateTimeOffset => "datetimeoffset(7)",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct StagedColumn {
+    name: String,
+    stage_type: StageType,
+}
+
+/// Convert a value into the staging type of its column.
+fn stage_data(value: Value, column: &StagedColumn) -> anyhow::Result<ColumnData<'static>> {
+    let data = match (column.stage_type, value) {
+        (StageType::BigInt, Value::Null) => ColumnData::I64(None),
+        (StageType::BigInt, Value::Int(v)) => ColumnData::I64(Some(v)),
+        (StageType::BigInt, Value::Bool(v)) => ColumnData::I64(Some(v.into())),
+        (StageType::BigInt, Value::String(v)) => ColumnData::I64(Some(v.trim().parse()?)),
+        (StageType::Bit, Value::Null) => ColumnData::Bit(None),
+        (StageType::Bit, Value::Bool(v)) => ColumnData::Bit(Some(v)),
+        (StageType::Bit, Value::Int(v)) => ColumnData::Bit(Some(v != 0)),
+        (StageType::Float, Value::Null) => ColumnData::F64(None),
+        (StageType::Float, Value::Float(v)) => ColumnData::F64(Some(v)),
+        (StageType::Float, Value::Int(v)) => ColumnData::F64(Some(v as f64)),
+        (StageType::Float, Value::String(v)) => ColumnData::F64(Some(v.trim().parse()?)),
+        (StageType::NVarChar, Value::Null) => ColumnData::String(None),
+        (StageType::NVarChar, Value::Binary(_)) => {
+            anyhow::bail!("Column {} does not accept binary values", column.name)
+        }
+        (StageType::NVarChar, v) => ColumnData::String(Some(Cow::Owned(v.to_string()))),
+        (StageType::VarBinary, Value::Null) => ColumnData::Binary(None),
+        (StageType::VarBinary, Value::Binary(v)) => ColumnData::Binary(Some(Cow::Owned(v))),
+        (StageType::Guid, Value::Null) => ColumnData::Guid(None),
+        (StageType::Guid, Value::Guid(v)) => ColumnData::Guid(Some(v)),
+        (StageType::Guid, Value::String(v)) => ColumnData::Guid(Some(v.trim().parse()?)),
+        (StageType::Date, Value::Null) => ColumnData::Date(None),
+        (StageType::Date, Value::Date(v)) => v.into_sql(),
+        (StageType::Date, Value::DateTime(v)) => v.date().into_sql(),
+        (StageType::Date, Value::String(v)) => v.trim().parse::<chrono::NaiveDate>()?.into_sql(),
+        (StageType::Time, Value::Null) => ColumnData::Time(None),
+        (StageType::Time, Value::Time(v)) => v.into_sql(),
+        (StageType::Time, Value::String(v)) => v.trim().parse::<chrono::NaiveTime>()?.into_sql(),
+        (StageType::DateTime2, Value::Null) => ColumnData::DateTime2(None),
+        (StageType::DateTime2, Value::DateTime(v)) => v.into_sql(),
+        (StageType::DateTime2, Value::Date(v)) => v.and_hms_opt(0, 0, 0).unwrap().into_sql(),
+        (StageType::DateTime2, Value::String(v)) => parse_datetime(&v)?.into_sql(),
+        (StageType::DateTimeOffset, Value::Null) => ColumnData::DateTimeOffset(None),
+        (StageType::DateTimeOffset, Value::DateTimeOffset(v)) => v.into_sql(),
+        (stage_type, v) => anyhow::bail!(
+            "Cannot stage {:?} into column {} of type {}",
+            v,
+            column.name,
+            stage_type.sql()
+        ),
+    };
+
+    Ok(data)
+}
+
+fn parse_datetime(value: &str) -> anyhow::Result<chrono::NaiveDateTime> {
+    let value = value.trim();
+
+    if let Ok(dt) = value.parse::<chrono::NaiveDateTime>() {
+        return Ok(dt);
+    }
+
+    if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f") {
+        return Ok(dt);
+    }
+
+    Ok(value.parse::<chrono::NaiveDate>()?.and_hms_opt(0, 0, 0).unwrap())
+}
+
+/// The statements for one upsert, derived from the target's metadata.
+#[derive(Debug, Clone)]
+pub struct UpsertPlan {
+    table: String,
+    strategy: UpsertStrategy,
+    delete_missing: bool,
+    staged: Vec<StagedColumn>,
+    key: Vec<String>,
+    insert: Vec<String>,
+    update: Vec<String>,
+}
+
+impl UpsertPlan {
+    pub fn new(
+        table: &str,
+        table_columns: &[TableColumn],
+        columns: &[&str],
+        options: &UpsertOptions,
+    ) -> anyhow::Result<Self> {
+        let find = |name: &str| {
+            table_columns
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow::anyhow!("Table {} has no column {}", table, name))
+        };
+
+        let staged = columns
+            .iter()
+            .map(|name| {
+                let column = find(name)?;
+                Ok(StagedColumn {
+                    name: column.name.clone(),
+                    stage_type: StageType::for_type(&column.type_name),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let key: Vec<String> = match &options.key {
+            Some(key) => key
+                .iter()
+                .map(|k| find(k).map(|c| c.name.clone()))
+                .collect::<anyhow::Result<_>>()?,
+            None => schema::primary_key(table_columns)
+                .into_iter()
+                .map(|c| c.name.clone())
+                .collect(),
+        };
+
+        if key.is_empty() {
+            anyhow::bail!("Table {} has no primary key, pass UpsertOptions::key", table);
+        }
+
+        let is_staged = |name: &str| staged.iter().any(|s| s.name.eq_ignore_ascii_case(name));
+        if let Some(missing) = key.iter().find(|k| !is_staged(k)) {
+            anyhow::bail!("Key column {} is missing from the upserted columns", missing);
+        }
+
+        let is_excluded = |name: &str| options.exclude.iter().any(|e| e.eq_ignore_ascii_case(name));
+        let writable: Vec<String> = staged
+            .iter()
+            .filter(|s| !is_excluded(&s.name))
+            .filter(|s| find(&s.name).map(|c| !c.is_generated()).unwrap_or(false))
+            .map(|s| s.name.clone())
+            .collect();
+
+        let update = writable
+            .iter()
+            .filter(|c| !key.iter().any(|k| k.eq_ignore_ascii_case(c)))
+            .cloned()
+            .collect();
+
+        Ok(UpsertPlan {
+            table: quote_table(table),
+            strategy: options.strategy,
+            delete_missing: options.delete_missing,
+            staged,
+            key,
+            insert: writable,
+            update,
+        })
+    }
+
+    /// (Re)create the staging temp table.
+    pub fn create_stage_sql(&self) -> String {
+        let columns = self
+            .staged
+            .iter()
+            .map(|c| format!("{} {} NULL", quote_ident(&c.name), c.stage_type.sql()))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!(
+            "DROP TABLE IF EXISTS {stage}; CREATE TABLE {stage} ({columns});",
+            stage = STAGE_TABLE,
+            columns = columns
+        )
+    }
+
+    /// Apply the staged rows and select the inserted, updated and deleted
+    /// counts.
+    pub fn apply_sql(&self) -> String {
+        let sql = match self.strategy {
+            UpsertStrategy::Merge => self.merge_sql(),
+            UpsertStrategy::UpdateInsert => self.update_insert_sql(),
+        };
+
+        format!("{}\nDROP TABLE {};", sql, STAGE_TABLE)
+    }
+
+    fn key_match(&self) -> String {
+        self.key
+            .iter()
+            .map(|k| format!("t.{c} = s.{c}", c = quote_ident(k)))
+            .collect::<Vec<_>>()
+            .join(" AND ")
+    }
+
+    /// `EXISTS (SELECT s.a, s.b EXCEPT SELECT t.a, t.b)` is true when any
+    /// value differs, treating two NULLs as equal.
+    fn changed(&self) -> String {
+        let list = |alias: &str| {
+            self.update
+                .iter()
+                .map(|c| format!("{}.{}", alias, quote_ident(c)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        format!("EXISTS (SELECT {} EXCEPT SELECT {})", list("s"), list("t"))
+    }
+
+    fn set_list(&self) -> String {
+        self.update
+            .iter()
+            .map(|c| format!("t.{c} = s.{c}", c = quote_ident(c)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn insert_lists(&self) -> (String, String) {
+        let columns = self.insert.iter().map(|c| quote_ident(c)).collect::<Vec<_>>();
+        let values = columns.iter().map(|c| format!("s.{}", c)).collect::<Vec<_>>();
+        (columns.join(", "), values.join(", "))
+    }
+
+    fn merge_sql(&self) -> String {
+        let mut sql = format!(
+            "DECLARE @actions TABLE (action nvarchar(10));\n\
+             MERGE INTO {} WITH (HOLDLOCK) AS t\n\
+             USING {} AS s ON {}\n",
+            self.table,
+            STAGE_TABLE,
+            self.key_match()
+        );
+
+        if !self.update.is_empty() {
+            sql.push_str(&format!(
+                "WHEN MATCHED AND {} THEN UPDATE SET {}\n",
+                self.changed(),
+                self.set_list()
+            ));
+        }
+
+        let (columns, values) = self.insert_lists();
+        sql.push_str(&format!(
+            "WHEN NOT MATCHED BY TARGET THEN INSERT ({}) VALUES ({})\n",
+            columns, values
+        ));
+
+        if self.delete_missing {
+            sql.push_str("WHEN NOT MATCHED BY SOURCE THEN DELETE\n");
+        }
+
+        sql.push_str(
+            "OUTPUT $action INTO @actions;\n\
+             SELECT\n    \
+                 CAST(COUNT(CASE WHEN action = 'INSERT' THEN 1 END) AS bigint),\n    \
+                 CAST(COUNT(CASE WHEN action = 'UPDATE' THEN 1 END) AS bigint),\n    \
+                 CAST(COUNT(CASE WHEN action = 'DELETE' THEN 1 END) AS bigint)\n\
+             FROM @actions;",
+        );
+
+        sql
+    }
+
+    fn update_insert_sql(&self) -> String {
+        let mut sql = String::from(
+            "SET XACT_ABORT ON;\n\
+             DECLARE @inserted bigint = 0, @updated bigint = 0, @deleted bigint = 0;\n\
+             BEGIN TRAN;\n",
+        );
+
+        if !self.update.is_empty() {
+            sql.push_str(&format!(
+                "UPDATE t SET {} FROM {} AS t WITH (UPDLOCK, HOLDLOCK) JOIN {} AS s ON {} WHERE {};\n\
+                 SET @updated = @@ROWCOUNT;\n",
+                self.set_list(),
+                self.table,
+                STAGE_TABLE,
+                self.key_match(),
+                self.changed()
+            ));
+        }
+
+        let (columns, values) = self.insert_lists();
+        sql.push_str(&format!(
+            "INSERT INTO {} ({}) SELECT {} FROM {} AS s\n\
+             WHERE NOT EXISTS (SELECT 1 FROM {} AS t WITH (UPDLOCK, HOLDLOCK) WHERE {});\n\
+             SET @inserted = @@ROWCOUNT;\n",
+            self.table,
+            columns,
+            values,
+            STAGE_TABLE,
+            self.table,
+            self.key_match()
+        ));
+
+        if self.delete_missing {
+            sql.push_str(&format!(
+                "DELETE t FROM {} AS t WHERE NOT EXISTS (SELECT 1 FROM {} AS s WHERE {});\n\
+                 SET @deleted = @@ROWCOUNT;\n",
+                self.table,
+                STAGE_TABLE,
+                self.key_match()
+            ));
+        }
+
+        sql.push_str("COMMIT;\nSELECT @inserted, @updated, @deleted;");
+        sql
+    }
+}
@@ -0,0 +1,185 @@
+//! A dynamic representation of SQL Server values, for code that does not
+//! know the shape of a result set at compile time.
+
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime};
+use tiberius::numeric::Numeric;
+use tiberius::{ColumnData, FromSql, Row, ToSql, Uuid};
+
+use crate::ident::quote_literal;
+
+/// A single SQL Server value of any type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    /// tinyint, smallint, int and bigint.
+    Int(i64),
+    /// real and float.
+    Float(f64),
+    /// decimal, numeric and money.
+    Decimal(Numeric),
+    /// char, varchar, nchar, nvarchar, text and xml.
+    String(String),
+    /// binary, varbinary, image and rowversion.
+    Binary(Vec<u8>),
+    Guid(Uuid),
+    Date(NaiveDate),
+    Time(NaiveTime),
+    /// datetime, smalldatetime and datetime2.
+    DateTime(NaiveDateTime),
+    DateTimeOffset(DateTime<FixedOffset>),
+}
+
+impl Value {
+    /// Convert a value read from the server.
+    pub fn from_column_data(data: &ColumnData<'static>) -> Value {
+        match data {
+            ColumnData::U8(v) => v.map(|v| Value::Int(v.into())),
+            ColumnData::I16(v) => v.map(|v| Value::Int(v.into())),
+            ColumnData::I32(v) => v.map(|v| Value::Int(v.into())),
+            ColumnData::I64(v) => v.map(Value::Int),
+            ColumnData::F32(v) => v.map(|v| Value::Float(v.into())),
+            ColumnData::F64(v) => v.map(Value::Float),
+            ColumnData::Bit(v) => v.map(Value::Bool),
+            ColumnData::String(v) => v.as_ref().map(|v| Value::String(v.to_string())),
+            ColumnData::Guid(v) => v.map(Value::Guid),
+            ColumnData::Binary(v) => v.as_ref().map(|v| Value::Binary(v.to_vec())),
+            ColumnData::Numeric(v) => v.map(Value::Decimal),
+            ColumnData::Xml(v) => v.as_ref().map(|v| Value::String(v.to_string())),
+            ColumnData::Date(_) => NaiveDate::from_sql(data).ok().flatten().map(Value::Date),
+            ColumnData::Time(_) => NaiveTime::from_sql(data).ok().flatten().map(Value::Time),
+            ColumnData::DateTime(_) | ColumnData::SmallDateTime(_) | ColumnData::DateTime2(_) => {
+                NaiveDateTime::from_sql(data).ok().flatten().map(Value::DateTime)
+            }
+            ColumnData::DateTimeOffset(_) => DateTime::<FixedOffset>::from_sql(data)
+                .ok()
+                .flatten()
+                .map(Value::DateTimeOffset),
+        }
+        .unwrap_or(Value::Null)
+    }
+
+    /// Convert every column of a row.
+    pub fn from_row(row: Row) -> Vec<Value> {
+        row.into_iter().map(|data| Value::from_column_data(&data)).collect()
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// The value as a T-SQL literal, for generated scripts and predicates
+    /// that cannot use parameters.
+    pub fn to_sql_literal(&self) -> String {
+        match self {
+            Value::Null => "NULL".to_string(),
+            Value::Bool(_) | Value::Int(_) | Value::Decimal(_) | Value::Binary(_) => self.to_string(),
+            Value::Float(v) => format!("{:e}", v),
+            Value::String(v) => quote_literal(v),
+            Value::Guid(_) => format!("'{}'", self),
+            Value::Date(_) => format!("CAST('{}' AS date)", self),
+            Value::Time(_) => format!("CAST('{}' AS time)", self),
+            Value::DateTime(v) => format!(
+                "CAST('{}' AS datetime2)",
+                v.format("%Y-%m-%dT%H:%M:%S%.f")
+            ),
+            Value::DateTimeOffset(v) => format!(
+                "CAST('{}' AS datetimeoffset)",
+                v.format("%Y-%m-%dT%H:%M:%S%.f%:z")
+            ),
+        }
+    }
+}
+
+impl ToSql for Value {
+    fn to_sql(&self) -> ColumnData<'_> {
+        match self {
+            // Typed as nvarchar, which converts implicitly to everything
+            // except binary columns.
+            Value::Null => ColumnData::String(None),
+            Value::Bool(v) => ColumnData::Bit(Some(*v)),
+            Value::Int(v) => ColumnData::I64(Some(*v)),
+            Value::Float(v) => ColumnData::F64(Some(*v)),
+            Value::Decimal(v) => ColumnData::Numeric(Some(*v)),
+            Value::String(v) => v.to_sql(),
+            Value::Binary(v) => v.to_sql(),
+            Value::Guid(v) => v.to_sql(),
+            Value::Date(v) => v.to_sql(),
+            Value::Time(v) => v.to_sql(),
+            Value::DateTime(v) => v.to_sql(),
+            Value::DateTimeOffset(v) => v.to_sql(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Bool(v) => write!(f, "{}", u8::from(*v)),
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Decimal(v) => write!(f, "{}", v),
+            Value::String(v) => write!(f, "{}", v),
+            Value::Binary(v) => {
+                write!(f, "0x")?;
+                for byte in v {
+                    write!(f, "{:02X}", byte)?;
+                }
+                Ok(())
+            }
+            Value::Guid(v) => write!(f, "{}", v.hyphenated().to_string().to_uppercase()),
+            Value::Date(v) => write!(f, "{}", v),
+            Value::Time(v) => write!(f, "{}", v),
+            Value::DateTime(v) => write!(f, "{}", v.format("%Y-%m-%d %H:%M:%S%.f")),
+            Value::DateTimeOffset(v) => write!(f, "{}", v.format("%Y-%m-%d %H:%M:%S%.f %:z")),
+        }
+    }
+}
+
+/// A row of values together with the names of its columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    columns: Arc<[String]>,
+    values: Vec<Value>,
+}
+
+impl Record {
+    pub fn new(columns: Arc<[String]>, values: Vec<Value>) -> Self {
+        Record { columns, values }
+    }
+
+    /// Convert a row, naming its columns after the row's own metadata.
+    pub fn from_row(row: Row) -> Self {
+        let columns = column_names(&row);
+        Record::new(columns, Value::from_row(row))
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<Value> {
+        self.values
+    }
+
+    /// The value of the column with the given name.
+    pub fn get(&self, column: &str) -> Option<&Value> {
+        self.columns
+            .iter()
+            .position(|c| c.eq_ignore_ascii_case(column))
+            .map(|i| &self.values[i])
+    }
+}
+
+/// The column names of a row, shareable between records of one result set.
+pub fn column_names(row: &Row) -> Arc<[String]> {
+    row.columns().iter().map(|c| c.name().to_string()).collect()
+}
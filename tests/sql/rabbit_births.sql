@@ -0,0 +1,18 @@
+-- test: register_rabbit_birth stores the rabbit
+-- setup
+DELETE FROM dbo.rabbit_births;
+INSERT dbo.rabbit_births (id, name, date_of_birth) VALUES (1, 'Bugs Bunny', '2023-01-01');
+-- action
+EXEC dbo.register_rabbit_birth @birth_date = '2023-08-24', @name = 'Lola Bunny';
+-- verify
+SELECT id, name, CAST(date_of_birth AS date) AS date_of_birth FROM dbo.rabbit_births ORDER BY id;
+-- expect
+| id | name       | date_of_birth |
+|----|------------|---------------|
+| 1  | Bugs Bunny | 2023-01-01    |
+| 2  | Lola Bunny | 2023-08-24    |
+
+-- test: register_rabbit_birth rejects an invalid date
+-- action
+EXEC dbo.register_rabbit_birth @birth_date = '2023-02-30', @name = 'Clyde Bunny';
+-- expect error 8114
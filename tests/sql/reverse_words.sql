@@ -0,0 +1,15 @@
+-- test: reverse_words reverses each word
+-- action
+SELECT RTRIM(dbo.reverse_words('hello big world')) AS result;
+-- expect
+| result          |
+| olleh gib dlrow |
+
+-- test: reverse_words keeps the word order
+-- action
+-- Compares the whole text: string_split only returns an ordinal to order
+-- the words by on SQL Server 2022 and later.
+SELECT RTRIM(dbo.reverse_words('ab cd ef')) AS result;
+-- expect csv
+result
+ba dc fe